@@ -0,0 +1,213 @@
+//! Allocator benchmarks.
+//!
+//! Run with `cargo bench`. The internal invariants are only validated after each operation
+//! with the `checks` feature, so comparing with `cargo bench --features checks` shows the
+//! cost of the validation.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use etagere::*;
+
+// A minimal stand-in for a benchmark harness: runs the closure for a fixed amount of time and
+// reports the best average duration of a few samples.
+fn bench<F: FnMut()>(name: &str, mut f: F) {
+    const SAMPLES: usize = 10;
+    const SAMPLE_DURATION: Duration = Duration::from_millis(100);
+
+    let mut best = Duration::MAX;
+    for _ in 0..SAMPLES {
+        let start = Instant::now();
+        let mut iterations = 0;
+        while start.elapsed() < SAMPLE_DURATION {
+            f();
+            iterations += 1;
+        }
+        best = best.min(start.elapsed() / iterations);
+    }
+
+    println!("{:<40} {:>12?}/iter", name, best);
+}
+
+// A xorshift generator, so that the traces are the same on every run.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self, max: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+
+        self.0 % max
+    }
+}
+
+enum Op {
+    Allocate(Size),
+    // Index in the list of live allocations.
+    Deallocate(u32),
+}
+
+// Record a trace of glyph-like allocations where a third of the operations free a random
+// live allocation.
+fn churn_trace(count: usize) -> Vec<Op> {
+    let mut rng = Rng(0x1234_5678);
+    let mut ops = Vec::with_capacity(count);
+    for _ in 0..count {
+        if rng.next(3) == 0 {
+            ops.push(Op::Deallocate(rng.next(u32::MAX)));
+        } else {
+            ops.push(Op::Allocate(size2(4 + rng.next(28) as i32, 8 + rng.next(24) as i32)));
+        }
+    }
+
+    ops
+}
+
+macro_rules! allocator_benches {
+    ($mod_name:ident, $name:expr, $Allocator:ty) => {
+        mod $mod_name {
+            use super::*;
+
+            pub fn allocate_uniform(atlas: &mut $Allocator) {
+                atlas.clear();
+                while let Some(alloc) = atlas.allocate(size2(16, 16)) {
+                    black_box(alloc);
+                }
+            }
+
+            pub fn churn(atlas: &mut $Allocator, trace: &[Op], live: &mut Vec<AllocId>) {
+                atlas.clear();
+                live.clear();
+                for op in trace {
+                    match *op {
+                        Op::Allocate(size) => {
+                            if let Some(alloc) = atlas.allocate(size) {
+                                live.push(alloc.id);
+                            }
+                        }
+                        Op::Deallocate(idx) if !live.is_empty() => {
+                            let id = live.swap_remove(idx as usize % live.len());
+                            atlas.deallocate(id);
+                        }
+                        Op::Deallocate(_) => {}
+                    }
+                }
+            }
+
+            // Fill the atlas with full-width shelves and free them every other one then the
+            // rest, so that each deallocation of the second pass merges shelves.
+            pub fn coalesce(atlas: &mut $Allocator, ids: &mut Vec<AllocId>) {
+                atlas.clear();
+                ids.clear();
+                let width = atlas.size().width;
+                while let Some(alloc) = atlas.allocate(size2(width, 16)) {
+                    ids.push(alloc.id);
+                }
+                for id in ids.iter().step_by(2) {
+                    atlas.deallocate(*id);
+                }
+                for id in ids.iter().skip(1).step_by(2) {
+                    atlas.deallocate(*id);
+                }
+                assert!(atlas.is_empty());
+            }
+
+            pub fn run(trace: &[Op]) {
+                let options = AllocatorOptions { num_columns: 1, ..DEFAULT_OPTIONS };
+
+                let mut atlas = <$Allocator>::new(size2(1024, 1024));
+                bench(concat!($name, "/allocate_uniform"), || allocate_uniform(&mut atlas));
+
+                let mut live = Vec::new();
+                bench(concat!($name, "/churn"), || churn(&mut atlas, trace, &mut live));
+
+                let mut atlas = <$Allocator>::with_options(size2(1024, 1024), &options);
+                let mut ids = Vec::new();
+                bench(concat!($name, "/coalesce"), || coalesce(&mut atlas, &mut ids));
+            }
+        }
+    };
+}
+
+allocator_benches!(allocator, "allocator", AtlasAllocator);
+allocator_benches!(bucketed, "bucketed", BucketedAtlasAllocator);
+
+// An atlas with thousands of mostly full shelves of various heights, in which a few rectangles
+// are allocated and deallocated again.
+fn many_shelves() {
+    let options = AllocatorOptions { num_columns: 16, ..DEFAULT_OPTIONS };
+    let mut atlas = AtlasAllocator::with_options(size2(4096, 4096), &options);
+    let mut rng = Rng(0x8765_4321);
+    while atlas.allocate(size2(250, 1 + rng.next(40) as i32)).is_some() {}
+
+    let mut ids = Vec::new();
+    bench("allocator/many_shelves", || {
+        for i in 0..16 {
+            if let Some(alloc) = atlas.allocate(size2(4, 8 + i)) {
+                ids.push(alloc.id);
+            }
+        }
+        for id in ids.drain(..) {
+            atlas.deallocate(id);
+        }
+    });
+}
+
+// Free all of the items of an atlas filled with glyph-like items, from the most recent one,
+// either one by one (garbage-collecting the shelves as they become empty) or as a batch.
+fn deallocate_many() {
+    let mut full = BucketedAtlasAllocator::new(size2(1024, 1024));
+    let mut rng = Rng(0x5eed_1234);
+    let mut ids = Vec::new();
+    while let Some(alloc) = full.allocate(size2(4 + rng.next(12) as i32, 8 + rng.next(8) as i32)) {
+        ids.push(alloc.id);
+    }
+    ids.reverse();
+
+    bench("bucketed/deallocate", || {
+        let mut atlas = full.clone();
+        for &id in &ids {
+            atlas.deallocate(id);
+        }
+        black_box(atlas);
+    });
+    bench("bucketed/deallocate_many", || {
+        let mut atlas = full.clone();
+        atlas.deallocate_many(&ids);
+        black_box(atlas);
+    });
+}
+
+// Fill an atlas with items of mixed sizes until an allocation fails with each placement
+// strategy, reporting the proportion of the atlas covered by the requested sizes.
+fn placement() {
+    for &(name, placement) in &[("bucketed/best_fit", Placement::BestFit), ("bucketed/first_fit", Placement::FirstFit)] {
+        let options = AllocatorOptions { placement, num_columns: 4, ..DEFAULT_OPTIONS };
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(2048, 2048), &options);
+        let mut used_area = 0;
+        bench(name, || {
+            atlas.clear();
+            used_area = 0;
+            let mut rng = Rng(0x0bad_cafe);
+            loop {
+                let size = size2(4 + rng.next(28) as i32, 4 + rng.next(60) as i32);
+                if atlas.allocate(size).is_none() {
+                    break;
+                }
+                used_area += size.area();
+            }
+        });
+        println!("{:<40} {:>11.1}% used", name, used_area as f32 * 100.0 / (2048.0 * 2048.0));
+    }
+}
+
+fn main() {
+    let trace = churn_trace(10_000);
+
+    allocator::run(&trace);
+    bucketed::run(&trace);
+    many_shelves();
+    deallocate_many();
+    placement();
+}
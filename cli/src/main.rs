@@ -59,7 +59,7 @@ fn main() {
             )
             .arg(Arg::with_name("ALIGN_Y")
                 .long("align-y")
-                .help("Round up the width of the allocated rectangle to a multiple of the provided value.")
+                .help("Round up the height of the allocated rectangle to a multiple of the provided value.")
                 .value_name("ALIGN_Y")
                 .takes_value(true)
                 .required(false)
@@ -171,19 +171,26 @@ fn main() {
                 .value_name("ATLAS")
                 .takes_value(true)
              )
+            .arg(Arg::with_name("ASCII")
+                .long("ascii")
+                .help("Also print the layout of the atlas as text.")
+                .value_name("ASCII")
+                .takes_value(false)
+                .required(false)
+            )
         )
         .get_matches();
 
     if let Some(cmd) = matches.subcommand_matches("init") {
-        init(&cmd);
+        init(cmd);
     } else if let Some(cmd) = matches.subcommand_matches("allocate") {
-        allocate(&cmd);
+        allocate(cmd);
     } else if let Some(cmd) = matches.subcommand_matches("deallocate") {
-        deallocate(&cmd);
+        deallocate(cmd);
     } else if let Some(cmd) = matches.subcommand_matches("svg") {
-        svg(&cmd);
+        svg(cmd);
     } else if let Some(cmd) = matches.subcommand_matches("list") {
-        list(&cmd);
+        list(cmd);
     }
 }
 
@@ -229,13 +236,13 @@ fn init(args: &ArgMatches) {
                 .unwrap_or(default_options.alignment.width),
             args.value_of("ALIGN_Y")
                 .map(|s| s.parse::<i32>().unwrap())
-                .unwrap_or(default_options.alignment.width),
+                .unwrap_or(default_options.alignment.height),
         ),
         vertical_shelves: args.is_present("VERTICAL_SHELVES"),
-        num_columns: args.value_of("ALIGN_X")
+        num_columns: args.value_of("COLUMNS")
             .map(|s| s.parse::<i32>().unwrap())
             .unwrap_or(default_options.num_columns),
-
+        ..default_options
     };
 
     let session = Session {
@@ -244,7 +251,7 @@ fn init(args: &ArgMatches) {
         next_id: 0,
     };
 
-    write_atlas(&session, &args);
+    write_atlas(&session, args);
 
     if args.is_present("SVG_OUTPUT") {
         svg(args);
@@ -323,15 +330,33 @@ fn svg(args: &ArgMatches) {
 fn list(args: &ArgMatches) {
     let session = read_atlas(args);
 
+    let mut names: Vec<&String> = session.names.keys().collect();
+    names.sort();
+
     println!("# Allocated rectangles");
-    for (name, &alloc) in &session.names {
+    for name in names {
+        let alloc = session.names[name];
         let rect = alloc.rectangle;
         println!(
             " - {}: size {}x{} at origin [{}, {}]",
             name, rect.size().width, rect.size().height, rect.min.x, rect.min.y
         );
 
-        break;
+        if !session.atlas.contains(alloc.id) {
+            eprintln!("Warning: {} is not allocated in the atlas.", name);
+        } else if session.atlas.get(alloc.id) != rect {
+            eprintln!("Warning: {} doesn't match the rectangle allocated in the atlas.", name);
+        }
     }
-}
 
+    if args.is_present("ASCII") {
+        // Fit the output in 64 columns, with cells twice as tall as wide like terminal characters.
+        let size = session.atlas.size();
+        let cell_width = ((size.width + 63) / 64).max(1);
+        let mut output = String::new();
+        session.atlas.dump_ascii(size2(cell_width, cell_width * 2), &mut output);
+
+        println!("# Layout");
+        print!("{}", output);
+    }
+}
@@ -0,0 +1,25 @@
+//! Fill an atlas with rectangles of various sizes and write it to `atlas.png`.
+//!
+//! Run with `cargo run --example dump_png --features png`.
+
+use etagere::*;
+
+fn main() -> std::io::Result<()> {
+    let mut atlas = BucketedAtlasAllocator::new(size2(512, 512));
+
+    let mut ids = Vec::new();
+    for i in 0..200 {
+        let size = size2(8 + (i * 7) % 40, 8 + (i * 13) % 30);
+        if let Some(alloc) = atlas.allocate(size) {
+            ids.push(alloc.id);
+        }
+    }
+
+    // Leave some holes.
+    for id in ids.iter().step_by(5) {
+        atlas.deallocate(*id);
+    }
+
+    let mut file = std::fs::File::create("atlas.png")?;
+    atlas.dump_png(&mut file)
+}
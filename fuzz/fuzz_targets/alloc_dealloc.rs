@@ -21,6 +21,7 @@ fuzz_target!(|events: Vec<Evt>| {
             alignment: size2(4, 8),
             vertical_shelves: false,
             num_columns: 2,
+            ..DEFAULT_OPTIONS
         },
     );
 
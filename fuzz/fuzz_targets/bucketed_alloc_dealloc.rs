@@ -16,12 +16,15 @@ enum Evt {
 }
 
 fuzz_target!(|events: Vec<Evt>| {
+    let padding = 2;
     let mut atlas = BucketedAtlasAllocator::with_options(
         size2(2048, 2048),
         &AllocatorOptions {
             alignment: size2(4, 8),
             vertical_shelves: false,
             num_columns: 2,
+            padding,
+            ..DEFAULT_OPTIONS
         },
     );
 
@@ -35,7 +38,10 @@ fuzz_target!(|events: Vec<Evt>| {
                     assert!(alloc.rectangle.size().height >= h);
 
                     for previous in &allocations {
-                        assert!(!alloc.rectangle.intersects(&previous.rectangle));
+                        // Allocations are at least 2 * padding apart.
+                        let a = alloc.rectangle.inflate(padding, padding);
+                        let b = previous.rectangle.inflate(padding, padding);
+                        assert!(!a.intersects(&b));
                     }
 
                     allocations.push(alloc);
@@ -0,0 +1,94 @@
+#![no_main]
+
+#[macro_use]
+extern crate arbitrary;
+
+use libfuzzer_sys::fuzz_target;
+use libfuzzer_sys::arbitrary::Arbitrary;
+
+
+use etagere::*;
+
+#[derive(Copy, Clone, Arbitrary, Debug)]
+enum Evt {
+    Alloc(i32, i32),
+    // Allocate an item almost as tall as the atlas, which forces coalescing empty shelves.
+    AllocTall(u8, u8),
+    Dealloc(usize),
+    Grow(u16, u16),
+    Clear,
+}
+
+fuzz_target!(|events: Vec<Evt>| {
+    let mut atlas = BucketedAtlasAllocator::with_options(
+        size2(1024, 1024),
+        &AllocatorOptions {
+            alignment: size2(4, 8),
+            vertical_shelves: false,
+            num_columns: 2,
+            ..DEFAULT_OPTIONS
+        },
+    );
+
+    let mut allocations: Vec<Allocation> = Vec::new();
+
+    for evt in &events {
+        match *evt {
+            Evt::Alloc(w, h) => {
+                if let Some(alloc) = atlas.allocate(size2(w, h)) {
+                    assert!(alloc.rectangle.size().width >= w);
+                    assert!(alloc.rectangle.size().height >= h);
+                    allocations.push(alloc);
+                }
+            }
+            Evt::AllocTall(w, h) => {
+                let size = size2(w as i32 + 1, atlas.size().height - h as i32);
+                if let Some(alloc) = atlas.allocate(size) {
+                    assert!(alloc.rectangle.size().width >= size.width);
+                    assert!(alloc.rectangle.size().height >= size.height);
+                    allocations.push(alloc);
+                }
+            }
+            Evt::Dealloc(idx) => {
+                if !allocations.is_empty() {
+                    let idx = idx % allocations.len();
+
+                    atlas.deallocate(allocations[idx].id);
+                    allocations.swap_remove(idx);
+                }
+            }
+            Evt::Grow(w, h) => {
+                let size = atlas.size();
+                let new_size = size2(
+                    (size.width + (w % 512) as i32).min(8192),
+                    (size.height + (h % 512) as i32).min(8192),
+                );
+
+                let moved = atlas.grow(new_size);
+                for (id, rectangle) in moved {
+                    let alloc = allocations.iter_mut().find(|alloc| alloc.id == id).unwrap();
+                    alloc.rectangle = rectangle;
+                }
+            }
+            Evt::Clear => {
+                atlas.clear();
+                allocations.clear();
+            }
+        }
+
+        let bounds = Rectangle { min: point2(0, 0), max: atlas.size().to_vector().to_point() };
+        for (i, alloc) in allocations.iter().enumerate() {
+            assert!(bounds.contains_box(&alloc.rectangle));
+            for other in &allocations[i + 1..] {
+                assert!(!alloc.rectangle.intersects(&other.rectangle));
+            }
+        }
+    }
+
+    for alloc in allocations {
+        atlas.deallocate(alloc.id);
+    }
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+});
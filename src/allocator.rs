@@ -1,7 +1,24 @@
-use crate::{AllocId, Allocation, AllocatorOptions, DEFAULT_OPTIONS, Size, Rectangle, point2, size2};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::cmp::Reverse;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::{AllocError, AllocId, Allocation, AllocatorOptions, DEFAULT_OPTIONS, DeallocError, NonEmpty, Point, Size, Rectangle, point2, size2};
+use crate::{atlas_size, format_allocation_table, format_ascii, pow2_size, trim_allocation};
+#[cfg(feature = "std")]
+use crate::{write_svg_label, SvgOptions};
+use crate::{cmp_spatial, FrozenAtlas, ValidationError, DEBUG_MAX_SHELVES};
+#[cfg(test)]
+use crate::testing::random_operations;
 
 const SHELF_SPLIT_THRESHOLD: u16 = 8;
 const ITEM_SPLIT_THRESHOLD: u16 = 8;
+const NUM_HEIGHT_BUCKETS: usize = 32;
+const NO_BUCKET: u8 = u8::MAX;
 
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -9,13 +26,13 @@ const ITEM_SPLIT_THRESHOLD: u16 = 8;
 struct ShelfIndex(u16);
 
 impl ShelfIndex {
-    const NONE: Self = ShelfIndex(std::u16::MAX);
+    const NONE: Self = ShelfIndex(u16::MAX);
 
     fn index(self) -> usize { self.0 as usize }
 
-    fn is_some(self) -> bool { self.0 != std::u16::MAX }
+    fn is_some(self) -> bool { self.0 != u16::MAX }
 
-    fn is_none(self) -> bool { self.0 == std::u16::MAX }
+    fn is_none(self) -> bool { self.0 == u16::MAX }
 }
 
 #[repr(transparent)]
@@ -24,13 +41,13 @@ impl ShelfIndex {
 struct ItemIndex(u16);
 
 impl ItemIndex {
-    const NONE: Self = ItemIndex(std::u16::MAX);
+    const NONE: Self = ItemIndex(u16::MAX);
 
     fn index(self) -> usize { self.0 as usize }
 
-    fn is_some(self) -> bool { self.0 != std::u16::MAX }
+    fn is_some(self) -> bool { self.0 != u16::MAX }
 
-    fn is_none(self) -> bool { self.0 == std::u16::MAX }
+    fn is_none(self) -> bool { self.0 == u16::MAX }
 }
 
 #[derive(Clone)]
@@ -44,6 +61,10 @@ struct Shelf {
     first_item: ItemIndex,
     first_unallocated: ItemIndex,
     is_empty: bool,
+    /// The height bucket the shelf is in, or `NO_BUCKET` if it has no unallocated item.
+    bucket: u8,
+    prev_in_bucket: ShelfIndex,
+    next_in_bucket: ShelfIndex,
 }
 
 #[derive(Clone)]
@@ -58,31 +79,128 @@ struct Item {
     shelf: ShelfIndex,
     allocated: bool,
     generation: u16,
+    /// The area of the size that was requested, before alignment and rounding.
+    requested_area: i32,
 }
 
-// Note: if allocating is slow we can use the guillotiere trick of storing multiple lists of free
-// rects (per shelf height) instead of iterating the shelves and items.
+// The shelves that have unallocated items are kept in lists bucketed by shelf height (see
+// `height_bucket`), so that allocating only visits the shelves of the smallest height class
+// that has room for the requested size instead of all shelves.
 
 /// A shelf-packing dynamic texture atlas allocator tracking each allocation individually and with support
 /// for coalescing empty shelves.
+///
+/// Each allocation can optionally carry some user data of type `T`, which is reset to
+/// `T::default()` when the allocation is created and dropped when it is deallocated.
+/// The data is stored alongside the items so that caches don't need a separate map from
+/// `AllocId` to their payload, and can update all of it in one pass with `for_each_data_mut`.
+/// `T` defaults to `()`, so code that doesn't use it is unaffected and `new` and
+/// `with_options` still create an `AtlasAllocator<()>`.
+///
+/// ```
+/// use etagere::*;
+/// // An atlas storing the frame each allocation was last used in.
+/// let mut atlas = AtlasAllocator::<u64>::with_user_data(size2(1024, 1024), &DEFAULT_OPTIONS);
+/// let alloc = atlas.allocate(size2(32, 32)).unwrap();
+/// *atlas.get_data_mut(alloc.id) = 42;
+/// atlas.for_each_data_mut(|_, frame| *frame += 1);
+/// assert_eq!(*atlas.get_data(alloc.id), 43);
+/// ```
 #[derive(Clone)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
-pub struct AtlasAllocator {
+pub struct AtlasAllocator<T = ()> {
     shelves: Vec<Shelf>,
     items: Vec<Item>,
+    // User data, stored in a parallel vector indexed like `items`. The data of items
+    // that aren't allocated is always `T::default()`.
+    data: Vec<T>,
     alignment: Size,
+    /// See `AllocatorOptions::padding`.
+    padding: u16,
     flip_xy: bool,
     size: Size,
     first_shelf: ShelfIndex,
     free_items: ItemIndex,
     free_shelves: ShelfIndex,
+    /// The first shelf of each height bucket.
+    buckets: [ShelfIndex; NUM_HEIGHT_BUCKETS],
     shelf_width: u16,
     allocated_space: i32,
+    fixed_shelf_height: Option<u16>,
+    /// Sum of the areas of the sizes requested by the live allocations.
+    requested_space: i32,
+    white_pixel: Option<Rectangle>,
+    /// The highest generation of the items released by `trim_unused_storage`. Items pushed at
+    /// the end of the storage start above it so that the ids of the released items stay invalid.
+    trimmed_generation: u16,
+    /// See `max_allocatable`.
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    max_allocatable: Cell<Option<MaxAllocatable>>,
+}
+
+// The cached result of `max_allocatable`, along with what is needed to check that the
+// free item it was computed from hasn't changed.
+#[derive(Copy, Clone)]
+struct MaxAllocatable {
+    /// NONE if nothing can be allocated.
+    item: ItemIndex,
+    x: u16,
+    width: u16,
+    shelf_height: u16,
+    size: Size,
 }
 
 impl AtlasAllocator {
     /// Create an atlas allocator with provided options.
     pub fn with_options(size: Size, options: &AllocatorOptions) -> Self {
+        Self::with_user_data(size, options)
+    }
+
+    /// Create an atlas allocator with default options.
+    pub fn new(size: Size) -> Self {
+        Self::with_options(size, &DEFAULT_OPTIONS)
+    }
+
+    /// Create an atlas allocator with some rectangles allocated up front.
+    ///
+    /// This is useful for regions that are always present in the atlas, for example a white
+    /// pixel used for solid fills. Each rectangle is allocated with `allocate_at` and the ids
+    /// are returned in the same order. Returns `None` if any of the rectangles can't be
+    /// allocated.
+    pub fn with_reserved(size: Size, options: &AllocatorOptions, reserved: &[Rectangle]) -> Option<(Self, Vec<AllocId>)> {
+        let mut atlas = Self::with_options(size, options);
+
+        let mut ids = Vec::with_capacity(reserved.len());
+        for rect in reserved {
+            ids.push(atlas.allocate_at(*rect)?.id);
+        }
+
+        Some((atlas, ids))
+    }
+}
+
+impl<T: Default> AtlasAllocator<T> {
+    /// Create an atlas allocator with provided options, storing user data of type `T`
+    /// for each allocation.
+    ///
+    /// ```
+    /// use etagere::*;
+    /// let atlas = AtlasAllocator::<u32>::with_user_data(size2(1024, 1024), &DEFAULT_OPTIONS);
+    /// # assert!(atlas.is_empty());
+    /// ```
+    pub fn with_user_data(size: Size, options: &AllocatorOptions) -> Self {
+        Self::with_storage(size, options, Vec::new(), Vec::new(), Vec::new())
+    }
+
+    // Create an atlas reusing the memory of existing vectors.
+    fn with_storage(
+        size: Size,
+        options: &AllocatorOptions,
+        shelves: Vec<Shelf>,
+        items: Vec<Item>,
+        data: Vec<T>,
+    ) -> Self {
+        let size = atlas_size(size, options);
         let (shelf_alignment, width, height) = if options.vertical_shelves {
             (options.alignment.height, size.height, size.width)
         } else {
@@ -91,17 +209,35 @@ impl AtlasAllocator {
         let mut shelf_width = width / options.num_columns;
         shelf_width -= shelf_width % shelf_alignment;
 
+        assert!(options.padding >= 0 && options.padding <= u16::MAX as i32 / 2);
+        let padding = options.padding;
+
         let mut atlas = AtlasAllocator {
-            shelves: Vec::new(),
-            items: Vec::new(),
+            shelves,
+            items,
+            data,
             size: size2(width, height),
             alignment: options.alignment,
+            padding: padding as u16,
             flip_xy: options.vertical_shelves,
             first_shelf: ShelfIndex(0),
             free_items: ItemIndex::NONE,
             free_shelves: ShelfIndex::NONE,
+            buckets: [ShelfIndex::NONE; NUM_HEIGHT_BUCKETS],
             shelf_width: shelf_width as u16,
             allocated_space: 0,
+            fixed_shelf_height: options.fixed_shelf_height,
+            requested_space: 0,
+            trimmed_generation: 0,
+            white_pixel: if options.reserve_white_pixel {
+                Some(Rectangle {
+                    min: point2(padding, padding),
+                    max: point2(padding + options.alignment.width, padding + options.alignment.height),
+                })
+            } else {
+                None
+            },
+            max_allocatable: Cell::new(None),
         };
 
         atlas.init();
@@ -109,20 +245,515 @@ impl AtlasAllocator {
         atlas
     }
 
-    /// Create an atlas allocator with default options.
-    pub fn new(size: Size) -> Self {
-        Self::with_options(size, &DEFAULT_OPTIONS)
-    }
-
     pub fn clear(&mut self) {
         self.init();
     }
 
+    /// Apply new options to an empty atlas, keeping its size.
+    ///
+    /// This is equivalent to creating a new atlas with the provided options, but reuses
+    /// the allocated memory. Returns `NonEmpty` if the atlas contains allocations.
+    pub fn reconfigure(&mut self, options: &AllocatorOptions) -> Result<(), NonEmpty> {
+        if !self.is_empty() {
+            return Err(NonEmpty);
+        }
+
+        let size = self.size();
+        let shelves = core::mem::take(&mut self.shelves);
+        let items = core::mem::take(&mut self.items);
+        let data = core::mem::take(&mut self.data);
+
+        *self = Self::with_storage(size, options, shelves, items, data);
+
+        Ok(())
+    }
+
+    /// Grow the atlas to a larger size.
+    ///
+    /// Existing allocations keep their ids and rectangles. The new height is added to the
+    /// top-most shelf of each column if it is empty, or as a new empty shelf otherwise. When
+    /// the atlas has a single column, the new width is added at the end of every shelf,
+    /// otherwise as many new columns as possible are added.
+    pub fn grow(&mut self, new_size: Size) {
+        let (new_width, new_height) = convert_coordinates(self.flip_xy, new_size.width, new_size.height);
+        assert!(new_width >= self.size.width);
+        assert!(new_height >= self.size.height);
+        assert!(new_width <= u16::MAX as i32);
+        assert!(new_height <= u16::MAX as i32);
+        assert!(
+            new_width.checked_mul(new_height).is_some(),
+            "The area of the atlas must fit in a i32 value"
+        );
+
+        let old_height = self.size.height as u16;
+        let num_columns = self.size.width as u16 / self.shelf_width;
+
+        if new_height > self.size.height {
+            let additional_height = new_height as u16 - old_height;
+
+            let mut top_shelves = Vec::new();
+            let mut shelf_idx = self.first_shelf;
+            while shelf_idx.is_some() {
+                let shelf = &self.shelves[shelf_idx.index()];
+                if shelf.y + shelf.height == old_height {
+                    top_shelves.push(shelf_idx);
+                }
+                shelf_idx = shelf.next;
+            }
+
+            for shelf_idx in top_shelves {
+                if self.shelves[shelf_idx.index()].is_empty {
+                    self.shelves[shelf_idx.index()].height += additional_height;
+                    self.update_bucket(shelf_idx);
+                } else {
+                    self.insert_empty_shelf_after(shelf_idx, old_height, additional_height);
+                }
+            }
+        }
+        self.size.height = new_height;
+
+        if new_width > self.size.width && num_columns == 1 {
+            let (shelf_alignment, _) = convert_coordinates(self.flip_xy, self.alignment.width, self.alignment.height);
+            let new_shelf_width = (new_width - new_width % shelf_alignment) as u16;
+            let additional_width = new_shelf_width - self.shelf_width;
+
+            let mut shelf_idx = self.first_shelf;
+            while shelf_idx.is_some() && additional_width > 0 {
+                let mut last_item = self.shelves[shelf_idx.index()].first_item;
+                while self.items[last_item.index()].next.is_some() {
+                    last_item = self.items[last_item.index()].next;
+                }
+
+                if !self.items[last_item.index()].allocated {
+                    self.items[last_item.index()].width += additional_width;
+                } else {
+                    let first_unallocated = self.shelves[shelf_idx.index()].first_unallocated;
+                    let new_item = self.add_item(Item {
+                        x: self.shelf_width,
+                        width: additional_width,
+                        prev: last_item,
+                        next: ItemIndex::NONE,
+                        prev_unallocated: ItemIndex::NONE,
+                        next_unallocated: first_unallocated,
+                        shelf: shelf_idx,
+                        allocated: false,
+                        generation: 1,
+                        requested_area: 0,
+                    });
+                    self.items[last_item.index()].next = new_item;
+                    if first_unallocated.is_some() {
+                        self.items[first_unallocated.index()].prev_unallocated = new_item;
+                    }
+                    self.shelves[shelf_idx.index()].first_unallocated = new_item;
+                    self.update_bucket(shelf_idx);
+                }
+
+                shelf_idx = self.shelves[shelf_idx.index()].next;
+            }
+
+            self.shelf_width = new_shelf_width;
+        } else if new_width > self.size.width {
+            let new_num_columns = new_width as u16 / self.shelf_width;
+
+            let mut last_shelf = self.first_shelf;
+            while self.shelves[last_shelf.index()].next.is_some() {
+                last_shelf = self.shelves[last_shelf.index()].next;
+            }
+
+            for column in num_columns..new_num_columns {
+                let x = column * self.shelf_width;
+                last_shelf = self.insert_empty_shelf_after(last_shelf, 0, new_height as u16);
+                self.shelves[last_shelf.index()].x = x;
+                let item = self.shelves[last_shelf.index()].first_item;
+                self.items[item.index()].x = x;
+            }
+        }
+        self.size.width = new_width;
+
+        self.max_allocatable.set(None);
+
+        self.check();
+    }
+
+    /// Shrink the atlas to the smallest size that covers all of the live allocations.
+    ///
+    /// Existing allocations keep their ids and rectangles. The size is rounded up to the
+    /// alignment, and when the atlas has multiple columns only the columns on the right that
+    /// are entirely empty are removed. Returns the new size, which is the current size if the
+    /// atlas can't be made smaller (for example if it is empty).
+    pub fn shrink_to_fit(&mut self) -> Size {
+        self.shrink_impl(false)
+    }
+
+    /// Same as `shrink_to_fit` but rounds the new width and height up to powers of two.
+    pub fn shrink_to_fit_pow2(&mut self) -> Size {
+        self.shrink_impl(true)
+    }
+
+    fn shrink_impl(&mut self, pow2: bool) -> Size {
+        let mut max_x = 0;
+        let mut max_y = 0;
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+            if !shelf.is_empty {
+                max_y = max_y.max(shelf.y + shelf.height);
+
+                let mut item_idx = shelf.first_item;
+                while item_idx.is_some() {
+                    let item = &self.items[item_idx.index()];
+                    if item.allocated {
+                        max_x = max_x.max(item.x + item.width);
+                    }
+                    item_idx = item.next;
+                }
+            }
+
+            shelf_idx = shelf.next;
+        }
+
+        if max_x == 0 {
+            return self.size();
+        }
+
+        let num_columns = self.size.width as u16 / self.shelf_width;
+        let (shelf_alignment, _) = convert_coordinates(self.flip_xy, self.alignment.width, self.alignment.height);
+
+        let mut new_width = if num_columns == 1 {
+            max_x as i32 + (shelf_alignment - max_x as i32 % shelf_alignment) % shelf_alignment
+        } else {
+            let sw = self.shelf_width as i32;
+            (max_x as i32 + sw - 1) / sw * sw
+        };
+        let mut new_height = max_y as i32;
+        if pow2 {
+            new_width = (new_width as u32).next_power_of_two() as i32;
+            new_height = (new_height as u32).next_power_of_two() as i32;
+        }
+        new_width = new_width.min(self.size.width);
+        new_height = new_height.min(self.size.height);
+
+        if new_width == self.size.width && new_height == self.size.height {
+            return self.size();
+        }
+
+        let new_shelf_width = if num_columns == 1 {
+            (new_width - new_width % shelf_alignment) as u16
+        } else {
+            self.shelf_width
+        };
+        // Columns starting at or after this are removed.
+        let max_column_x = if num_columns == 1 {
+            u16::MAX
+        } else {
+            new_width as u16 / self.shelf_width * self.shelf_width
+        };
+
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = self.shelves[shelf_idx.index()].clone();
+            let next_idx = shelf.next;
+
+            if shelf.x >= max_column_x || shelf.y as i32 >= new_height {
+                // Only empty shelves can be outside of the new size.
+                debug_assert!(shelf.is_empty);
+                if shelf.prev.is_some() {
+                    self.shelves[shelf.prev.index()].next = shelf.next;
+                } else {
+                    self.first_shelf = shelf.next;
+                }
+                if shelf.next.is_some() {
+                    self.shelves[shelf.next.index()].prev = shelf.prev;
+                }
+
+                self.remove_shelf(shelf_idx);
+                shelf_idx = next_idx;
+                continue;
+            }
+
+            if (shelf.y + shelf.height) as i32 > new_height {
+                self.shelves[shelf_idx.index()].height = new_height as u16 - shelf.y;
+            }
+
+            if new_shelf_width != self.shelf_width {
+                let mut last_item = shelf.first_item;
+                while self.items[last_item.index()].next.is_some() {
+                    last_item = self.items[last_item.index()].next;
+                }
+
+                let item = self.items[last_item.index()].clone();
+                if !item.allocated {
+                    if item.x >= new_shelf_width {
+                        self.items[item.prev.index()].next = ItemIndex::NONE;
+                        self.remove_from_unallocated_list(last_item);
+                        self.remove_item(last_item);
+                    } else {
+                        self.items[last_item.index()].width = new_shelf_width - item.x;
+                    }
+                }
+            }
+
+            self.update_bucket(shelf_idx);
+            shelf_idx = next_idx;
+        }
+
+        self.shelf_width = new_shelf_width;
+        self.size = size2(new_width, new_height);
+
+        self.max_allocatable.set(None);
+
+        self.check();
+
+        self.size()
+    }
+
+    // Add an empty shelf in the same column as shelf_idx and right after it in the list of
+    // shelves.
+    fn insert_empty_shelf_after(&mut self, shelf_idx: ShelfIndex, y: u16, height: u16) -> ShelfIndex {
+        let shelf = self.shelves[shelf_idx.index()].clone();
+
+        let new_shelf_idx = self.add_shelf(Shelf {
+            x: shelf.x,
+            y,
+            height,
+            prev: shelf_idx,
+            next: shelf.next,
+            first_item: ItemIndex::NONE,
+            first_unallocated: ItemIndex::NONE,
+            is_empty: true,
+            bucket: NO_BUCKET,
+            prev_in_bucket: ShelfIndex::NONE,
+            next_in_bucket: ShelfIndex::NONE,
+        });
+
+        let new_item_idx = self.add_item(Item {
+            x: shelf.x,
+            width: self.shelf_width,
+            prev: ItemIndex::NONE,
+            next: ItemIndex::NONE,
+            prev_unallocated: ItemIndex::NONE,
+            next_unallocated: ItemIndex::NONE,
+            shelf: new_shelf_idx,
+            allocated: false,
+            generation: 1,
+            requested_area: 0,
+        });
+
+        self.shelves[new_shelf_idx.index()].first_item = new_item_idx;
+        self.shelves[new_shelf_idx.index()].first_unallocated = new_item_idx;
+        self.shelves[shelf_idx.index()].next = new_shelf_idx;
+
+        if shelf.next.is_some() {
+            self.shelves[shelf.next.index()].prev = new_shelf_idx;
+        }
+
+        self.update_bucket(new_shelf_idx);
+
+        new_shelf_idx
+    }
+
+    /// Re-pack the live allocations into a new atlas of the provided size and options.
+    ///
+    /// This is the clean-slate alternative to growing an atlas in place, for when all of the
+    /// content is going to be uploaded again anyway. The allocations are sorted by decreasing
+    /// height and width before being allocated, which usually packs them more densely than the
+    /// order in which they were first allocated. The user data is moved to the new allocations.
+    ///
+    /// Returns the new atlas, a map from the old ids to the new allocations, and the old ids of
+    /// the allocations that didn't fit (their user data is dropped). The white pixel is not part
+    /// of the map: the new atlas reserves its own if `options` request it.
+    #[cfg(feature = "std")]
+    pub fn reallocate(self, new_size: Size, options: &AllocatorOptions) -> (Self, HashMap<AllocId, Allocation>, Vec<AllocId>) {
+        let (atlas, moved, failed) = self.reallocate_impl(new_size, options);
+        let moved = moved.into_iter().map(|change| (change.old.id, change.new)).collect();
+        let failed = failed.into_iter().map(|alloc| alloc.id).collect();
+
+        (atlas, moved, failed)
+    }
+
+    // Returns the new atlas, the allocations that were moved and the ones that didn't fit,
+    // in the order they were allocated.
+    fn reallocate_impl(mut self, new_size: Size, options: &AllocatorOptions) -> (Self, Vec<Change>, Vec<Allocation>) {
+        let white_pixel = self.white_pixel;
+        let mut allocations: Vec<Allocation> = self.iter().filter(|alloc| Some(alloc.rectangle) != white_pixel).collect();
+        allocations.sort_by_key(|alloc| {
+            let size = alloc.rectangle.size();
+            if options.vertical_shelves {
+                (Reverse(size.width), Reverse(size.height))
+            } else {
+                (Reverse(size.height), Reverse(size.width))
+            }
+        });
+
+        let mut atlas = Self::with_user_data(new_size, options);
+        let mut moved = Vec::with_capacity(allocations.len());
+        let mut failed = Vec::new();
+        for old in allocations {
+            let new = match atlas.allocate(old.rectangle.size()) {
+                Some(new) => new,
+                None => {
+                    failed.push(old);
+                    continue;
+                }
+            };
+
+            let old_index = old.id.index() as usize;
+            let new_index = new.id.index() as usize;
+
+            // Keep track of the originally requested size rather than the rounded one.
+            let requested_area = self.items[old_index].requested_area;
+            atlas.requested_space += requested_area - atlas.items[new_index].requested_area;
+            atlas.items[new_index].requested_area = requested_area;
+
+            atlas.data[new_index] = core::mem::take(&mut self.data[old_index]);
+            moved.push(Change { old, new });
+        }
+
+        atlas.check();
+
+        (atlas, moved, failed)
+    }
+
+    /// Re-pack the live allocations in place to reduce fragmentation.
+    ///
+    /// This is `reallocate` with the current size and options of the atlas: the allocations
+    /// are sorted by decreasing size and allocated again from an empty atlas, keeping their
+    /// user data. The returned changes can be used to copy the content of the allocations
+    /// from their old to their new rectangles. The old ids become invalid (some of them may
+    /// refer to different allocations afterwards). The white pixel, if any, doesn't move.
+    pub fn rearrange(&mut self) -> RearrangeResult {
+        let size = self.size();
+        let options = self.options();
+
+        let atlas = core::mem::replace(self, Self::with_user_data(size, &options));
+        let (atlas, mut changes, mut failures) = atlas.reallocate_impl(size, &options);
+        *self = atlas;
+
+        changes.sort_unstable_by_key(|change| change.old.id.index());
+        failures.sort_unstable_by_key(|alloc| alloc.id.index());
+
+        RearrangeResult { changes, failures }
+    }
+
+    /// Allocate the content of another atlas at an offset.
+    ///
+    /// Each live allocation of `other` (except its white pixel) is allocated with
+    /// `allocate_at` at its rectangle translated by `offset`, and its user data is copied.
+    /// This is typically used to move the content of an atlas into a larger one. Returns the
+    /// pairs of ids of `other` and the corresponding new ids, or an empty vector if any of
+    /// the rectangles can't be allocated, in which case this atlas is left unchanged.
+    ///
+    /// The new allocations cover the whole height of the shelves they are placed in, so they
+    /// can be larger than the translated rectangles if the shelves already contain allocations.
+    pub fn absorb(&mut self, other: &AtlasAllocator<T>, offset: Point) -> Vec<(AllocId, AllocId)>
+    where
+        T: Clone,
+    {
+        let backup = self.clone();
+        let mut ids = Vec::with_capacity(other.allocation_count());
+        for old in other.iter() {
+            if Some(old.rectangle) == other.white_pixel {
+                continue;
+            }
+
+            let new = match self.allocate_at(old.rectangle.translate(offset.to_vector())) {
+                Some(new) => new,
+                None => {
+                    *self = backup;
+                    return Vec::new();
+                }
+            };
+
+            let old_index = old.id.index() as usize;
+            let new_index = new.id.index() as usize;
+
+            let requested_area = other.items[old_index].requested_area;
+            self.requested_space += requested_area - self.items[new_index].requested_area;
+            self.items[new_index].requested_area = requested_area;
+            self.data[new_index] = other.data[old_index].clone();
+
+            ids.push((old.id, new.id));
+        }
+
+        ids
+    }
+
+    /// Save the state of the atlas so that it can be rolled back with `restore`.
+    ///
+    /// This is typically used to try a sequence of allocations and undo it if the result is
+    /// not satisfactory.
+    pub fn snapshot(&self) -> AtlasSnapshot<T>
+    where
+        T: Clone,
+    {
+        AtlasSnapshot {
+            shelves: self.shelves.clone(),
+            items: self.items.clone(),
+            data: self.data.clone(),
+            alignment: self.alignment,
+            padding: self.padding,
+            flip_xy: self.flip_xy,
+            size: self.size,
+            first_shelf: self.first_shelf,
+            free_items: self.free_items,
+            free_shelves: self.free_shelves,
+            buckets: self.buckets,
+            shelf_width: self.shelf_width,
+            allocated_space: self.allocated_space,
+            fixed_shelf_height: self.fixed_shelf_height,
+            requested_space: self.requested_space,
+            white_pixel: self.white_pixel,
+        }
+    }
+
+    /// Roll the atlas back to the state it was in when the snapshot was taken.
+    ///
+    /// The ids of the allocations that were live at the time of the snapshot are valid again,
+    /// and the ids of the allocations made since then must not be used anymore. The snapshot
+    /// can be restored any number of times. The storage of the atlas is reused when possible.
+    pub fn restore(&mut self, snapshot: &AtlasSnapshot<T>)
+    where
+        T: Clone,
+    {
+        self.shelves.clone_from(&snapshot.shelves);
+        self.items.clone_from(&snapshot.items);
+        self.data.clone_from(&snapshot.data);
+        self.alignment = snapshot.alignment;
+        self.padding = snapshot.padding;
+        self.flip_xy = snapshot.flip_xy;
+        self.size = snapshot.size;
+        self.first_shelf = snapshot.first_shelf;
+        self.free_items = snapshot.free_items;
+        self.free_shelves = snapshot.free_shelves;
+        self.buckets = snapshot.buckets;
+        self.shelf_width = snapshot.shelf_width;
+        self.allocated_space = snapshot.allocated_space;
+        self.fixed_shelf_height = snapshot.fixed_shelf_height;
+        self.requested_space = snapshot.requested_space;
+        self.white_pixel = snapshot.white_pixel;
+        self.max_allocatable.set(None);
+
+        self.check();
+    }
+
+    // The options that produce the layout of this atlas.
+    fn options(&self) -> AllocatorOptions {
+        AllocatorOptions {
+            vertical_shelves: self.flip_xy,
+            alignment: self.alignment,
+            padding: self.padding as i32,
+            num_columns: (self.size.width / (self.shelf_width as i32).max(1)).max(1),
+            reserve_white_pixel: self.white_pixel.is_some(),
+            fixed_shelf_height: self.fixed_shelf_height,
+            ..DEFAULT_OPTIONS
+        }
+    }
+
     fn init(&mut self) {
         assert!(self.size.width > 0);
         assert!(self.size.height > 0);
-        assert!(self.size.width <= std::u16::MAX as i32);
-        assert!(self.size.height <= std::u16::MAX as i32);
+        assert!(self.size.width <= u16::MAX as i32);
+        assert!(self.size.height <= u16::MAX as i32);
         assert!(
             self.size.width.checked_mul(self.size.height).is_some(),
             "The area of the atlas must fit in a i32 value"
@@ -133,6 +764,7 @@ impl AtlasAllocator {
 
         self.shelves.clear();
         self.items.clear();
+        self.data.clear();
 
         let num_columns = self.size.width as u16 / self.shelf_width;
 
@@ -152,6 +784,9 @@ impl AtlasAllocator {
                 is_empty: true,
                 first_item,
                 first_unallocated: first_item,
+                bucket: NO_BUCKET,
+                prev_in_bucket: ShelfIndex::NONE,
+                next_in_bucket: ShelfIndex::NONE,
             });
 
             self.items.push(Item {
@@ -164,7 +799,9 @@ impl AtlasAllocator {
                 shelf: current,
                 allocated: false,
                 generation: 1,
+                requested_area: 0,
             });
+            self.data.push(T::default());
 
             prev = current;
         }
@@ -172,7 +809,27 @@ impl AtlasAllocator {
         self.first_shelf = ShelfIndex(0);
         self.free_items = ItemIndex::NONE;
         self.free_shelves = ShelfIndex::NONE;
+        self.buckets = [ShelfIndex::NONE; NUM_HEIGHT_BUCKETS];
+        for i in 0..num_columns {
+            self.update_bucket(ShelfIndex(i));
+        }
         self.allocated_space = 0;
+        self.requested_space = 0;
+        self.trimmed_generation = 0;
+        self.max_allocatable.set(None);
+
+        if let Some(rect) = self.white_pixel {
+            let allocation = self.allocate_at(rect);
+            debug_assert_eq!(allocation.map(|alloc| alloc.rectangle), Some(rect));
+        }
+    }
+
+    /// The rectangle reserved by the `reserve_white_pixel` option, if it is set.
+    ///
+    /// The white pixel is allocated like any other rectangle (for example it is visited by
+    /// `iter`) but can't be deallocated and isn't taken into account by `is_empty`.
+    pub fn white_pixel(&self) -> Option<Rectangle> {
+        self.white_pixel
     }
 
     pub fn size(&self) -> Size {
@@ -183,68 +840,34 @@ impl AtlasAllocator {
         }
     }
 
-    /// Allocate a rectangle in the atlas.
-    pub fn allocate(&mut self, mut size: Size) -> Option<Allocation> {
-        if size.is_empty()
-            || size.width > std::u16::MAX as i32
-            || size.height > std::u16::MAX as i32 {
-            return None;
-        }
-
-        adjust_size(self.alignment.width, &mut size.width);
-        adjust_size(self.alignment.height, &mut size.height);
-
-        let (width, height) = convert_coordinates(self.flip_xy, size.width, size.height);
-
-        if width > self.shelf_width as i32 || height > self.size.height {
-            return None;
-        }
-
-        let height = shelf_height(height, self.size.height);
-
-        let mut width = width as u16;
-        let mut height = height as u16;
-
-        let mut selected_shelf_height = std::u16::MAX;
-        let mut selected_shelf = ShelfIndex::NONE;
-        let mut selected_item = ItemIndex::NONE;
-        let mut shelf_idx = self.first_shelf;
-        while shelf_idx.is_some() {
-            let shelf = &self.shelves[shelf_idx.index()];
-
-            if shelf.height < height
-                || shelf.height >= selected_shelf_height
-                || (!shelf.is_empty && shelf.height > height + height / 2) {
-                shelf_idx = shelf.next;
-                continue;
-            }
-
-            let mut item_idx = shelf.first_unallocated;
-            while item_idx.is_some() {
-                let item = &self.items[item_idx.index()];
-                if !item.allocated && item.width >= width {
-                    break;
-                }
-
-                item_idx = item.next_unallocated;
-            }
-
-            if item_idx.is_some() {
-                selected_shelf = shelf_idx;
-                selected_shelf_height = shelf.height;
-                selected_item = item_idx;
+    /// Returns true if both dimensions of the atlas are powers of two.
+    pub fn is_pow2(&self) -> bool {
+        (self.size.width as u32).is_power_of_two() && (self.size.height as u32).is_power_of_two()
+    }
 
-                if shelf.height == height {
-                    // Perfect fit, stop searching.
-                    break;
-                }
-            }
+    /// Allocate a rectangle in the atlas.
+    pub fn allocate(&mut self, size: Size) -> Option<Allocation> {
+        self.try_allocate(size).ok()
+    }
 
-            shelf_idx = shelf.next;
+    /// Allocate a rectangle in the atlas, reporting why the allocation failed if it did.
+    ///
+    /// `AllocError::TooLarge` and `AllocError::AlignmentOverflow` mean that the size can't
+    /// be allocated in an atlas of this size, while `AllocError::OutOfSpace` means that it
+    /// could be after deallocating some rectangles. `AllocError::CapacityExhausted` is
+    /// returned once the atlas can't hold more shelves or items.
+    pub fn try_allocate(&mut self, size: Size) -> Result<Allocation, AllocError> {
+        let (mut width, mut height) = self.shelf_space_size(size)?;
+        let requested_area = size.area();
+
+        // Splitting the shelf and the free item can require a new shelf and two new items.
+        if !self.has_room_for_items(2) || !self.has_room_for_shelves(1) {
+            return Err(AllocError::CapacityExhausted);
         }
 
+        let (selected_shelf, selected_item) = self.find_free_item(width, height);
         if selected_shelf.is_none() {
-            return None;
+            return Err(AllocError::OutOfSpace);
         }
 
         let shelf = self.shelves[selected_shelf.index()].clone();
@@ -253,42 +876,7 @@ impl AtlasAllocator {
         }
 
         if shelf.is_empty && shelf.height > height + SHELF_SPLIT_THRESHOLD {
-            // Split the empty shelf into one of the desired size and a new
-            // empty one with a single empty item.
-
-            let new_shelf_idx =  self.add_shelf(Shelf {
-                x: shelf.x,
-                y: shelf.y + height,
-                height: shelf.height - height,
-                prev: selected_shelf,
-                next: shelf.next,
-                first_item: ItemIndex::NONE,
-                first_unallocated: ItemIndex::NONE,
-                is_empty: true,
-            });
-
-            let new_item_idx = self.add_item(Item {
-                x: shelf.x,
-                width: self.shelf_width,
-                prev: ItemIndex::NONE,
-                next: ItemIndex::NONE,
-                prev_unallocated: ItemIndex::NONE,
-                next_unallocated: ItemIndex::NONE,
-                shelf: new_shelf_idx,
-                allocated: false,
-                generation: 1,
-            });
-
-            self.shelves[new_shelf_idx.index()].first_item = new_item_idx;
-            self.shelves[new_shelf_idx.index()].first_unallocated = new_item_idx;
-
-            let next = self.shelves[selected_shelf.index()].next;
-            self.shelves[selected_shelf.index()].height = height;
-            self.shelves[selected_shelf.index()].next = new_shelf_idx;
-
-            if next.is_some() {
-                self.shelves[next.index()].prev = new_shelf_idx;
-            }
+            self.split_empty_shelf(selected_shelf, height);
         } else {
             height = shelf.height;
         }
@@ -307,6 +895,7 @@ impl AtlasAllocator {
                 shelf: item.shelf,
                 allocated: false,
                 generation: 1,
+                requested_area: 0,
             });
 
             self.items[selected_item.index()].width = width;
@@ -343,7 +932,11 @@ impl AtlasAllocator {
             width = item.width;
         }
 
+        self.update_bucket(selected_shelf);
+
         self.items[selected_item.index()].allocated = true;
+        self.items[selected_item.index()].requested_area = requested_area;
+        self.requested_space += requested_area;
         let generation = self.items[selected_item.index()].generation;
 
         let x0 = item.x;
@@ -363,24 +956,512 @@ impl AtlasAllocator {
 
         self.allocated_space += rectangle.area();
 
-        Some(Allocation {
+        Ok(Allocation {
             id: AllocId::new(selected_item.0, generation),
-            rectangle,
+            rectangle: self.remove_padding(rectangle),
         })
     }
 
-    /// Deallocate a rectangle in the atlas.
-    pub fn deallocate(&mut self, id: AllocId) {
-        let item_idx = ItemIndex(id.index());
+    /// Allocate a rectangle that lies entirely within `bounds`.
+    ///
+    /// This is useful to dedicate a region of the atlas to a category of items. The free space
+    /// overlapping the bounds is searched for a spot, preferring the shelves that already
+    /// contain allocations and have the smallest height, and the rectangle is then allocated
+    /// like with `allocate_at`. The allocation is otherwise like any other.
+    pub fn allocate_in_rect(&mut self, size: Size, bounds: Rectangle) -> Option<Allocation> {
+        let (width, height) = self.shelf_space_size(size).ok()?;
+
+        // Only the part of the bounds inside of the atlas matters, and clamping them keeps the
+        // coordinates computed below from overflowing.
+        let bounds = bounds.intersection(&Rectangle::from_size(self.size()))?;
+        let (bx0, by0) = convert_coordinates(self.flip_xy, bounds.min.x, bounds.min.y);
+        let (bx1, by1) = convert_coordinates(self.flip_xy, bounds.max.x, bounds.max.y);
+        let (x_alignment, y_alignment) = convert_coordinates(self.flip_xy, self.alignment.width, self.alignment.height);
+        let (width, height) = (width as i32, height as i32);
+
+        let mut best: Option<((bool, u16), i32, i32)> = None;
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+            let shelf_y1 = (shelf.y + shelf.height) as i32;
+
+            // Allocations cover the whole height of the shelves that aren't empty.
+            let y = if shelf.is_empty {
+                align_up(by0.max(shelf.y as i32), y_alignment)
+            } else {
+                shelf.y as i32
+            };
+            let y1 = if shelf.is_empty { y + height } else { shelf_y1 };
+            let score = (shelf.is_empty, shelf.height);
+
+            let fits_vertically = y >= by0 && y1 <= by1 && y1 <= shelf_y1
+                && (shelf.is_empty || shelf.height as i32 >= height);
+            if fits_vertically && best.map_or(true, |(best_score, _, _)| score < best_score) {
+                let mut item_idx = shelf.first_unallocated;
+                while item_idx.is_some() {
+                    let item = &self.items[item_idx.index()];
+                    let x = align_up(bx0.max(item.x as i32), x_alignment);
+                    if x.checked_add(width).map_or(false, |x1| x1 <= bx1.min((item.x + item.width) as i32)) {
+                        best = Some((score, x, y));
+                        break;
+                    }
+
+                    item_idx = item.next_unallocated;
+                }
+            }
 
-        let Item { mut prev, mut next, mut width, allocated, shelf, generation, .. } = self.items[item_idx.index()];
-        assert!(allocated);
-        assert_eq!(generation, id.generation(), "Invalid AllocId");
+            shelf_idx = shelf.next;
+        }
 
-        self.items[item_idx.index()].allocated = false;
-        self.allocated_space -= width as i32 * self.shelves[shelf.index()].height as i32;
+        let (_, x, y) = best?;
+        let (x0, y0) = convert_coordinates(self.flip_xy, x, y);
+        let (x1, y1) = convert_coordinates(self.flip_xy, x + width, y + height);
+        let rect = Rectangle {
+            min: point2(x0, y0),
+            max: point2(x1, y1),
+        };
 
-        if next.is_some() && !self.items[next.index()].allocated {
+        // Keep track of the originally requested size rather than the rectangle's.
+        self.allocate_padded_rect(rect, size.area())
+    }
+
+    // Returns the width of the item and the height of the shelf that an allocation of the
+    // provided size needs, or an error if it can't fit in the atlas.
+    fn shelf_space_size(&self, mut size: Size) -> Result<(u16, u16), AllocError> {
+        if size.is_empty() {
+            return Err(AllocError::EmptySize);
+        }
+
+        let padding = 2 * self.padding as i32;
+        size.width = size.width.saturating_add(padding);
+        size.height = size.height.saturating_add(padding);
+
+        if size.width > u16::MAX as i32 || size.height > u16::MAX as i32 {
+            return Err(AllocError::TooLarge);
+        }
+
+        let unaligned_size = size;
+
+        adjust_size(self.alignment.width, &mut size.width);
+        adjust_size(self.alignment.height, &mut size.height);
+
+        let (width, height) = convert_coordinates(self.flip_xy, size.width, size.height);
+
+        if width > self.shelf_width as i32 || height > self.size.height {
+            // The shelf width is itself rounded down to the alignment.
+            let num_columns = (self.size.width / (self.shelf_width as i32).max(1)).max(1);
+            let unaligned_shelf_width = self.size.width / num_columns;
+            let (unaligned_width, unaligned_height) = convert_coordinates(self.flip_xy, unaligned_size.width, unaligned_size.height);
+            if unaligned_width <= unaligned_shelf_width && unaligned_height <= self.size.height {
+                return Err(AllocError::AlignmentOverflow {
+                    requested: unaligned_size,
+                    aligned: size,
+                });
+            }
+
+            return Err(AllocError::TooLarge);
+        }
+
+        let height = match self.fixed_shelf_height {
+            Some(fixed) if height > fixed as i32 => return Err(AllocError::TooLarge),
+            Some(fixed) => fixed as i32,
+            None => shelf_height(height, self.size.height),
+        };
+
+        Ok((width as u16, height as u16))
+    }
+
+    // Returns false if allocating could require more than `count` new items and the item
+    // indices can't represent them.
+    fn has_room_for_items(&self, count: usize) -> bool {
+        let mut room = ItemIndex::NONE.index().saturating_sub(self.items.len());
+        let mut item = self.free_items;
+        while room < count && item.is_some() {
+            room += 1;
+            item = self.items[item.index()].next;
+        }
+
+        room >= count
+    }
+
+    // Same as `has_room_for_items` for shelves.
+    fn has_room_for_shelves(&self, count: usize) -> bool {
+        let mut room = ShelfIndex::NONE.index().saturating_sub(self.shelves.len());
+        let mut shelf = self.free_shelves;
+        while room < count && shelf.is_some() {
+            room += 1;
+            shelf = self.shelves[shelf.index()].next;
+        }
+
+        room >= count
+    }
+
+    // Find the free item of the best fitting shelf for an item of the provided size.
+    fn find_free_item(&self, width: u16, height: u16) -> (ShelfIndex, ItemIndex) {
+        // Select the shelf of smallest height that fits, and the first one in the list of
+        // shelves (which is sorted by x and y) among those of the same height.
+        let mut selected_shelf_height = u16::MAX;
+        let mut selected_shelf_pos = (0, 0);
+        let mut selected_shelf = ShelfIndex::NONE;
+        let mut selected_item = ItemIndex::NONE;
+
+        // Heights only increase from one bucket to the next, so the search can stop at the
+        // first bucket containing a shelf that fits.
+        for bucket in height_bucket(height)..NUM_HEIGHT_BUCKETS {
+            let mut shelf_idx = self.buckets[bucket];
+            while shelf_idx.is_some() {
+                let shelf = &self.shelves[shelf_idx.index()];
+                let next = shelf.next_in_bucket;
+
+                if shelf.height < height
+                    || shelf.height > selected_shelf_height
+                    || (shelf.height == selected_shelf_height
+                        && (selected_shelf.is_none() || (shelf.x, shelf.y) > selected_shelf_pos))
+                    || (!shelf.is_empty && shelf.height > height + height / 2) {
+                    shelf_idx = next;
+                    continue;
+                }
+
+                let mut item_idx = shelf.first_unallocated;
+                while item_idx.is_some() {
+                    let item = &self.items[item_idx.index()];
+                    if !item.allocated && item.width >= width {
+                        break;
+                    }
+
+                    item_idx = item.next_unallocated;
+                }
+
+                if item_idx.is_some() {
+                    selected_shelf = shelf_idx;
+                    selected_shelf_height = shelf.height;
+                    selected_shelf_pos = (shelf.x, shelf.y);
+                    selected_item = item_idx;
+                }
+
+                shelf_idx = next;
+            }
+
+            if selected_shelf.is_some() {
+                break;
+            }
+        }
+
+        (selected_shelf, selected_item)
+    }
+
+    /// The largest size (by area) that can currently be allocated, or an empty size if
+    /// nothing can.
+    ///
+    /// This is the size of one of the free rectangles of the atlas, rounded down so that
+    /// `allocate` accepts it. A size can fit without being smaller than this one in both
+    /// dimensions, if it fits in another free rectangle. This makes it possible to check
+    /// whether a large item fits before attempting to allocate it, without modifying the atlas.
+    ///
+    /// The result is cached and only recomputed when needed, so that this can be called in
+    /// the hot path. Allocating may shrink the free rectangle the cached size was computed
+    /// from, so the cached value may over-estimate what can be allocated: when queried, it is
+    /// checked in constant time against that free rectangle and is recomputed by visiting all
+    /// free rectangles if it changed. Deallocating may only make the value grow and always
+    /// causes a recomputation. The returned size is always allocatable.
+    pub fn max_allocatable(&self) -> Size {
+        if let Some(cached) = self.max_allocatable.get() {
+            if self.is_max_allocatable_valid(&cached) {
+                return cached.size;
+            }
+        }
+
+        let result = self.compute_max_allocatable();
+        self.max_allocatable.set(Some(result));
+
+        result.size
+    }
+
+    fn is_max_allocatable_valid(&self, cached: &MaxAllocatable) -> bool {
+        // Space is only freed by deallocations, which reset the cache.
+        if cached.item.is_none() {
+            return true;
+        }
+
+        let item = match self.items.get(cached.item.index()) {
+            Some(item) => item,
+            None => return false,
+        };
+
+        // If the free item is unchanged, the others can only have shrunk.
+        !item.allocated
+            && item.x == cached.x
+            && item.width == cached.width
+            && self.shelves[item.shelf.index()].height == cached.shelf_height
+    }
+
+    fn compute_max_allocatable(&self) -> MaxAllocatable {
+        let mut best = MaxAllocatable {
+            item: ItemIndex::NONE,
+            x: 0,
+            width: 0,
+            shelf_height: 0,
+            size: size2(0, 0),
+        };
+
+        let (align_w, align_h) = convert_coordinates(self.flip_xy, self.alignment.width, self.alignment.height);
+
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+            let mut item_idx = shelf.first_unallocated;
+            while item_idx.is_some() {
+                let current = item_idx;
+                let item = &self.items[item_idx.index()];
+                item_idx = item.next_unallocated;
+
+                let width = item.width as i32 - item.width as i32 % align_w;
+                if width * shelf.height as i32 <= best.size.area() {
+                    continue;
+                }
+
+                // Find the tallest height that isn't rounded up past the height of the shelf.
+                let mut height = shelf.height as i32;
+                if let Some(fixed) = self.fixed_shelf_height {
+                    height = height.min(fixed as i32);
+                }
+                height -= height % align_h;
+                while height > 0 && shelf_height(height, self.size.height) > shelf.height as i32 {
+                    height -= align_h;
+                }
+
+                let (w, h) = convert_coordinates(self.flip_xy, width, height);
+                let padding = 2 * self.padding as i32;
+                let size = size2(w - padding, h - padding);
+                if size.is_empty() || size.area() <= best.size.area() {
+                    continue;
+                }
+
+                let fits = self.shelf_space_size(size).map_or(false, |(w, h)| self.find_free_item(w, h).1.is_some());
+                if fits {
+                    best = MaxAllocatable {
+                        item: current,
+                        x: item.x,
+                        width: item.width,
+                        shelf_height: shelf.height,
+                        size,
+                    };
+                }
+            }
+
+            shelf_idx = shelf.next;
+        }
+
+        best
+    }
+
+    /// Allocate the first size of a list that fits in the atlas.
+    ///
+    /// Sizes are tried in order, typically from the preferred to the smallest acceptable one.
+    /// Returns the index of the size that was allocated along with the allocation.
+    pub fn allocate_first_fit(&mut self, sizes: &[Size]) -> Option<(usize, Allocation)> {
+        for (index, size) in sizes.iter().enumerate() {
+            if let Some(allocation) = self.allocate(*size) {
+                return Some((index, allocation));
+            }
+        }
+
+        None
+    }
+
+    /// Allocate a rectangle, evicting existing allocations until it fits.
+    ///
+    /// When the atlas is full, `evict` is called to pick an allocation to deallocate before
+    /// trying again, so that the caller decides of the eviction policy (for example the least
+    /// recently used entry of a cache). Returns `None` if `evict` runs out of victims, or
+    /// without evicting anything if the size can never be allocated in this atlas.
+    pub fn allocate_or_evict<F>(&mut self, size: Size, mut evict: F) -> Option<Allocation>
+    where
+        F: FnMut() -> Option<AllocId>,
+    {
+        loop {
+            match self.try_allocate(size) {
+                Ok(allocation) => {
+                    return Some(allocation);
+                }
+                Err(AllocError::OutOfSpace) | Err(AllocError::CapacityExhausted) => {
+                    self.deallocate(evict()?);
+                }
+                Err(_) => {
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Allocate a rectangle with power-of-two dimensions.
+    ///
+    /// Both dimensions of the requested size are rounded up to the next power of two and the
+    /// returned rectangle has exactly the rounded size. Returns `None` if the rounded size
+    /// doesn't fit in the atlas.
+    pub fn allocate_pow2(&mut self, size: Size) -> Option<Allocation> {
+        let size = pow2_size(size)?;
+        let allocation = self.allocate(size)?;
+
+        Some(trim_allocation(allocation, size))
+    }
+
+    /// Allocate a specific rectangle of the atlas.
+    ///
+    /// The rectangle must be within the atlas and must not overlap existing allocations or
+    /// cross shelf or column boundaries, otherwise `None` is returned. If the rectangle is in
+    /// an empty shelf, the shelf is split around it and the allocation matches the rectangle
+    /// exactly. Otherwise the allocation covers the whole height of the shelf.
+    ///
+    /// With the `padding` option, the padding around the rectangle must also be available.
+    pub fn allocate_at(&mut self, rect: Rectangle) -> Option<Allocation> {
+        if rect.is_empty() {
+            return None;
+        }
+
+        let padding = self.padding as i32;
+        self.allocate_padded_rect(rect.inflate(padding, padding), rect.area())
+    }
+
+    // Allocate a specific rectangle, including the padding around the allocation.
+    fn allocate_padded_rect(&mut self, rect: Rectangle, requested_area: i32) -> Option<Allocation> {
+        if rect.is_empty() || rect.min.x < 0 || rect.min.y < 0 {
+            return None;
+        }
+
+        // The shelf and the item can each be split on both sides of the rectangle.
+        if !self.has_room_for_items(4) || !self.has_room_for_shelves(2) {
+            return None;
+        }
+
+        let (x0, y0) = convert_coordinates(self.flip_xy, rect.min.x, rect.min.y);
+        let (x1, y1) = convert_coordinates(self.flip_xy, rect.max.x, rect.max.y);
+
+        if x1 > self.size.width || y1 > self.size.height {
+            return None;
+        }
+
+        let (x0, y0, x1, y1) = (x0 as u16, y0 as u16, x1 as u16, y1 as u16);
+
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+            if shelf.x <= x0 && x1 <= shelf.x + self.shelf_width
+                && shelf.y <= y0 && y1 <= shelf.y + shelf.height {
+                break;
+            }
+
+            shelf_idx = shelf.next;
+        }
+
+        if shelf_idx.is_none() {
+            return None;
+        }
+
+        // Find the unallocated item containing the rectangle.
+        let mut item_idx = self.shelves[shelf_idx.index()].first_unallocated;
+        while item_idx.is_some() {
+            let item = &self.items[item_idx.index()];
+            if item.x <= x0 && x1 <= item.x + item.width {
+                break;
+            }
+
+            item_idx = item.next_unallocated;
+        }
+
+        if item_idx.is_none() {
+            return None;
+        }
+
+        if self.shelves[shelf_idx.index()].is_empty {
+            // Split the shelf so that the rectangle covers its whole height.
+            let shelf_y = self.shelves[shelf_idx.index()].y;
+            if y0 > shelf_y {
+                shelf_idx = self.split_empty_shelf(shelf_idx, y0 - shelf_y);
+                item_idx = self.shelves[shelf_idx.index()].first_item;
+            }
+            if y1 < y0 + self.shelves[shelf_idx.index()].height {
+                self.split_empty_shelf(shelf_idx, y1 - y0);
+            }
+
+            self.shelves[shelf_idx.index()].is_empty = false;
+        }
+
+        // Split the item so that the rectangle covers its whole width.
+        let item_x = self.items[item_idx.index()].x;
+        if x0 > item_x {
+            item_idx = self.split_unallocated_item(item_idx, x0 - item_x);
+        }
+        if x1 < x0 + self.items[item_idx.index()].width {
+            self.split_unallocated_item(item_idx, x1 - x0);
+        }
+
+        self.remove_from_unallocated_list(item_idx);
+        self.items[item_idx.index()].allocated = true;
+        self.items[item_idx.index()].requested_area = requested_area;
+
+        let shelf = &self.shelves[shelf_idx.index()];
+        let item = &self.items[item_idx.index()];
+
+        let (x0, y0) = convert_coordinates(self.flip_xy, item.x as i32, shelf.y as i32);
+        let (x1, y1) = convert_coordinates(self.flip_xy, (item.x + item.width) as i32, (shelf.y + shelf.height) as i32);
+
+        let rectangle = Rectangle {
+            min: point2(x0, y0),
+            max: point2(x1, y1),
+        };
+
+        self.allocated_space += rectangle.area();
+        self.requested_space += requested_area;
+
+        let id = AllocId::new(item_idx.0, item.generation);
+
+        self.check();
+
+        Some(Allocation { id, rectangle: self.remove_padding(rectangle) })
+    }
+
+    /// Deallocate a rectangle in the atlas.
+    ///
+    /// The id must correspond to a live allocation of the atlas. This is asserted in debug
+    /// builds, while invalid ids are ignored in release builds (see `try_deallocate`).
+    ///
+    /// Returns the user data of the allocation, or the default value if the id was ignored.
+    pub fn deallocate(&mut self, id: AllocId) -> T {
+        #[cfg(not(debug_assertions))]
+        {
+            self.try_deallocate(id).unwrap_or_default()
+        }
+
+        #[cfg(debug_assertions)]
+        self.deallocate_internal(id)
+    }
+
+    /// Deallocate a rectangle in the atlas and return its user data, or report why the id
+    /// can't be deallocated instead of panicking.
+    pub fn try_deallocate(&mut self, id: AllocId) -> Result<T, DeallocError> {
+        self.validate_id(id)?;
+
+        Ok(self.deallocate_internal(id))
+    }
+
+    fn deallocate_internal(&mut self, id: AllocId) -> T {
+        let item_idx = ItemIndex(id.index());
+
+        let Item { mut prev, mut next, mut width, allocated, shelf, generation, requested_area, .. } = self.items[item_idx.index()];
+        assert!(allocated);
+        assert_eq!(generation, id.generation(), "Invalid AllocId");
+
+        // Bump the generation so that the stale id can't be mistaken for the id of a future
+        // allocation reusing this item.
+        self.items[item_idx.index()].allocated = false;
+        self.items[item_idx.index()].generation = generation.wrapping_add(1);
+        let data = core::mem::take(&mut self.data[item_idx.index()]);
+        self.max_allocatable.set(None);
+        self.allocated_space -= width as i32 * self.shelves[shelf.index()].height as i32;
+        self.requested_space -= requested_area;
+
+        if next.is_some() && !self.items[next.index()].allocated {
             // Merge the next item into this one.
 
             let next_next = self.items[next.index()].next;
@@ -439,6 +1520,8 @@ impl AtlasAllocator {
             self.shelves[shelf.index()].first_unallocated = item_idx;
         }
 
+        self.update_bucket(shelf);
+
         if prev.is_none() && next.is_none() {
             let shelf_idx = shelf;
             // The shelf is now empty.
@@ -465,6 +1548,7 @@ impl AtlasAllocator {
 
                 // Add next to the free list.
                 self.remove_shelf(next_shelf);
+                self.update_bucket(shelf_idx);
             }
 
             let prev_shelf = self.shelves[shelf_idx.index()].prev;
@@ -477,91 +1561,567 @@ impl AtlasAllocator {
                 self.shelves[prev_shelf.index()].next = next_shelf;
                 self.shelves[prev_shelf.index()].height += self.shelves[shelf_idx.index()].height;
 
-                self.shelves[prev_shelf.index()].next = self.shelves[shelf_idx.index()].next;
                 if next_shelf.is_some() {
                     self.shelves[next_shelf.index()].prev = prev_shelf;
                 }
 
                 // Add the shelf to the free list.
                 self.remove_shelf(shelf_idx);
+                self.update_bucket(prev_shelf);
             }
         }
 
         self.check();
+
+        data
     }
 
-    pub fn is_empty(&self) -> bool {
-        let mut shelf_idx = self.first_shelf;
+    /// Deallocate a rectangle in the atlas and return it.
+    ///
+    /// The returned rectangle is the one the allocation was created with, which is useful
+    /// to clear the region of the texture that was freed.
+    pub fn deallocate_and_get(&mut self, id: AllocId) -> Rectangle {
+        let rectangle = self.get(id);
+        self.deallocate(id);
 
-        while shelf_idx.is_some() {
-            let shelf = &self.shelves[shelf_idx.index()];
-            if !shelf.is_empty {
-                return false;
-            }
+        rectangle
+    }
 
-            shelf_idx = shelf.next;
+    /// Deallocate all allocations for which `keep` returns false.
+    ///
+    /// The predicate is called once per live allocation with its id and rectangle, in no
+    /// particular order. The rejected allocations are deallocated after the traversal, each in
+    /// constant time, so that the items and shelves are coalesced as with `deallocate`.
+    /// The white pixel, if any, is kept and not passed to the predicate.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(AllocId, Rectangle) -> bool,
+    {
+        let white_pixel = self.white_pixel;
+        let removed: Vec<AllocId> = self
+            .iter()
+            .filter(|alloc| Some(alloc.rectangle) != white_pixel && !keep(alloc.id, alloc.rectangle))
+            .map(|alloc| alloc.id)
+            .collect();
+
+        for id in removed {
+            self.deallocate_internal(id);
         }
+    }
+
+    /// Deallocate the allocations that are entirely contained in `bounds`.
+    ///
+    /// Unlike `clear`, the allocations outside of `bounds` or overlapping its edges are left
+    /// untouched, which is useful to wipe one region of an atlas partitioned by content. The
+    /// freed space is coalesced as with `deallocate`. The white pixel, if any, is kept.
+    pub fn clear_region(&mut self, bounds: Rectangle) {
+        self.retain(|_, rect| !bounds.contains_box(&rect));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let reserved_space = self.white_pixel.map_or(0, |rect| rect.area());
 
-        true
+        self.allocated_space == reserved_space
     }
 
     /// Amount of occupied space in the atlas.
+    ///
+    /// This is the sum of the areas of the rectangles of the live allocations (including the
+    /// white pixel, if any). The free items of the atlas, such as the one spanning the whole
+    /// width of an empty shelf, are not counted.
     pub fn allocated_space(&self) -> i32 {
         self.allocated_space
     }
 
+    /// Number of live allocations, including the white pixel if it is reserved.
+    pub fn allocation_count(&self) -> usize {
+        self.items.iter().filter(|item| item.allocated).count()
+    }
+
     /// How much space is available for future allocations.
+    ///
+    /// This is the area of the atlas minus `allocated_space()`. Since free space is split into
+    /// shelves, it is an upper bound on what can actually be packed. See `max_allocatable` for
+    /// the largest rectangle that fits.
     pub fn free_space(&self) -> i32 {
         self.size.area() - self.allocated_space
     }
 
-    pub fn iter(&self) -> Iter {
-        Iter {
-            atlas: self,
-            idx: 0,
-        }
-    }
-
-    fn remove_item(&mut self, idx: ItemIndex) {
-        self.items[idx.index()].next = self.free_items;
-        self.free_items = idx;
+    /// The fraction of the area of the atlas that is allocated, between 0 and 1.
+    ///
+    /// This is `allocated_space()` divided by the area of the atlas, so the space lost to the
+    /// alignment and to rounding up the height of the shelves counts as occupied.
+    pub fn occupancy(&self) -> f32 {
+        (self.allocated_space as f32 / self.size.area() as f32).clamp(0.0, 1.0)
     }
 
-    fn remove_shelf(&mut self, idx: ShelfIndex) {
-        // Remove the shelf's item.
-        self.remove_item(self.shelves[idx.index()].first_item);
+    /// The fraction of the allocated space that isn't part of the requested sizes.
+    ///
+    /// This padding comes from rounding sizes up to the alignment and from placing items in
+    /// taller shelves or in slightly wider free slots. A high value means that a finer alignment
+    /// or more uniform item sizes would help. Returns 0 if the atlas is empty.
+    pub fn internal_fragmentation(&self) -> f32 {
+        if self.allocated_space == 0 {
+            return 0.0;
+        }
 
-        self.shelves[idx.index()].next = self.free_shelves;
-        self.free_shelves = idx;
+        (self.allocated_space - self.requested_space) as f32 / self.allocated_space as f32
     }
 
-    fn add_item(&mut self, mut item: Item) -> ItemIndex {
-        if self.free_items.is_some() {
-            let idx = self.free_items;
-            item.generation = self.items[idx.index()].generation.wrapping_add(1);
-            self.free_items = self.items[idx.index()].next;
-            self.items[idx.index()] = item;
+    /// The range of y coordinates occupied by live allocations, as `(min, max)` with `max`
+    /// exclusive.
+    ///
+    /// This is useful to find the band of the atlas that needs to be cleared. If the
+    /// `vertical_shelves` option is set, shelves are stacked horizontally and the range is
+    /// along the x axis instead. Returns `(0, 0)` if the atlas is empty.
+    pub fn content_y_extent(&self) -> (i32, i32) {
+        let mut min = u16::MAX;
+        let mut max = 0;
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+            if !shelf.is_empty {
+                min = min.min(shelf.y);
+                max = max.max(shelf.y + shelf.height);
+            }
 
-            return idx;
+            shelf_idx = shelf.next;
         }
 
-        let idx = ItemIndex(self.items.len() as u16);
-        self.items.push(item);
+        if min > max {
+            return (0, 0);
+        }
 
-        idx
+        (min as i32, max as i32)
     }
 
-    fn add_shelf(&mut self, shelf: Shelf) -> ShelfIndex {
-        if self.free_shelves.is_some() {
-            let idx = self.free_shelves;
-            self.free_shelves = self.shelves[idx.index()].next;
-            self.shelves[idx.index()] = shelf;
+    /// The bounding box of all live allocations, or `None` if the atlas is empty.
+    pub fn coverage(&self) -> Option<Rectangle> {
+        self.iter().map(|alloc| alloc.rectangle).reduce(|a, b| a.union(&b))
+    }
 
-            return idx;
-        }
+    /// Split the whole surface of the atlas into disjoint rectangles, each flagged as
+    /// allocated (`true`) or free (`false`).
+    ///
+    /// The union of the rectangles is exactly the atlas. This can be used to clear only the
+    /// free parts of the atlas, or to check the consistency of the allocator.
+    pub fn partition(&self) -> Vec<(Rectangle, bool)> {
+        let mut rects = Vec::new();
+        let mut push = |min_x: u16, min_y: u16, max_x: u16, max_y: u16, allocated: bool| {
+            if min_x == max_x || min_y == max_y {
+                return;
+            }
+            let (min_x, min_y) = convert_coordinates(self.flip_xy, min_x as i32, min_y as i32);
+            let (max_x, max_y) = convert_coordinates(self.flip_xy, max_x as i32, max_y as i32);
+            rects.push((
+                Rectangle {
+                    min: point2(min_x, min_y),
+                    max: point2(max_x, max_y),
+                },
+                allocated,
+            ));
+        };
 
-        let idx = ShelfIndex(self.shelves.len() as u16);
-        self.shelves.push(shelf);
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+
+            let mut item_idx = shelf.first_item;
+            while item_idx.is_some() {
+                let item = &self.items[item_idx.index()];
+                push(item.x, shelf.y, item.x + item.width, shelf.y + shelf.height, item.allocated);
+
+                item_idx = item.next;
+            }
+
+            shelf_idx = shelf.next;
+        }
+
+        // The space on the right of the last column if the width isn't a multiple of the
+        // shelf width.
+        let num_columns = self.size.width as u16 / self.shelf_width;
+        push(num_columns * self.shelf_width, 0, self.size.width as u16, self.size.height as u16, false);
+
+        rects
+    }
+
+    /// The highest generation among the items of the atlas.
+    ///
+    /// Item generations are stored in 16 bits and wrap around, after which stale ids can be
+    /// mistaken for live ones. This can be monitored to detect when it is becoming a risk.
+    pub fn max_generation(&self) -> u16 {
+        self.items.iter().map(|item| item.generation).max().unwrap_or(0)
+    }
+
+    /// Number of items with a generation greater or equal to the provided threshold.
+    pub fn items_near_wraparound(&self, threshold: u16) -> usize {
+        self.items.iter().filter(|item| item.generation >= threshold).count()
+    }
+
+    /// Dump the live allocations as a text table, for example for snapshot tests.
+    ///
+    /// Each line contains the id (as returned by `AllocId::serialize`), position and size of an
+    /// allocation. Lines are sorted by position (see `cmp_spatial`) so that the output only
+    /// depends on the state of the atlas.
+    pub fn dump_table(&self) -> String {
+        format_allocation_table(self.iter().collect())
+    }
+
+    /// Render the atlas as text, with one character per `cell_size` area of the atlas.
+    ///
+    /// Free space is shown as `.` and allocations with a character that changes from one shelf
+    /// to the next, which is handy in test failures and terminals. For example a cell size of
+    /// 25x25 renders a 1000x1000 atlas in 40 lines of 40 characters.
+    pub fn dump_ascii(&self, cell_size: Size, output: &mut String) {
+        let mut allocations = Vec::new();
+        let mut shelf_idx = self.first_shelf;
+        let mut shelf_number = 0;
+        while shelf_idx.is_some() {
+            let shelf = &self.shelves[shelf_idx.index()];
+            let mut item_idx = shelf.first_item;
+            while item_idx.is_some() {
+                let item = &self.items[item_idx.index()];
+                if item.allocated {
+                    let id = AllocId::new(item_idx.0, item.generation);
+                    allocations.push((self.get(id), shelf_number));
+                }
+                item_idx = item.next;
+            }
+
+            shelf_idx = shelf.next;
+            shelf_number += 1;
+        }
+
+        format_ascii(self.size(), cell_size, &allocations, output);
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            atlas: self,
+            idx: 0,
+        }
+    }
+
+    /// Check that the allocations are within the atlas and don't overlap each other.
+    ///
+    /// This holds by construction, so an error indicates a bug in the allocator.
+    pub fn verify_no_overlap(&self) -> Result<(), ValidationError> {
+        let bounds = Rectangle {
+            min: point2(0, 0),
+            max: self.size().to_vector().to_point(),
+        };
+
+        let mut allocations: Vec<Allocation> = self.iter().collect();
+        allocations.sort_by(cmp_spatial);
+
+        for (i, a) in allocations.iter().enumerate() {
+            if !bounds.contains_box(&a.rectangle) {
+                return Err(ValidationError::OutOfBounds(a.id));
+            }
+
+            // Allocations are sorted by their top edge, so only the ones that start above the
+            // bottom edge of this one can overlap it.
+            for b in allocations[i + 1..].iter().take_while(|b| b.rectangle.min.y < a.rectangle.max.y) {
+                if a.rectangle.intersects(&b.rectangle) {
+                    return Err(ValidationError::Overlap(a.id, b.id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the atlas and turn it into a read-only structure optimized for lookups.
+    ///
+    /// This is meant for static atlases that are built once and never modified afterwards.
+    /// The allocations keep their ids and user data, and only the allocations are stored so
+    /// the result is typically much more compact than the allocator. Returns an error if
+    /// `verify_no_overlap` fails.
+    pub fn finalize(mut self) -> Result<FrozenAtlas<T>, ValidationError> {
+        self.verify_no_overlap()?;
+
+        let len = self.items.iter().rposition(|item| item.allocated).map_or(0, |idx| idx + 1);
+        let mut allocations = vec![None; len];
+        for allocation in self.iter() {
+            allocations[allocation.id.index() as usize] = Some(allocation);
+        }
+
+        self.data.truncate(len);
+
+        Ok(FrozenAtlas::new(self.size(), allocations, self.data))
+    }
+
+    /// Release the unused shelves and items at the end of the internal storage.
+    ///
+    /// Removed shelves and items are kept in free lists for reuse, so an atlas that was once
+    /// very fragmented keeps carrying them. This is typically useful before serializing an atlas
+    /// that has mostly been emptied, to keep the serialized form small. Live allocations keep
+    /// their ids and subsequent allocations are placed exactly as they would have been without
+    /// trimming, although the ids they get may differ.
+    ///
+    /// Since the ids of the allocations are the indices of their items, unused items between
+    /// live ones can't be compacted and only the ones past the last live item are released.
+    /// The ids of deallocated items remain invalid after trimming.
+    pub fn trim_unused_storage(&mut self) {
+        let mut free_items = Vec::new();
+        let mut item_idx = self.free_items;
+        while item_idx.is_some() {
+            free_items.push(item_idx);
+            item_idx = self.items[item_idx.index()].next;
+        }
+
+        let mut unused = vec![false; self.items.len()];
+        for idx in &free_items {
+            unused[idx.index()] = true;
+        }
+
+        let mut len = self.items.len();
+        while len > 0 && unused[len - 1] {
+            len -= 1;
+        }
+
+        // Rebuild the free list in the same order without the trimmed items.
+        self.free_items = ItemIndex::NONE;
+        for &idx in free_items.iter().rev() {
+            if idx.index() < len {
+                self.items[idx.index()].next = self.free_items;
+                self.free_items = idx;
+            }
+        }
+
+        for item in &self.items[len..] {
+            self.trimmed_generation = self.trimmed_generation.max(item.generation);
+        }
+
+        self.items.truncate(len);
+        self.items.shrink_to_fit();
+        self.data.truncate(len);
+        self.data.shrink_to_fit();
+
+        let mut free_shelves = Vec::new();
+        let mut shelf_idx = self.free_shelves;
+        while shelf_idx.is_some() {
+            free_shelves.push(shelf_idx);
+            shelf_idx = self.shelves[shelf_idx.index()].next;
+        }
+
+        let mut unused = vec![false; self.shelves.len()];
+        for idx in &free_shelves {
+            unused[idx.index()] = true;
+        }
+
+        let mut len = self.shelves.len();
+        while len > 0 && unused[len - 1] {
+            len -= 1;
+        }
+
+        self.free_shelves = ShelfIndex::NONE;
+        for &idx in free_shelves.iter().rev() {
+            if idx.index() < len {
+                self.shelves[idx.index()].next = self.free_shelves;
+                self.free_shelves = idx;
+            }
+        }
+
+        self.shelves.truncate(len);
+        self.shelves.shrink_to_fit();
+
+        self.check();
+    }
+
+    // Split an empty shelf into one of the desired height and a new empty one
+    // with a single empty item, returning the new shelf.
+    fn split_empty_shelf(&mut self, shelf_idx: ShelfIndex, height: u16) -> ShelfIndex {
+        let shelf = self.shelves[shelf_idx.index()].clone();
+
+        let new_shelf_idx =  self.add_shelf(Shelf {
+            x: shelf.x,
+            y: shelf.y + height,
+            height: shelf.height - height,
+            prev: shelf_idx,
+            next: shelf.next,
+            first_item: ItemIndex::NONE,
+            first_unallocated: ItemIndex::NONE,
+            is_empty: true,
+            bucket: NO_BUCKET,
+            prev_in_bucket: ShelfIndex::NONE,
+            next_in_bucket: ShelfIndex::NONE,
+        });
+
+        let new_item_idx = self.add_item(Item {
+            x: shelf.x,
+            width: self.shelf_width,
+            prev: ItemIndex::NONE,
+            next: ItemIndex::NONE,
+            prev_unallocated: ItemIndex::NONE,
+            next_unallocated: ItemIndex::NONE,
+            shelf: new_shelf_idx,
+            allocated: false,
+            generation: 1,
+            requested_area: 0,
+        });
+
+        self.shelves[new_shelf_idx.index()].first_item = new_item_idx;
+        self.shelves[new_shelf_idx.index()].first_unallocated = new_item_idx;
+
+        let next = self.shelves[shelf_idx.index()].next;
+        self.shelves[shelf_idx.index()].height = height;
+        self.shelves[shelf_idx.index()].next = new_shelf_idx;
+
+        if next.is_some() {
+            self.shelves[next.index()].prev = new_shelf_idx;
+        }
+
+        self.update_bucket(shelf_idx);
+        self.update_bucket(new_shelf_idx);
+
+        new_shelf_idx
+    }
+
+    // Split an unallocated item into one of the desired width and a new unallocated
+    // one on its right, returning the new item.
+    fn split_unallocated_item(&mut self, item_idx: ItemIndex, width: u16) -> ItemIndex {
+        let item = self.items[item_idx.index()].clone();
+
+        let new_item_idx = self.add_item(Item {
+            x: item.x + width,
+            width: item.width - width,
+            prev: item_idx,
+            next: item.next,
+            prev_unallocated: item_idx,
+            next_unallocated: item.next_unallocated,
+            shelf: item.shelf,
+            allocated: false,
+            generation: 1,
+            requested_area: 0,
+        });
+
+        self.items[item_idx.index()].width = width;
+        self.items[item_idx.index()].next = new_item_idx;
+        self.items[item_idx.index()].next_unallocated = new_item_idx;
+
+        if item.next.is_some() {
+            self.items[item.next.index()].prev = new_item_idx;
+        }
+        if item.next_unallocated.is_some() {
+            self.items[item.next_unallocated.index()].prev_unallocated = new_item_idx;
+        }
+
+        new_item_idx
+    }
+
+    fn remove_from_unallocated_list(&mut self, item_idx: ItemIndex) {
+        let item = self.items[item_idx.index()].clone();
+
+        let shelf = &mut self.shelves[item.shelf.index()];
+        if shelf.first_unallocated == item_idx {
+            shelf.first_unallocated = item.next_unallocated;
+        }
+        if item.prev_unallocated.is_some() {
+            self.items[item.prev_unallocated.index()].next_unallocated = item.next_unallocated;
+        }
+        if item.next_unallocated.is_some() {
+            self.items[item.next_unallocated.index()].prev_unallocated = item.prev_unallocated;
+        }
+
+        self.update_bucket(item.shelf);
+    }
+
+    fn remove_item(&mut self, idx: ItemIndex) {
+        self.items[idx.index()].next = self.free_items;
+        self.free_items = idx;
+    }
+
+    fn remove_shelf(&mut self, idx: ShelfIndex) {
+        self.remove_from_bucket(idx);
+
+        // Remove the shelf's item.
+        self.remove_item(self.shelves[idx.index()].first_item);
+
+        self.shelves[idx.index()].next = self.free_shelves;
+        self.free_shelves = idx;
+    }
+
+    fn add_item(&mut self, mut item: Item) -> ItemIndex {
+        if self.free_items.is_some() {
+            let idx = self.free_items;
+            item.generation = self.items[idx.index()].generation.wrapping_add(1);
+            self.free_items = self.items[idx.index()].next;
+            self.items[idx.index()] = item;
+
+            return idx;
+        }
+
+        let idx = ItemIndex(self.items.len() as u16);
+        item.generation = item.generation.max(self.trimmed_generation.wrapping_add(1));
+        self.items.push(item);
+        self.data.push(T::default());
+
+        idx
+    }
+
+    // Move the shelf to the bucket matching its height, or out of the buckets if it has
+    // no unallocated item. Must be called after changing either.
+    fn update_bucket(&mut self, shelf_idx: ShelfIndex) {
+        let shelf = &self.shelves[shelf_idx.index()];
+        let bucket = if shelf.first_unallocated.is_some() {
+            height_bucket(shelf.height) as u8
+        } else {
+            NO_BUCKET
+        };
+
+        if bucket == shelf.bucket {
+            return;
+        }
+
+        self.remove_from_bucket(shelf_idx);
+
+        if bucket != NO_BUCKET {
+            let first = self.buckets[bucket as usize];
+            if first.is_some() {
+                self.shelves[first.index()].prev_in_bucket = shelf_idx;
+            }
+
+            let shelf = &mut self.shelves[shelf_idx.index()];
+            shelf.bucket = bucket;
+            shelf.prev_in_bucket = ShelfIndex::NONE;
+            shelf.next_in_bucket = first;
+            self.buckets[bucket as usize] = shelf_idx;
+        }
+    }
+
+    fn remove_from_bucket(&mut self, shelf_idx: ShelfIndex) {
+        let shelf = &mut self.shelves[shelf_idx.index()];
+        let (bucket, prev, next) = (shelf.bucket, shelf.prev_in_bucket, shelf.next_in_bucket);
+        if bucket == NO_BUCKET {
+            return;
+        }
+
+        shelf.bucket = NO_BUCKET;
+        shelf.prev_in_bucket = ShelfIndex::NONE;
+        shelf.next_in_bucket = ShelfIndex::NONE;
+
+        if prev.is_some() {
+            self.shelves[prev.index()].next_in_bucket = next;
+        } else {
+            self.buckets[bucket as usize] = next;
+        }
+        if next.is_some() {
+            self.shelves[next.index()].prev_in_bucket = prev;
+        }
+    }
+
+    fn add_shelf(&mut self, shelf: Shelf) -> ShelfIndex {
+        if self.free_shelves.is_some() {
+            let idx = self.free_shelves;
+            self.free_shelves = self.shelves[idx.index()].next;
+            self.shelves[idx.index()] = shelf;
+
+            return idx;
+        }
+
+        let idx = ShelfIndex(self.shelves.len() as u16);
+        self.shelves.push(shelf);
 
         idx
     }
@@ -571,25 +2131,62 @@ impl AtlasAllocator {
 
     #[cfg(feature = "checks")]
     fn check(&self) {
+        if let Err(error) = self.validate() {
+            panic!("{}", error);
+        }
+    }
+
+    /// Check the internal invariants of the atlas, returning a
+    /// `ValidationError::InvalidState` describing the first violation found.
+    ///
+    /// This is meant for diagnosing bugs in the allocator and runs in linear time. With the
+    /// `checks` feature, it is run after every operation that modifies the atlas.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.data.len() != self.items.len() {
+            return Err(ValidationError::InvalidState(format!("{} items but {} user data", self.items.len(), self.data.len())));
+        }
+
         let mut prev_empty = false;
         let mut accum_h = 0;
+        let mut requested_space = 0;
+        let mut bucketed_shelves = 0;
+        let mut prev_pos = None;
         let mut shelf_idx = self.first_shelf;
         let mut shelf_x = 0;
         while shelf_idx.is_some() {
             let shelf = &self.shelves[shelf_idx.index()];
+            if prev_pos >= Some((shelf.x, shelf.y)) {
+                return Err(ValidationError::InvalidState(format!("shelf {} is out of order", shelf_idx.0)));
+            }
+            prev_pos = Some((shelf.x, shelf.y));
+            let expected_bucket = if shelf.first_unallocated.is_some() {
+                height_bucket(shelf.height) as u8
+            } else {
+                NO_BUCKET
+            };
+            if shelf.bucket != expected_bucket {
+                return Err(ValidationError::InvalidState(format!("shelf {} is in bucket {} instead of {}", shelf_idx.0, shelf.bucket, expected_bucket)));
+            }
+            if shelf.bucket != NO_BUCKET {
+                bucketed_shelves += 1;
+            }
             let new_column = shelf_x != shelf.x;
             if new_column {
-                assert_eq!(accum_h as i32, self.size.height);
+                if accum_h as i32 != self.size.height {
+                    return Err(ValidationError::InvalidState(format!("the shelves of column {} are {} high instead of {}", shelf_x, accum_h, self.size.height)));
+                }
                 accum_h = 0;
             }
             shelf_x = shelf.x;
             accum_h += shelf.height;
-            if prev_empty && !new_column {
-                assert!(!shelf.is_empty);
+            if prev_empty && !new_column && shelf.is_empty {
+                return Err(ValidationError::InvalidState(format!("empty shelf {} should have been merged into the previous one", shelf_idx.0)));
             }
             if shelf.is_empty {
-                assert!(!self.items[shelf.first_item.index()].allocated);
-                assert!(self.items[shelf.first_item.index()].next.is_none());
+                let first_item = &self.items[shelf.first_item.index()];
+                if first_item.allocated || first_item.next.is_some() {
+                    return Err(ValidationError::InvalidState(format!("empty shelf {} should contain a single free item", shelf_idx.0)));
+                }
             }
             prev_empty = shelf.is_empty;
 
@@ -601,14 +2198,18 @@ impl AtlasAllocator {
             while item_idx.is_some() {
                 let item = &self.items[item_idx.index()];
                 accum_w += item.width;
-                if !item.allocated {
+                if item.allocated {
+                    requested_space += item.requested_area;
+                } else {
                     accum_unallocated_w += item.width;
                 }
 
-                assert_eq!(item.prev, prev_item_idx);
+                if item.prev != prev_item_idx {
+                    return Err(ValidationError::InvalidState(format!("item {} has the wrong previous item", item_idx.0)));
+                }
 
-                if !prev_allocated {
-                    assert!(item.allocated, "item {:?} should be allocated", item_idx.0);
+                if !prev_allocated && !item.allocated {
+                    return Err(ValidationError::InvalidState(format!("free item {} should have been merged into the previous one", item_idx.0)));
                 }
                 prev_allocated = item.allocated;
 
@@ -616,28 +2217,61 @@ impl AtlasAllocator {
                 item_idx = item.next;
             }
 
-            assert_eq!(accum_w, self.shelf_width);
+            if accum_w != self.shelf_width {
+                return Err(ValidationError::InvalidState(format!("the items of shelf {} are {} wide instead of {}", shelf_idx.0, accum_w, self.shelf_width)));
+            }
 
             // Traverse the shelf's unallocated list, validate it and check that it matches
-            // the amount of unallocated space we found from traversing the whole shelf. 
+            // the amount of unallocated space we found from traversing the whole shelf.
             accum_w = 0;
             let mut item_idx = shelf.first_unallocated;
             let mut prev_unallocated_idx = ItemIndex::NONE;
             while item_idx.is_some() {
                 let item = &self.items[item_idx.index()];
-                assert!(!item.allocated);
+                if item.allocated {
+                    return Err(ValidationError::InvalidState(format!("allocated item {} is in the unallocated list", item_idx.0)));
+                }
 
-                assert_eq!(item.prev_unallocated, prev_unallocated_idx);
+                if item.prev_unallocated != prev_unallocated_idx {
+                    return Err(ValidationError::InvalidState(format!("item {} has the wrong previous unallocated item", item_idx.0)));
+                }
                 accum_w += item.width;
 
                 prev_unallocated_idx = item_idx;
                 item_idx = item.next_unallocated;
             }
 
-            assert_eq!(accum_w, accum_unallocated_w, "items missing from the unallocated list?");
+            if accum_w != accum_unallocated_w {
+                return Err(ValidationError::InvalidState(format!("items of shelf {} are missing from the unallocated list", shelf_idx.0)));
+            }
 
             shelf_idx = shelf.next;
         }
+
+        if requested_space != self.requested_space {
+            return Err(ValidationError::InvalidState(format!("requested space is {} instead of {}", self.requested_space, requested_space)));
+        }
+
+        for (bucket, &first) in self.buckets.iter().enumerate() {
+            let mut prev_idx = ShelfIndex::NONE;
+            let mut shelf_idx = first;
+            while shelf_idx.is_some() {
+                let shelf = &self.shelves[shelf_idx.index()];
+                if shelf.bucket as usize != bucket || shelf.prev_in_bucket != prev_idx {
+                    return Err(ValidationError::InvalidState(format!("shelf {} is misplaced in the list of bucket {}", shelf_idx.0, bucket)));
+                }
+                bucketed_shelves -= 1;
+
+                prev_idx = shelf_idx;
+                shelf_idx = shelf.next_in_bucket;
+            }
+        }
+
+        if bucketed_shelves != 0 {
+            return Err(ValidationError::InvalidState(format!("{} shelves are missing from the bucket lists", bucketed_shelves)));
+        }
+
+        Ok(())
     }
 
     /// Turn a valid AllocId into an index that can be used as a key for external storage.
@@ -661,14 +2295,40 @@ impl AtlasAllocator {
 
     /// Returns the allocation info associated to the allocation ID.
     ///
-    /// The id must correspond to an existing allocation in the atlas.
+    /// The id must correspond to an existing allocation in the atlas. This runs in constant
+    /// time since the index encoded in the id is the index of the allocation's item.
     pub fn get(&self, id: AllocId) -> Rectangle {
-        let index = id.index()as usize;
-        let item = &self.items[index];
+        self.try_get(id).expect("Invalid AllocId")
+    }
 
-        assert!(item.allocated);
-        assert_eq!(item.generation, id.generation(), "Invalid AllocId");
+    /// Returns true if the id refers to a live allocation of the atlas.
+    ///
+    /// Unlike most other methods, this doesn't panic if the id is stale or comes from
+    /// another atlas.
+    pub fn contains(&self, id: AllocId) -> bool {
+        self.validate_id(id).is_ok()
+    }
 
+    fn validate_id(&self, id: AllocId) -> Result<(), DeallocError> {
+        let item = self.items.get(id.index() as usize).ok_or(DeallocError::NotAllocated)?;
+        if item.generation != id.generation() {
+            return Err(DeallocError::StaleGeneration);
+        }
+        if !item.allocated {
+            return Err(DeallocError::NotAllocated);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the allocation info associated to the allocation ID, or `None` if the id
+    /// doesn't refer to a live allocation of the atlas.
+    pub fn try_get(&self, id: AllocId) -> Option<Rectangle> {
+        if !self.contains(id) {
+            return None;
+        }
+
+        let item = &self.items[id.index() as usize];
         let shelf = &self.shelves[item.shelf.index()];
 
         let mut rectangle = Rectangle {
@@ -683,14 +2343,81 @@ impl AtlasAllocator {
         };
 
         if self.flip_xy {
-            std::mem::swap(&mut rectangle.min.x, &mut rectangle.min.y);
-            std::mem::swap(&mut rectangle.max.x, &mut rectangle.max.y);
+            core::mem::swap(&mut rectangle.min.x, &mut rectangle.min.y);
+            core::mem::swap(&mut rectangle.max.x, &mut rectangle.max.y);
         }
 
-        rectangle
+        Some(self.remove_padding(rectangle))
+    }
+
+    // The rectangle handed out for an item, inside of the padding.
+    fn remove_padding(&self, rectangle: Rectangle) -> Rectangle {
+        let padding = self.padding as i32;
+        rectangle.inflate(-padding, -padding)
+    }
+
+    /// Returns a reference to the user data associated to the allocation ID.
+    ///
+    /// The id must correspond to an existing allocation in the atlas.
+    pub fn get_data(&self, id: AllocId) -> &T {
+        let index = id.index() as usize;
+        let item = &self.items[index];
+
+        assert!(item.allocated);
+        assert_eq!(item.generation, id.generation(), "Invalid AllocId");
+
+        &self.data[index]
+    }
+
+    /// Returns a mutable reference to the user data associated to the allocation ID.
+    ///
+    /// The id must correspond to an existing allocation in the atlas.
+    pub fn get_data_mut(&mut self, id: AllocId) -> &mut T {
+        let index = id.index() as usize;
+        let item = &self.items[index];
+
+        assert!(item.allocated);
+        assert_eq!(item.generation, id.generation(), "Invalid AllocId");
+
+        &mut self.data[index]
+    }
+
+    /// Replace the user data associated to the allocation ID and return the previous value.
+    ///
+    /// The id must correspond to an existing allocation in the atlas.
+    pub fn set_data(&mut self, id: AllocId, data: T) -> T {
+        core::mem::replace(self.get_data_mut(id), data)
+    }
+
+    /// Invoke a callback with the user data of each live allocation.
+    pub fn for_each_data<F>(&self, mut callback: F)
+    where
+        F: FnMut(AllocId, &T),
+    {
+        for (index, (item, data)) in self.items.iter().zip(self.data.iter()).enumerate() {
+            if item.allocated {
+                callback(AllocId::new(index as u16, item.generation), data);
+            }
+        }
+    }
+
+    /// Invoke a callback with a mutable reference to the user data of each live allocation.
+    ///
+    /// This is faster than calling `get_data_mut` for each allocation when sweeping over
+    /// the whole atlas, for example to update timestamps.
+    pub fn for_each_data_mut<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(AllocId, &mut T),
+    {
+        for (index, (item, data)) in self.items.iter().zip(self.data.iter_mut()).enumerate() {
+            if item.allocated {
+                callback(AllocId::new(index as u16, item.generation), data);
+            }
+        }
     }
 
     /// Dump a visual representation of the atlas in SVG format.
+    #[cfg(feature = "std")]
     pub fn dump_svg(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
         use svg_fmt::*;
 
@@ -708,12 +2435,59 @@ impl AtlasAllocator {
         writeln!(output, "{}", EndSvg)
     }
 
+    /// Dump a visual representation of the atlas as a PNG image, at one pixel per unit.
+    ///
+    /// Uses the same colors as the SVG output.
+    #[cfg(feature = "png")]
+    pub fn dump_png(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        crate::png::dump_partition(self.size(), &self.partition(), output)
+    }
+
     /// Dump a visual representation of the atlas in SVG, omitting the beginning and end of the
     /// SVG document, so that it can be included in a larger document.
     ///
     /// If a rectangle is provided, translate and scale the output to fit it.
+    #[cfg(feature = "std")]
     pub fn dump_into_svg(&self, rect: Option<&Rectangle>, output: &mut dyn std::io::Write) -> std::io::Result<()> {
-        use svg_fmt::*;
+        self.dump_into_svg_with_options(rect, &SvgOptions::default(), output)
+    }
+
+    /// Same as `dump_into_svg` with control over the colors and labels of the output.
+    #[cfg(feature = "std")]
+    pub fn dump_into_svg_with_options(
+        &self,
+        rect: Option<&Rectangle>,
+        options: &SvgOptions,
+        output: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        self.dump_into_svg_impl(rect, options, &|_| options.allocated_color, output)
+    }
+
+    /// Dump a visual representation of the atlas in SVG, coloring allocations by group.
+    ///
+    /// The provided function maps each allocation to a group number. Allocations of the same
+    /// group are drawn with the same color, which makes it easy to see how allocations from
+    /// different sources (for example glyphs from different fonts) are laid out.
+    ///
+    /// Like `dump_into_svg`, this omits the beginning and end of the SVG document and translates
+    /// and scales the output to fit the rectangle if one is provided.
+    #[cfg(feature = "std")]
+    pub fn dump_into_svg_grouped<F>(&self, rect: Option<&Rectangle>, key: F, output: &mut dyn std::io::Write) -> std::io::Result<()>
+    where
+        F: Fn(AllocId) -> u32,
+    {
+        self.dump_into_svg_impl(rect, &SvgOptions::default(), &|id| group_color(key(id)), output)
+    }
+
+    #[cfg(feature = "std")]
+    fn dump_into_svg_impl(
+        &self,
+        rect: Option<&Rectangle>,
+        options: &SvgOptions,
+        allocated_color: &dyn Fn(AllocId) -> svg_fmt::Color,
+        output: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        use svg_fmt::*;
 
         let (sx, sy, tx, ty) = if let Some(rect) = rect {
             (
@@ -730,8 +2504,8 @@ impl AtlasAllocator {
             output,
             r#"    {}"#,
             rectangle(tx, ty, self.size.width as f32 * sx, self.size.height as f32 * sy)
-                .fill(rgb(40, 40, 40))
-                .stroke(Stroke::Color(black(), 1.0))
+                .fill(options.background_color)
+                .stroke(Stroke::Color(black(), options.stroke_width))
         )?;
 
         let mut shelf_idx = self.first_shelf;
@@ -748,10 +2522,11 @@ impl AtlasAllocator {
                 let x = item.x as f32 * sx;
                 let w = item.width as f32 * sx;
 
+                let id = AllocId::new(item_idx.0, item.generation);
                 let color = if item.allocated {
-                    rgb(70, 70, 180)
+                    allocated_color(id)
                 } else {
-                    rgb(50, 50, 50)
+                    options.free_color
                 };
 
                 let (x, y) = if self.flip_xy { (y, x) } else { (x, y) };
@@ -760,9 +2535,13 @@ impl AtlasAllocator {
                 writeln!(
                     output,
                     r#"    {}"#,
-                    rectangle(x + tx, y + ty, w, h).fill(color).stroke(Stroke::Color(black(), 1.0))
+                    rectangle(x + tx, y + ty, w, h).fill(color).stroke(Stroke::Color(black(), options.stroke_width))
                 )?;
 
+                if item.allocated {
+                    write_svg_label(options, id, (x + tx, y + ty, w, h), output)?;
+                }
+
                 item_idx = item.next;
             }
 
@@ -775,6 +2554,18 @@ impl AtlasAllocator {
 }
 
 
+/// Pick a color for a group of allocations in the SVG output.
+#[cfg(feature = "std")]
+fn group_color(group: u32) -> svg_fmt::Color {
+    // Scramble the bits so that consecutive group numbers get very different colors.
+    let h = group.wrapping_add(1).wrapping_mul(0x9E37_79B9);
+    svg_fmt::rgb(
+        60 + (h >> 24) as u8 % 180,
+        60 + (h >> 16) as u8 % 180,
+        60 + (h >> 8) as u8 % 180,
+    )
+}
+
 fn adjust_size(alignment: i32, size: &mut i32) {
     let rem = *size % alignment;
     if rem > 0 {
@@ -790,6 +2581,27 @@ fn convert_coordinates(flip_xy: bool, x: i32, y: i32) -> (i32, i32) {
     }
 }
 
+fn align_up(value: i32, alignment: i32) -> i32 {
+    let mut value = value;
+    adjust_size(alignment, &mut value);
+
+    value
+}
+
+// The height class of a shelf. The classes follow the rounding of `shelf_height` so that shelves
+// of the same rounded height share a bucket, up to the last bucket which contains all the
+// shelves of height 1088 and more.
+fn height_bucket(height: u16) -> usize {
+    let bucket = match height {
+        0 ..= 31 => height / 8,
+        32 ..= 127 => 4 + (height - 32) / 16,
+        128 ..= 511 => 10 + (height - 128) / 32,
+        _ => 22 + (height - 512) / 64,
+    };
+
+    (bucket as usize).min(NUM_HEIGHT_BUCKETS - 1)
+}
+
 fn shelf_height(size: i32, atlas_height: i32) -> i32 {
     let alignment = match size {
         0 ..= 31 => 8,
@@ -810,13 +2622,55 @@ fn shelf_height(size: i32, atlas_height: i32) -> i32 {
     adjusted_size
 }
 
+/// An allocation that was moved by `AtlasAllocator::rearrange`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Change {
+    /// The allocation before rearranging (its id is no longer valid).
+    pub old: Allocation,
+    /// The allocation after rearranging.
+    pub new: Allocation,
+}
+
+/// The result of `AtlasAllocator::rearrange`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RearrangeResult {
+    /// The allocations that were moved, in increasing old id order.
+    pub changes: Vec<Change>,
+    /// The allocations that didn't fit in the new layout and were deallocated.
+    ///
+    /// Sorting the allocations by size almost always packs them at least as well as the
+    /// original layout so this is usually empty, but it isn't guaranteed.
+    pub failures: Vec<Allocation>,
+}
+
+/// A saved state of an `AtlasAllocator`, see `AtlasAllocator::snapshot`.
+#[derive(Clone)]
+pub struct AtlasSnapshot<T = ()> {
+    shelves: Vec<Shelf>,
+    items: Vec<Item>,
+    data: Vec<T>,
+    alignment: Size,
+    padding: u16,
+    flip_xy: bool,
+    size: Size,
+    first_shelf: ShelfIndex,
+    free_items: ItemIndex,
+    free_shelves: ShelfIndex,
+    buckets: [ShelfIndex; NUM_HEIGHT_BUCKETS],
+    shelf_width: u16,
+    allocated_space: i32,
+    fixed_shelf_height: Option<u16>,
+    requested_space: i32,
+    white_pixel: Option<Rectangle>,
+}
+
 /// Iterator over the allocations of an atlas.
-pub struct Iter<'l> {
-    atlas: &'l AtlasAllocator,
+pub struct Iter<'l, T = ()> {
+    atlas: &'l AtlasAllocator<T>,
     idx: usize,
 }
 
-impl<'l> Iterator for Iter<'l> {
+impl<'l, T> Iterator for Iter<'l, T> {
     type Item = Allocation;
 
     fn next(&mut self) -> Option<Allocation> {
@@ -849,9 +2703,11 @@ impl<'l> Iterator for Iter<'l> {
         };
 
         if self.atlas.flip_xy {
-            std::mem::swap(&mut alloc.rectangle.min.x, &mut alloc.rectangle.min.y);
-            std::mem::swap(&mut alloc.rectangle.max.x, &mut alloc.rectangle.max.y);
+            core::mem::swap(&mut alloc.rectangle.min.x, &mut alloc.rectangle.min.y);
+            core::mem::swap(&mut alloc.rectangle.max.x, &mut alloc.rectangle.max.y);
         }
+        let padding = self.atlas.padding as i32;
+        alloc.rectangle = alloc.rectangle.inflate(-padding, -padding);
 
         self.idx += 1;
 
@@ -859,14 +2715,74 @@ impl<'l> Iterator for Iter<'l> {
     }
 }
 
-impl<'l> std::iter::IntoIterator for &'l AtlasAllocator {
+impl<'l, T: Default> core::iter::IntoIterator for &'l AtlasAllocator<T> {
     type Item = Allocation;
-    type IntoIter = Iter<'l>;
-    fn into_iter(self) -> Iter<'l> {
+    type IntoIter = Iter<'l, T>;
+    fn into_iter(self) -> Iter<'l, T> {
         self.iter()
     }
 }
 
+/// Prints the size and configuration of the atlas followed by a summary of the first shelves
+/// (see `dump_svg` for a complete representation).
+impl<T> core::fmt::Debug for AtlasAllocator<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let mut num_shelves = 0;
+        let mut shelf_idx = self.first_shelf;
+        while shelf_idx.is_some() {
+            num_shelves += 1;
+            shelf_idx = self.shelves[shelf_idx.index()].next;
+        }
+
+        let size = if self.flip_xy { size2(self.size.height, self.size.width) } else { self.size };
+        writeln!(
+            f,
+            "AtlasAllocator {{ size: {}x{}, columns: {}, vertical_shelves: {}, shelves: {}, allocations: {}, occupancy: {:.1}% }}",
+            size.width,
+            size.height,
+            self.size.width / (self.shelf_width as i32).max(1),
+            self.flip_xy,
+            num_shelves,
+            self.items.iter().filter(|item| item.allocated).count(),
+            self.allocated_space as f32 * 100.0 / self.size.area() as f32,
+        )?;
+
+        let mut shelf_idx = self.first_shelf;
+        for _ in 0..num_shelves.min(DEBUG_MAX_SHELVES) {
+            let shelf = &self.shelves[shelf_idx.index()];
+
+            let (mut items, mut allocated_width) = (0, 0);
+            let mut item_idx = shelf.first_item;
+            while item_idx.is_some() {
+                let item = &self.items[item_idx.index()];
+                if item.allocated {
+                    items += 1;
+                    allocated_width += item.width as u32;
+                }
+                item_idx = item.next;
+            }
+
+            writeln!(
+                f,
+                "  shelf x={} y={} h={}: {} items, {}% filled",
+                shelf.x,
+                shelf.y,
+                shelf.height,
+                items,
+                allocated_width * 100 / (self.shelf_width as u32).max(1),
+            )?;
+
+            shelf_idx = shelf.next;
+        }
+
+        if num_shelves > DEBUG_MAX_SHELVES {
+            writeln!(f, "  ... {} more shelves", num_shelves - DEBUG_MAX_SHELVES)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[test]
 fn test_simple() {
     let mut atlas = AtlasAllocator::with_options(
@@ -875,6 +2791,7 @@ fn test_simple() {
             alignment: size2(4, 8),
             vertical_shelves: false,
             num_columns: 2,
+            ..DEFAULT_OPTIONS
         },
     );
 
@@ -909,6 +2826,7 @@ fn test_options() {
             alignment,
             vertical_shelves: true,
             num_columns: 1,
+            ..DEFAULT_OPTIONS
         },
     );
     assert!(atlas.is_empty());
@@ -1077,6 +2995,25 @@ fn clear() {
     }
 }
 
+#[test]
+fn svg_grouped() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(32, 32)).unwrap();
+    let b = atlas.allocate(size2(32, 32)).unwrap();
+    atlas.allocate(size2(64, 64)).unwrap();
+
+    let mut grouped = Vec::new();
+    atlas.dump_into_svg_grouped(None, |id| if id == a.id || id == b.id { 0 } else { 1 }, &mut grouped).unwrap();
+    let grouped = String::from_utf8(grouped).unwrap();
+
+    let first = group_color(0).to_string();
+    let second = group_color(1).to_string();
+    assert_ne!(first, second);
+    assert_eq!(grouped.matches(&first).count(), 2);
+    assert_eq!(grouped.matches(&second).count(), 1);
+}
+
 #[test]
 fn fuzz_01() {
     let s = 65472;
@@ -1103,7 +3040,7 @@ fn fuzz_01() {
 
     // Because of potential alignment we won't necessarily
     // succeed at allocation something this big
-    let s = std::u16::MAX as i32;
+    let s = u16::MAX as i32;
 
     let mut atlas = AtlasAllocator::new(size2(s, 64));
     if let Some(alloc) = atlas.allocate(size2(s, 64)) {
@@ -1198,3 +3135,1397 @@ fn issue_17_2() {
 
     atlas.deallocate(a.id);
 }
+
+#[test]
+fn user_data() {
+    let mut atlas = AtlasAllocator::<u32>::with_user_data(size2(1000, 1000), &DEFAULT_OPTIONS);
+
+    let a = atlas.allocate(size2(100, 100)).unwrap().id;
+    let b = atlas.allocate(size2(100, 100)).unwrap().id;
+    let c = atlas.allocate(size2(100, 100)).unwrap().id;
+
+    assert_eq!(*atlas.get_data(a), 0);
+    *atlas.get_data_mut(a) = 1;
+    *atlas.get_data_mut(b) = 2;
+    *atlas.get_data_mut(c) = 3;
+
+    atlas.deallocate(b);
+
+    let mut visited = Vec::new();
+    atlas.for_each_data(|id, data| visited.push((id, *data)));
+    visited.sort_by_key(|&(_, data)| data);
+    assert_eq!(visited, vec![(a, 1), (c, 3)]);
+
+    atlas.for_each_data_mut(|_, data| *data += 10);
+    assert_eq!(*atlas.get_data(a), 11);
+    assert_eq!(*atlas.get_data(c), 13);
+
+    // The data of new allocations starts from the default value.
+    let d = atlas.allocate(size2(100, 100)).unwrap().id;
+    assert_eq!(*atlas.get_data(d), 0);
+}
+
+#[test]
+fn generation_report() {
+    let mut atlas = AtlasAllocator::new(size2(1000, 1000));
+    assert_eq!(atlas.max_generation(), 1);
+    assert_eq!(atlas.items_near_wraparound(2), 0);
+
+    for _ in 0..10 {
+        let a = atlas.allocate(size2(100, 100)).unwrap();
+        atlas.deallocate(a.id);
+    }
+
+    assert!(atlas.max_generation() >= 10);
+    assert!(atlas.items_near_wraparound(10) >= 1);
+    assert_eq!(atlas.items_near_wraparound(u16::MAX), 0);
+}
+
+#[test]
+fn content_y_extent() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.content_y_extent(), (0, 0));
+
+    let a = atlas.allocate(size2(100, 16)).unwrap();
+    let b = atlas.allocate(size2(100, 64)).unwrap();
+    assert_eq!(atlas.content_y_extent(), (a.rectangle.min.y, b.rectangle.max.y));
+
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.content_y_extent(), (b.rectangle.min.y, b.rectangle.max.y));
+
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.content_y_extent(), (0, 0));
+
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(16, 100)).unwrap();
+    assert_eq!(atlas.content_y_extent(), (a.rectangle.min.x, a.rectangle.max.x));
+}
+
+#[test]
+fn allocate_pow2() {
+    let mut atlas = AtlasAllocator::new(size2(1024, 1024));
+
+    let mut allocations = Vec::new();
+    for size in &[size2(3, 5), size2(100, 30), size2(17, 200), size2(512, 512), size2(1, 1)] {
+        let alloc = atlas.allocate_pow2(*size).unwrap();
+        let rect_size = alloc.rectangle.size();
+        assert!((rect_size.width as u32).is_power_of_two());
+        assert!((rect_size.height as u32).is_power_of_two());
+        assert!(rect_size.width >= size.width && rect_size.width < size.width * 2);
+        assert!(rect_size.height >= size.height && rect_size.height < size.height * 2);
+        allocations.push(alloc);
+    }
+
+    for (i, a) in allocations.iter().enumerate() {
+        for b in &allocations[i + 1..] {
+            assert!(!a.rectangle.intersects(&b.rectangle));
+        }
+    }
+
+    // Rounds up to 2048 which doesn't fit.
+    assert!(atlas.allocate_pow2(size2(1025, 1)).is_none());
+}
+
+#[test]
+fn coverage() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.coverage(), None);
+
+    let a = atlas.allocate(size2(100, 16)).unwrap();
+    let b = atlas.allocate(size2(50, 64)).unwrap();
+    assert_eq!(atlas.coverage(), Some(a.rectangle.union(&b.rectangle)));
+
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.coverage(), Some(b.rectangle));
+
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.coverage(), None);
+
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(16, 100)).unwrap();
+    assert_eq!(atlas.coverage(), Some(a.rectangle));
+}
+
+#[test]
+fn allocate_first_fit() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let sizes = [size2(512, 512), size2(256, 256), size2(128, 128)];
+    let (index, a) = atlas.allocate_first_fit(&sizes).unwrap();
+    assert_eq!(index, 1);
+    assert_eq!(a.rectangle.size(), size2(256, 256));
+
+    assert!(atlas.allocate_first_fit(&sizes).is_none());
+    assert!(atlas.allocate_first_fit(&[]).is_none());
+
+    atlas.deallocate(a.id);
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn allocate_at() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let r1 = Rectangle { min: point2(10, 20), max: point2(50, 40) };
+    let a = atlas.allocate_at(r1).unwrap();
+    assert_eq!(a.rectangle, r1);
+    assert_eq!(atlas.get(a.id), r1);
+    assert_eq!(atlas.allocated_space(), r1.area());
+
+    // Overlapping rectangles are rejected.
+    assert!(atlas.allocate_at(Rectangle { min: point2(40, 30), max: point2(60, 50) }).is_none());
+    // Out of bounds.
+    assert!(atlas.allocate_at(Rectangle { min: point2(200, 200), max: point2(300, 210) }).is_none());
+
+    // In the same shelf, so the allocation covers the shelf's height.
+    let b = atlas.allocate_at(Rectangle { min: point2(100, 25), max: point2(120, 35) }).unwrap();
+    assert_eq!(b.rectangle, Rectangle { min: point2(100, 20), max: point2(120, 40) });
+
+    let mut allocations = vec![a, b];
+    while let Some(alloc) = atlas.allocate(size2(30, 30)) {
+        for previous in &allocations {
+            assert!(!alloc.rectangle.intersects(&previous.rectangle));
+        }
+        allocations.push(alloc);
+    }
+
+    for alloc in &allocations {
+        atlas.deallocate(alloc.id);
+    }
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+    assert!(atlas.allocate(size2(256, 256)).is_some());
+}
+
+#[test]
+fn with_reserved() {
+    let white_pixel = Rectangle { min: point2(0, 0), max: point2(1, 1) };
+    // With vertical shelves, a column along the right edge is contained in a single shelf.
+    let header = Rectangle { min: point2(250, 0), max: point2(256, 256) };
+
+    let (mut atlas, ids) = AtlasAllocator::with_reserved(
+        size2(256, 256),
+        &AllocatorOptions {
+            vertical_shelves: true,
+            ..DEFAULT_OPTIONS
+        },
+        &[white_pixel, header],
+    ).unwrap();
+
+    assert_eq!(ids.len(), 2);
+    assert_eq!(atlas.get(ids[0]), white_pixel);
+    assert_eq!(atlas.get(ids[1]), header);
+
+    let a = atlas.allocate(size2(100, 100)).unwrap();
+    assert!(!a.rectangle.intersects(&white_pixel));
+    assert!(!a.rectangle.intersects(&header));
+
+    assert!(AtlasAllocator::with_reserved(size2(256, 256), &DEFAULT_OPTIONS, &[white_pixel, white_pixel]).is_none());
+}
+
+#[test]
+fn white_pixel() {
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        alignment: size2(4, 2),
+        reserve_white_pixel: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let white_pixel = Rectangle { min: point2(0, 0), max: point2(4, 2) };
+    assert_eq!(atlas.white_pixel(), Some(white_pixel));
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), white_pixel.area());
+
+    let a = atlas.allocate(size2(256, 200)).unwrap();
+    assert!(!a.rectangle.intersects(&white_pixel));
+    assert!(!atlas.is_empty());
+
+    atlas.deallocate(a.id);
+    assert!(atlas.is_empty());
+
+    atlas.clear();
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.iter().next().map(|alloc| alloc.rectangle), Some(white_pixel));
+
+    assert_eq!(AtlasAllocator::new(size2(256, 256)).white_pixel(), None);
+}
+
+#[test]
+fn partition() {
+    fn check_partition(atlas: &AtlasAllocator) {
+        let bounds = Rectangle { min: point2(0, 0), max: atlas.size().to_vector().to_point() };
+        let rects = atlas.partition();
+
+        let mut area = 0;
+        let mut allocated_area = 0;
+        for (i, &(rect, allocated)) in rects.iter().enumerate() {
+            assert!(bounds.contains_box(&rect));
+            for &(other, _) in &rects[i + 1..] {
+                assert!(!rect.intersects(&other));
+            }
+            area += rect.area();
+            if allocated {
+                allocated_area += rect.area();
+            }
+        }
+
+        assert_eq!(area, bounds.area());
+        assert_eq!(allocated_area, atlas.allocated_space());
+    }
+
+    for &vertical_shelves in &[false, true] {
+        let mut atlas = AtlasAllocator::with_options(size2(1000, 800), &AllocatorOptions {
+            num_columns: 3,
+            vertical_shelves,
+            ..DEFAULT_OPTIONS
+        });
+        check_partition(&atlas);
+
+        let a = atlas.allocate(size2(100, 30)).unwrap();
+        let b = atlas.allocate(size2(50, 30)).unwrap();
+        let c = atlas.allocate(size2(200, 100)).unwrap();
+        let _d = atlas.allocate(size2(30, 250)).unwrap();
+        check_partition(&atlas);
+
+        atlas.deallocate(a.id);
+        check_partition(&atlas);
+
+        atlas.deallocate(b.id);
+        atlas.deallocate(c.id);
+        check_partition(&atlas);
+    }
+}
+
+#[test]
+fn trim_unused_storage() {
+    let mut atlas = AtlasAllocator::new(size2(1000, 1000));
+
+    let mut ids = Vec::new();
+    for i in 0..200 {
+        ids.push(atlas.allocate(size2(10 + i % 20, 10 + i % 30)).unwrap().id);
+    }
+    let kept: Vec<AllocId> = ids.iter().step_by(80).cloned().collect();
+    for id in &ids {
+        if !kept.contains(id) {
+            atlas.deallocate(*id);
+        }
+    }
+
+    let mut trimmed = atlas.clone();
+    trimmed.trim_unused_storage();
+    assert!(trimmed.items.len() < atlas.items.len());
+
+    for i in 0..100 {
+        let size = size2(5 + i % 40, 5 + i % 25);
+        let a = atlas.allocate(size).map(|alloc| alloc.rectangle);
+        let b = trimmed.allocate(size).map(|alloc| alloc.rectangle);
+        assert_eq!(a, b);
+    }
+
+    for id in &kept {
+        assert_eq!(trimmed.get(*id), atlas.get(*id));
+    }
+}
+
+#[test]
+fn trim_unused_storage_stale_ids() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+    atlas.deallocate(b.id);
+    atlas.deallocate(a.id);
+    atlas.trim_unused_storage();
+
+    let c = atlas.allocate(size2(10, 10)).unwrap();
+    let d = atlas.allocate(size2(10, 10)).unwrap();
+    for stale in [a.id, b.id] {
+        assert_ne!(stale, c.id);
+        assert_ne!(stale, d.id);
+        assert_eq!(atlas.try_get(stale), None);
+        assert!(atlas.try_deallocate(stale).is_err());
+    }
+
+    assert_eq!(atlas.try_get(c.id), Some(c.rectangle));
+    assert_eq!(atlas.try_get(d.id), Some(d.rectangle));
+}
+
+#[cfg(feature = "serialization")]
+#[test]
+fn trim_unused_storage_serialization() {
+    let mut atlas = AtlasAllocator::new(size2(1000, 1000));
+
+    let mut ids = Vec::new();
+    for i in 0..200 {
+        ids.push(atlas.allocate(size2(10 + i % 20, 10 + i % 30)).unwrap().id);
+    }
+    let kept = [ids[0], ids[50]];
+    for id in &ids {
+        if !kept.contains(id) {
+            atlas.deallocate(*id);
+        }
+    }
+
+    let mut trimmed = atlas.clone();
+    trimmed.trim_unused_storage();
+    let serialized = ron::ser::to_string(&trimmed).unwrap();
+    assert!(serialized.len() < ron::ser::to_string(&atlas).unwrap().len());
+
+    let mut deserialized: AtlasAllocator = ron::de::from_str(&serialized).unwrap();
+    for id in &kept {
+        assert_eq!(deserialized.get(*id), atlas.get(*id));
+    }
+
+    for i in 0..100 {
+        let size = size2(5 + i % 40, 5 + i % 25);
+        let a = atlas.allocate(size).map(|alloc| alloc.rectangle);
+        let b = deserialized.allocate(size).map(|alloc| alloc.rectangle);
+        assert_eq!(a, b);
+    }
+}
+
+#[test]
+fn reconfigure() {
+    let mut atlas = AtlasAllocator::new(size2(256, 512));
+
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    assert_eq!(atlas.reconfigure(&DEFAULT_OPTIONS), Err(NonEmpty));
+
+    atlas.deallocate(a.id);
+    atlas.reconfigure(&AllocatorOptions {
+        alignment: size2(8, 8),
+        vertical_shelves: true,
+        reserve_white_pixel: true,
+        ..DEFAULT_OPTIONS
+    }).unwrap();
+    assert_eq!(atlas.size(), size2(256, 512));
+    assert!(atlas.is_empty());
+    assert!(atlas.white_pixel().is_some());
+
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+    assert_eq!(b.rectangle.size(), size2(16, 16));
+    let c = atlas.allocate(size2(10, 10)).unwrap();
+    // Vertical shelves: the items of a shelf are stacked along the y axis.
+    assert_eq!(b.rectangle.min.x, c.rectangle.min.x);
+
+    atlas.deallocate(b.id);
+    atlas.deallocate(c.id);
+
+    // Only the white pixel is allocated.
+    atlas.reconfigure(&DEFAULT_OPTIONS).unwrap();
+    assert_eq!(atlas.white_pixel(), None);
+    assert_eq!(atlas.allocated_space(), 0);
+}
+
+#[test]
+fn internal_fragmentation() {
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        alignment: size2(4, 4),
+        ..DEFAULT_OPTIONS
+    });
+    assert_eq!(atlas.internal_fragmentation(), 0.0);
+
+    let a = atlas.allocate(size2(16, 32)).unwrap();
+    assert_eq!(a.rectangle.area(), 16 * 32);
+    assert_eq!(atlas.internal_fragmentation(), 0.0);
+
+    // Rounded up to 32x32.
+    let b = atlas.allocate(size2(30, 30)).unwrap();
+    assert_eq!(b.rectangle.area(), 32 * 32);
+    let padding = (32 * 32 - 30 * 30) as f32;
+    let allocated = (16 * 32 + 32 * 32) as f32;
+    assert_eq!(atlas.internal_fragmentation(), padding / allocated);
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.internal_fragmentation(), 0.0);
+}
+
+#[test]
+fn fixed_shelf_height() {
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        fixed_shelf_height: Some(32),
+        ..DEFAULT_OPTIONS
+    });
+
+    assert!(atlas.allocate(size2(10, 33)).is_none());
+
+    let mut ids = Vec::new();
+    for i in 0..40 {
+        let alloc = atlas.allocate(size2(20 + i % 7, 1 + (i * 5) % 32)).unwrap();
+        assert_eq!(alloc.rectangle.height(), 32);
+        assert_eq!(alloc.rectangle.min.y % 32, 0);
+        ids.push(alloc.id);
+    }
+
+    for id in ids {
+        atlas.deallocate(id);
+    }
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn dump_table() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(32, 64)).unwrap();
+    let b = atlas.allocate(size2(10, 20)).unwrap();
+    let c = atlas.allocate(size2(20, 20)).unwrap();
+    atlas.deallocate(a.id);
+
+    let expected = concat!(
+        "        id     x     y     w     h\n",
+        "     65537     0    64    10    24\n",
+        "     65540    10    64    20    24\n",
+    );
+    assert_eq!(atlas.dump_table(), expected);
+    assert_eq!(b.id.serialize(), 65537);
+    assert_eq!(c.id.serialize(), 65540);
+}
+
+#[test]
+fn reallocate() {
+    let mut atlas = AtlasAllocator::<u32>::with_user_data(size2(256, 256), &DEFAULT_OPTIONS);
+
+    let mut ids = Vec::new();
+    for i in 0..40 {
+        let size = size2(5 + (i * 7) % 30, 3 + (i * 13) % 40);
+        let alloc = atlas.allocate(size).unwrap();
+        *atlas.get_data_mut(alloc.id) = i as u32;
+        ids.push(alloc);
+    }
+    for alloc in ids.drain(20..) {
+        atlas.deallocate(alloc.id);
+    }
+
+    let requested_space = atlas.requested_space;
+    let (atlas, moved, failed) = atlas.reallocate(size2(512, 512), &DEFAULT_OPTIONS);
+    assert!(failed.is_empty());
+    assert_eq!(moved.len(), ids.len());
+    assert_eq!(atlas.requested_space, requested_space);
+
+    for (i, old) in ids.iter().enumerate() {
+        let new = moved[&old.id];
+        assert!(new.rectangle.width() >= old.rectangle.width());
+        assert!(new.rectangle.height() >= old.rectangle.height());
+        assert_eq!(*atlas.get_data(new.id), i as u32);
+        assert_eq!(atlas.get(new.id), new.rectangle);
+    }
+
+    // Not everything fits in a smaller atlas.
+    let (small, moved, failed) = atlas.reallocate(size2(64, 64), &DEFAULT_OPTIONS);
+    assert!(!failed.is_empty());
+    assert_eq!(moved.len() + failed.len(), ids.len());
+    assert_eq!(small.iter().count(), moved.len());
+}
+
+#[test]
+fn max_allocatable() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.max_allocatable(), size2(256, 256));
+
+    let a = atlas.allocate(size2(256, 100)).unwrap();
+    let max = atlas.max_allocatable();
+    assert!(max.area() <= 256 * 156);
+    assert!(atlas.clone().allocate(max).is_some());
+
+    // Allocating elsewhere doesn't invalidate the cached value.
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+    let max = atlas.max_allocatable();
+    assert!(atlas.clone().allocate(max).is_some());
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.max_allocatable(), size2(256, 256));
+
+    let full = atlas.allocate(size2(256, 256)).unwrap();
+    assert_eq!(atlas.max_allocatable(), size2(0, 0));
+    atlas.deallocate(full.id);
+
+    let mut atlas = AtlasAllocator::with_options(
+        size2(300, 200),
+        &AllocatorOptions {
+            alignment: size2(4, 4),
+            vertical_shelves: true,
+            ..DEFAULT_OPTIONS
+        },
+    );
+    let mut ids = Vec::new();
+    for i in 0..50 {
+        if let Some(alloc) = atlas.allocate(size2(3 + (i * 7) % 50, 5 + (i * 11) % 60)) {
+            ids.push(alloc.id);
+        }
+        let max = atlas.max_allocatable();
+        if !max.is_empty() {
+            assert!(atlas.clone().allocate(max).is_some(), "{:?}", max);
+        }
+        if i % 4 == 0 {
+            atlas.deallocate(ids.remove(0));
+        }
+    }
+}
+
+#[test]
+fn finalize() {
+    let mut atlas = AtlasAllocator::<u32>::with_user_data(size2(256, 256), &DEFAULT_OPTIONS);
+
+    let mut allocations = Vec::new();
+    for i in 0..30 {
+        let alloc = atlas.allocate(size2(10 + i % 20, 8 + i % 30)).unwrap();
+        *atlas.get_data_mut(alloc.id) = i as u32;
+        allocations.push(alloc);
+    }
+    let removed = allocations.remove(3);
+    atlas.deallocate(removed.id);
+    assert_eq!(atlas.verify_no_overlap(), Ok(()));
+
+    let frozen = atlas.finalize().unwrap();
+    assert_eq!(frozen.size(), size2(256, 256));
+    assert_eq!(frozen.len(), allocations.len());
+    assert_eq!(frozen.iter().count(), allocations.len());
+    for alloc in &allocations {
+        assert_eq!(frozen.get(alloc.id), Some(alloc.rectangle));
+    }
+    assert_eq!(frozen.get_data(allocations[0].id), Some(&0));
+    assert_eq!(frozen.get_data(allocations[5].id), Some(&6));
+    assert_eq!(frozen.get(removed.id), None);
+    assert_eq!(frozen.get(AllocId::new(1000, 1)), None);
+}
+
+#[test]
+fn allocated_space() {
+    for &vertical_shelves in &[false, true] {
+        let mut atlas = AtlasAllocator::with_options(
+            size2(256, 512),
+            &AllocatorOptions {
+                vertical_shelves,
+                num_columns: 2,
+                ..DEFAULT_OPTIONS
+            },
+        );
+        assert_eq!(atlas.allocated_space(), 0);
+
+        let a = atlas.allocate(size2(30, 20)).unwrap();
+        let b = atlas.allocate(size2(128, 100)).unwrap();
+        assert_eq!(atlas.allocated_space(), a.rectangle.area() + b.rectangle.area());
+        assert_eq!(atlas.free_space(), atlas.size().area() - atlas.allocated_space());
+        assert_eq!(atlas.occupancy(), atlas.allocated_space() as f32 / atlas.size().area() as f32);
+        assert_eq!(atlas.allocated_space(), atlas.iter().map(|alloc| alloc.rectangle.area()).sum::<i32>());
+
+        atlas.deallocate(b.id);
+        assert_eq!(atlas.allocated_space(), a.rectangle.area());
+        atlas.deallocate(a.id);
+        assert_eq!(atlas.allocated_space(), 0);
+        assert_eq!(atlas.occupancy(), 0.0);
+    }
+}
+
+#[test]
+fn try_get() {
+    let mut atlas = AtlasAllocator::with_options(
+        size2(256, 256),
+        &AllocatorOptions {
+            vertical_shelves: true,
+            ..DEFAULT_OPTIONS
+        },
+    );
+
+    let a = atlas.allocate(size2(20, 30)).unwrap();
+    let b = atlas.allocate(size2(40, 10)).unwrap();
+    assert_eq!(atlas.try_get(a.id), Some(a.rectangle));
+    assert_eq!(atlas.get(b.id), b.rectangle);
+
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.try_get(a.id), None);
+    assert_eq!(atlas.try_get(AllocId::new(1000, 1)), None);
+}
+
+#[test]
+fn stale_ids() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(20, 30)).unwrap();
+    let _b = atlas.allocate(size2(40, 30)).unwrap();
+    atlas.deallocate(a.id);
+
+    // The same item is reused for the new allocation, with a different id.
+    let c = atlas.allocate(size2(20, 30)).unwrap();
+    assert_eq!(c.rectangle, a.rectangle);
+    assert_eq!(c.id.index(), a.id.index());
+    assert_ne!(c.id, a.id);
+
+    assert_eq!(atlas.try_get(a.id), None);
+    assert_eq!(atlas.try_get(c.id), Some(c.rectangle));
+
+    assert_eq!(atlas.try_deallocate(a.id), Err(DeallocError::StaleGeneration));
+    assert!(atlas.contains(c.id));
+}
+
+#[test]
+fn grow() {
+    for &vertical_shelves in &[false, true] {
+        let options = AllocatorOptions { vertical_shelves, ..DEFAULT_OPTIONS };
+
+        let mut atlas = AtlasAllocator::with_options(size2(256, 256), &options);
+        let a = atlas.allocate(size2(50, 50)).unwrap();
+        let b = atlas.allocate(size2(50, 50)).unwrap();
+
+        atlas.grow(size2(512, 512));
+        assert_eq!(atlas.size(), size2(512, 512));
+        assert_eq!(atlas.get(a.id), a.rectangle);
+        assert_eq!(atlas.get(b.id), b.rectangle);
+
+        let c = atlas.allocate(size2(400, 400)).unwrap();
+        // The first shelf is extended.
+        let d = atlas.allocate(size2(400, 50)).unwrap();
+        for (i, x) in [a, b, c, d].iter().enumerate() {
+            for y in &[a, b, c, d][i + 1..] {
+                assert!(!x.rectangle.intersects(&y.rectangle));
+            }
+        }
+
+        for id in [a.id, b.id, c.id, d.id] {
+            atlas.deallocate(id);
+        }
+        assert!(atlas.is_empty());
+        assert!(atlas.allocate(size2(512, 512)).is_some());
+
+        // A full shelf gets a new item.
+        let mut atlas = AtlasAllocator::with_options(size2(256, 256), &options);
+        let a = atlas.allocate(size2(256, 256)).unwrap();
+        atlas.grow(size2(512, 256));
+        let b = atlas.allocate(size2(256, 256)).unwrap();
+        assert!(!a.rectangle.intersects(&b.rectangle));
+    }
+
+    // With multiple columns, new columns are added.
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+    let a = atlas.allocate(size2(128, 256)).unwrap();
+    let b = atlas.allocate(size2(128, 256)).unwrap();
+    assert!(atlas.allocate(size2(10, 10)).is_none());
+
+    atlas.grow(size2(512, 300));
+    let c = atlas.allocate(size2(128, 300)).unwrap();
+    let d = atlas.allocate(size2(128, 300)).unwrap();
+    let e = atlas.allocate(size2(128, 32)).unwrap();
+    assert_eq!(e.rectangle.min.y, 256);
+    for id in [a.id, b.id, c.id, d.id, e.id] {
+        atlas.deallocate(id);
+    }
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn shrink_to_fit() {
+    for &vertical_shelves in &[false, true] {
+        let options = AllocatorOptions { vertical_shelves, ..DEFAULT_OPTIONS };
+
+        let mut atlas = AtlasAllocator::with_options(size2(1024, 1024), &options);
+        // Empty atlases can't shrink.
+        assert_eq!(atlas.shrink_to_fit(), size2(1024, 1024));
+
+        let a = atlas.allocate(size2(100, 50)).unwrap();
+        let b = atlas.allocate(size2(100, 50)).unwrap();
+        let c = atlas.allocate(size2(500, 500)).unwrap();
+        atlas.deallocate(c.id);
+
+        let size = atlas.shrink_to_fit();
+        assert_eq!(size, atlas.size());
+        assert!(size.width < 1024 && size.height < 1024);
+        assert!(atlas.coverage().unwrap().max.x <= size.width);
+        assert!(atlas.coverage().unwrap().max.y <= size.height);
+        assert_eq!(atlas.get(a.id), a.rectangle);
+        assert_eq!(atlas.get(b.id), b.rectangle);
+
+        // Already as small as it gets.
+        assert_eq!(atlas.shrink_to_fit(), size);
+
+        // The remaining space is still usable and the atlas can grow again.
+        atlas.grow(size2(512, 512));
+        let d = atlas.allocate(size2(300, 300)).unwrap();
+        assert!(!d.rectangle.intersects(&a.rectangle));
+        assert!(!d.rectangle.intersects(&b.rectangle));
+        atlas.deallocate(d.id);
+
+        assert_eq!(atlas.shrink_to_fit(), size);
+        atlas.deallocate(b.id);
+        atlas.grow(size2(1024, 1024));
+        let size = atlas.shrink_to_fit_pow2();
+        assert!(size.width < 1024 && size.height < 1024);
+        assert!((size.width as u32).is_power_of_two() && (size.height as u32).is_power_of_two());
+        assert!(atlas.coverage().unwrap().max.x <= size.width);
+        assert!(atlas.coverage().unwrap().max.y <= size.height);
+        assert_eq!(atlas.get(a.id), a.rectangle);
+
+        atlas.deallocate(a.id);
+        assert!(atlas.is_empty());
+        assert!(atlas.allocate(size).is_some());
+    }
+
+    // With multiple columns, the empty columns on the right are removed.
+    let mut atlas = AtlasAllocator::with_options(size2(512, 256), &AllocatorOptions {
+        num_columns: 4,
+        ..DEFAULT_OPTIONS
+    });
+    let a = atlas.allocate(size2(128, 200)).unwrap();
+    let b = atlas.allocate(size2(128, 200)).unwrap();
+    let c = atlas.allocate(size2(128, 200)).unwrap();
+    assert_ne!(a.rectangle.min.x, b.rectangle.min.x);
+    atlas.deallocate(b.id);
+    atlas.deallocate(c.id);
+
+    assert_eq!(atlas.shrink_to_fit(), size2(128, 224));
+    assert_eq!(atlas.get(a.id), a.rectangle);
+    atlas.deallocate(a.id);
+    assert!(atlas.allocate(size2(128, 200)).is_some());
+}
+
+#[test]
+fn deallocate_and_get() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(10, 20)).unwrap();
+    let b = atlas.allocate(size2(30, 20)).unwrap();
+
+    assert_eq!(atlas.deallocate_and_get(b.id), b.rectangle);
+    assert_eq!(atlas.deallocate_and_get(a.id), a.rectangle);
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn allocation_count() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.allocation_count(), 0);
+
+    let mut allocations = Vec::new();
+    for i in 0..50 {
+        if let Some(alloc) = atlas.allocate(size2(10 + i, 20 + i)) {
+            allocations.push(alloc.id);
+        }
+        assert_eq!(atlas.allocation_count(), allocations.len());
+    }
+    assert!(atlas.allocate(size2(300, 300)).is_none());
+    assert_eq!(atlas.allocation_count(), allocations.len());
+
+    let count = allocations.len();
+    for id in allocations.iter().step_by(2) {
+        atlas.deallocate(*id);
+    }
+    assert_eq!(atlas.allocation_count(), count / 2);
+
+    atlas.clear();
+    assert_eq!(atlas.allocation_count(), 0);
+}
+
+#[test]
+fn contains() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    assert!(atlas.contains(a.id));
+
+    atlas.deallocate(a.id);
+    assert!(!atlas.contains(a.id));
+
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+    assert!(atlas.contains(b.id));
+    assert!(!atlas.contains(a.id));
+    assert!(!atlas.contains(AllocId(12345)));
+}
+
+#[test]
+fn try_deallocate() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+
+    assert_eq!(atlas.try_deallocate(a.id), Ok(()));
+    assert_eq!(atlas.try_deallocate(a.id), Err(DeallocError::StaleGeneration));
+    assert_eq!(atlas.try_deallocate(AllocId(12345)), Err(DeallocError::NotAllocated));
+    assert!(atlas.contains(b.id));
+
+    assert_eq!(atlas.try_deallocate(b.id), Ok(()));
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn iter() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let mut allocations = Vec::new();
+    for i in 0..30 {
+        allocations.push(atlas.allocate(size2(5 + i, 10 + i % 7)).unwrap());
+    }
+    for alloc in allocations.iter().step_by(3) {
+        atlas.deallocate(alloc.id);
+    }
+    allocations.retain(|alloc| atlas.contains(alloc.id));
+    allocations.extend((0..30).filter_map(|i| atlas.allocate(size2(5 + i, 12))));
+
+    let mut iterated: Vec<Allocation> = atlas.iter().collect();
+    assert_eq!(iterated.len(), allocations.len());
+
+    allocations.sort_by_key(|alloc| alloc.id.serialize());
+    iterated.sort_by_key(|alloc| alloc.id.serialize());
+    assert_eq!(iterated, allocations);
+}
+
+#[test]
+fn svg_options() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(32, 32)).unwrap();
+    atlas.allocate(size2(32, 32)).unwrap();
+
+    let label = |id: AllocId| if id == a.id { Some("glyph-a".to_string()) } else { None };
+    let options = SvgOptions {
+        allocated_color: svg_fmt::rgb(1, 2, 3),
+        label: Some(&label),
+        ..SvgOptions::default()
+    };
+    let mut output = Vec::new();
+    atlas.dump_into_svg_with_options(None, &options, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains(&svg_fmt::rgb(1, 2, 3).to_string()));
+    assert!(!output.contains(&SvgOptions::default().allocated_color.to_string()));
+    assert_eq!(output.matches("glyph-a").count(), 1);
+
+    // Labels are escaped.
+    let label = |_| Some("<a> & </text>".to_string());
+    let options = SvgOptions { label: Some(&label), ..SvgOptions::default() };
+    let mut output = Vec::new();
+    atlas.dump_into_svg_with_options(None, &options, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.matches("&lt;a&gt; &amp; &lt;/text&gt;").count(), 2);
+    assert!(!output.contains("<a>"));
+
+    // The default output is unchanged.
+    let mut default = Vec::new();
+    atlas.dump_into_svg(None, &mut default).unwrap();
+    assert!(String::from_utf8(default).unwrap().contains(&SvgOptions::default().allocated_color.to_string()));
+}
+
+#[test]
+fn try_allocate() {
+    let mut atlas = AtlasAllocator::with_options(size2(1000, 1000), &AllocatorOptions {
+        alignment: size2(16, 16),
+        ..DEFAULT_OPTIONS
+    });
+
+    assert_eq!(atlas.try_allocate(size2(0, 10)), Err(AllocError::EmptySize));
+    assert_eq!(atlas.try_allocate(size2(10, -1)), Err(AllocError::EmptySize));
+    assert_eq!(atlas.try_allocate(size2(1001, 10)), Err(AllocError::TooLarge));
+    assert_eq!(atlas.try_allocate(size2(10, 100000)), Err(AllocError::TooLarge));
+    assert_eq!(
+        atlas.try_allocate(size2(1000, 10)),
+        Err(AllocError::AlignmentOverflow {
+            requested: size2(1000, 10),
+            aligned: size2(1008, 16),
+        }),
+    );
+
+    let full = atlas.try_allocate(size2(992, 992)).unwrap();
+    assert_eq!(atlas.try_allocate(size2(100, 100)), Err(AllocError::OutOfSpace));
+    atlas.deallocate(full.id);
+    assert!(atlas.try_allocate(size2(100, 100)).is_ok());
+}
+
+// Validating the atlas after each of the tens of thousands of allocations would be too slow.
+#[cfg(not(feature = "checks"))]
+#[test]
+fn allocate_at_capacity() {
+    let mut atlas = AtlasAllocator::new(size2(65000, 1000));
+    while atlas.try_allocate(size2(1, 1)).is_ok() {}
+    assert_eq!(atlas.try_allocate(size2(1, 1)), Err(AllocError::CapacityExhausted));
+
+    let rect = Rectangle { min: point2(100, 500), max: point2(110, 510) };
+    assert!(atlas.allocate_at(rect).is_none());
+    assert!(atlas.allocate_in_rect(size2(10, 10), rect).is_none());
+    assert_eq!(atlas.validate(), Ok(()));
+}
+
+#[test]
+fn rearrange() {
+    let mut atlas = AtlasAllocator::<u32>::with_user_data(size2(128, 128), &DEFAULT_OPTIONS);
+
+    let mut allocations = Vec::new();
+    while let Some(alloc) = atlas.allocate(size2(16, 16)) {
+        *atlas.get_data_mut(alloc.id) = alloc.rectangle.min.x as u32;
+        allocations.push(alloc);
+    }
+
+    // Leave a checkerboard of holes.
+    for alloc in &allocations {
+        let r = alloc.rectangle;
+        if (r.min.x / 16 + r.min.y / 16) % 2 == 0 {
+            atlas.deallocate(alloc.id);
+        }
+    }
+
+    let occupancy = atlas.occupancy();
+    let max_allocatable = atlas.max_allocatable();
+    let count = atlas.allocation_count();
+
+    let result = atlas.rearrange();
+
+    assert!(result.failures.is_empty());
+    assert_eq!(result.changes.len(), count);
+    assert_eq!(atlas.allocation_count(), count);
+    assert_eq!(atlas.occupancy(), occupancy);
+    assert!(atlas.max_allocatable().area() > max_allocatable.area());
+    for change in &result.changes {
+        assert_eq!(change.old.rectangle.size(), change.new.rectangle.size());
+        assert_eq!(atlas.get(change.new.id), change.new.rectangle);
+        assert_eq!(*atlas.get_data(change.new.id), change.old.rectangle.min.x as u32);
+    }
+    atlas.verify_no_overlap().unwrap();
+}
+
+#[test]
+fn allocate_in_rect() {
+    let mut atlas = AtlasAllocator::new(size2(1024, 1024));
+
+    let bounds = Rectangle {
+        min: point2(512, 256),
+        max: point2(1024, 768),
+    };
+
+    let mut allocations = Vec::new();
+    while let Some(alloc) = atlas.allocate_in_rect(size2(100, 60), bounds) {
+        assert!(bounds.contains_box(&alloc.rectangle));
+        allocations.push(alloc);
+    }
+    // 5 columns of 8 rows of 64 pixels.
+    assert_eq!(allocations.len(), 40);
+
+    // The rest of the atlas is still available.
+    let outside = atlas.allocate(size2(500, 200)).unwrap();
+    assert!(!outside.rectangle.intersects(&bounds));
+
+    // Bounds contain the rectangle after rounding up to the shelf height.
+    let small = Rectangle {
+        min: point2(0, 600),
+        max: point2(100, 610),
+    };
+    assert!(atlas.allocate_in_rect(size2(20, 10), small).is_none());
+    assert!(atlas.allocate_in_rect(size2(200, 10), bounds).is_none());
+    assert!(atlas.allocate_in_rect(size2(600, 32), bounds).is_none());
+
+    for alloc in &allocations {
+        atlas.deallocate(alloc.id);
+    }
+    atlas.deallocate(outside.id);
+    assert!(atlas.is_empty());
+
+    let mut atlas = AtlasAllocator::with_options(size2(1024, 1024), &AllocatorOptions {
+        vertical_shelves: true,
+        alignment: size2(4, 4),
+        ..DEFAULT_OPTIONS
+    });
+    let bounds = Rectangle {
+        min: point2(10, 10),
+        max: point2(200, 200),
+    };
+    for _ in 0..10 {
+        let alloc = atlas.allocate_in_rect(size2(30, 30), bounds).unwrap();
+        assert!(bounds.contains_box(&alloc.rectangle));
+        assert_eq!(alloc.rectangle.min.x % 4, 0);
+        assert_eq!(alloc.rectangle.min.y % 4, 0);
+    }
+    atlas.verify_no_overlap().unwrap();
+
+    // Bounds outside of the atlas don't overflow.
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        alignment: size2(16, 16),
+        ..DEFAULT_OPTIONS
+    });
+    let far = Rectangle {
+        min: point2(i32::MAX - 3, i32::MAX - 3),
+        max: point2(i32::MAX, i32::MAX),
+    };
+    assert!(atlas.allocate_in_rect(size2(2, 2), far).is_none());
+    let partial = Rectangle {
+        min: point2(-100, 200),
+        max: point2(i32::MAX, i32::MAX),
+    };
+    let alloc = atlas.allocate_in_rect(size2(20, 20), partial).unwrap();
+    assert!(alloc.rectangle.min.y >= 200);
+}
+
+#[test]
+fn absorb() {
+    let mut small = AtlasAllocator::<u32>::with_user_data(size2(256, 256), &DEFAULT_OPTIONS);
+    let mut small_allocations = Vec::new();
+    for (i, &(w, h)) in [(100, 32), (50, 32), (200, 64), (10, 10)].iter().enumerate() {
+        let alloc = small.allocate(size2(w, h)).unwrap();
+        *small.get_data_mut(alloc.id) = i as u32;
+        small_allocations.push(alloc);
+    }
+
+    let mut large = AtlasAllocator::<u32>::with_user_data(size2(512, 512), &DEFAULT_OPTIONS);
+    let existing = large.allocate_at(Rectangle {
+        min: point2(300, 300),
+        max: point2(400, 400),
+    }).unwrap();
+    let before: Vec<Allocation> = large.iter().collect();
+
+    // Overlaps the existing allocation.
+    assert!(large.absorb(&small, point2(200, 200)).is_empty());
+    assert_eq!(large.iter().collect::<Vec<_>>(), before);
+    assert_eq!(large.allocation_count(), 1);
+
+    // Outside of the atlas.
+    assert!(large.absorb(&small, point2(400, 0)).is_empty());
+    assert_eq!(large.allocation_count(), 1);
+
+    let ids = large.absorb(&small, point2(0, 0));
+    assert_eq!(ids.len(), small_allocations.len());
+    for &(old_id, new_id) in &ids {
+        assert!(large.get(new_id).contains_box(&small.get(old_id)));
+        assert_eq!(large.get_data(new_id), small.get_data(old_id));
+    }
+    assert_eq!(large.get(existing.id), existing.rectangle);
+    assert_eq!(large.allocation_count(), 5);
+    large.verify_no_overlap().unwrap();
+}
+
+#[test]
+fn allocate_or_evict() {
+    use std::collections::VecDeque;
+
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    let mut fifo = VecDeque::new();
+
+    // Only 16 of these fit at once.
+    for i in 0..40 {
+        let alloc = atlas.allocate_or_evict(size2(64, 64), || fifo.pop_front()).unwrap();
+        fifo.push_back(alloc.id);
+        assert_eq!(atlas.allocation_count(), (i + 1).min(16));
+    }
+    atlas.verify_no_overlap().unwrap();
+
+    // Never fits: nothing is evicted.
+    assert!(atlas.allocate_or_evict(size2(300, 10), || fifo.pop_front()).is_none());
+    assert_eq!(atlas.allocation_count(), 16);
+
+    // Runs out of victims.
+    assert!(atlas.allocate_or_evict(size2(256, 256), || None).is_none());
+    assert_eq!(atlas.allocation_count(), 16);
+
+    assert!(atlas.allocate_or_evict(size2(256, 256), || fifo.pop_front()).is_some());
+    assert_eq!(atlas.allocation_count(), 1);
+}
+
+#[test]
+fn user_data_round_trip() {
+    let mut atlas = AtlasAllocator::<Option<String>>::with_user_data(size2(256, 256), &DEFAULT_OPTIONS);
+
+    let a = atlas.allocate(size2(32, 32)).unwrap().id;
+    let b = atlas.allocate(size2(32, 32)).unwrap().id;
+    assert_eq!(atlas.set_data(a, Some("a".into())), None);
+    assert_eq!(atlas.set_data(b, Some("b".into())), None);
+    assert_eq!(atlas.set_data(b, Some("bb".into())), Some("b".into()));
+
+    assert_eq!(atlas.deallocate(a), Some("a".into()));
+    assert_eq!(atlas.try_deallocate(a), Err(DeallocError::StaleGeneration));
+    assert_eq!(atlas.try_deallocate(b), Ok(Some("bb".into())));
+
+    // The item is reused with fresh data.
+    let c = atlas.allocate(size2(32, 32)).unwrap().id;
+    assert_eq!(*atlas.get_data(c), None);
+    assert_eq!(atlas.deallocate(c), None);
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn retain() {
+    let mut atlas = AtlasAllocator::new(size2(512, 512));
+
+    let mut small = Vec::new();
+    let mut large = Vec::new();
+    for i in 0..20 {
+        if i % 3 == 0 {
+            large.push(atlas.allocate(size2(64, 64)).unwrap().id);
+        } else {
+            small.push(atlas.allocate(size2(16, 16)).unwrap().id);
+        }
+    }
+
+    atlas.retain(|_, rect| rect.area() < 64 * 64);
+    assert_eq!(atlas.allocation_count(), small.len());
+    for &id in &small {
+        assert!(atlas.contains(id));
+    }
+    for &id in &large {
+        assert!(!atlas.contains(id));
+    }
+
+    // The freed space is coalesced.
+    assert!(atlas.allocate(size2(512, 256)).is_some());
+
+    atlas.retain(|_, _| false);
+    assert!(atlas.is_empty());
+
+    // The white pixel is kept.
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        reserve_white_pixel: true,
+        ..DEFAULT_OPTIONS
+    });
+    let a = atlas.allocate(size2(16, 16)).unwrap();
+    let b = atlas.allocate(size2(16, 16)).unwrap();
+    atlas.retain(|id, _| id == a.id);
+    assert!(!atlas.contains(b.id));
+    atlas.deallocate(a.id);
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), atlas.white_pixel().unwrap().area());
+    assert!(atlas.allocate(size2(256, 256)).is_none());
+}
+
+#[test]
+fn clear_region() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+
+    let mut allocations = Vec::new();
+    while let Some(alloc) = atlas.allocate(size2(32, 32)) {
+        allocations.push(alloc);
+    }
+    assert_eq!(allocations.len(), 64);
+
+    let top = Rectangle { min: point2(0, 0), max: point2(256, 128) };
+    atlas.clear_region(top);
+
+    for alloc in &allocations {
+        let in_top = top.contains_box(&alloc.rectangle);
+        assert_eq!(atlas.contains(alloc.id), !in_top);
+        if !in_top {
+            assert_eq!(atlas.get(alloc.id), alloc.rectangle);
+        }
+    }
+    assert_eq!(atlas.allocation_count(), 32);
+
+    // The top half is coalesced and can be allocated around the bottom half.
+    let alloc = atlas.allocate(size2(256, 128)).unwrap();
+    assert_eq!(alloc.rectangle, top);
+
+    // Allocations overlapping the edges of the region are kept.
+    atlas.clear_region(Rectangle { min: point2(0, 100), max: point2(256, 256) });
+    assert!(atlas.contains(alloc.id));
+    assert_eq!(atlas.allocation_count(), 1);
+}
+
+#[test]
+fn snapshot_restore() {
+    let mut atlas = AtlasAllocator::<u32>::with_user_data(size2(256, 256), &DEFAULT_OPTIONS);
+    let a = atlas.allocate(size2(32, 32)).unwrap();
+    let b = atlas.allocate(size2(64, 48)).unwrap();
+    *atlas.get_data_mut(b.id) = 7;
+    atlas.deallocate(a.id);
+
+    let snapshot = atlas.snapshot();
+    let mut reference = atlas.clone();
+    let before: Vec<Allocation> = atlas.iter().collect();
+
+    for _ in 0..2 {
+        atlas.deallocate(b.id);
+        for _ in 0..10 {
+            atlas.allocate(size2(50, 50));
+        }
+        atlas.grow(size2(512, 512));
+
+        atlas.restore(&snapshot);
+        assert_eq!(atlas.size(), size2(256, 256));
+        assert_eq!(atlas.iter().collect::<Vec<_>>(), before);
+        assert_eq!(*atlas.get_data(b.id), 7);
+        assert!(!atlas.contains(a.id));
+    }
+
+    // Allocating after restoring behaves as if the rolled back allocations never happened.
+    for size in [size2(10, 10), size2(100, 20), size2(200, 100)] {
+        assert_eq!(atlas.allocate(size), reference.allocate(size));
+    }
+    assert_eq!(atlas.allocated_space(), reference.allocated_space());
+}
+
+#[test]
+fn merge_stacked_shelves() {
+    let options = AllocatorOptions {
+        num_columns: 1,
+        ..DEFAULT_OPTIONS
+    };
+
+    // Free three stacked shelves in every order, which merges each shelf into the next one,
+    // the previous one or both.
+    let orders = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+    for order in &orders {
+        let mut atlas = AtlasAllocator::with_options(size2(256, 256), &options);
+        // The remaining space is taken by a fourth shelf, freed last.
+        let ids: Vec<AllocId> = (0..4).map(|_| atlas.allocate(size2(256, 64)).unwrap().id).collect();
+        assert!(atlas.allocate(size2(1, 1)).is_none());
+
+        for &i in order {
+            atlas.deallocate(ids[i]);
+            assert!(!atlas.is_empty());
+        }
+
+        // The three shelves were merged into one.
+        let merged = atlas.allocate(size2(256, 192)).unwrap();
+        assert_eq!(merged.rectangle.min.y, 0);
+        atlas.deallocate(merged.id);
+
+        atlas.deallocate(ids[3]);
+        assert!(atlas.is_empty());
+        assert!(atlas.allocate(size2(256, 256)).is_some());
+    }
+}
+
+#[test]
+fn validate() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.validate(), Ok(()));
+
+    let a = atlas.allocate(size2(20, 30)).unwrap();
+    let b = atlas.allocate(size2(100, 20)).unwrap();
+    atlas.allocate(size2(10, 100)).unwrap();
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.validate(), Ok(()));
+
+    let index = b.id.index() as usize;
+    atlas.items[index].width += 1;
+    assert!(matches!(atlas.validate(), Err(ValidationError::InvalidState(_))));
+    atlas.items[index].width -= 1;
+
+    atlas.requested_space += 1;
+    assert!(atlas.validate().is_err());
+}
+
+#[test]
+fn get_matches_allocation() {
+    // For any live id, get returns the rectangle returned when allocating.
+    let options = AllocatorOptions { num_columns: 2, ..DEFAULT_OPTIONS };
+    let mut atlas = AtlasAllocator::with_options(size2(512, 512), &options);
+    random_operations(&mut atlas, 29, 3000, 60, |atlas, allocations, _| {
+        for alloc in allocations {
+            assert_eq!(atlas.get(alloc.id), alloc.rectangle);
+        }
+    });
+}
+
+#[test]
+fn force_pow2() {
+    let options = AllocatorOptions { force_pow2: true, ..DEFAULT_OPTIONS };
+
+    let atlas = AtlasAllocator::with_options(size2(1000, 1000), &options);
+    assert_eq!(atlas.size(), size2(1024, 1024));
+    assert!(atlas.is_pow2());
+
+    let atlas = AtlasAllocator::with_options(size2(1000, 200), &AllocatorOptions { vertical_shelves: true, ..options });
+    assert_eq!(atlas.size(), size2(1024, 256));
+
+    let atlas = AtlasAllocator::new(size2(1000, 1024));
+    assert_eq!(atlas.size(), size2(1000, 1024));
+    assert!(!atlas.is_pow2());
+}
+
+#[test]
+fn padding() {
+    let padding = 2;
+    for &vertical_shelves in &[false, true] {
+        let options = AllocatorOptions { padding, vertical_shelves, ..DEFAULT_OPTIONS };
+        let mut atlas = AtlasAllocator::with_options(size2(512, 512), &options);
+        let bounds = Rectangle::from_size(atlas.size());
+        let allocations = random_operations(&mut atlas, 7, 2000, 40, |_, allocations, requested| {
+            if let (Some(size), Some((alloc, previous))) = (requested, allocations.split_last()) {
+                assert!(alloc.rectangle.width() >= size.width);
+                assert!(alloc.rectangle.height() >= size.height);
+
+                let padded = alloc.rectangle.inflate(padding, padding);
+                assert!(bounds.contains_box(&padded));
+                for other in previous {
+                    assert!(!padded.intersects(&other.rectangle.inflate(padding, padding)));
+                }
+            }
+        });
+
+        for alloc in &allocations {
+            assert_eq!(atlas.get(alloc.id), alloc.rectangle);
+        }
+        assert_eq!(atlas.iter().count(), allocations.len());
+        assert!(atlas.iter().all(|alloc| atlas.get(alloc.id) == alloc.rectangle));
+    }
+
+    // The padding of a specific rectangle must be inside of the atlas as well.
+    let options = AllocatorOptions { padding, ..DEFAULT_OPTIONS };
+    let mut atlas = AtlasAllocator::with_options(size2(256, 256), &options);
+    assert!(atlas.allocate_at(Rectangle { min: point2(0, 0), max: point2(10, 10) }).is_none());
+    let rect = Rectangle { min: point2(2, 2), max: point2(12, 12) };
+    assert_eq!(atlas.allocate_at(rect).unwrap().rectangle, rect);
+}
+
+#[test]
+fn debug() {
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    atlas.allocate(size2(64, 32)).unwrap();
+    atlas.allocate(size2(64, 32)).unwrap();
+    let output = format!("{:?}", atlas);
+    assert!(output.starts_with("AtlasAllocator { size: 256x256, columns: 1,"));
+    assert!(output.contains("allocations: 2"));
+    assert!(output.contains("2 items, 50% filled"));
+
+    let atlas = AtlasAllocator::with_options(size2(512, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+    let output = format!("{:?}", atlas);
+    assert!(output.starts_with("AtlasAllocator { size: 512x256,"));
+
+    // The output is bounded for large atlases.
+    let mut atlas = AtlasAllocator::new(size2(512, 512));
+    while atlas.allocate(size2(8, 8)).is_some() {}
+    let output = format!("{:?}", atlas);
+    assert_eq!(output.lines().count(), DEBUG_MAX_SHELVES + 2);
+}
+
+#[test]
+fn dump_ascii() {
+    let mut atlas = AtlasAllocator::new(size2(64, 32));
+    atlas.allocate(size2(32, 8)).unwrap();
+    atlas.allocate(size2(16, 8)).unwrap();
+    atlas.allocate(size2(16, 16)).unwrap();
+
+    let mut output = String::new();
+    atlas.dump_ascii(size2(4, 4), &mut output);
+    assert_eq!(output, concat!(
+        "############....\n",
+        "############....\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "@@@@............\n",
+    ));
+}
+
+#[test]
+fn dump_ascii_vertical_shelves() {
+    let mut atlas = AtlasAllocator::with_options(size2(64, 32), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+    atlas.allocate(size2(8, 32)).unwrap();
+
+    let mut output = String::new();
+    atlas.dump_ascii(size2(4, 4), &mut output);
+    assert_eq!(output.lines().count(), 8);
+    assert!(output.lines().all(|line| line.len() == 16 && line.starts_with("##..")));
+}
@@ -0,0 +1,123 @@
+use alloc::vec::Vec;
+
+use crate::{AllocatorOptions, AllocId, Allocation, BucketedAtlasAllocator, Rectangle, Size};
+
+/// A list of atlases (pages) of the same size, growing and shrinking with the allocations.
+///
+/// Allocations go into the first page that can fit them, and a new page is pushed when none
+/// of the existing ones can. Unlike with a [`MultiAtlas`](struct.MultiAtlas.html), there is no
+/// limit on the number of pages and allocations are identified by their page index and the id
+/// from that page's allocator, so there is no id table to maintain. Empty pages at the end of
+/// the list are removed when deallocating.
+#[derive(Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct AtlasAllocatorList {
+    pages: Vec<BucketedAtlasAllocator>,
+    size: Size,
+    options: AllocatorOptions,
+}
+
+impl AtlasAllocatorList {
+    /// Create an empty list of pages of the provided size.
+    pub fn new(size: Size, options: &AllocatorOptions) -> Self {
+        AtlasAllocatorList {
+            pages: Vec::new(),
+            size,
+            options: *options,
+        }
+    }
+
+    /// Allocate a rectangle in one of the pages, adding a page if needed.
+    ///
+    /// Returns the index of the page as well as the allocation, or `None` if the rectangle
+    /// doesn't even fit in an empty page.
+    pub fn allocate(&mut self, size: Size) -> Option<(usize, Allocation)> {
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            if let Some(alloc) = page.allocate(size) {
+                return Some((index, alloc));
+            }
+        }
+
+        let mut page = BucketedAtlasAllocator::with_options(self.size, &self.options);
+        let alloc = page.allocate(size)?;
+        self.pages.push(page);
+
+        Some((self.pages.len() - 1, alloc))
+    }
+
+    /// Deallocate a rectangle and remove the empty pages at the end of the list.
+    pub fn deallocate(&mut self, (page, id): (usize, AllocId)) {
+        self.pages[page].deallocate(id);
+
+        while self.pages.last().map_or(false, |page| page.is_empty()) {
+            self.pages.pop();
+        }
+    }
+
+    /// Returns the rectangle of an allocation, in the coordinate space of its page.
+    pub fn get(&self, (page, id): (usize, AllocId)) -> Rectangle {
+        self.pages[page].get(id)
+    }
+
+    /// Number of pages that are currently in use.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Access one of the pages.
+    pub fn page(&self, index: usize) -> &BucketedAtlasAllocator {
+        &self.pages[index]
+    }
+
+    /// Iterate over the pages, in increasing index order.
+    pub fn iter_pages(&self) -> impl Iterator<Item = &BucketedAtlasAllocator> + '_ {
+        self.pages.iter()
+    }
+
+    /// Size of each page.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Deallocate all rectangles and remove all pages.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+#[test]
+fn atlas_list() {
+    use crate::{size2, DEFAULT_OPTIONS};
+
+    let mut atlas = AtlasAllocatorList::new(size2(256, 256), &DEFAULT_OPTIONS);
+    assert!(atlas.is_empty());
+
+    assert!(atlas.allocate(size2(300, 10)).is_none());
+    assert_eq!(atlas.page_count(), 0);
+
+    let (p0, a) = atlas.allocate(size2(256, 256)).unwrap();
+    let (p1, b) = atlas.allocate(size2(256, 200)).unwrap();
+    let (p2, c) = atlas.allocate(size2(100, 24)).unwrap();
+    let (p3, d) = atlas.allocate(size2(256, 256)).unwrap();
+    assert_eq!((p0, p1, p2, p3), (0, 1, 1, 2));
+    assert_eq!(atlas.page_count(), 3);
+    assert_eq!(atlas.get((p2, c.id)), c.rectangle);
+    assert_eq!(atlas.iter_pages().filter(|page| !page.is_empty()).count(), 3);
+
+    // Empty pages are only removed at the end of the list.
+    atlas.deallocate((p0, a.id));
+    assert_eq!(atlas.page_count(), 3);
+    assert!(atlas.page(0).is_empty());
+
+    atlas.deallocate((p3, d.id));
+    assert_eq!(atlas.page_count(), 2);
+
+    atlas.deallocate((p1, b.id));
+    atlas.deallocate((p2, c.id));
+    assert_eq!(atlas.page_count(), 0);
+    assert!(atlas.is_empty());
+}
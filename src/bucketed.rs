@@ -1,19 +1,32 @@
-use std::num::Wrapping;
-use std::u16;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::num::Wrapping;
+use core::ops::Range;
+
+use crate::{AllocatorOptions, DEFAULT_OPTIONS, Allocation, AllocError, AllocId, DeallocError, InvalidAlignment, NonEmpty, Placement, ReserveError, Size, Rectangle, point2, size2};
+use crate::{atlas_size, format_allocation_table, format_ascii, pow2_size, trim_allocation, DEBUG_MAX_SHELVES};
+#[cfg(feature = "std")]
+use crate::{write_svg_label, SvgOptions};
+use crate::id_table::IdTable;
+#[cfg(any(test, feature = "testing"))]
+use crate::testing::Lcg;
+#[cfg(test)]
+use crate::testing::random_operations;
+
+// The generation is always in the 8 high bits, see IdLayout.
+const GEN_SHIFT: u32 = 24;
 
-use crate::{AllocatorOptions, DEFAULT_OPTIONS, Allocation, AllocId, Size, Rectangle, point2, size2};
-
-const BIN_BITS: u32 = 12;
-const ITEM_BITS: u32 = 12;
-const GEN_BITS: u32 = 8;
+const MAX_SHELF_COUNT: usize = u16::MAX as usize;
 
-const BIN_MASK: u32   = (1 << BIN_BITS) - 1;
-const ITEM_MASK: u32  = ((1 << ITEM_BITS) - 1) << BIN_BITS;
-const GEN_MASK: u32   = ((1 << GEN_BITS) - 1) << (BIN_BITS + ITEM_BITS);
+// Larger sizes are rejected before aligning them, so that aligning can't overflow.
+const MAX_SIZE: i32 = 1 << 30;
 
-const MAX_ITEMS_PER_BIN: u16 = (ITEM_MASK >> 12) as u16;
-const MAX_BIN_COUNT: usize = BIN_MASK as usize;
-const MAX_SHELF_COUNT: usize = u16::MAX as usize;
+/// The key of the space that isn't assigned to any shelf yet in
+/// [`BucketedAtlasAllocator::free_space_by_height`](struct.BucketedAtlasAllocator.html#method.free_space_by_height).
+pub const ANY_HEIGHT: u32 = u32::MAX;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
@@ -30,10 +43,12 @@ impl BucketIndex {
 #[derive(Clone)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 struct Shelf {
-    x: u16,
-    y: u16,
-    height: u16,
-    bucket_width: u16,
+    x: u32,
+    y: u32,
+    height: u32,
+    bucket_width: u32,
+    /// Set for shelves that span all columns, see `allocate_spanning`.
+    spanning: bool,
 
     first_bucket: BucketIndex,
 }
@@ -41,8 +56,8 @@ struct Shelf {
 #[derive(Clone)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 struct Bucket {
-    x: u16,
-    free_space: u16,
+    x: u32,
+    free_space: u32,
 
     next: BucketIndex,
 
@@ -54,6 +69,25 @@ struct Bucket {
     item_count: u16,
     shelf: u16,
     generation: Wrapping<u8>,
+    /// The items allocated in the bucket, indexed by their id's item index.
+    items: Vec<BucketItem>,
+    /// The indices of the items that are still occupying space, in increasing x order.
+    /// They are adjacent to each other and end where the bucket's free space starts.
+    occupied: Vec<u16>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+struct BucketItem {
+    /// Position of the item relative to the start of its bucket, so that `get` doesn't
+    /// have to add up the widths of the items before it.
+    offset: u32,
+    width: u32,
+    /// Empty space before the item, so that it doesn't cross a tile boundary.
+    padding: u32,
+    allocated: bool,
+    /// The area of the size that was requested, before alignment and rounding.
+    requested_area: i32,
 }
 
 /// A faster but less precise Shelf-packing dynamic texture atlas allocator, inspired by https://github.com/mapbox/shelf-pack/
@@ -62,8 +96,9 @@ struct Bucket {
 /// When allocating we first look for a suitable bucket. If none is found, a new shelf of the desired height
 /// is pushed.
 ///
-/// Lifetime isn't tracked at item granularity. Instead, items are grouped into buckets and deallocation happens
-/// per bucket when all items of the buckets are removed.
+/// Items are grouped into buckets and allocated linearly from the left of their bucket. When the right-most items
+/// of a bucket are deallocated their space is immediately given back to the bucket, however space freed in the middle
+/// of a bucket is only reclaimed when all items to its right are removed as well.
 /// When the top-most shelf is empty, it is removed, potentially cascading into garbage-collecting the next
 /// shelf, etc.
 ///
@@ -73,32 +108,82 @@ struct Bucket {
 pub struct BucketedAtlasAllocator {
     shelves: Vec<Shelf>,
     buckets: Vec<Bucket>,
-    available_height: u16,
-    width: u16,
-    height: u16,
+    available_height: u32,
+    width: u32,
+    height: u32,
+    /// The height available to the columns, below the shelves spanning all columns.
+    column_height: u32,
     first_unallocated_bucket: BucketIndex,
     flip_xy: bool,
     alignment: Size,
-    current_column: u16,
-    column_width: u16,
-    num_columns: u16,
+    /// See `AllocatorOptions::padding`.
+    padding: u32,
+    current_column: u32,
+    column_width: u32,
+    num_columns: u32,
     allocated_space: i32,
+    /// Number of live allocations.
+    allocation_count: usize,
+    /// The height of all shelves, if the `fixed_shelf_height` option is set.
+    fixed_shelf_height: Option<u32>,
+    /// The size of the tiles allocations shouldn't cross, in shelf space.
+    tile_size: Option<(u32, u32)>,
+    /// Sum of the areas of the sizes requested by the live allocations.
+    requested_space: i32,
+    /// Number of shelves at the bottom of the atlas reserved for a specific height.
+    reserved_shelves: u16,
+    /// The height of the reserved shelves.
+    reserved_height: u32,
+    /// Maximum number of shelves (including empty ones).
+    max_shelves: usize,
+    /// The highest y coordinate reached by a shelf since the atlas was created or cleared.
+    high_water_height: u32,
+    /// Maps the ids handed out to the internal ids, if the `stable_ids` option is set.
+    stable_ids: Option<IdTable<AllocId>>,
+    /// Number of bits of the bin index in allocation ids.
+    bin_bits: u32,
+    placement: Placement,
+    autogrow: Option<AutoGrow>,
+    /// The size the atlas was automatically grown to, if not reported yet.
+    grow_event: Option<Size>,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+struct AutoGrow {
+    target_occupancy: f32,
+    max_size: Size,
 }
 
 impl BucketedAtlasAllocator {
     /// Create an atlas allocator with provided options.
+    ///
+    /// The width and height must be smaller than 2^30 and the area of the atlas must fit in
+    /// a `i32` value, which allows atlases wider or taller than 65535.
     pub fn with_options(size: Size, options: &AllocatorOptions) -> Self {
-        assert!(size.width < u16::MAX as i32);
-        assert!(size.height < u16::MAX as i32);
+        let size = atlas_size(size, options);
+        assert_valid_size(size);
+        assert!(options.padding >= 0 && options.padding <= MAX_SIZE / 2);
+        assert!(options.tile_size.map_or(true, |tile| tile.width > 0 && tile.height > 0));
 
         let (width, height, shelf_alignment) = if options.vertical_shelves {
-            (size.height as u16, size.width as u16, options.alignment.height as u16)
+            (size.height as u32, size.width as u32, options.alignment.height as u32)
         } else {
-            (size.width as u16, size.height as u16, options.alignment.width as u16)
+            (size.width as u32, size.height as u32, options.alignment.width as u32)
         };
 
-        let mut column_width = width / (options.num_columns as u16);
+        let tile_size = options.tile_size.map(|tile| {
+            convert_coordinates(options.vertical_shelves, tile.width as u32, tile.height as u32)
+        });
+
+        let mut column_width = width / (options.num_columns as u32);
         column_width = column_width - column_width % shelf_alignment;
+        if let Some((tile_width, _)) = tile_size {
+            // Make columns start on tile boundaries.
+            if column_width >= tile_width {
+                column_width -= column_width % tile_width;
+            }
+        }
 
         BucketedAtlasAllocator {
             shelves: Vec::new(),
@@ -106,28 +191,148 @@ impl BucketedAtlasAllocator {
             available_height: height,
             width,
             height,
+            column_height: height,
             first_unallocated_bucket: BucketIndex::INVALID,
             flip_xy: options.vertical_shelves,
             alignment: options.alignment,
+            padding: options.padding as u32,
             current_column: 0,
-            num_columns: options.num_columns as u16,
+            num_columns: options.num_columns as u32,
             column_width,
             allocated_space: 0,
+            allocation_count: 0,
+            fixed_shelf_height: options.fixed_shelf_height.map(u32::from),
+            tile_size,
+            requested_space: 0,
+            reserved_shelves: 0,
+            reserved_height: 0,
+            max_shelves: options.max_shelves.map_or(MAX_SHELF_COUNT, |n| n as usize).min(MAX_SHELF_COUNT),
+            high_water_height: 0,
+            stable_ids: if options.stable_ids { Some(IdTable::new()) } else { None },
+            bin_bits: options.id_layout.bin_bits(),
+            placement: options.placement,
+            autogrow: None,
+            grow_event: None,
         }
     }
 
+    /// Create an atlas allocator with provided options and storage for `shelf_capacity` shelves
+    /// and `bin_capacity` bins.
+    ///
+    /// This is equivalent to `with_options` followed by `reserve`, and avoids growing the internal
+    /// vectors while the atlas fills up. For items of roughly `s` by `s` units, the atlas has up to
+    /// `num_columns * height / s` shelves and each shelf is split into about `column_width / (16 * s)`
+    /// bins (rounded up to a power of two, at least one). For example a 1024x1024 atlas of 16x16
+    /// glyphs needs 64 shelves of 4 bins, so capacities of 64 and 256.
+    pub fn with_options_and_capacity(size: Size, options: &AllocatorOptions, shelf_capacity: usize, bin_capacity: usize) -> Self {
+        let mut atlas = Self::with_options(size, options);
+        atlas.reserve(shelf_capacity, bin_capacity);
+
+        atlas
+    }
+
     /// Create an atlas allocator with default options.
     pub fn new(size: Size) -> Self {
         Self::with_options(size, &DEFAULT_OPTIONS)
     }
 
+    /// Create an atlas filled with rectangles of pseudo-random sizes up to the target
+    /// occupancy (between 0 and 1), returning the ids of the allocations.
+    ///
+    /// The sequence of sizes is always the same so the resulting atlas only depends on the
+    /// parameters. The rectangles are small compared to the atlas, and allocation stops early
+    /// if the atlas can't fit them anymore, so very high occupancies may not be reached.
+    ///
+    /// This is only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn filled_to(size: Size, options: &AllocatorOptions, target_occupancy: f32) -> (Self, Vec<AllocId>) {
+        let mut atlas = Self::with_options(size, options);
+        let mut ids = Vec::new();
+
+        let mut rng = Lcg::new(17);
+        let max_side = (size.width.min(size.height) / 16).max(1) as u32;
+        let area = size.area() as f32;
+        let mut failures = 0;
+        while (atlas.allocated_space() as f32) < target_occupancy * area && failures < 64 {
+            let size = size2((1 + rng.next() % max_side) as i32, (1 + rng.next() % max_side) as i32);
+            match atlas.allocate(size) {
+                Some(allocation) => {
+                    ids.push(allocation.id);
+                    failures = 0;
+                }
+                None => {
+                    failures += 1;
+                }
+            }
+        }
+
+        (atlas, ids)
+    }
+
     pub fn clear(&mut self) {
         self.shelves.clear();
         self.buckets.clear();
         self.first_unallocated_bucket = BucketIndex::INVALID;
         self.available_height = self.height;
+        self.column_height = self.height;
         self.current_column = 0;
         self.allocated_space = 0;
+        self.allocation_count = 0;
+        self.requested_space = 0;
+        self.reserved_shelves = 0;
+        self.reserved_height = 0;
+        self.high_water_height = 0;
+        if let Some(ids) = &mut self.stable_ids {
+            ids.clear();
+        }
+    }
+
+    /// Apply new options to an empty atlas, keeping its size.
+    ///
+    /// This is equivalent to creating a new atlas with the provided options, but reuses
+    /// the allocated memory. The reserved height band is removed like with `clear`, while
+    /// the autogrow policy is kept. Returns `NonEmpty` if the atlas contains allocations.
+    pub fn reconfigure(&mut self, options: &AllocatorOptions) -> Result<(), NonEmpty> {
+        if !self.is_empty() {
+            return Err(NonEmpty);
+        }
+
+        let mut shelves = core::mem::take(&mut self.shelves);
+        let mut buckets = core::mem::take(&mut self.buckets);
+        shelves.clear();
+        buckets.clear();
+
+        *self = BucketedAtlasAllocator {
+            shelves,
+            buckets,
+            autogrow: self.autogrow,
+            ..Self::with_options(self.size(), options)
+        };
+
+        self.check();
+
+        Ok(())
+    }
+
+    /// Change the alignment of the future allocations.
+    ///
+    /// Existing allocations keep their positions. Allocations placed next to them in existing
+    /// shelves are only guaranteed to be aligned if the atlas is empty or if the new alignment
+    /// divides the previous one. Returns `InvalidAlignment` if the alignment is not positive or
+    /// doesn't divide the width of the columns, in which case the alignment is left unchanged.
+    pub fn set_alignment(&mut self, alignment: Size) -> Result<(), InvalidAlignment> {
+        if alignment.width <= 0 || alignment.height <= 0 {
+            return Err(InvalidAlignment);
+        }
+
+        let (shelf_alignment, _) = convert_coordinates(self.flip_xy, alignment.width as u32, alignment.height as u32);
+        if self.column_width % shelf_alignment != 0 {
+            return Err(InvalidAlignment);
+        }
+
+        self.alignment = alignment;
+
+        Ok(())
     }
 
     pub fn size(&self) -> Size {
@@ -135,20 +340,41 @@ impl BucketedAtlasAllocator {
         size2(w as i32, h as i32)
     }
 
-    pub fn grow(&mut self, new_size: Size) {
-        assert!(new_size.width < u16::MAX as i32);
-        assert!(new_size.height < u16::MAX as i32);
+    /// Returns true if both dimensions of the atlas are powers of two.
+    pub fn is_pow2(&self) -> bool {
+        self.width.is_power_of_two() && self.height.is_power_of_two()
+    }
+
+    /// Grow the atlas to a larger size.
+    ///
+    /// Returns the allocations that were moved by the operation along with their new
+    /// rectangles, so that the caller can move the corresponding texels. Shelves are
+    /// always stacked from the origin in increasing y (or x if `vertical_shelves` is set)
+    /// and the new space is appended past the existing content, so with the current
+    /// layout existing allocations always keep their coordinates and the returned
+    /// vector is empty.
+    ///
+    /// The shelves spanning all columns are placed at the top of the columns and don't move
+    /// either, so once there are some, the additional height isn't used and only growing the
+    /// width adds space (in the form of new columns).
+    pub fn grow(&mut self, new_size: Size) -> Vec<(AllocId, Rectangle)> {
+        assert_valid_size(new_size);
 
         let (new_width, new_height) = if self.flip_xy {
-            (new_size.height as u16, new_size.width as u16)
+            (new_size.height as u32, new_size.width as u32)
         } else {
-            (new_size.width as u16, new_size.height as u16)
+            (new_size.width as u32, new_size.height as u32)
         };
 
         assert!(new_width >= self.width);
         assert!(new_height >= self.height);
 
-        self.available_height += new_height - self.height;
+        // The shelves spanning all columns are at the top of the columns, so the new height
+        // can only be used by the columns if there isn't any.
+        if self.column_height == self.height {
+            self.available_height += new_height - self.height;
+            self.column_height = new_height;
+        }
         self.width = new_width;
         self.height = new_height;
 
@@ -163,7 +389,7 @@ impl BucketedAtlasAllocator {
                 let mut x = self.column_width;
                 let bucket_width = shelf.bucket_width;
 
-                let max_new_buckets = (MAX_BIN_COUNT - self.buckets.len()) as u16;
+                let max_new_buckets = (self.max_bin_count() - self.buckets.len()) as u32;
                 let mut num_buckets_to_add = additional_width / bucket_width;
                 num_buckets_to_add = num_buckets_to_add.min(max_new_buckets);
 
@@ -178,6 +404,8 @@ impl BucketedAtlasAllocator {
                         shelf: shelf_index as u16,
                         generation: Wrapping(0),
                         item_count: 0,
+                        items: Vec::new(),
+                        occupied: Vec::new(),
                     };
 
                     x += bucket_width;
@@ -196,42 +424,236 @@ impl BucketedAtlasAllocator {
             // Add as many new columns as possible.
             self.num_columns = self.width / self.column_width;
         }
+
+        Vec::new()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.shelves.is_empty()
+        // Occupied space is only zero when there is no live item.
+        self.allocated_space == 0
+    }
+
+    /// Reserve shelves at the bottom of the atlas for items of a specific height.
+    ///
+    /// Up to `max_shelves` shelves are created for the height class of `height` (the size of
+    /// the items along the shelf axis, which is their width with vertical shelves). Only items
+    /// of that height class can go in the reserved shelves, other allocations skip them.
+    /// This keeps the atlas dense when most items have the same height, for example the body
+    /// text size in a glyph atlas.
+    ///
+    /// Fewer shelves are created if there isn't enough space for `max_shelves` of them.
+    /// The reserved shelves are never garbage-collected, however `clear` removes the reservation.
+    ///
+    /// This must be called before any allocation, otherwise `ReserveError::NonEmpty` is
+    /// returned. `ReserveError::InvalidHeight` is returned if items of this height can't be
+    /// allocated in the atlas.
+    pub fn reserve_height_band(&mut self, height: i32, max_shelves: u16) -> Result<(), ReserveError> {
+        if !self.shelves.is_empty() {
+            return Err(ReserveError::NonEmpty);
+        }
+
+        let mut size: Size = size2(height, height);
+        if height > 0 {
+            self.add_padding(&mut size);
+        }
+        adjust_size(self.alignment.width, &mut size.width);
+        adjust_size(self.alignment.height, &mut size.height);
+        let h = if self.flip_xy { size.width } else { size.height };
+
+        if h <= 0 || h > self.height as i32 {
+            return Err(ReserveError::InvalidHeight);
+        }
+
+        let h = h as u32;
+        if matches!(self.fixed_shelf_height, Some(fixed) if h > fixed) {
+            return Err(ReserveError::InvalidHeight);
+        }
+
+        let h = self.shelf_height_class(h);
+        for _ in 0..max_shelves {
+            if !self.can_add_shelf(h) {
+                break;
+            }
+
+            self.add_shelf(h, h);
+        }
+
+        self.reserved_shelves = self.shelves.len() as u16;
+        self.reserved_height = h;
+
+        self.check();
+
+        Ok(())
     }
 
     /// Allocate a rectangle in the atlas.
-    pub fn allocate(&mut self, mut requested_size: Size) -> Option<Allocation> {
-        if requested_size.is_empty()
-            || requested_size.width > std::u16::MAX as i32
-            || requested_size.height > std::u16::MAX as i32 {
-            return None;
+    pub fn allocate(&mut self, requested_size: Size) -> Option<Allocation> {
+        self.try_allocate(requested_size).ok()
+    }
+
+    /// Allocate the first size of a list that fits in the atlas.
+    ///
+    /// Sizes are tried in order, typically from the preferred to the smallest acceptable one.
+    /// Returns the index of the size that was allocated along with the allocation.
+    pub fn allocate_first_fit(&mut self, sizes: &[Size]) -> Option<(usize, Allocation)> {
+        for (index, size) in sizes.iter().enumerate() {
+            if let Some(allocation) = self.allocate(*size) {
+                return Some((index, allocation));
+            }
         }
 
-        adjust_size(self.alignment.width, &mut requested_size.width);
-        adjust_size(self.alignment.height, &mut requested_size.height);
+        None
+    }
 
-        if requested_size.width > self.column_width as i32 || requested_size.height > self.height as i32 {
-            return None;
+    /// Allocate a rectangle with power-of-two dimensions.
+    ///
+    /// Both dimensions of the requested size are rounded up to the next power of two and the
+    /// returned rectangle has exactly the rounded size. Returns `None` if the rounded size
+    /// doesn't fit in the atlas.
+    pub fn allocate_pow2(&mut self, size: Size) -> Option<Allocation> {
+        let size = pow2_size(size)?;
+        let allocation = self.allocate(size)?;
+
+        Some(trim_allocation(allocation, size))
+    }
+
+    /// Allocate a rectangle in the atlas, reporting why the allocation failed if it did.
+    ///
+    /// `AllocError::TooLarge` and `AllocError::AlignmentOverflow` mean that the size can't
+    /// be allocated in an atlas of this size, while `AllocError::OutOfSpace` means that it
+    /// could be after deallocating some rectangles. The other errors report the limits of
+    /// the atlas being reached.
+    pub fn try_allocate(&mut self, requested_size: Size) -> Result<Allocation, AllocError> {
+        if requested_size.is_empty() {
+            return Err(AllocError::EmptySize);
+        }
+
+        if !self.has_room_for_id() {
+            return Err(AllocError::CapacityExhausted);
+        }
+
+        let allocation = self.allocate_and_autogrow(requested_size)?;
+
+        Ok(self.export_allocation(allocation))
+    }
+
+    /// Allocate a rectangle and hand it out with the provided id.
+    ///
+    /// The rectangle is placed exactly like with `allocate`, so replaying the same sequence of
+    /// operations with the same ids on another atlas (for example on another machine or after
+    /// deserializing) produces the same allocations. This requires the `stable_ids` option,
+    /// since ids otherwise encode the location of the allocations.
+    ///
+    /// Returns `None` if the `stable_ids` option isn't set, if `id` refers to a live allocation
+    /// or if the rectangle doesn't fit.
+    pub fn allocate_with_id(&mut self, requested_size: Size, id: AllocId) -> Option<Allocation> {
+        match &self.stable_ids {
+            Some(ids) if ids.is_available(id) => {}
+            _ => return None,
+        }
+
+        let allocation = self.allocate_and_autogrow(requested_size).ok()?;
+        self.stable_ids.as_mut().unwrap().insert_at(id, allocation.id);
+
+        Some(Allocation { id, rectangle: allocation.rectangle })
+    }
+
+    // Allocates, growing the atlas according to the autogrow policy, and returns the internal
+    // id of the allocation.
+    fn allocate_and_autogrow(&mut self, requested_size: Size) -> Result<Allocation, AllocError> {
+        let allocation = loop {
+            match self.allocate_internal(requested_size) {
+                Ok(allocation) => break allocation,
+                Err(error) => {
+                    if !self.autogrow_step() {
+                        return Err(error);
+                    }
+                }
+            }
+        };
+
+        if let Some(autogrow) = self.autogrow {
+            let area = self.width as f32 * self.height as f32;
+            if self.allocated_space as f32 / area > autogrow.target_occupancy {
+                self.autogrow_step();
+            }
+        }
+
+        Ok(allocation)
+    }
+
+    /// Let the atlas grow automatically to keep its occupancy below a target.
+    ///
+    /// After each call to `allocate` (or `try_allocate`), if the ratio of allocated space
+    /// exceeds `target_occupancy` the atlas grows by doubling its width and height, without
+    /// exceeding `max_size`. If an allocation fails, the atlas grows until the allocation
+    /// succeeds or `max_size` is reached. Existing allocations keep their coordinates (see
+    /// `grow`).
+    ///
+    /// Call `take_grow_event` after allocating to know whether the texture needs to be resized.
+    pub fn set_autogrow(&mut self, target_occupancy: f32, max_size: Size) {
+        self.autogrow = Some(AutoGrow { target_occupancy, max_size });
+    }
+
+    /// Stop growing the atlas automatically.
+    pub fn disable_autogrow(&mut self) {
+        self.autogrow = None;
+    }
+
+    /// Returns the new size of the atlas if it was automatically grown since the last call.
+    pub fn take_grow_event(&mut self) -> Option<Size> {
+        self.grow_event.take()
+    }
+
+    // Grow the atlas one step according to the autogrow policy. Returns false if it can't grow.
+    fn autogrow_step(&mut self) -> bool {
+        let autogrow = match self.autogrow {
+            Some(autogrow) => autogrow,
+            None => return false,
+        };
+
+        let size = self.size();
+        let limit = MAX_SIZE - 1;
+        let new_size = size2(
+            (size.width * 2).min(autogrow.max_size.width).min(limit).max(size.width),
+            (size.height * 2).min(autogrow.max_size.height).min(limit).max(size.height),
+        );
+
+        if new_size == size || new_size.width.checked_mul(new_size.height).is_none() {
+            return false;
         }
 
-        let (w, h) = convert_coordinates(self.flip_xy, requested_size.width as u16, requested_size.height as u16);
+        self.grow(new_size);
+        self.grow_event = Some(new_size);
+
+        true
+    }
+
+    // Allocates and returns the internal id of the allocation.
+    fn allocate_internal(&mut self, requested_size: Size) -> Result<Allocation, AllocError> {
+        let (w, h) = self.shelf_space_size(requested_size)?;
+        if w > self.column_width {
+            return self.allocate_spanning(w, h, requested_size.area());
+        }
 
-        let mut selected_shelf = std::usize::MAX;
+        let mut selected_shelf = usize::MAX;
         let mut selected_bucket = BucketIndex::INVALID;
-        let mut best_waste = u16::MAX;
+        let mut best_waste = u32::MAX;
+        // Set if we found a bucket with enough space that can't hold more items.
+        let mut hit_item_limit = false;
 
-        let can_add_shelf = (self.available_height >= h || self.current_column + 1 < self.num_columns)
-            && self.shelves.len() < MAX_SHELF_COUNT
-            && self.buckets.len() < MAX_BIN_COUNT;
+        let can_add_shelf = self.can_add_shelf(h);
+        let use_reserved_shelves = self.shelf_height_class(h) == self.reserved_height;
 
         'shelves: for (shelf_index, shelf) in self.shelves.iter().enumerate() {
             if shelf.height < h || shelf.bucket_width < w {
                 continue;
             }
 
+            if shelf_index < self.reserved_shelves as usize && !use_reserved_shelves {
+                continue;
+            }
+
             let y_waste = shelf.height - h;
             if y_waste > best_waste || (can_add_shelf && y_waste > h) {
                 continue;
@@ -240,9 +662,12 @@ impl BucketedAtlasAllocator {
             let mut bucket_index = shelf.first_bucket;
             while bucket_index != BucketIndex::INVALID {
                 let bucket = &self.buckets[bucket_index.to_usize()];
+                let needed = w.saturating_add(self.tile_padding(shelf_index, bucket_index, w));
 
-                if bucket.free_space >= w && bucket.item_count < MAX_ITEMS_PER_BIN {
-                    if y_waste == 0 && bucket.free_space == w {
+                if bucket.free_space >= needed && bucket.item_count >= self.max_items_per_bin() {
+                    hit_item_limit = true;
+                } else if bucket.free_space >= needed {
+                    if self.placement == Placement::FirstFit || (y_waste == 0 && bucket.free_space == needed) {
                         selected_shelf = shelf_index;
                         selected_bucket = bucket_index;
 
@@ -264,7 +689,11 @@ impl BucketedAtlasAllocator {
         if selected_bucket == BucketIndex::INVALID {
             if can_add_shelf {
                 selected_shelf = self.add_shelf(w, h);
-                selected_bucket = self.shelves[selected_shelf].first_bucket;
+                selected_bucket = self.find_bucket_in_shelf(selected_shelf, w);
+                if selected_bucket == BucketIndex::INVALID {
+                    // The tile padding doesn't leave enough room in the new shelf.
+                    self.cleanup_shelves();
+                }
             } else {
                 // Attempt to merge some empty shelves to make a big enough spot.
                 let selected = self.coalesce_shelves(w, h);
@@ -273,837 +702,4077 @@ impl BucketedAtlasAllocator {
             }
         }
 
-        if selected_bucket != BucketIndex::INVALID {
-            return self.alloc_from_bucket(selected_shelf, selected_bucket, w);
+        if selected_bucket == BucketIndex::INVALID {
+            return Err(if hit_item_limit {
+                AllocError::BinItemLimit
+            } else if self.has_room_for_shelf(h) && self.shelf_limit_reached(h) {
+                AllocError::CapacityExhausted
+            } else {
+                AllocError::OutOfSpace
+            });
         }
 
-        return  None;
+        let padding = self.tile_padding(selected_shelf, selected_bucket, w);
+
+        Ok(self.alloc_from_bucket(selected_shelf, selected_bucket, w, padding, requested_size.area()))
     }
 
-    /// Deallocate a rectangle in the atlas.
-    ///
-    /// Space is only reclaimed when all items of the same bucket are deallocated.
-    pub fn deallocate(&mut self, id: AllocId) {
-        if self.deallocate_from_bucket(id) {
-            self.cleanup_shelves();
+    // Allocate an item that is too wide for a column on a shelf spanning all columns.
+    //
+    // These shelves are stacked at the top of the atlas, reducing the height of all columns,
+    // and hold a single bucket. Adding one is only possible while the first column is in use,
+    // since the other columns are filled to their full height afterwards.
+    fn allocate_spanning(&mut self, w: u32, h: u32, requested_area: i32) -> Result<Allocation, AllocError> {
+        if self.tile_size.is_some() {
+            return Err(AllocError::TooLarge);
         }
 
-        self.check()
-    }
+        for shelf_index in 0..self.shelves.len() {
+            let shelf = &self.shelves[shelf_index];
+            if !shelf.spanning || shelf.height < h || shelf.height - h > h {
+                continue;
+            }
 
-    /// Amount of occupied space in the atlas.
-    pub fn allocated_space(&self) -> i32 {
-        self.allocated_space
-    }
+            let bucket_index = shelf.first_bucket;
+            let bucket = &self.buckets[bucket_index.to_usize()];
+            if bucket.free_space >= w && bucket.item_count < self.max_items_per_bin() {
+                return Ok(self.alloc_from_bucket(shelf_index, bucket_index, w, 0, requested_area));
+            }
+        }
 
-    /// How much space is available for future allocations.
-    pub fn free_space(&self) -> i32 {
-        (self.width as i32 * self.height as i32) - self.allocated_space
-    }
+        let height = self.shelf_height_class(h).min(self.available_height);
+        if self.current_column != 0 || height < h {
+            return Err(AllocError::OutOfSpace);
+        }
 
-    fn alloc_from_bucket(&mut self, shelf_index: usize, bucket_index: BucketIndex, width: u16) -> Option<Allocation> {
-        let shelf = &mut self.shelves[shelf_index];
-        let bucket = &mut self.buckets[bucket_index.to_usize()];
+        if self.shelves.len() >= self.max_shelves || self.buckets.len() >= self.max_bin_count() {
+            return Err(AllocError::CapacityExhausted);
+        }
 
-        debug_assert!(bucket.free_space >= width);
+        self.column_height -= height;
+        self.available_height -= height;
 
-        let min_x = bucket.x + shelf.bucket_width - bucket.free_space;
-        let min_y = shelf.y;
-        let max_x = min_x + width;
-        let max_y = min_y + shelf.height;
+        let shelf_index = self.shelves.len();
+        let bucket_width = self.num_columns * self.column_width;
+        let bucket_index = self.add_bucket(Bucket {
+            next: BucketIndex::INVALID,
+            x: 0,
+            free_space: bucket_width,
+            refcount: 0,
+            shelf: shelf_index as u16,
+            generation: Wrapping(0),
+            item_count: 0,
+            items: Vec::new(),
+            occupied: Vec::new(),
+        });
 
-        let (min_x, min_y) = convert_coordinates(self.flip_xy, min_x, min_y);
-        let (max_x, max_y) = convert_coordinates(self.flip_xy, max_x, max_y);
+        self.shelves.push(Shelf {
+            x: 0,
+            y: self.column_height,
+            height,
+            bucket_width,
+            spanning: true,
+            first_bucket: bucket_index,
+        });
 
-        bucket.free_space -= width;
-        bucket.refcount += 1;
-        bucket.item_count += 1;
+        self.high_water_height = self.high_water_height.max(self.column_height + height);
 
-        let id = AllocId(
-            (bucket_index.0 as u32) & BIN_MASK
-            | ((bucket.item_count as u32) << 12) & ITEM_MASK
-            | (bucket.generation.0 as u32) << 24
+        Ok(self.alloc_from_bucket(shelf_index, bucket_index, w, 0, requested_area))
+    }
+
+    /// Allocate a sequence of rectangles sorted by decreasing size.
+    ///
+    /// This is a fast path for structured workloads like mip pyramids, where each size is
+    /// smaller or equal to the previous one in both dimensions (checked in debug builds).
+    /// Each rectangle is first placed in the shelf that received the previous one if it is of
+    /// the same size class, skipping the search over all shelves.
+    ///
+    /// Allocation stops at the first rectangle that doesn't fit, so the returned vector
+    /// contains the allocations of the successful prefix of `sizes`.
+    pub fn allocate_descending(&mut self, sizes: &[Size]) -> Vec<Allocation> {
+        debug_assert!(
+            sizes.windows(2).all(|pair| pair[0].width >= pair[1].width && pair[0].height >= pair[1].height),
+            "sizes must be sorted in descending order"
         );
 
-        let rectangle = Rectangle {
-            min: point2(min_x as i32, min_y as i32),
-            max: point2(max_x as i32, max_y as i32),
-        };
+        let mut allocations = Vec::with_capacity(sizes.len());
+        let mut current_shelf = usize::MAX;
+        for size in sizes {
+            if !self.has_room_for_id() {
+                break;
+            }
 
-        self.allocated_space += rectangle.size().area();
+            let allocation = match self.allocate_in_shelf(current_shelf, *size, true) {
+                Some(allocation) => allocation,
+                None => match self.allocate_internal(*size) {
+                    Ok(allocation) => allocation,
+                    Err(..) => break,
+                },
+            };
 
-        self.check();
+            let bucket_index = self.bucket_index(allocation.id);
+            current_shelf = self.buckets[bucket_index].shelf as usize;
 
-        Some(Allocation { id, rectangle })
+            allocations.push(self.export_allocation(allocation));
+        }
+
+        allocations
     }
 
-    fn add_bucket(&mut self, mut bucket: Bucket) -> BucketIndex {
-        let mut bucket_index = self.first_unallocated_bucket;
+    /// Allocate a batch of rectangles.
+    ///
+    /// The rectangles are allocated by decreasing height which usually packs them better than
+    /// allocating them in the provided order, since the space left in tall shelves can be used
+    /// by the smaller rectangles. `out` is cleared and receives the result of each allocation
+    /// in the same order as `sizes`, with `None` for the rectangles that didn't fit.
+    pub fn allocate_many(&mut self, sizes: &[Size], out: &mut Vec<Option<Allocation>>) {
+        let mut order: Vec<usize> = (0..sizes.len()).collect();
+        order.sort_by_key(|&index| {
+            let size = sizes[index];
+            let h = if self.flip_xy { size.width } else { size.height };
+            Reverse(h)
+        });
 
-        if bucket_index == BucketIndex::INVALID {
-            bucket_index = BucketIndex(self.buckets.len() as u16);
-            self.buckets.push(bucket);
-        } else {
-            let idx = bucket_index.to_usize();
-            bucket.generation = self.buckets[idx].generation + Wrapping(1);
-            self.first_unallocated_bucket = self.buckets[idx].next;
-            self.buckets[idx] = bucket;
+        out.clear();
+        out.resize(sizes.len(), None);
+        for index in order {
+            out[index] = self.allocate(sizes[index]);
         }
-
-        bucket_index
     }
 
-    fn add_shelf(&mut self, width: u16, height: u16) -> usize {
+    /// Allocate a rectangle, preferably close to an existing allocation.
+    ///
+    /// The shelf containing `near` and its direct neighbors are tried first, which helps with
+    /// texture cache locality when the rectangles are sampled together (for example the glyphs
+    /// of a word). If none of them can hold the rectangle, this falls back to a regular
+    /// allocation.
+    ///
+    /// `near` must be a live allocation of this atlas.
+    pub fn allocate_near(&mut self, size: Size, near: AllocId) -> Option<Allocation> {
+        if !self.has_room_for_id() {
+            return None;
+        }
 
-        let can_add_column = self.current_column + 1 < self.num_columns;
+        let shelf_index = self.shelf_of(near);
+        let candidates = [Some(shelf_index), shelf_index.checked_sub(1), Some(shelf_index + 1)];
+        for &candidate in &candidates {
+            if let Some(candidate) = candidate {
+                if let Some(allocation) = self.allocate_in_shelf(candidate, size, false) {
+                    return Some(self.export_allocation(allocation));
+                }
+            }
+        }
 
-        if self.available_height != 0 && self.available_height < height && can_add_column {
-            // We have room to add a shelf in a new column but current one doesn't have
-            // enough available space. First add a shelf to fill the current column's
-            // remaining height.
-            self.add_shelf(0, self.available_height);
-            debug_assert_eq!(self.available_height, 0);
+        self.allocate(size)
+    }
+
+    /// Allocate `count` rectangles of the same size next to each other on a single shelf.
+    ///
+    /// The rectangles are contiguous and sorted by increasing x (or y if `vertical_shelves`
+    /// is set), which is useful for example to keep the frames of an animation on the same
+    /// row. Either all of the rectangles are allocated or none of them.
+    pub fn allocate_row(&mut self, count: usize, cell: Size) -> Option<Vec<Allocation>> {
+        if count == 0 {
+            return Some(Vec::new());
         }
 
-        if self.available_height == 0 && can_add_column {
-            self.current_column += 1;
-            self.available_height = self.height;
+        if count > self.max_items_per_bin() as usize {
+            return None;
         }
 
-        let height = shelf_height(height).min(self.available_height);
-        let num_buckets = self.num_buckets(width, height);
-        let mut bucket_width = self.column_width / num_buckets;
-        bucket_width = bucket_width - (bucket_width % self.alignment.width as u16); // TODO
-        let y = self.height - self.available_height;
-        self.available_height -= height;
+        if let Some(ids) = &self.stable_ids {
+            if !ids.has_room_for(count) {
+                return None;
+            }
+        }
 
-        let shelf_index = self.shelves.len();
+        let requested_area = cell.area();
+        let (w, h) = self.shelf_space_size(cell).ok()?;
+        let row_width = w as usize * count;
+        if row_width > self.column_width as usize {
+            return None;
+        }
+        let row_width = row_width as u32;
+        let max_item_count = self.max_items_per_bin() - count as u16;
+        let use_reserved_shelves = self.shelf_height_class(h) == self.reserved_height;
 
-        // Initialize the buckets for our new shelf.
-        let mut x = self.current_column * self.column_width;
-        let mut bucket_next = BucketIndex::INVALID;
-        for _ in 0..num_buckets {
-            let bucket = Bucket {
-                next: bucket_next,
-                x,
-                free_space: bucket_width,
-                refcount: 0,
-                shelf: shelf_index as u16,
-                generation: Wrapping(0),
-                item_count: 0,
-            };
+        let mut selected = None;
+        'shelves: for (shelf_index, shelf) in self.shelves.iter().enumerate() {
+            if shelf_index < self.reserved_shelves as usize && !use_reserved_shelves {
+                continue;
+            }
 
-            x += bucket_width;
+            if shelf.height < h || shelf.height - h > h || shelf.bucket_width < row_width {
+                continue;
+            }
 
-            let bucket_index = self.add_bucket(bucket);
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+                let needed = row_width.saturating_add(self.tile_padding(shelf_index, bucket_index, row_width));
+                if bucket.free_space >= needed && bucket.item_count <= max_item_count {
+                    selected = Some((shelf_index, bucket_index));
+                    break 'shelves;
+                }
 
-            bucket_next = bucket_index;
+                bucket_index = bucket.next;
+            }
         }
 
-        self.shelves.push(Shelf {
-            x: self.current_column * self.column_width,
-            y,
-            height,
-            bucket_width,
-            first_bucket: bucket_next,
-        });
+        if selected.is_none() {
+            let (shelf_index, bucket_index) = if self.can_add_shelf(h) {
+                let shelf_index = self.add_shelf(row_width, h);
+                (shelf_index, self.find_bucket_in_shelf(shelf_index, row_width))
+            } else {
+                self.coalesce_shelves(row_width, h)
+            };
 
-        shelf_index
+            let fits = bucket_index != BucketIndex::INVALID && {
+                let needed = row_width.saturating_add(self.tile_padding(shelf_index, bucket_index, row_width));
+                self.buckets[bucket_index.to_usize()].free_space >= needed
+            };
+            if fits {
+                selected = Some((shelf_index, bucket_index));
+            } else {
+                // Remove the shelf we may have added.
+                self.cleanup_shelves();
+            }
+        }
+
+        let (shelf_index, bucket_index) = selected?;
+
+        // The row is padded as a whole so that its cells stay contiguous.
+        let mut padding = self.tile_padding(shelf_index, bucket_index, row_width);
+        let mut allocations = Vec::with_capacity(count);
+        for _ in 0..count {
+            let allocation = self.alloc_from_bucket(shelf_index, bucket_index, w, padding, requested_area);
+            allocations.push(self.export_allocation(allocation));
+            padding = 0;
+        }
+
+        Some(allocations)
     }
 
-    /// Find a sequence of consecutive shelves that can be coalesced into a single one
-    /// tall enough to fit the provided size.
+    /// Allocate a rectangle within a range of y coordinates.
     ///
-    /// If such a sequence is found, grow the height of first shelf and squash the other
-    /// ones to zero.
-    /// The squashed shelves are not removed, their height is just set to zero so no item
-    /// can go in, and they will be garbage-collected whenever there's no shelf above them.
-    /// For simplicity, the bucket width is not modified.
+    /// Only the shelves that are entirely within `y_range` are considered, and a new shelf is
+    /// only added if it would be within the range as well. This can be used to keep allocations
+    /// with different lifetimes in separate regions of the atlas. If the `vertical_shelves` option
+    /// is set, the range applies to the x axis instead.
+    ///
+    /// Returns `None` if the rectangle doesn't fit in the range, even if there is space elsewhere.
+    pub fn allocate_in_y_range(&mut self, size: Size, y_range: Range<i32>) -> Option<Allocation> {
+        if !self.has_room_for_id() {
+            return None;
+        }
 
-    fn coalesce_shelves(&mut self, w: u16, h: u16) -> (usize, BucketIndex) {
-        let len = self.shelves.len();
-        let mut coalesce_range = None;
-        let mut coalesced_height = 0;
+        let in_range = |y: u32, height: u32| y as i32 >= y_range.start && (y + height) as i32 <= y_range.end;
 
-        'outer: for shelf_index in 0..len {
-            if self.shelves[shelf_index].bucket_width < w {
+        for shelf_index in 0..self.shelves.len() {
+            let shelf = &self.shelves[shelf_index];
+            if !in_range(shelf.y, shelf.height) {
                 continue;
             }
-            if !self.shelf_is_empty(shelf_index) {
-                continue;
-            }
-            let shelf_x = self.shelves[shelf_index].x;
-            coalesced_height = self.shelves[shelf_index].height;
-            for i in 1..3 {
-                if self.shelves[shelf_index + i].x != shelf_x {
-                    // Can't coalesce shelves from different columns.
-                    continue 'outer;
-                }
-
-                if shelf_index + i >= len {
-                    break 'outer;
-                }
 
-                if !self.shelf_is_empty(shelf_index + i) {
-                    continue 'outer;
-                }
-
-                coalesced_height += self.shelves[shelf_index + i].height;
-
-                if coalesced_height >= h {
-                    coalesce_range = Some(shelf_index .. (shelf_index + i + 1));
-                    break 'outer;
-                }
+            if let Some(allocation) = self.allocate_in_shelf(shelf_index, size, false) {
+                return Some(self.export_allocation(allocation));
             }
         }
 
-        if let Some(range) = coalesce_range {
-            let y_top = self.shelves[range.start].y + coalesced_height;
-            for i in range.start + 1 .. range.end {
-                self.shelves[i].y = y_top;
-                self.shelves[i].height = 0;
-            }
+        // Otherwise, try to add a shelf.
+        let (w, h) = self.shelf_space_size(size).ok()?;
+        if w > self.column_width {
+            return None;
+        }
 
-            let shelf_index = range.start;
-            let shelf = &mut self.shelves[shelf_index];
-            shelf.height = coalesced_height;
+        if !self.can_add_shelf(h) {
+            return None;
+        }
 
-            return (shelf_index, shelf.first_bucket);
+        // The new shelf goes at the top of the current column (after the tile filler, if any),
+        // or at the bottom of the next one if there isn't enough space left, see add_shelf.
+        let top = self.column_height - self.available_height;
+        let filler = self.tile_filler(top, h);
+        let (y, available_height) = if self.available_height >= h.saturating_add(filler) {
+            (top + filler, self.available_height - filler)
+        } else {
+            (0, self.column_height)
+        };
+        if !in_range(y, self.shelf_height_class(h).min(available_height)) {
+            return None;
         }
 
-        (0, BucketIndex::INVALID)
+        let shelf_index = self.add_shelf(w, h);
+        let bucket_index = self.find_bucket_in_shelf(shelf_index, w);
+        if bucket_index == BucketIndex::INVALID {
+            self.cleanup_shelves();
+            return None;
+        }
+        let padding = self.tile_padding(shelf_index, bucket_index, w);
+        let allocation = self.alloc_from_bucket(shelf_index, bucket_index, w, padding, size.area());
+
+        Some(self.export_allocation(allocation))
     }
 
-    fn num_buckets(&self, width: u16, height: u16) -> u16 {
-        match self.column_width / u16::max(width, height) {
-            0 ..= 4 => 1,
-            5 ..= 16 => 2,
-            17 ..= 32 => 4,
-            n => (n /16 - 1).next_power_of_two(),
-        }.min((MAX_BIN_COUNT - self.buckets.len()) as u16)
+    /// Returns the rectangle of an allocation.
+    ///
+    /// The id must correspond to a live allocation of the atlas, see `try_get` otherwise.
+    /// This runs in constant time: the id addresses the bucket and the item directly
+    /// (through the id table with stable ids), so it can be called for every allocation
+    /// each frame.
+    pub fn get(&self, id: AllocId) -> Rectangle {
+        self.try_get(id).expect("Invalid AllocId")
     }
 
-    /// Returns true if we should garbage-collect the shelves as a result of
-    /// removing this element (we deallocated the last item from the bucket on
-    /// the top-most shelf).
-    fn deallocate_from_bucket(&mut self, id: AllocId) -> bool {
-        let bucket_index = (id.0 & BIN_MASK) as usize;
-        let generation = ((id.0 & GEN_MASK) >> 24 ) as u8;
+    /// Returns the rectangle of an allocation, or `None` if the id doesn't refer to a live
+    /// allocation of the atlas.
+    pub fn try_get(&self, id: AllocId) -> Option<Rectangle> {
+        let id = self.validate_id(id).ok()?;
+        let bucket = &self.buckets[self.bucket_index(id)];
+        let item = &bucket.items[self.item_index(id) - 1];
 
-        let bucket = &mut self.buckets[bucket_index];
+        Some(self.item_rectangle(bucket, bucket.x + item.offset, item.width))
+    }
 
-        let expected_generation = bucket.generation.0;
-        assert_eq!(generation, expected_generation);
+    /// Iterate over the live allocations.
+    ///
+    /// Items don't store their position, so the rectangles are reconstructed from the position
+    /// of their bucket and the width of the items that precede them.
+    pub fn iter(&self) -> impl Iterator<Item = Allocation> + '_ {
+        // Map the internal ids back to the ones that were handed out.
+        let external_ids = self.stable_ids.as_ref().map(IdTable::external_ids);
+
+        self.buckets.iter().enumerate().filter(|(_, bucket)| bucket.refcount > 0).flat_map(move |(bucket_index, bucket)| {
+            let mut x = bucket.x;
+            bucket.occupied.iter().filter_map(move |&item_index| {
+                let item = &bucket.items[item_index as usize];
+                x += item.padding;
+                let item_x = x;
+                x += item.width;
+                if !item.allocated {
+                    return None;
+                }
 
-        assert!(bucket.refcount > 0);
-        bucket.refcount -= 1;
+                Some((bucket_index, bucket, item_index, item_x, item.width))
+            })
+        }).map(move |(bucket_index, bucket, item_index, x, width)| {
+            let mut id = self.encode_id(bucket_index, item_index + 1, bucket.generation.0);
+            if let Some(external_ids) = &external_ids {
+                id = external_ids.get(id);
+            }
 
-        let shelf = &self.shelves[bucket.shelf as usize];
+            Allocation { id, rectangle: self.item_rectangle(bucket, x, width) }
+        })
+    }
 
-        let bucket_is_empty = bucket.refcount == 0;
-        if bucket_is_empty {
-            self.allocated_space -= (shelf.bucket_width - bucket.free_space) as i32 * shelf.height as i32;
-            bucket.free_space = shelf.bucket_width;
-        }
+    fn item_rectangle(&self, bucket: &Bucket, x: u32, width: u32) -> Rectangle {
+        let shelf = &self.shelves[bucket.shelf as usize];
+        let (min_x, min_y) = convert_coordinates(self.flip_xy, x, shelf.y);
+        let (max_x, max_y) = convert_coordinates(self.flip_xy, x + width, shelf.y + shelf.height);
 
-        bucket_is_empty && bucket.shelf as usize == self.shelves.len() - 1
+        self.remove_padding(Rectangle {
+            min: point2(min_x as i32, min_y as i32),
+            max: point2(max_x as i32, max_y as i32),
+        })
     }
 
-    fn cleanup_shelves(&mut self) {
-        while self.shelves.len() > 0 {
-            {
-                let shelf = self.shelves.last().unwrap();
-                let mut bucket_index = shelf.first_bucket;
-                let mut last_bucket = shelf.first_bucket;
+    // Pad, align and convert a requested size to shelf coordinates, with the fixed shelf height
+    // applied. The returned width can be larger than a column for spanning shelves, callers that
+    // don't support them check it.
+    fn shelf_space_size(&self, mut size: Size) -> Result<(u32, u32), AllocError> {
+        if size.is_empty() {
+            return Err(AllocError::EmptySize);
+        }
 
-                while bucket_index != BucketIndex::INVALID {
-                    let bucket = &self.buckets[bucket_index.to_usize()];
+        self.add_padding(&mut size);
 
-                    if bucket.refcount != 0 {
-                        return;
-                    }
+        if size.width > MAX_SIZE || size.height > MAX_SIZE {
+            return Err(AllocError::TooLarge);
+        }
 
-                    last_bucket = bucket_index;
-                    bucket_index = bucket.next;
-                }
+        let unaligned_size = size;
+
+        adjust_size(self.alignment.width, &mut size.width);
+        adjust_size(self.alignment.height, &mut size.height);
+
+        let (w, h) = convert_coordinates(self.flip_xy, size.width as u32, size.height as u32);
+        if w > self.num_columns * self.column_width || h > self.height {
+            // The column width is itself rounded down to the alignment.
+            let unaligned_width = self.width / self.num_columns * self.num_columns;
+            let (unaligned_w, unaligned_h) = convert_coordinates(self.flip_xy, unaligned_size.width as u32, unaligned_size.height as u32);
+            if unaligned_w <= unaligned_width && unaligned_h <= self.height {
+                return Err(AllocError::AlignmentOverflow {
+                    requested: unaligned_size,
+                    aligned: size,
+                });
+            }
 
-                // We didn't run into any bucket on this shelf with live elements,
-                // this means we can remove it.
+            return Err(AllocError::TooLarge);
+        }
 
-                // Can't have a shelf with no buckets.
-                debug_assert!(last_bucket != BucketIndex::INVALID);
-                // Add the buckets to the free list.
-                self.buckets[last_bucket.to_usize()].next = self.first_unallocated_bucket;
-                self.first_unallocated_bucket = shelf.first_bucket;
+        let h = match self.fixed_shelf_height {
+            Some(fixed) if h > fixed => return Err(AllocError::TooLarge),
+            Some(fixed) => fixed,
+            None => h,
+        };
 
-                if shelf.y == 0 && self.current_column > 0 {
-                    self.current_column -= 1;
-                    let prev_shelf = &self.shelves[self.shelves.len() - 2];
-                    self.available_height = self.height - (prev_shelf.y + prev_shelf.height);
-                } else {
-                    // Reclaim the height of the shelf.
-                    self.available_height += shelf.height;
-                }
-            }
+        Ok((w, h))
+    }
 
-            self.shelves.pop();
-        }
+    // Grow a requested size by the space to leave around the allocation.
+    fn add_padding(&self, size: &mut Size) {
+        let padding = 2 * self.padding as i32;
+        size.width = size.width.saturating_add(padding);
+        size.height = size.height.saturating_add(padding);
     }
 
-    fn shelf_is_empty(&self, idx: usize) -> bool {
-        let shelf = &self.shelves[idx];
-        let mut bucket_index = shelf.first_bucket;
+    // The rectangle handed out for an item, inside of the padding.
+    fn remove_padding(&self, rectangle: Rectangle) -> Rectangle {
+        let padding = self.padding as i32;
+        rectangle.inflate(-padding, -padding)
+    }
 
-        while bucket_index != BucketIndex::INVALID {
-            let bucket = &self.buckets[bucket_index.to_usize()];
+    /// Returns true if the id refers to a live allocation of the atlas.
+    ///
+    /// Unlike most other methods, this doesn't panic if the id is stale or comes from
+    /// another atlas.
+    pub fn contains(&self, id: AllocId) -> bool {
+        self.validate_id(id).is_ok()
+    }
 
-            if bucket.refcount != 0 {
-                return false;
-            }
+    // Returns the internal id of a live allocation, or the reason why the id doesn't refer
+    // to one.
+    fn validate_id(&self, id: AllocId) -> Result<AllocId, DeallocError> {
+        let id = match &self.stable_ids {
+            Some(ids) => ids.validate(id)?,
+            None => id,
+        };
 
-            bucket_index = bucket.next;
+        let bucket = self.buckets.get(self.bucket_index(id)).ok_or(DeallocError::NotAllocated)?;
+        if bucket.generation.0 != generation_of(id) {
+            return Err(DeallocError::StaleGeneration);
         }
 
-        true
+        let allocated = self.item_index(id)
+            .checked_sub(1)
+            .and_then(|index| bucket.items.get(index))
+            .map_or(false, |item| item.allocated);
+        if bucket.refcount == 0 || !allocated {
+            return Err(DeallocError::NotAllocated);
+        }
+
+        Ok(id)
     }
 
+    // Returns the index of the shelf containing a live allocation.
+    fn shelf_of(&self, id: AllocId) -> usize {
+        let id = match &self.stable_ids {
+            Some(ids) => ids.get(id),
+            None => id,
+        };
 
-    /// Dump a visual representation of the atlas in SVG format.
-    pub fn dump_svg(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
-        use svg_fmt::*;
+        let bucket = &self.buckets[self.bucket_index(id)];
+        let generation = generation_of(id);
+        assert_eq!(generation, bucket.generation.0, "Invalid AllocId");
 
-        writeln!(
-            output,
-            "{}",
-            BeginSvg {
-                w: self.width as f32,
-                h: self.height as f32
-            }
-        )?;
+        bucket.shelf as usize
+    }
 
-        self.dump_into_svg(None, output)?;
+    // Attempt to allocate in a specific shelf. If exact_height is true, only if its height
+    // class matches the requested size exactly, otherwise if it doesn't waste more than the
+    // requested height.
+    fn allocate_in_shelf(&mut self, shelf_index: usize, requested_size: Size, exact_height: bool) -> Option<Allocation> {
+        if shelf_index >= self.shelves.len() {
+            return None;
+        }
 
-        writeln!(output, "{}", EndSvg)
-    }
+        let (w, h) = self.shelf_space_size(requested_size).ok()?;
+        if w > self.column_width {
+            return None;
+        }
 
+        if shelf_index < self.reserved_shelves as usize && self.shelf_height_class(h) != self.reserved_height {
+            return None;
+        }
 
-    #[cfg(not(feature = "checks"))]
-    fn check(&self) {}
+        let shelf = &self.shelves[shelf_index];
+        let height_fits = if exact_height {
+            shelf.height == self.shelf_height_class(h)
+        } else {
+            shelf.height >= h && shelf.height - h <= h
+        };
 
-    #[cfg(feature = "checks")]
-    fn check(&self) {
-        let mut h = 0;
-        for shelf in &self.shelves {
-            h += shelf.height;
+        if !height_fits || shelf.bucket_width < w {
+            return None;
+        }
+
+        let bucket_index = self.find_bucket_in_shelf(shelf_index, w);
+        if bucket_index == BucketIndex::INVALID {
+            return None;
         }
-        h += self.available_height;
 
-        // Total height must be a multiple of the actual height, up to height * num_columns.
-        assert_eq!(h % self.height, 0);
-        assert!(h <= self.height * self.num_columns);
-        assert!(h >= self.height);
+        let padding = self.tile_padding(shelf_index, bucket_index, w);
 
-        assert_eq!(self.is_empty(), self.allocated_space() == 0)
+        Some(self.alloc_from_bucket(shelf_index, bucket_index, w, padding, requested_size.area()))
     }
 
-    /// Dump a visual representation of the atlas in SVG, omitting the beginning and end of the
-    /// SVG document, so that it can be included in a larger document.
-    ///
-    /// If a rectangle is provided, translate and scale the output to fit it.
-    pub fn dump_into_svg(&self, rect: Option<&Rectangle>, output: &mut dyn std::io::Write) -> std::io::Result<()> {
-        use svg_fmt::*;
+    // Returns the first bucket of the shelf that can hold an item of the provided width, or
+    // BucketIndex::INVALID.
+    fn find_bucket_in_shelf(&self, shelf_index: usize, w: u32) -> BucketIndex {
+        let mut bucket_index = self.shelves[shelf_index].first_bucket;
+        while bucket_index != BucketIndex::INVALID {
+            let bucket = &self.buckets[bucket_index.to_usize()];
+            let needed = w.saturating_add(self.tile_padding(shelf_index, bucket_index, w));
+            if bucket.free_space >= needed && bucket.item_count < self.max_items_per_bin() {
+                return bucket_index;
+            }
 
-        let (sx, sy, tx, ty) = if let Some(rect) = rect {
-            (
-                rect.size().width as f32 / self.width as f32,
-                rect.size().height as f32 / self.height as f32,
-                rect.min.x as f32,
-                rect.min.y as f32,
-            )
-        } else {
-            (1.0, 1.0, 0.0, 0.0)
-        };
+            bucket_index = bucket.next;
+        }
 
-        writeln!(
-            output,
-            r#"    {}"#,
-            rectangle(tx, ty, self.width as f32 * sx, self.height as f32 * sy)
-                .fill(rgb(40, 40, 40))
-                .stroke(Stroke::Color(black(), 1.0))
-        )?;
+        BucketIndex::INVALID
+    }
 
+    // The space to skip before an item of the provided width at the start of a bucket's free
+    // space so that it doesn't cross a tile boundary, or starts on one if it is wider than a tile.
+    fn tile_padding(&self, shelf_index: usize, bucket_index: BucketIndex, w: u32) -> u32 {
+        let tile_width = match self.tile_size {
+            Some((tile_width, _)) => tile_width,
+            None => return 0,
+        };
 
-        for shelf in &self.shelves {
-            let mut bucket_index = shelf.first_bucket;
+        let bucket = &self.buckets[bucket_index.to_usize()];
+        let x = bucket.x + self.shelves[shelf_index].bucket_width - bucket.free_space;
+        let offset = x % tile_width;
+        if offset == 0 || (w <= tile_width && offset + w <= tile_width) {
+            return 0;
+        }
 
-            let y = shelf.y as f32 * sy;
-            let h = shelf.height as f32 * sy;
-            while bucket_index != BucketIndex::INVALID {
-                let bucket = &self.buckets[bucket_index.to_usize()];
+        tile_width - offset
+    }
 
-                let x = bucket.x as f32 * sx;
-                let w = (shelf.bucket_width - bucket.free_space) as f32 * sx;
+    // The height to skip before a shelf for items of the provided height starting at y, so that
+    // it doesn't cross a tile boundary, or starts on one if it is taller than a tile.
+    fn tile_filler(&self, y: u32, height: u32) -> u32 {
+        let tile_height = match self.tile_size {
+            Some((_, tile_height)) => tile_height,
+            None => return 0,
+        };
 
-                {
-                    let (x, y) = if self.flip_xy { (y, x) } else { (x, y) };
-                    let (w, h) = if self.flip_xy { (h, w) } else { (w, h) };
+        let height = self.shelf_height_class(height);
+        let offset = y % tile_height;
+        if offset == 0 || (height <= tile_height && offset + height <= tile_height) {
+            return 0;
+        }
 
-                    writeln!(
-                        output,
-                        r#"    {}"#,
-                        rectangle(x + tx, y + ty, w, h)
-                            .fill(rgb(70, 70, 180))
-                            .stroke(Stroke::Color(black(), 1.0))
-                    )?;
-                }
+        tile_height - offset
+    }
 
-                if bucket.free_space > 0 {
-                    let x_free = x + w;
-                    let w_free = bucket.free_space as f32 * sx;
+    /// Returns false if none of the existing bins can hold more items and no new shelf
+    /// can be added.
+    ///
+    /// Each bin can hold a limited number of items, 4095 with the default `IdLayout` (including
+    /// the ones that were deallocated since the bin was created). When this returns false allocations fail with
+    /// `AllocError::BinItemLimit` or `AllocError::OutOfSpace` regardless of the remaining
+    /// free space, so it is a good time to start a new atlas instead of growing this one.
+    pub fn bin_has_room(&self) -> bool {
+        if self.can_add_shelf(1) {
+            return true;
+        }
 
-                    let (x_free, y) = if self.flip_xy { (y, x_free) } else { (x_free, y) };
-                    let (w_free, h) = if self.flip_xy { (h, w_free) } else { (w_free, h) };
+        for shelf in &self.shelves {
+            if shelf.height == 0 {
+                continue;
+            }
 
-                    writeln!(
-                        output,
-                        r#"    {}"#,
-                        rectangle(x_free + tx, y + ty, w_free, h)
-                            .fill(rgb(50, 50, 50))
-                            .stroke(Stroke::Color(black(), 1.0))
-                    )?;
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+                if bucket.free_space > 0 && bucket.item_count < self.max_items_per_bin() {
+                    return true;
                 }
 
                 bucket_index = bucket.next;
             }
         }
 
-        Ok(())
+        false
     }
-}
 
-fn convert_coordinates(flip_xy: bool, x: u16, y: u16) -> (u16, u16) {
-    if flip_xy {
-        (y, x)
+    /// Deallocate a rectangle in the atlas.
+    ///
+    /// The space of the deallocated items at the end of a bucket is reclaimed, while space
+    /// freed in the middle of a bucket is only reclaimed once the items after it are
+    /// deallocated as well.
+    ///
+    /// The id must correspond to a live allocation of the atlas. This is asserted in debug
+    /// builds, while invalid ids are ignored in release builds (see `try_deallocate`).
+    pub fn deallocate(&mut self, id: AllocId) {
+        #[cfg(not(debug_assertions))]
+        {
+            let _ = self.try_deallocate(id);
+        }
+
+        #[cfg(debug_assertions)]
+        self.deallocate_internal(id);
+    }
+
+    /// Deallocate a rectangle in the atlas, or report why the id can't be deallocated instead
+    /// of panicking.
+    pub fn try_deallocate(&mut self, id: AllocId) -> Result<(), DeallocError> {
+        self.validate_id(id)?;
+        self.deallocate_internal(id);
+
+        Ok(())
+    }
+
+    fn deallocate_internal(&mut self, id: AllocId) {
+        let id = match &mut self.stable_ids {
+            Some(ids) => ids.remove(id),
+            None => id,
+        };
+
+        if self.deallocate_from_bucket(id) {
+            self.cleanup_shelves();
+        }
+
+        self.check()
+    }
+
+    /// Deallocate a batch of rectangles.
+    ///
+    /// This is equivalent to deallocating the ids one by one, except that the empty shelves at
+    /// the top of the atlas are garbage-collected once at the end instead of after each
+    /// deallocation, which is faster when freeing many items at once, for example all of the
+    /// glyphs of a frame.
+    ///
+    /// The ids must correspond to live allocations of the atlas, see `deallocate`.
+    pub fn deallocate_many(&mut self, ids: &[AllocId]) {
+        let mut cleanup = false;
+        for &id in ids {
+            #[cfg(not(debug_assertions))]
+            {
+                if self.validate_id(id).is_err() {
+                    continue;
+                }
+            }
+
+            let id = match &mut self.stable_ids {
+                Some(ids) => ids.remove(id),
+                None => id,
+            };
+
+            // The shelves aren't removed until the end, so the last one stays the same.
+            cleanup |= self.deallocate_from_bucket(id);
+        }
+
+        if cleanup {
+            self.cleanup_shelves();
+        }
+
+        self.check();
+    }
+
+    /// Deallocate a rectangle in the atlas and return it.
+    ///
+    /// The returned rectangle is the one the allocation was created with, which is useful
+    /// to clear the region of the texture that was freed.
+    pub fn deallocate_and_get(&mut self, id: AllocId) -> Rectangle {
+        let rectangle = self.get(id);
+        self.deallocate(id);
+
+        rectangle
+    }
+
+    /// Remove all empty shelves at the top of the atlas.
+    ///
+    /// This normally happens automatically when deallocating, but can be triggered manually
+    /// after operations that skip it. This leaves the atlas in the same state as if each
+    /// deallocation had individually triggered the cleanup.
+    pub fn collect_garbage(&mut self) {
+        self.cleanup_shelves();
+
+        self.check();
+    }
+
+    /// Amount of occupied space in the atlas.
+    ///
+    /// This is the sum of the areas of the rectangles of the live allocations, which includes the
+    /// padding added by the alignment and the height of their shelves since that space is consumed.
+    pub fn allocated_space(&self) -> i32 {
+        self.allocated_space
+    }
+
+    /// Number of live allocations.
+    pub fn allocation_count(&self) -> usize {
+        self.allocation_count
+    }
+
+    /// How much space is available for future allocations.
+    ///
+    /// This is the area of the atlas minus `allocated_space()`, so it includes the space of
+    /// partially used shelves that can only hold items of a similar height, as well as the space
+    /// freed in the middle of buckets. It is an upper bound on what can actually be packed, and
+    /// says nothing about the largest rectangle that fits. See also `free_space_by_height`.
+    pub fn free_space(&self) -> i32 {
+        (self.width as i32 * self.height as i32) - self.allocated_space
+    }
+
+    /// The fraction of the area of the atlas that is allocated, between 0 and 1.
+    ///
+    /// This is `allocated_space()` divided by the area of the atlas, so the space lost to the
+    /// alignment and to rounding up the height of the shelves counts as occupied.
+    pub fn occupancy(&self) -> f32 {
+        (self.allocated_space as f32 / (self.width as i32 * self.height as i32) as f32).clamp(0.0, 1.0)
+    }
+
+    /// The fraction of the allocated space that isn't part of the requested sizes.
+    ///
+    /// This padding comes from rounding sizes up to the alignment and from placing items in
+    /// taller shelves. A high value means that a finer alignment or more uniform item sizes
+    /// would help. Returns 0 if the atlas is empty.
+    pub fn internal_fragmentation(&self) -> f32 {
+        if self.allocated_space == 0 {
+            return 0.0;
+        }
+
+        (self.allocated_space - self.requested_space) as f32 / self.allocated_space as f32
+    }
+
+    /// The highest generation among the bins of the atlas.
+    ///
+    /// Bin generations are stored in 8 bits and wrap around, after which stale ids can be
+    /// mistaken for live ones. This can be monitored to detect when it is becoming a risk.
+    pub fn max_generation(&self) -> u8 {
+        self.buckets.iter().map(|bucket| bucket.generation.0).max().unwrap_or(0)
+    }
+
+    /// Number of bins with a generation greater or equal to the provided threshold.
+    pub fn bins_near_wraparound(&self, threshold: u8) -> usize {
+        self.buckets.iter().filter(|bucket| bucket.generation.0 >= threshold).count()
+    }
+
+    /// The range of y coordinates occupied by live allocations, as `(min, max)` with `max`
+    /// exclusive.
+    ///
+    /// This is useful to find the band of the atlas that needs to be cleared. If the
+    /// `vertical_shelves` option is set, shelves are stacked horizontally and the range is
+    /// along the x axis instead. Returns `(0, 0)` if the atlas is empty.
+    pub fn content_y_extent(&self) -> (i32, i32) {
+        let mut min = u32::MAX;
+        let mut max = 0;
+        for bucket in &self.buckets {
+            if bucket.refcount == 0 {
+                continue;
+            }
+
+            let shelf = &self.shelves[bucket.shelf as usize];
+            min = min.min(shelf.y);
+            max = max.max(shelf.y + shelf.height);
+        }
+
+        if min > max {
+            return (0, 0);
+        }
+
+        (min as i32, max as i32)
+    }
+
+    /// The maximum height the shelves of the atlas have reached since it was created or
+    /// last cleared, even if the space was reclaimed since.
+    ///
+    /// This tells whether a smaller atlas would have been sufficient. If the atlas has multiple
+    /// columns, this is the maximum height reached in any column. If the `vertical_shelves`
+    /// option is set, it is measured along the x axis instead.
+    pub fn high_water_mark(&self) -> u32 {
+        self.high_water_height
+    }
+
+    /// The bounding box of all live allocations, or `None` if the atlas is empty.
+    ///
+    /// Only the deallocated items at the end of a bucket are merged into its free space, so this
+    /// is computed from the occupied part of each non-empty bucket and may be larger than the
+    /// union of the live allocations.
+    pub fn coverage(&self) -> Option<Rectangle> {
+        let mut coverage: Option<Rectangle> = None;
+        for bucket in &self.buckets {
+            if bucket.refcount == 0 {
+                continue;
+            }
+
+            let shelf = &self.shelves[bucket.shelf as usize];
+            let (min_x, min_y) = convert_coordinates(self.flip_xy, bucket.x, shelf.y);
+            let (max_x, max_y) = convert_coordinates(
+                self.flip_xy,
+                bucket.x + shelf.bucket_width - bucket.free_space,
+                shelf.y + shelf.height,
+            );
+
+            let rect = Rectangle {
+                min: point2(min_x as i32, min_y as i32),
+                max: point2(max_x as i32, max_y as i32),
+            };
+
+            coverage = Some(match coverage {
+                Some(coverage) => coverage.union(&rect),
+                None => rect,
+            });
+        }
+
+        coverage
+    }
+
+    /// The free space available to each height class of the atlas.
+    ///
+    /// Returns, for each shelf height, the total free area in the buckets of the shelves of that
+    /// height. The area where new shelves can still be added is reported under the
+    /// `ANY_HEIGHT` key. Space freed in the middle of a bucket isn't counted since it can't be
+    /// reused until the items to its right are deallocated.
+    ///
+    /// This is useful to decide whether a batch of items of mixed heights will fit, which the
+    /// total free space doesn't tell since items can't use the space of shelves of a very
+    /// different height.
+    pub fn free_space_by_height(&self) -> BTreeMap<u32, i32> {
+        let mut free_space = BTreeMap::new();
+        for shelf in &self.shelves {
+            if shelf.height == 0 {
+                continue;
+            }
+
+            let mut free_width = 0;
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+                free_width += bucket.free_space as i32;
+                bucket_index = bucket.next;
+            }
+
+            *free_space.entry(shelf.height).or_insert(0) += free_width * shelf.height as i32;
+        }
+
+        let num_free_columns = (self.num_columns - self.current_column - 1) as i32;
+        let growable_height = self.available_height as i32 + num_free_columns * self.column_height as i32;
+        free_space.insert(ANY_HEIGHT, growable_height * self.column_width as i32);
+
+        free_space
+    }
+
+    /// The largest size that `allocate` can currently succeed with, or an empty size if
+    /// nothing can be allocated.
+    ///
+    /// This accounts for the alignment and padding, the width of the columns (or of the whole
+    /// atlas for the items spanning all columns), the free space of the existing bins, the height
+    /// left for new shelves and columns, and the empty shelves that can be coalesced. Wide and tall
+    /// sizes may fit in different places, in which case the one with the largest area is returned.
+    /// Either way, allocating this size succeeds while allocating a size one unit larger in either
+    /// dimension fails, not counting the growth from `set_autogrow`.
+    pub fn max_allocatable_size(&self) -> Size {
+        let mut best = size2(0, 0);
+        if !self.has_room_for_id() {
+            return best;
+        }
+
+        let mut add_space = |w: u32, h: u32| {
+            let size = self.allocatable_size(w, h);
+            if size.area() > best.area() {
+                best = size;
+            }
+        };
+
+        // Free space at the end of the existing bins.
+        let max_items_per_bin = self.max_items_per_bin();
+        for (shelf_index, shelf) in self.shelves.iter().enumerate() {
+            let mut height = shelf.height;
+            if shelf_index < self.reserved_shelves as usize {
+                // Only the items of the reserved height class go in the reserved shelves.
+                let alignment = if self.flip_xy { self.alignment.width } else { self.alignment.height } as u32;
+                height = height.min(self.reserved_height);
+                if self.shelf_height_class(height - height % alignment) != self.reserved_height {
+                    continue;
+                }
+            }
+
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+                if bucket.item_count < max_items_per_bin {
+                    let x = bucket.x + shelf.bucket_width - bucket.free_space;
+                    add_space(self.tile_fit_width(x, bucket.free_space), height);
+                }
+
+                bucket_index = bucket.next;
+            }
+        }
+
+        // Runs of empty shelves that can be coalesced.
+        if self.tile_size.is_none() {
+            let mut shelf_index = self.reserved_shelves as usize;
+            while shelf_index < self.shelves.len() {
+                let first = &self.shelves[shelf_index];
+                shelf_index += 1;
+                if first.spanning || !self.shelf_is_empty(shelf_index - 1) {
+                    continue;
+                }
+
+                let mut height = first.height;
+                for (i, shelf) in self.shelves.iter().enumerate().skip(shelf_index) {
+                    if shelf.x != first.x || shelf.spanning || shelf.y != first.y + height || !self.shelf_is_empty(i) {
+                        break;
+                    }
+                    height += shelf.height;
+                }
+                add_space(first.bucket_width, height);
+            }
+        }
+
+        // New shelves in the current column, or at the bottom of the next one.
+        let (fitting, skipping) = self.new_shelf_heights();
+        let mut new_shelves = [(self.current_column, fitting), (self.current_column, skipping), (0, 0)];
+        // Items only go to the next column if they don't fit in the current one.
+        let height = self.fixed_shelf_height.unwrap_or(self.column_height);
+        let y = self.column_height - self.available_height;
+        let fits_current_column = self.available_height >= height.saturating_add(self.tile_filler(y, height));
+        if self.current_column + 1 < self.num_columns && !fits_current_column {
+            new_shelves[2] = (self.current_column + 1, self.column_height);
+        }
+        for (column, height) in new_shelves {
+            if height > 0 && self.can_add_shelf(height) {
+                add_space(self.tile_fit_width(column * self.column_width, self.bucket_width(1)), height);
+            }
+        }
+
+        // A new shelf spanning all columns.
+        if self.num_columns > 1
+            && self.tile_size.is_none()
+            && self.current_column == 0
+            && self.shelves.len() < self.max_shelves
+            && self.buckets.len() < self.max_bin_count()
+        {
+            add_space(self.num_columns * self.column_width, self.available_height);
+        }
+
+        best
+    }
+
+    // The largest size that can be allocated in a space of the provided size, in shelf coordinates.
+    fn allocatable_size(&self, w: u32, h: u32) -> Size {
+        // All items are as tall as the shelves with the fixed_shelf_height option.
+        let h = match self.fixed_shelf_height {
+            Some(fixed) if h >= fixed => fixed,
+            Some(_) => 0,
+            None => h,
+        };
+
+        let (width, height) = convert_coordinates(self.flip_xy, w, h);
+        let fit = |space: u32, alignment: i32| {
+            let space = space as i32;
+            (space - space % alignment - 2 * self.padding as i32).max(0)
+        };
+        let size = size2(fit(width, self.alignment.width), fit(height, self.alignment.height));
+
+        if size.is_empty() { size2(0, 0) } else { size }
+    }
+
+    // The width of the widest item that can be placed at x in a space of the provided width
+    // without crossing a tile boundary, by either fitting in the rest of the current tile or
+    // skipping to the next one.
+    fn tile_fit_width(&self, x: u32, width: u32) -> u32 {
+        let tile_width = match self.tile_size {
+            Some((tile_width, _)) => tile_width,
+            None => return width,
+        };
+
+        let remaining = (tile_width - x % tile_width) % tile_width;
+        width.saturating_sub(remaining).max(width.min(remaining))
+    }
+
+    // The heights of the tallest shelves that can be added to the current column, by fitting
+    // in the rest of the current tile and by skipping to the next tile boundary (which adds
+    // one more shelf).
+    fn new_shelf_heights(&self) -> (u32, u32) {
+        let tile_height = match self.tile_size {
+            Some((_, tile_height)) => tile_height,
+            None => return (self.available_height, 0),
+        };
+
+        let remaining = (tile_height - (self.column_height - self.available_height) % tile_height) % tile_height;
+        if remaining == 0 {
+            return (self.available_height, 0);
+        }
+
+        let (mut min, mut max) = (0, self.available_height.min(remaining));
+        while min < max {
+            let height = (min + max + 1) / 2;
+            if self.shelf_height_class(height) <= remaining {
+                min = height;
+            } else {
+                max = height - 1;
+            }
+        }
+
+        (min, self.available_height.saturating_sub(remaining))
+    }
+
+    /// Dump the live allocations as a text table, for example for snapshot tests.
+    ///
+    /// Each line contains the id (as returned by `AllocId::serialize`), position and size of an
+    /// allocation. Lines are sorted by position (see `cmp_spatial`) so that the output only
+    /// depends on the state of the atlas.
+    pub fn dump_table(&self) -> String {
+        format_allocation_table(self.iter().collect())
+    }
+
+    /// Render the atlas as text, with one character per `cell_size` area of the atlas.
+    ///
+    /// Free space is shown as `.` and allocations with a character that changes from one shelf
+    /// to the next, which is handy in test failures and terminals. For example a cell size of
+    /// 25x25 renders a 1000x1000 atlas in 40 lines of 40 characters.
+    pub fn dump_ascii(&self, cell_size: Size, output: &mut String) {
+        let mut allocations = Vec::new();
+        for bucket in self.buckets.iter().filter(|bucket| bucket.refcount > 0) {
+            let mut x = bucket.x;
+            for &item_index in &bucket.occupied {
+                let item = &bucket.items[item_index as usize];
+                x += item.padding;
+                if item.allocated {
+                    allocations.push((self.item_rectangle(bucket, x, item.width), bucket.shelf as usize));
+                }
+                x += item.width;
+            }
+        }
+
+        format_ascii(self.size(), cell_size, &allocations, output);
+    }
+
+    /// Split the whole surface of the atlas into disjoint rectangles, each flagged as
+    /// allocated (`true`) or free (`false`).
+    ///
+    /// The union of the rectangles is exactly the atlas: in addition to the items, this
+    /// contains the free space at the end of each bucket and of each shelf, as well as the
+    /// height that isn't used by shelves yet. Space freed in the middle of a bucket is
+    /// reported as free even though the allocator can't reuse it yet.
+    ///
+    /// This can be used to clear only the free parts of the atlas, or to check the consistency
+    /// of the allocator.
+    pub fn partition(&self) -> Vec<(Rectangle, bool)> {
+        let mut rects = Vec::new();
+        let mut push = |min_x: u32, min_y: u32, max_x: u32, max_y: u32, allocated: bool| {
+            if min_x == max_x || min_y == max_y {
+                return;
+            }
+            let (min_x, min_y) = convert_coordinates(self.flip_xy, min_x, min_y);
+            let (max_x, max_y) = convert_coordinates(self.flip_xy, max_x, max_y);
+            rects.push((
+                Rectangle {
+                    min: point2(min_x as i32, min_y as i32),
+                    max: point2(max_x as i32, max_y as i32),
+                },
+                allocated,
+            ));
+        };
+
+        let columns_width = self.num_columns * self.column_width;
+        let mut column_heights = vec![0; self.num_columns as usize];
+        let mut spanning_top = self.column_height;
+        let mut buckets = Vec::new();
+        for shelf in &self.shelves {
+            let min_y = shelf.y;
+            let max_y = shelf.y + shelf.height;
+
+            if shelf.spanning {
+                spanning_top = u32::max(spanning_top, max_y);
+            } else {
+                let column = &mut column_heights[(shelf.x / self.column_width) as usize];
+                *column = u32::max(*column, max_y);
+            }
+
+            buckets.clear();
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+                buckets.push(bucket);
+                bucket_index = bucket.next;
+            }
+            buckets.sort_by_key(|bucket| bucket.x);
+
+            let mut x = shelf.x;
+            for bucket in &buckets {
+                // Space that isn't covered by a bucket.
+                push(x, min_y, bucket.x, max_y, false);
+
+                x = bucket.x;
+                for &item in &bucket.occupied {
+                    let item = &bucket.items[item as usize];
+                    push(x, min_y, x + item.padding, max_y, false);
+                    x += item.padding;
+                    push(x, min_y, x + item.width, max_y, item.allocated);
+                    x += item.width;
+                }
+
+                push(x, min_y, x + bucket.free_space, max_y, false);
+                x += bucket.free_space;
+            }
+
+            let shelf_end = if shelf.spanning { columns_width } else { shelf.x + self.column_width };
+            push(x, min_y, shelf_end, max_y, false);
+        }
+
+        // The height of each column that isn't used by shelves.
+        for (column, &height) in column_heights.iter().enumerate() {
+            let x = column as u32 * self.column_width;
+            push(x, height, x + self.column_width, self.column_height, false);
+        }
+
+        // The space above the shelves spanning all columns.
+        push(0, spanning_top, columns_width, self.height, false);
+
+        // The space on the right of the last column.
+        push(columns_width, 0, self.width, self.height, false);
+
+        rects
+    }
+
+    /// Reserve storage for at least this many additional shelves and bins.
+    ///
+    /// This avoids growing the internal vectors while the atlas fills up. The reservations are
+    /// capped to the maximum number of shelves and bins that the atlas can have.
+    pub fn reserve(&mut self, shelves: usize, bins: usize) {
+        let shelves = shelves.min(self.max_shelves.saturating_sub(self.shelves.len()));
+        let bins = bins.min(self.max_bin_count().saturating_sub(self.buckets.len()));
+        self.shelves.reserve(shelves);
+        self.buckets.reserve(bins);
+    }
+
+    /// The number of shelves and bins the internal storage can hold without reallocating.
+    pub fn capacity(&self) -> (usize, usize) {
+        (self.shelves.capacity(), self.buckets.capacity())
+    }
+
+    /// Release the unused buckets at the end of the internal storage.
+    ///
+    /// The buckets of removed shelves are kept in a free list for reuse, so an atlas that was
+    /// once full keeps carrying them. This is typically useful before serializing an atlas
+    /// that has mostly been emptied, to keep the serialized form small. Live allocations keep
+    /// their ids and subsequent allocations are placed exactly as they would have been without
+    /// trimming, although the ids they get may differ.
+    pub fn trim_unused_storage(&mut self) {
+        let mut free_buckets = Vec::new();
+        let mut bucket_index = self.first_unallocated_bucket;
+        while bucket_index != BucketIndex::INVALID {
+            free_buckets.push(bucket_index);
+            bucket_index = self.buckets[bucket_index.to_usize()].next;
+        }
+
+        let mut unused = vec![false; self.buckets.len()];
+        for idx in &free_buckets {
+            unused[idx.to_usize()] = true;
+        }
+
+        let mut len = self.buckets.len();
+        while len > 0 && unused[len - 1] {
+            len -= 1;
+        }
+
+        // Rebuild the free list in the same order without the trimmed buckets.
+        self.first_unallocated_bucket = BucketIndex::INVALID;
+        for &idx in free_buckets.iter().rev() {
+            if idx.to_usize() < len {
+                let bucket = &mut self.buckets[idx.to_usize()];
+                // The items of unused buckets aren't needed anymore.
+                bucket.items = Vec::new();
+                bucket.occupied = Vec::new();
+                bucket.next = self.first_unallocated_bucket;
+                self.first_unallocated_bucket = idx;
+            }
+        }
+
+        self.buckets.truncate(len);
+        self.buckets.shrink_to_fit();
+        self.shelves.shrink_to_fit();
+
+        self.check();
+    }
+
+    // The height of the shelves that can hold items of the provided height.
+    fn shelf_height_class(&self, height: u32) -> u32 {
+        match (self.fixed_shelf_height, self.tile_size) {
+            (Some(fixed), _) => fixed,
+            // Don't round items that fit in a tile up to a shelf that doesn't.
+            (None, Some((_, tile_height))) if height <= tile_height => shelf_height(height).min(tile_height),
+            (None, _) => shelf_height(height),
+        }
+    }
+
+    fn max_bin_count(&self) -> usize {
+        (1 << self.bin_bits) - 1
+    }
+
+    fn max_items_per_bin(&self) -> u16 {
+        ((1u32 << (GEN_SHIFT - self.bin_bits)) - 1) as u16
+    }
+
+    // The item number is the item's index in the bucket plus one.
+    fn encode_id(&self, bucket_index: usize, item_number: u16, generation: u8) -> AllocId {
+        AllocId(
+            bucket_index as u32
+            | (item_number as u32) << self.bin_bits
+            | (generation as u32) << GEN_SHIFT
+        )
+    }
+
+    fn bucket_index(&self, id: AllocId) -> usize {
+        (id.0 & ((1 << self.bin_bits) - 1)) as usize
+    }
+
+    fn item_index(&self, id: AllocId) -> usize {
+        ((id.0 & ((1 << GEN_SHIFT) - 1)) >> self.bin_bits) as usize
+    }
+
+    fn has_room_for_id(&self) -> bool {
+        match &self.stable_ids {
+            Some(ids) => ids.has_room(),
+            None => true,
+        }
+    }
+
+    // Replace the internal id of a new allocation with the one handed out to the user.
+    fn export_allocation(&mut self, allocation: Allocation) -> Allocation {
+        match &mut self.stable_ids {
+            Some(ids) => Allocation {
+                id: ids.insert(allocation.id),
+                rectangle: allocation.rectangle,
+            },
+            None => allocation,
+        }
+    }
+
+    fn can_add_shelf(&self, height: u32) -> bool {
+        self.has_room_for_shelf(height)
+            && !self.shelf_limit_reached(height)
+            && self.buckets.len() < self.max_bin_count()
+    }
+
+    fn has_room_for_shelf(&self, height: u32) -> bool {
+        // The shelves spanning all columns reduce the height of the columns.
+        if height > self.column_height {
+            return false;
+        }
+
+        let filler = self.tile_filler(self.column_height - self.available_height, height);
+        self.available_height >= height.saturating_add(filler) || self.current_column + 1 < self.num_columns
+    }
+
+    fn shelf_limit_reached(&self, height: u32) -> bool {
+        // When moving to a new column, add_shelf first pushes a shelf to fill the
+        // remaining height of the current one. Otherwise it may push one to skip to
+        // the next tile boundary.
+        let tile_filler = self.tile_filler(self.column_height - self.available_height, height);
+        let fits = self.available_height >= height.saturating_add(tile_filler);
+        let filler = if fits { tile_filler > 0 } else { self.available_height != 0 };
+        let new_shelves = if filler { 2 } else { 1 };
+
+        self.shelves.len() + new_shelves > self.max_shelves
+    }
+
+    fn alloc_from_bucket(&mut self, shelf_index: usize, bucket_index: BucketIndex, width: u32, padding: u32, requested_area: i32) -> Allocation {
+        let shelf = &mut self.shelves[shelf_index];
+        let bucket = &mut self.buckets[bucket_index.to_usize()];
+
+        debug_assert!(bucket.free_space >= width + padding);
+
+        let offset = shelf.bucket_width - bucket.free_space + padding;
+        let min_x = bucket.x + offset;
+        let min_y = shelf.y;
+        let max_x = min_x + width;
+        let max_y = min_y + shelf.height;
+
+        let (min_x, min_y) = convert_coordinates(self.flip_xy, min_x, min_y);
+        let (max_x, max_y) = convert_coordinates(self.flip_xy, max_x, max_y);
+
+        bucket.free_space -= width + padding;
+        bucket.refcount += 1;
+        bucket.occupied.push(bucket.item_count);
+        bucket.items.push(BucketItem { offset, width, padding, allocated: true, requested_area });
+        bucket.item_count += 1;
+
+        let (item_count, generation) = (bucket.item_count, bucket.generation.0);
+        let id = self.encode_id(bucket_index.to_usize(), item_count, generation);
+
+        let rectangle = Rectangle {
+            min: point2(min_x as i32, min_y as i32),
+            max: point2(max_x as i32, max_y as i32),
+        };
+
+        self.allocated_space += rectangle.size().area();
+        self.allocation_count += 1;
+        self.requested_space += requested_area;
+
+        self.check();
+
+        Allocation { id, rectangle: self.remove_padding(rectangle) }
+    }
+
+    fn add_bucket(&mut self, mut bucket: Bucket) -> BucketIndex {
+        let mut bucket_index = self.first_unallocated_bucket;
+
+        if bucket_index == BucketIndex::INVALID {
+            bucket_index = BucketIndex(self.buckets.len() as u16);
+            self.buckets.push(bucket);
+        } else {
+            let idx = bucket_index.to_usize();
+            bucket.generation = self.buckets[idx].generation + Wrapping(1);
+            self.first_unallocated_bucket = self.buckets[idx].next;
+            self.buckets[idx] = bucket;
+        }
+
+        bucket_index
+    }
+
+    fn add_shelf(&mut self, width: u32, height: u32) -> usize {
+
+        let can_add_column = self.current_column + 1 < self.num_columns;
+
+        let filler = self.tile_filler(self.column_height - self.available_height, height);
+        if self.available_height != 0 && self.available_height < height.saturating_add(filler) && can_add_column {
+            // We have room to add a shelf in a new column but current one doesn't have
+            // enough available space. First add a shelf to fill the current column's
+            // remaining height.
+            self.push_shelf(0, self.available_height);
+            debug_assert_eq!(self.available_height, 0);
+        }
+
+        if self.available_height == 0 && can_add_column {
+            self.current_column += 1;
+            self.available_height = self.column_height;
+        }
+
+        let filler = self.tile_filler(self.column_height - self.available_height, height);
+        if filler > 0 && self.available_height >= height.saturating_add(filler) {
+            // Skip to the next tile boundary with an empty shelf.
+            self.push_shelf(0, filler);
+        }
+
+        let height = self.shelf_height_class(height).min(self.available_height);
+        self.push_shelf(width, height)
+    }
+
+    // Push a shelf of the provided height at the top of the current column.
+    fn push_shelf(&mut self, width: u32, height: u32) -> usize {
+        let num_buckets = self.num_buckets(width, height);
+        let bucket_width = self.bucket_width(num_buckets);
+        let y = self.column_height - self.available_height;
+        self.available_height -= height;
+
+        let shelf_index = self.shelves.len();
+
+        // Initialize the buckets for our new shelf.
+        let mut x = self.current_column * self.column_width;
+        let mut bucket_next = BucketIndex::INVALID;
+        for _ in 0..num_buckets {
+            let bucket = Bucket {
+                next: bucket_next,
+                x,
+                free_space: bucket_width,
+                refcount: 0,
+                shelf: shelf_index as u16,
+                generation: Wrapping(0),
+                item_count: 0,
+                items: Vec::new(),
+                occupied: Vec::new(),
+            };
+
+            x += bucket_width;
+
+            let bucket_index = self.add_bucket(bucket);
+
+            bucket_next = bucket_index;
+        }
+
+        self.shelves.push(Shelf {
+            x: self.current_column * self.column_width,
+            y,
+            height,
+            bucket_width,
+            spanning: false,
+            first_bucket: bucket_next,
+        });
+
+        // Shelves of width zero only fill the end of a column and don't hold content.
+        if width > 0 {
+            self.high_water_height = self.high_water_height.max(y + height);
+        }
+
+        shelf_index
+    }
+
+    /// Find a sequence of consecutive shelves that can be coalesced into a single one
+    /// tall enough to fit the provided size.
+    ///
+    /// If such a sequence is found, grow the height of first shelf and squash the other
+    /// ones to zero.
+    /// The squashed shelves are not removed, their height is just set to zero so no item
+    /// can go in, and they will be garbage-collected whenever there's no shelf above them.
+    /// Their height is moved to the first shelf so that the column's total height is unchanged,
+    /// and `cleanup_shelves` gives it back to the available height when removing that shelf.
+    /// For simplicity, the bucket width is not modified.
+    fn coalesce_shelves(&mut self, w: u32, h: u32) -> (usize, BucketIndex) {
+        if self.tile_size.is_some() {
+            // Coalesced shelves could cross tile boundaries.
+            return (0, BucketIndex::INVALID);
+        }
+
+        let len = self.shelves.len();
+        let mut coalesce_range = None;
+        let mut coalesced_height = 0;
+
+        'outer: for shelf_index in self.reserved_shelves as usize..len {
+            if self.shelves[shelf_index].bucket_width < w || self.shelves[shelf_index].spanning {
+                continue;
+            }
+            if !self.shelf_is_empty(shelf_index) {
+                continue;
+            }
+            let shelf_x = self.shelves[shelf_index].x;
+            coalesced_height = self.shelves[shelf_index].height;
+            for i in 1.. {
+                if shelf_index + i >= len {
+                    break 'outer;
+                }
+
+                let prev = &self.shelves[shelf_index + i - 1];
+                let shelf = &self.shelves[shelf_index + i];
+
+                if shelf.x != shelf_x || shelf.spanning {
+                    // Can't coalesce shelves from different columns.
+                    continue 'outer;
+                }
+
+                if shelf.y != prev.y + prev.height {
+                    // Only coalesce shelves that are vertically contiguous.
+                    continue 'outer;
+                }
+
+                if !self.shelf_is_empty(shelf_index + i) {
+                    continue 'outer;
+                }
+
+                coalesced_height += shelf.height;
+
+                if coalesced_height >= h {
+                    coalesce_range = Some(shelf_index .. (shelf_index + i + 1));
+                    break 'outer;
+                }
+            }
+        }
+
+        if let Some(range) = coalesce_range {
+            let y_top = self.shelves[range.start].y + coalesced_height;
+            for i in range.start + 1 .. range.end {
+                self.shelves[i].y = y_top;
+                self.shelves[i].height = 0;
+            }
+
+            let shelf_index = range.start;
+            let shelf = &mut self.shelves[shelf_index];
+            shelf.height = coalesced_height;
+
+            return (shelf_index, shelf.first_bucket);
+        }
+
+        (0, BucketIndex::INVALID)
+    }
+
+    // The width of the buckets of a new shelf split into the provided number of buckets.
+    fn bucket_width(&self, num_buckets: u32) -> u32 {
+        let shelf_alignment = if self.flip_xy { self.alignment.height } else { self.alignment.width } as u32;
+        let mut bucket_width = self.column_width / num_buckets;
+        bucket_width -= bucket_width % shelf_alignment;
+        if let Some((tile_width, _)) = self.tile_size {
+            if bucket_width >= tile_width {
+                bucket_width -= bucket_width % tile_width;
+            }
+        }
+
+        bucket_width
+    }
+
+    fn num_buckets(&self, width: u32, height: u32) -> u32 {
+        match self.column_width / u32::max(width, height) {
+            0 ..= 4 => 1,
+            5 ..= 16 => 2,
+            17 ..= 32 => 4,
+            n => (n /16 - 1).next_power_of_two(),
+        }.min((self.max_bin_count() - self.buckets.len()) as u32)
+    }
+
+    /// Returns true if we should garbage-collect the shelves as a result of
+    /// removing this element (we deallocated the last item from the bucket on
+    /// the top-most shelf).
+    fn deallocate_from_bucket(&mut self, id: AllocId) -> bool {
+        let bucket_index = self.bucket_index(id);
+        let item_index = self.item_index(id).wrapping_sub(1);
+        let generation = generation_of(id);
+
+        let bucket = &mut self.buckets[bucket_index];
+
+        let expected_generation = bucket.generation.0;
+        assert_eq!(generation, expected_generation);
+
+        let item = bucket.items.get_mut(item_index).expect("Invalid AllocId");
+        assert!(item.allocated, "Invalid AllocId");
+        item.allocated = false;
+
+        assert!(bucket.refcount > 0);
+        bucket.refcount -= 1;
+
+        let shelf = &self.shelves[bucket.shelf as usize];
+        self.allocated_space -= item.width as i32 * shelf.height as i32;
+        self.allocation_count -= 1;
+        self.requested_space -= item.requested_area;
+
+        // Give the space of the right-most deallocated items back to the bucket.
+        while let Some(&last) = bucket.occupied.last() {
+            let item = &bucket.items[last as usize];
+            if item.allocated {
+                break;
+            }
+
+            bucket.free_space += item.width + item.padding;
+            bucket.occupied.pop();
+        }
+
+        let bucket_is_empty = bucket.refcount == 0;
+        debug_assert!(!bucket_is_empty || bucket.free_space == shelf.bucket_width);
+
+        bucket_is_empty && bucket.shelf as usize == self.shelves.len() - 1
+    }
+
+    fn cleanup_shelves(&mut self) {
+        while self.shelves.len() > self.reserved_shelves as usize {
+            {
+                let shelf = self.shelves.last().unwrap();
+                let mut bucket_index = shelf.first_bucket;
+                let mut last_bucket = shelf.first_bucket;
+
+                while bucket_index != BucketIndex::INVALID {
+                    let bucket = &self.buckets[bucket_index.to_usize()];
+
+                    if bucket.refcount != 0 {
+                        return;
+                    }
+
+                    last_bucket = bucket_index;
+                    bucket_index = bucket.next;
+                }
+
+                // We didn't run into any bucket on this shelf with live elements,
+                // this means we can remove it.
+
+                // Can't have a shelf with no buckets.
+                debug_assert!(last_bucket != BucketIndex::INVALID);
+                // Add the buckets to the free list.
+                self.buckets[last_bucket.to_usize()].next = self.first_unallocated_bucket;
+                self.first_unallocated_bucket = shelf.first_bucket;
+
+                if shelf.spanning {
+                    // Spanning shelves are only added while the first column is in use and
+                    // removed in the reverse order.
+                    debug_assert_eq!(self.current_column, 0);
+                    self.column_height += shelf.height;
+                    self.available_height += shelf.height;
+                } else if shelf.y == 0 && self.current_column > 0 {
+                    self.current_column -= 1;
+                    let prev_shelf = &self.shelves[self.shelves.len() - 2];
+                    self.available_height = if prev_shelf.spanning {
+                        // The previous column was filled by the spanning shelf.
+                        0
+                    } else {
+                        self.column_height - (prev_shelf.y + prev_shelf.height)
+                    };
+                } else {
+                    // Reclaim the height of the shelf.
+                    self.available_height += shelf.height;
+                }
+            }
+
+            self.shelves.pop();
+        }
+    }
+
+    fn shelf_is_empty(&self, idx: usize) -> bool {
+        let shelf = &self.shelves[idx];
+        let mut bucket_index = shelf.first_bucket;
+
+        while bucket_index != BucketIndex::INVALID {
+            let bucket = &self.buckets[bucket_index.to_usize()];
+
+            if bucket.refcount != 0 {
+                return false;
+            }
+
+            bucket_index = bucket.next;
+        }
+
+        true
+    }
+
+
+    /// Dump a visual representation of the atlas in SVG format.
+    #[cfg(feature = "std")]
+    pub fn dump_svg(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        use svg_fmt::*;
+
+        writeln!(
+            output,
+            "{}",
+            BeginSvg {
+                w: self.width as f32,
+                h: self.height as f32
+            }
+        )?;
+
+        self.dump_into_svg(None, output)?;
+
+        writeln!(output, "{}", EndSvg)
+    }
+
+
+    #[cfg(not(feature = "checks"))]
+    fn check(&self) {}
+
+    #[cfg(feature = "checks")]
+    fn check(&self) {
+        // The shelves of each column are stacked from the bottom without gaps (squashed shelves
+        // have a height of zero). The available height of the current column starts at the top
+        // of its shelves, while the previous columns can end below the column height if the atlas
+        // was grown after they were filled.
+        let mut spanning_height = 0;
+        let mut column_tops = vec![0; self.num_columns as usize];
+        for shelf in &self.shelves {
+            if shelf.spanning {
+                spanning_height += shelf.height;
+                continue;
+            }
+
+            let column = (shelf.x / self.column_width.max(1)) as usize;
+            assert!(column <= self.current_column as usize);
+            assert_eq!(shelf.y, column_tops[column]);
+            column_tops[column] += shelf.height;
+        }
+
+        assert!(spanning_height <= self.height - self.column_height);
+        for &top in &column_tops[..self.current_column as usize] {
+            assert!(top <= self.column_height);
+        }
+        assert_eq!(column_tops[self.current_column as usize] + self.available_height, self.column_height);
+
+        let mut allocated_space = 0;
+        let mut allocation_count = 0;
+        let mut requested_space = 0;
+        for shelf in &self.shelves {
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+
+                let mut occupied_width = 0;
+                for &item in &bucket.occupied {
+                    let item = &bucket.items[item as usize];
+                    assert_eq!(item.offset, occupied_width + item.padding);
+                    occupied_width += item.width + item.padding;
+                }
+                assert_eq!(occupied_width + bucket.free_space, shelf.bucket_width);
+
+                for item in &bucket.items {
+                    if item.allocated {
+                        allocated_space += item.width as i32 * shelf.height as i32;
+                        allocation_count += 1;
+                        requested_space += item.requested_area;
+                    }
+                }
+
+                bucket_index = bucket.next;
+            }
+        }
+        assert_eq!(allocated_space, self.allocated_space);
+        assert_eq!(allocation_count, self.allocation_count);
+        assert_eq!(requested_space, self.requested_space);
+
+        assert_eq!(self.is_empty(), self.allocated_space() == 0)
+    }
+
+    /// Dump a visual representation of the atlas as a PNG image, at one pixel per unit.
+    ///
+    /// Uses the same colors as the SVG output.
+    #[cfg(feature = "png")]
+    pub fn dump_png(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        crate::png::dump_partition(self.size(), &self.partition(), output)
+    }
+
+    /// Dump a visual representation of the atlas in SVG, omitting the beginning and end of the
+    /// SVG document, so that it can be included in a larger document.
+    ///
+    /// If a rectangle is provided, translate and scale the output to fit it.
+    #[cfg(feature = "std")]
+    pub fn dump_into_svg(&self, rect: Option<&Rectangle>, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        self.dump_into_svg_with_options(rect, &SvgOptions::default(), output)
+    }
+
+    /// Same as `dump_into_svg` with control over the colors and labels of the output.
+    ///
+    /// The allocated part of each bucket is drawn as a single rectangle, while labels are
+    /// placed at the center of each allocation.
+    #[cfg(feature = "std")]
+    pub fn dump_into_svg_with_options(
+        &self,
+        rect: Option<&Rectangle>,
+        options: &SvgOptions,
+        output: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        use svg_fmt::*;
+
+        let (sx, sy, tx, ty) = if let Some(rect) = rect {
+            (
+                rect.size().width as f32 / self.width as f32,
+                rect.size().height as f32 / self.height as f32,
+                rect.min.x as f32,
+                rect.min.y as f32,
+            )
+        } else {
+            (1.0, 1.0, 0.0, 0.0)
+        };
+
+        writeln!(
+            output,
+            r#"    {}"#,
+            rectangle(tx, ty, self.width as f32 * sx, self.height as f32 * sy)
+                .fill(options.background_color)
+                .stroke(Stroke::Color(black(), options.stroke_width))
+        )?;
+
+
+        for shelf in &self.shelves {
+            let mut bucket_index = shelf.first_bucket;
+
+            let y = shelf.y as f32 * sy;
+            let h = shelf.height as f32 * sy;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+
+                let x = bucket.x as f32 * sx;
+                let w = (shelf.bucket_width - bucket.free_space) as f32 * sx;
+
+                {
+                    let (x, y) = if self.flip_xy { (y, x) } else { (x, y) };
+                    let (w, h) = if self.flip_xy { (h, w) } else { (w, h) };
+
+                    writeln!(
+                        output,
+                        r#"    {}"#,
+                        rectangle(x + tx, y + ty, w, h)
+                            .fill(options.allocated_color)
+                            .stroke(Stroke::Color(black(), options.stroke_width))
+                    )?;
+                }
+
+                if bucket.free_space > 0 {
+                    let x_free = x + w;
+                    let w_free = bucket.free_space as f32 * sx;
+
+                    let (x_free, y) = if self.flip_xy { (y, x_free) } else { (x_free, y) };
+                    let (w_free, h) = if self.flip_xy { (h, w_free) } else { (w_free, h) };
+
+                    writeln!(
+                        output,
+                        r#"    {}"#,
+                        rectangle(x_free + tx, y + ty, w_free, h)
+                            .fill(options.free_color)
+                            .stroke(Stroke::Color(black(), options.stroke_width))
+                    )?;
+                }
+
+                bucket_index = bucket.next;
+            }
+        }
+
+        if options.label.is_some() {
+            for alloc in self.iter() {
+                let r = &alloc.rectangle;
+                let rect = (
+                    r.min.x as f32 * sx + tx,
+                    r.min.y as f32 * sy + ty,
+                    r.width() as f32 * sx,
+                    r.height() as f32 * sy,
+                );
+                write_svg_label(options, alloc.id, rect, output)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints the size and configuration of the atlas followed by a summary of the first shelves,
+/// with the fill ratio of each bin (see `dump_svg` for a complete representation).
+impl core::fmt::Debug for BucketedAtlasAllocator {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        const MAX_BINS: usize = 16;
+
+        let size = self.size();
+        writeln!(
+            f,
+            "BucketedAtlasAllocator {{ size: {}x{}, columns: {} x {}, vertical_shelves: {}, shelves: {}, allocations: {}, occupancy: {:.1}% }}",
+            size.width,
+            size.height,
+            self.num_columns,
+            self.column_width,
+            self.flip_xy,
+            self.shelves.len(),
+            self.allocation_count,
+            self.allocated_space as f32 * 100.0 / size.area() as f32,
+        )?;
+
+        for shelf in self.shelves.iter().take(DEBUG_MAX_SHELVES) {
+            let mut bins = Vec::new();
+            let mut bucket_index = shelf.first_bucket;
+            while bucket_index != BucketIndex::INVALID {
+                let bucket = &self.buckets[bucket_index.to_usize()];
+                bins.push((bucket.x, (shelf.bucket_width - bucket.free_space) * 100 / shelf.bucket_width.max(1)));
+                bucket_index = bucket.next;
+            }
+            bins.sort_unstable();
+
+            write!(f, "  shelf x={} y={} h={}:", shelf.x, shelf.y, shelf.height)?;
+            for &(_, fill) in bins.iter().take(MAX_BINS) {
+                write!(f, " {}%", fill)?;
+            }
+            if bins.len() > MAX_BINS {
+                write!(f, " ... ({} bins)", bins.len())?;
+            }
+            writeln!(f)?;
+        }
+
+        if self.shelves.len() > DEBUG_MAX_SHELVES {
+            writeln!(f, "  ... {} more shelves", self.shelves.len() - DEBUG_MAX_SHELVES)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn assert_valid_size(size: Size) {
+    assert!(size.width < MAX_SIZE);
+    assert!(size.height < MAX_SIZE);
+    assert!(
+        size.width.checked_mul(size.height).is_some(),
+        "The area of the atlas must fit in a i32 value"
+    );
+}
+
+fn convert_coordinates(flip_xy: bool, x: u32, y: u32) -> (u32, u32) {
+    if flip_xy {
+        (y, x)
     } else {
         (x, y)
     }
 }
 
 
-fn shelf_height(mut size: u16) -> u16 {
-    let alignment = match size {
-        0 ..= 31 => 8,
-        32 ..= 127 => 16,
-        128 ..= 511 => 32,
-        _ => 64,
-    };
+fn generation_of(id: AllocId) -> u8 {
+    (id.0 >> GEN_SHIFT) as u8
+}
+
+fn shelf_height(mut size: u32) -> u32 {
+    let alignment = match size {
+        0 ..= 31 => 8,
+        32 ..= 127 => 16,
+        128 ..= 511 => 32,
+        _ => 64,
+    };
+
+    let rem = size % alignment;
+    if rem > 0 {
+        size += alignment - rem;
+    }
+
+    size
+}
+
+fn adjust_size(alignment: i32, size: &mut i32) {
+    let rem = *size % alignment;
+    if rem > 0 {
+        *size += alignment - rem;
+    }
+}
+
+#[test]
+fn atlas_basic() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+
+    let full = atlas.allocate(size2(1000, 1000)).unwrap().id;
+    assert!(atlas.allocate(size2(1, 1)).is_none());
+
+    atlas.deallocate(full);
+    let a = atlas.allocate(size2(10, 10)).unwrap().id;
+    let b = atlas.allocate(size2(50, 30)).unwrap().id;
+    let c = atlas.allocate(size2(12, 45)).unwrap().id;
+    let d = atlas.allocate(size2(60, 45)).unwrap().id;
+    let e = atlas.allocate(size2(1, 1)).unwrap().id;
+    let f = atlas.allocate(size2(128, 128)).unwrap().id;
+    let g = atlas.allocate(size2(256, 256)).unwrap().id;
+
+    atlas.deallocate(b);
+    atlas.deallocate(f);
+    atlas.deallocate(c);
+    atlas.deallocate(e);
+    let h = atlas.allocate(size2(500, 200)).unwrap().id;
+    atlas.deallocate(a);
+    let i = atlas.allocate(size2(500, 200)).unwrap().id;
+    atlas.deallocate(g);
+    atlas.deallocate(h);
+    atlas.deallocate(d);
+    atlas.deallocate(i);
+
+    let full = atlas.allocate(size2(1000, 1000)).unwrap().id;
+    assert!(atlas.allocate(size2(1, 1)).is_none());
+    atlas.deallocate(full);
+}
+
+#[test]
+#[allow(clippy::needless_range_loop)]
+fn test_coalesce_shelves() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    // Allocate 7 shelves (leaving 32px of remaining space on top).
+    let mut ids = Vec::new();
+    for _ in 0..7 {
+        for _ in 0..8 {
+            ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
+        }
+    }
+
+    // Free the first shelf.
+    for i in 0..8 {
+        atlas.deallocate(ids[i]);
+    }
+
+    // Free the 3rd and 4th shelf.
+    for i in 16..32 {
+        atlas.deallocate(ids[i]);
+    }
+
+    // Not enough space left in existing shelves and above.
+    // even coalescing is not sufficient.
+    assert!(atlas.allocate(size2(70, 70)).is_none());
+
+    // Not enough space left in existing shelves and above.
+    // The 3rd and 4th row can be coalesced to fit this allocation, though.
+    let id = atlas.allocate(size2(64, 64)).unwrap().id;
+
+    // Deallocate everything
+    for i in 8..16 {
+        atlas.deallocate(ids[i]);
+    }
+
+    atlas.deallocate(id);
+
+    for i in 32..56 {
+        atlas.deallocate(ids[i]);
+    }
+
+    //dump_svg(&atlas, &mut std::fs::File::create("tmp.svg").expect("!!"));
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+
+    // Fill the atlas with 8 shelves and free 5 adjacent ones.
+    let mut ids = Vec::new();
+    for _ in 0..64 {
+        ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
+    }
+    for id in &ids[8..48] {
+        atlas.deallocate(*id);
+    }
+
+    // Taller than any 3 of the free shelves.
+    let id = atlas.allocate(size2(32, 150)).unwrap().id;
+
+    atlas.deallocate(id);
+    for id in ids[0..8].iter().chain(&ids[48..64]) {
+        atlas.deallocate(*id);
+    }
+    assert!(atlas.is_empty());
+}
+
+#[test]
+#[allow(clippy::needless_range_loop)]
+fn grow_vertically() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    // Allocate 7 shelves (leaving 32px of remaining space on top).
+    let mut ids = Vec::new();
+    for _ in 0..7 {
+        for _ in 0..8 {
+            ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
+        }
+    }
+
+    // Free the first shelf.
+    for i in 0..8 {
+        atlas.deallocate(ids[i]);
+    }
+
+    // Free the 3rd and 4th shelf.
+    for i in 16..32 {
+        atlas.deallocate(ids[i]);
+    }
+
+    // Not enough space left in existing shelves and above.
+    // even coalescing is not sufficient.
+    assert!(atlas.allocate(size2(70, 70)).is_none());
+
+    // Grow just enough vertically to fit the previous region
+    atlas.grow(size2(256, 256 + 70 - 32));
+
+    // Allocation should succeed now
+    assert!(atlas.allocate(size2(70, 70)).is_some());
+}
+
+#[test]
+#[allow(clippy::needless_range_loop)]
+fn grow_horizontally() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    // Allocate 7 shelves (leaving 32px of remaining space on top).
+    let mut ids = Vec::new();
+    for _ in 0..7 {
+        for _ in 0..8 {
+            ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
+        }
+    }
+
+    // Free the first shelf.
+    for i in 0..8 {
+        atlas.deallocate(ids[i]);
+    }
+
+    // Free the 3rd and 4th shelf.
+    for i in 16..32 {
+        atlas.deallocate(ids[i]);
+    }
+
+    // Not enough space left in existing shelves and above.
+    // even coalescing is not sufficient.
+    assert!(atlas.allocate(size2(512, 32)).is_none());
+
+    // Grow just enough horizontally to add more buckets
+    assert!(atlas.grow(size2(256 * 2, 256)).is_empty());
+
+    // Allocation should succeed now
+    assert!(atlas.allocate(size2(512, 32)).is_some());
+}
+
+#[test]
+fn grow_to_fit_allocation() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(32, 32));
+
+    // Allocate a shelve to make sure we have a non-empty atlas to test the update.
+    atlas.allocate(size2(32, 32)).unwrap();
+
+    // Try to make a big allocation that doesn't fit.
+    let big_allocation = size2(256, 256);
+
+    assert!(atlas.allocate(big_allocation).is_none());
+
+    // Grow to make enough space for the wanted allocation plus the original shelf.
+    atlas.grow(size2(256, 32 + 256));
+
+    // Adding to the original shelf should succeed.
+    assert!(atlas.allocate(size2(32, 32)).is_some());
+
+    // Big allocation should also succeed now.
+    assert!(atlas.allocate(big_allocation).is_some());
+}
+
+#[test]
+fn columns() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(64, 64), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(24, 46)).unwrap();
+    let b = atlas.allocate(size2(24, 32)).unwrap();
+    let c = atlas.allocate(size2(24, 32)).unwrap();
+
+    fn in_range(val: i32, range: core::ops::Range<i32>) -> bool {
+        let ok = val >= range.start && val < range.end;
+
+        if !ok {
+            println!("{:?} not in {:?}", val, range);
+        }
+
+        ok
+    }
+
+    assert!(in_range(a.rectangle.min.x, 0..32));
+    assert!(in_range(a.rectangle.max.x, 0..32));
+    assert!(in_range(b.rectangle.min.x, 32..64));
+    assert!(in_range(b.rectangle.max.x, 32..64));
+    assert!(in_range(c.rectangle.min.x, 32..64));
+    assert!(in_range(c.rectangle.max.x, 32..64));
+
+    atlas.deallocate(b.id);
+    atlas.deallocate(c.id);
+    atlas.deallocate(a.id);
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+
+    let a = atlas.allocate(size2(24, 46)).unwrap();
+    let b = atlas.allocate(size2(24, 32)).unwrap();
+    let c = atlas.allocate(size2(24, 32)).unwrap();
+    let d = atlas.allocate(size2(24, 8)).unwrap();
+
+    assert_eq!(a.rectangle.min.x, 0);
+    assert_eq!(b.rectangle.min.x, 32);
+    assert_eq!(c.rectangle.min.x, 32);
+    assert_eq!(d.rectangle.min.x, 0);
+}
+
+#[test]
+fn vertical() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(128, 256), &AllocatorOptions {
+        num_columns: 2,
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    assert_eq!(atlas.size(), size2(128, 256));
+
+    let a = atlas.allocate(size2(32, 16)).unwrap();
+    let b = atlas.allocate(size2(16, 32)).unwrap();
+
+    assert!(a.rectangle.size().width >= 32);
+    assert!(a.rectangle.size().height >= 16);
+
+    assert!(b.rectangle.size().width >= 16);
+    assert!(b.rectangle.size().height >= 32);
+
+    let c = atlas.allocate(size2(128, 128)).unwrap();
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+    atlas.deallocate(c.id);
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+}
+
+#[test]
+fn clear() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(2048, 2048));
+
+    // Run a workload a few hundred times to make sure clearing properly resets everything.
+    for _ in 0..500 {
+        atlas.clear();
+        assert_eq!(atlas.allocated_space(), 0);
+
+        atlas.allocate(size2(8, 2)).unwrap();
+        atlas.allocate(size2(2, 8)).unwrap();
+        atlas.allocate(size2(16, 512)).unwrap();
+        atlas.allocate(size2(34, 34)).unwrap();
+        atlas.allocate(size2(34, 34)).unwrap();
+        atlas.allocate(size2(34, 34)).unwrap();
+        atlas.allocate(size2(34, 34)).unwrap();
+        atlas.allocate(size2(2, 8)).unwrap();
+        atlas.allocate(size2(2, 8)).unwrap();
+        atlas.allocate(size2(8, 2)).unwrap();
+        atlas.allocate(size2(2, 8)).unwrap();
+        atlas.allocate(size2(8, 2)).unwrap();
+        atlas.allocate(size2(8, 8)).unwrap();
+        atlas.allocate(size2(8, 8)).unwrap();
+        atlas.allocate(size2(8, 8)).unwrap();
+        atlas.allocate(size2(8, 8)).unwrap();
+        atlas.allocate(size2(82, 80)).unwrap();
+        atlas.allocate(size2(56, 56)).unwrap();
+        atlas.allocate(size2(64, 66)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(40, 40)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(155, 52)).unwrap();
+        atlas.allocate(size2(256, 52)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(24, 24)).unwrap();
+        atlas.allocate(size2(64, 64)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(84, 84)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(8, 2)).unwrap();
+        atlas.allocate(size2(34, 34)).unwrap();
+        atlas.allocate(size2(34, 34)).unwrap();
+        atlas.allocate(size2(192, 192)).unwrap();
+        atlas.allocate(size2(192, 192)).unwrap();
+        atlas.allocate(size2(52, 52)).unwrap();
+        atlas.allocate(size2(144, 144)).unwrap();
+        atlas.allocate(size2(192, 192)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(144, 144)).unwrap();
+        atlas.allocate(size2(24, 24)).unwrap();
+        atlas.allocate(size2(192, 192)).unwrap();
+        atlas.allocate(size2(192, 192)).unwrap();
+        atlas.allocate(size2(432, 243)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+        atlas.allocate(size2(8, 2)).unwrap();
+        atlas.allocate(size2(2, 8)).unwrap();
+        atlas.allocate(size2(9, 9)).unwrap();
+        atlas.allocate(size2(14, 14)).unwrap();
+        atlas.allocate(size2(14, 14)).unwrap();
+        atlas.allocate(size2(14, 14)).unwrap();
+        atlas.allocate(size2(14, 14)).unwrap();
+        atlas.allocate(size2(8, 8)).unwrap();
+        atlas.allocate(size2(27, 27)).unwrap();
+        atlas.allocate(size2(27, 27)).unwrap();
+        atlas.allocate(size2(27, 27)).unwrap();
+        atlas.allocate(size2(27, 27)).unwrap();
+        atlas.allocate(size2(11, 12)).unwrap();
+        atlas.allocate(size2(29, 28)).unwrap();
+        atlas.allocate(size2(32, 32)).unwrap();
+    }
+}
+
+
+#[test]
+fn fuzz_01() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+
+    assert!(atlas.allocate(size2(65280, 1)).is_none());
+    assert!(atlas.allocate(size2(1, 65280)).is_none());
+}
+
+#[test]
+fn fuzz_02() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+
+    assert!(atlas.allocate(size2(255, 65599)).is_none());
+}
+
+#[test]
+fn fuzz_03() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+
+    let sizes = &[
+        size2(999, 128),
+        size2(168492810, 10),
+        size2(45, 96),
+        size2(-16711926, 0),
+    ];
+
+    let mut allocations = Vec::new();
+    let mut allocated_space = 0;
+
+    for size in sizes {
+        if let Some(alloc) = atlas.allocate(*size) {
+            allocations.push(alloc);
+            allocated_space += alloc.rectangle.area();
+            assert_eq!(allocated_space, atlas.allocated_space());
+        }
+    }
+
+    for alloc in &allocations {
+        atlas.deallocate(alloc.id);
+
+        allocated_space -= alloc.rectangle.area();
+        assert_eq!(allocated_space, atlas.allocated_space());
+    }
+
+    assert_eq!(atlas.allocated_space(), 0);
+}
+
+#[test]
+fn fuzz_04() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+
+    assert!(atlas.allocate(size2(2560, 2147483647)).is_none());
+}
+
+#[test]
+fn fuzz_05() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(2048, 2048));
+
+    assert!(atlas.allocate(size2(0, -1978597547)).is_none());
+}
+
+#[test]
+fn bin_item_limit() {
+    // A single bucket per shelf and a single shelf in the atlas.
+    let mut atlas = BucketedAtlasAllocator::new(size2(4096, 1024));
+
+    for _ in 0..atlas.max_items_per_bin() {
+        atlas.allocate(size2(1, 1024)).unwrap();
+    }
+
+    assert!(!atlas.bin_has_room());
+    assert_eq!(atlas.try_allocate(size2(1, 1024)), Err(AllocError::BinItemLimit));
+
+    let mut atlas = BucketedAtlasAllocator::new(size2(1024, 1024));
+    atlas.allocate(size2(1024, 1024)).unwrap();
+
+    assert!(!atlas.bin_has_room());
+    assert_eq!(atlas.try_allocate(size2(1, 1)), Err(AllocError::OutOfSpace));
+}
+
+#[test]
+fn id_layouts() {
+    use crate::IdLayout;
+
+    let options = AllocatorOptions { id_layout: IdLayout::ManyItems, ..DEFAULT_OPTIONS };
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(4096, 1024), &options);
+    assert_eq!(atlas.max_items_per_bin(), 16383);
+    assert_eq!(atlas.max_bin_count(), 1023);
+
+    // More than the 4095 items per bin of the default layout.
+    let mut ids = Vec::new();
+    for _ in 0..4096 {
+        ids.push(atlas.allocate(size2(1, 1024)).unwrap().id);
+    }
+    assert_eq!(atlas.buckets.len(), 1);
+    for id in ids {
+        atlas.deallocate(id);
+    }
+    assert!(atlas.is_empty());
+
+    let options = AllocatorOptions { id_layout: IdLayout::ManyBins, ..DEFAULT_OPTIONS };
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(1024, 1024), &options);
+    assert_eq!(atlas.max_items_per_bin(), 1023);
+    assert_eq!(atlas.max_bin_count(), 16383);
+
+    let mut ids = Vec::new();
+    for _ in 0..1023 {
+        ids.push(atlas.allocate(size2(1, 1024)).unwrap().id);
+    }
+    assert_eq!(atlas.try_allocate(size2(1, 1024)), Err(AllocError::BinItemLimit));
+    for id in ids {
+        atlas.deallocate(id);
+    }
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn reserved_height_band() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    atlas.reserve_height_band(16, 2).unwrap();
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.reserve_height_band(16, 2), Err(ReserveError::NonEmpty));
+
+    // Items of the reserved height go into the band at the bottom of the atlas.
+    let a = atlas.allocate(size2(10, 16)).unwrap();
+    let b = atlas.allocate(size2(20, 14)).unwrap();
+    assert!(a.rectangle.max.y <= 32);
+    assert!(b.rectangle.max.y <= 32);
+
+    // Other heights skip it.
+    let c = atlas.allocate(size2(10, 8)).unwrap();
+    let d = atlas.allocate(size2(10, 40)).unwrap();
+    assert!(c.rectangle.min.y >= 32);
+    assert!(d.rectangle.min.y >= 32);
+
+    for id in &[a.id, b.id, c.id, d.id] {
+        atlas.deallocate(*id);
+    }
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+
+    // The band is still here.
+    let a = atlas.allocate(size2(10, 16)).unwrap();
+    assert!(a.rectangle.max.y <= 32);
+    let c = atlas.allocate(size2(10, 8)).unwrap();
+    assert!(c.rectangle.min.y >= 32);
+
+    atlas.clear();
+    assert!(atlas.reserve_height_band(16, 2).is_ok());
+
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        fixed_shelf_height: Some(32),
+        ..DEFAULT_OPTIONS
+    });
+    assert_eq!(atlas.reserve_height_band(0, 2), Err(ReserveError::InvalidHeight));
+    assert_eq!(atlas.reserve_height_band(300, 2), Err(ReserveError::InvalidHeight));
+    assert_eq!(atlas.reserve_height_band(40, 2), Err(ReserveError::InvalidHeight));
+    assert!(atlas.reserve_height_band(20, 2).is_ok());
+}
+
+#[test]
+fn grow_keeps_coordinates() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(100, 100)).unwrap();
+    let b = atlas.allocate(size2(100, 100)).unwrap();
+
+    // New space is appended past the existing content so nothing has to move.
+    assert!(atlas.grow(size2(512, 512)).is_empty());
+
+    let c = atlas.allocate(size2(200, 200)).unwrap();
+    assert!(!c.rectangle.intersects(&a.rectangle));
+    assert!(!c.rectangle.intersects(&b.rectangle));
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+    atlas.deallocate(c.id);
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn grow_with_spanning_shelves() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(200, 64)).unwrap();
+    assert!(atlas.grow(size2(256, 512)).is_empty());
+    assert_eq!(atlas.get(a.id), a.rectangle);
+
+    // The spanning shelf stays at the top of the columns, so they don't get taller.
+    assert!(atlas.allocate(size2(100, 193)).is_none());
+    let b = atlas.allocate(size2(100, 192)).unwrap();
+    assert!(!b.rectangle.intersects(&a.rectangle));
+
+    // Growing the width adds columns.
+    assert!(atlas.grow(size2(512, 512)).is_empty());
+    let c = atlas.allocate(size2(100, 192)).unwrap();
+    let d = atlas.allocate(size2(100, 192)).unwrap();
+    for (i, x) in [&a, &b, &c, &d].iter().enumerate() {
+        for y in [&a, &b, &c, &d].iter().skip(i + 1) {
+            assert!(!x.rectangle.intersects(&y.rectangle));
+        }
+    }
+}
+
+#[test]
+fn generation_report() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.max_generation(), 0);
+    assert_eq!(atlas.bins_near_wraparound(1), 0);
+
+    for _ in 0..10 {
+        let a = atlas.allocate(size2(32, 32)).unwrap();
+        atlas.deallocate(a.id);
+    }
+
+    assert!(atlas.max_generation() >= 9);
+    assert!(atlas.bins_near_wraparound(9) >= 1);
+    assert_eq!(atlas.bins_near_wraparound(255), 0);
+}
+
+#[test]
+fn allocate_descending() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1024, 1024));
+
+    let mut sizes = Vec::new();
+    for i in 0..10 {
+        sizes.push(size2(512 >> i, 512 >> i));
+        sizes.push(size2(512 >> i, 512 >> i));
+    }
+
+    let allocations = atlas.allocate_descending(&sizes);
+    assert_eq!(allocations.len(), sizes.len());
+
+    for (i, a) in allocations.iter().enumerate() {
+        assert!(a.rectangle.size().width >= sizes[i].width);
+        assert!(a.rectangle.size().height >= sizes[i].height);
+        for b in &allocations[i + 1..] {
+            assert!(!a.rectangle.intersects(&b.rectangle));
+        }
+    }
+
+    for a in &allocations {
+        atlas.deallocate(a.id);
+    }
+    assert!(atlas.is_empty());
+
+    // Stops at the first allocation that does not fit.
+    let allocations = atlas.allocate_descending(&[size2(1024, 1024), size2(1, 1)]);
+    assert_eq!(allocations.len(), 1);
+}
+
+#[test]
+fn max_shelves() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        max_shelves: Some(2),
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.try_allocate(size2(10, 10)).unwrap();
+    let b = atlas.try_allocate(size2(10, 50)).unwrap();
+    assert_eq!(atlas.try_allocate(size2(10, 100)), Err(AllocError::CapacityExhausted));
+
+    // Existing shelves can still be used.
+    atlas.try_allocate(size2(10, 10)).unwrap();
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+}
+
+#[test]
+fn content_y_extent() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.content_y_extent(), (0, 0));
+
+    let a = atlas.allocate(size2(100, 16)).unwrap();
+    let b = atlas.allocate(size2(100, 64)).unwrap();
+    assert_eq!(atlas.content_y_extent(), (a.rectangle.min.y, b.rectangle.max.y));
+
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.content_y_extent(), (b.rectangle.min.y, b.rectangle.max.y));
+
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.content_y_extent(), (0, 0));
+
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(16, 100)).unwrap();
+    assert_eq!(atlas.content_y_extent(), (a.rectangle.min.x, a.rectangle.max.x));
+}
+
+#[test]
+fn collect_garbage() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    let b = atlas.allocate(size2(10, 100)).unwrap();
+
+    // Nothing to collect.
+    atlas.collect_garbage();
+    assert_eq!(atlas.shelves.len(), 2);
+
+    // Deallocating the bottom shelf doesn't trigger a cleanup since the top one is still used.
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.shelves.len(), 2);
+
+    // Deallocate the top shelf's last item without triggering the cleanup, then collect
+    // both shelves at once.
+    atlas.deallocate_from_bucket(b.id);
+    assert_eq!(atlas.shelves.len(), 2);
+    atlas.collect_garbage();
+    assert!(atlas.shelves.is_empty());
+    assert!(atlas.is_empty());
+
+    assert!(atlas.allocate(size2(256, 256)).is_some());
+}
+
+#[test]
+fn deallocate_many() {
+    let mut a = BucketedAtlasAllocator::with_options(size2(512, 512), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+
+    let mut ids = Vec::new();
+    for i in 0..400 {
+        ids.push(a.allocate(size2(4 + i % 28, 8 + i % 24)).unwrap().id);
+    }
+    let mut b = a.clone();
+
+    // Free everything but a few items at the bottom of the first column.
+    for &id in &ids[10..] {
+        a.deallocate(id);
+    }
+    b.deallocate_many(&ids[10..]);
+
+    assert_eq!(b.shelves.len(), a.shelves.len());
+    assert_eq!(b.available_height, a.available_height);
+    assert_eq!(b.current_column, 0);
+    assert_eq!(b.allocated_space(), a.allocated_space());
+    assert_eq!(b.dump_table(), a.dump_table());
+
+    assert_eq!(b.allocate(size2(200, 400)), a.allocate(size2(200, 400)));
+
+    b.deallocate_many(&ids[..10]);
+    b.deallocate_many(&[]);
+    assert_eq!(b.allocation_count(), 1);
+}
+
+#[test]
+fn coalesce_two_columns() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+
+    // Fill both columns with one item per shelf.
+    let mut allocations = Vec::new();
+    for _ in 0..16 {
+        allocations.push(atlas.allocate(size2(100, 32)).unwrap());
+    }
+    assert!(atlas.allocate(size2(100, 32)).is_none());
+
+    // Fragment the first column.
+    for alloc in &allocations[1..4] {
+        atlas.deallocate(alloc.id);
+    }
+
+    // Leave the last two shelves of the second column empty without cleaning them up
+    // so that the coalescing scan reaches the end of the shelf list.
+    atlas.deallocate_from_bucket(allocations[14].id);
+    atlas.deallocate_from_bucket(allocations[15].id);
+
+    // Too tall to be coalesced, must fail without reading past the last shelf.
+    assert!(atlas.allocate(size2(100, 128)).is_none());
+
+    let tall = atlas.allocate(size2(100, 96)).unwrap();
+    assert_eq!(tall.rectangle.min.x, 0);
+    for (i, alloc) in allocations.iter().enumerate() {
+        if (1..4).contains(&i) || i >= 14 {
+            continue;
+        }
+        assert!(!tall.rectangle.intersects(&alloc.rectangle));
+    }
+}
+
+#[test]
+fn coalesce_multi_bin_shelves() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(512, 256), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+
+    fn assert_disjoint(allocations: &[Allocation]) {
+        for (i, alloc) in allocations.iter().enumerate() {
+            for other in &allocations[i + 1..] {
+                assert!(!alloc.rectangle.intersects(&other.rectangle), "{:?} overlaps {:?}", alloc, other);
+            }
+        }
+    }
+
+    // Fill both columns with shelves of two bins.
+    let mut allocations = Vec::new();
+    while let Some(alloc) = atlas.allocate(size2(16, 16)) {
+        allocations.push(alloc);
+    }
+    assert_eq!(allocations.len(), 512);
+    assert_eq!(atlas.buckets.len(), 64);
+
+    // Free four adjacent shelves of the first column.
+    let freed: Vec<Allocation> = allocations.drain(32..96).collect();
+    for alloc in &freed {
+        atlas.deallocate(alloc.id);
+    }
+
+    // Coalesce the free shelves and fill both of the grown shelf's bins.
+    let tall = atlas.allocate(size2(40, 64)).unwrap();
+    assert_eq!(tall.rectangle.min.y, 32);
+    allocations.push(tall);
+    while let Some(alloc) = atlas.allocate(size2(16, 64)) {
+        allocations.push(alloc);
+    }
+    assert_eq!(allocations.len(), 448 + 1 + 13);
+    assert_disjoint(&allocations);
+
+    // Give the space back and coalesce it again with a different layout.
+    for alloc in allocations.drain(448..) {
+        atlas.deallocate(alloc.id);
+    }
+    while let Some(alloc) = atlas.allocate(size2(100, 40)) {
+        allocations.push(alloc);
+    }
+    assert_eq!(allocations.len(), 450);
+    assert_disjoint(&allocations);
+
+    for alloc in allocations {
+        atlas.deallocate(alloc.id);
+    }
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn coalesce_then_free_full_height() {
+    for num_columns in 1..3 {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(256 * num_columns, 256), &AllocatorOptions {
+            num_columns,
+            ..DEFAULT_OPTIONS
+        });
+
+        // Fill the last column with shelves of one item each.
+        let mut filler = Vec::new();
+        for _ in 0..(num_columns - 1) * 8 {
+            filler.push(atlas.allocate(size2(256, 32)).unwrap().id);
+        }
+        let mut rows = Vec::new();
+        for _ in 0..8 {
+            rows.push(atlas.allocate(size2(256, 32)).unwrap().id);
+        }
+        assert_eq!(atlas.available_height, 0);
+
+        // Coalesce all but the top shelf.
+        for &id in &rows[..7] {
+            atlas.deallocate(id);
+        }
+        let tall = atlas.allocate(size2(256, 224)).unwrap().id;
+
+        // Freeing the top shelf removes the squashed shelves below it, but not the grown one.
+        atlas.deallocate(rows[7]);
+        assert_eq!(atlas.available_height, 32);
+
+        atlas.deallocate(tall);
+        let full = atlas.allocate(size2(256, 256)).unwrap();
+        assert_eq!(full.rectangle.min, point2(256 * (num_columns - 1), 0));
+        assert_eq!(atlas.available_height, 0);
+    }
+}
+
+#[test]
+fn allocate_pow2() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1024, 1024));
+
+    let mut allocations = Vec::new();
+    for size in &[size2(3, 5), size2(100, 30), size2(17, 200), size2(512, 512), size2(1, 1)] {
+        let alloc = atlas.allocate_pow2(*size).unwrap();
+        let rect_size = alloc.rectangle.size();
+        assert!((rect_size.width as u32).is_power_of_two());
+        assert!((rect_size.height as u32).is_power_of_two());
+        assert!(rect_size.width >= size.width && rect_size.width < size.width * 2);
+        assert!(rect_size.height >= size.height && rect_size.height < size.height * 2);
+        allocations.push(alloc);
+    }
+
+    for (i, a) in allocations.iter().enumerate() {
+        for b in &allocations[i + 1..] {
+            assert!(!a.rectangle.intersects(&b.rectangle));
+        }
+    }
+
+    // Rounds up to 2048 which doesn't fit.
+    assert!(atlas.allocate_pow2(size2(1025, 1)).is_none());
+}
+
+#[test]
+fn high_water_mark() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.high_water_mark(), 0);
+
+    let a = atlas.allocate(size2(100, 32)).unwrap();
+    let b = atlas.allocate(size2(100, 64)).unwrap();
+    assert_eq!(atlas.high_water_mark(), b.rectangle.max.y as u32);
+
+    atlas.deallocate(b.id);
+    atlas.deallocate(a.id);
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.high_water_mark(), b.rectangle.max.y as u32);
+
+    atlas.clear();
+    assert_eq!(atlas.high_water_mark(), 0);
+}
+
+#[test]
+fn alignment_overflow() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(1000, 1000), &AllocatorOptions {
+        alignment: size2(16, 16),
+        ..DEFAULT_OPTIONS
+    });
+
+    assert_eq!(
+        atlas.try_allocate(size2(1000, 1000)),
+        Err(AllocError::AlignmentOverflow {
+            requested: size2(1000, 1000),
+            aligned: size2(1008, 1008),
+        }),
+    );
+    assert_eq!(atlas.try_allocate(size2(1001, 10)), Err(AllocError::TooLarge));
+    assert!(atlas.try_allocate(size2(992, 992)).is_ok());
+}
+
+#[test]
+fn coverage() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.coverage(), None);
+
+    let a = atlas.allocate(size2(100, 16)).unwrap();
+    let b = atlas.allocate(size2(50, 64)).unwrap();
+    assert_eq!(atlas.coverage(), Some(a.rectangle.union(&b.rectangle)));
+
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.coverage(), Some(b.rectangle));
+
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.coverage(), None);
+
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(16, 100)).unwrap();
+    assert_eq!(atlas.coverage(), Some(a.rectangle));
+}
+
+#[test]
+fn allocate_first_fit() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    let sizes = [size2(512, 512), size2(256, 256), size2(128, 128)];
+    let (index, a) = atlas.allocate_first_fit(&sizes).unwrap();
+    assert_eq!(index, 1);
+    assert_eq!(a.rectangle.size(), size2(256, 256));
+
+    assert!(atlas.allocate_first_fit(&sizes).is_none());
+    assert!(atlas.allocate_first_fit(&[]).is_none());
+
+    atlas.deallocate(a.id);
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn stable_ids() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        stable_ids: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+    let c = atlas.allocate_descending(&[size2(50, 50), size2(20, 20)]);
+    assert_eq!(c.len(), 2);
+    assert_ne!(a.id, b.id);
+
+    atlas.deallocate(a.id);
+    let d = atlas.allocate(size2(10, 10)).unwrap();
+    assert_ne!(a.id, d.id);
+
+    atlas.deallocate(b.id);
+    atlas.deallocate(c[0].id);
+    atlas.deallocate(c[1].id);
+    atlas.deallocate(d.id);
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn reclaim_deallocated_items() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(100, 100));
+
+    let a = atlas.allocate(size2(30, 10)).unwrap();
+    let b = atlas.allocate(size2(30, 10)).unwrap();
+    let c = atlas.allocate(size2(30, 10)).unwrap();
+    assert!(atlas.allocate(size2(30, 10)).unwrap().rectangle.min.y > 0);
+
+    // The space of the last item is immediately reusable.
+    atlas.deallocate(c.id);
+    let d = atlas.allocate(size2(30, 10)).unwrap();
+    assert_eq!(d.rectangle, c.rectangle);
+
+    // Space in the middle is reclaimed once the items to its right are deallocated.
+    atlas.deallocate(b.id);
+    atlas.deallocate(d.id);
+    let e = atlas.allocate(size2(60, 10)).unwrap();
+    assert_eq!(e.rectangle.min, b.rectangle.min);
+    assert!(!e.rectangle.intersects(&a.rectangle));
+}
+
+#[test]
+fn random_alloc_dealloc() {
+    // A small deterministic pseudo-random sequence of allocations and deallocations,
+    // checking that allocations never overlap.
+    let mut atlas = BucketedAtlasAllocator::new(size2(512, 512));
+    let allocations = random_operations(&mut atlas, 17, 5000, 40, |_, allocations, requested| {
+        if let (Some(_), Some((alloc, previous))) = (requested, allocations.split_last()) {
+            for other in previous {
+                assert!(!alloc.rectangle.intersects(&other.rectangle));
+            }
+        }
+    });
+
+    for alloc in allocations {
+        atlas.deallocate(alloc.id);
+    }
+
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.allocated_space(), 0);
+}
+
+#[test]
+fn allocate_near() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(10, 20)).unwrap();
+    let c = atlas.allocate(size2(10, 48)).unwrap();
+    let b = atlas.allocate(size2(10, 60)).unwrap();
+    assert!(b.rectangle.min.y >= c.rectangle.max.y);
+
+    // A regular allocation would go in the shelf of c, which has a tighter fit.
+    let d = atlas.allocate_near(size2(10, 40), b.id).unwrap();
+    assert_eq!(d.rectangle.min.y, b.rectangle.min.y);
+
+    // Falls back to a regular allocation if the nearby shelves are not suitable.
+    let e = atlas.allocate_near(size2(10, 100), a.id).unwrap();
+    assert!(e.rectangle.min.y >= b.rectangle.max.y);
+
+    for id in &[a.id, b.id, c.id, d.id, e.id] {
+        atlas.deallocate(*id);
+    }
+    assert!(atlas.is_empty());
+
+    // With vertical shelves, rectangles wider than the columns in user space can still be
+    // allocated near another one.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(1024, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let c = atlas.allocate(size2(300, 10)).unwrap();
+    let b = atlas.allocate(size2(400, 10)).unwrap();
+    assert!(b.rectangle.min.x >= c.rectangle.max.x);
+
+    let d = atlas.allocate_near(size2(290, 10), b.id).unwrap();
+    assert_eq!(d.rectangle.min.x, b.rectangle.min.x);
+}
+
+#[test]
+fn partition() {
+    fn check_partition(atlas: &BucketedAtlasAllocator) {
+        let bounds = Rectangle { min: point2(0, 0), max: atlas.size().to_vector().to_point() };
+        let rects = atlas.partition();
+
+        let mut area = 0;
+        let mut allocated_area = 0;
+        for (i, &(rect, allocated)) in rects.iter().enumerate() {
+            assert!(bounds.contains_box(&rect));
+            for &(other, _) in &rects[i + 1..] {
+                assert!(!rect.intersects(&other));
+            }
+            area += rect.area();
+            if allocated {
+                allocated_area += rect.area();
+            }
+        }
+
+        assert_eq!(area, bounds.area());
+        assert_eq!(allocated_area, atlas.allocated_space());
+    }
+
+    for &vertical_shelves in &[false, true] {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(1000, 800), &AllocatorOptions {
+            num_columns: 3,
+            vertical_shelves,
+            ..DEFAULT_OPTIONS
+        });
+        check_partition(&atlas);
+
+        let a = atlas.allocate(size2(10, 30)).unwrap();
+        let b = atlas.allocate(size2(12, 30)).unwrap();
+        let c = atlas.allocate(size2(200, 100)).unwrap();
+        let _d = atlas.allocate(size2(30, 250)).unwrap();
+        check_partition(&atlas);
+
+        // Deallocating a creates a hole in the middle of a bucket.
+        atlas.deallocate(a.id);
+        check_partition(&atlas);
+
+        atlas.deallocate(b.id);
+        atlas.deallocate(c.id);
+        check_partition(&atlas);
+    }
+}
+
+#[test]
+fn trim_unused_storage() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+
+    let mut ids = Vec::new();
+    for i in 0..200 {
+        ids.push(atlas.allocate(size2(10 + i % 20, 10 + i % 30)).unwrap().id);
+    }
+    let kept: Vec<AllocId> = ids.iter().step_by(80).cloned().collect();
+    for id in &ids {
+        if !kept.contains(id) {
+            atlas.deallocate(*id);
+        }
+    }
+
+    let mut trimmed = atlas.clone();
+    trimmed.trim_unused_storage();
+    assert!(trimmed.buckets.len() < atlas.buckets.len());
+
+    for i in 0..100 {
+        let size = size2(5 + i % 40, 5 + i % 25);
+        let a = atlas.allocate(size).map(|alloc| alloc.rectangle);
+        let b = trimmed.allocate(size).map(|alloc| alloc.rectangle);
+        assert_eq!(a, b);
+    }
+
+    for id in &kept {
+        trimmed.deallocate(*id);
+    }
+}
+
+#[test]
+fn allocate_row() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    let a = atlas.allocate(size2(10, 16)).unwrap();
+
+    let row = atlas.allocate_row(5, size2(20, 16)).unwrap();
+    assert_eq!(row.len(), 5);
+    for pair in row.windows(2) {
+        assert_eq!(pair[0].rectangle.min.y, pair[1].rectangle.min.y);
+        assert_eq!(pair[0].rectangle.max.x, pair[1].rectangle.min.x);
+    }
+
+    // Doesn't fit in a single shelf: nothing is allocated.
+    let allocated_space = atlas.allocated_space();
+    assert!(atlas.allocate_row(20, size2(20, 16)).is_none());
+    assert_eq!(atlas.allocated_space(), allocated_space);
+
+    assert_eq!(atlas.allocate_row(0, size2(20, 16)), Some(Vec::new()));
+
+    atlas.deallocate(a.id);
+    for alloc in &row {
+        atlas.deallocate(alloc.id);
+    }
+    assert!(atlas.is_empty());
+
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let row = atlas.allocate_row(3, size2(16, 50)).unwrap();
+    for pair in row.windows(2) {
+        assert_eq!(pair[0].rectangle.min.x, pair[1].rectangle.min.x);
+        assert_eq!(pair[0].rectangle.max.y, pair[1].rectangle.min.y);
+    }
+}
+
+#[test]
+fn autogrow() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    atlas.set_autogrow(0.5, size2(1024, 1024));
+
+    atlas.allocate(size2(128, 128)).unwrap();
+    atlas.allocate(size2(128, 128)).unwrap();
+    assert_eq!(atlas.take_grow_event(), None);
+
+    // Occupancy goes over the target.
+    atlas.allocate(size2(128, 128)).unwrap();
+    assert_eq!(atlas.take_grow_event(), Some(size2(512, 512)));
+    assert_eq!(atlas.take_grow_event(), None);
+    assert_eq!(atlas.size(), size2(512, 512));
+
+    // Doesn't fit without growing.
+    let a = atlas.allocate(size2(1000, 10)).unwrap();
+    assert!(a.rectangle.max.x <= 1024);
+    assert_eq!(atlas.take_grow_event(), Some(size2(1024, 1024)));
+
+    // Can't grow past the maximum size.
+    assert!(atlas.allocate(size2(2000, 10)).is_none());
+    assert_eq!(atlas.take_grow_event(), None);
+    assert_eq!(atlas.size(), size2(1024, 1024));
+
+    atlas.set_autogrow(0.5, size2(4096, 4096));
+    atlas.disable_autogrow();
+    assert!(atlas.allocate(size2(2000, 10)).is_none());
+    assert_eq!(atlas.take_grow_event(), None);
+}
+
+#[test]
+fn free_space_by_height() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+
+    let free_space = atlas.free_space_by_height();
+    assert_eq!(free_space.len(), 1);
+    assert_eq!(free_space[&ANY_HEIGHT], 256 * 256);
 
-    let rem = size % alignment;
-    if rem > 0 {
-        size += alignment - rem;
+    atlas.allocate(size2(10, 16)).unwrap();
+    atlas.allocate(size2(20, 30)).unwrap();
+    atlas.allocate(size2(6, 16)).unwrap();
+
+    let free_space = atlas.free_space_by_height();
+    assert_eq!(free_space.len(), 3);
+    assert_eq!(free_space[&16], (256 - 16) * 16);
+    assert_eq!(free_space[&32], (256 - 20) * 32);
+    assert_eq!(free_space[&ANY_HEIGHT], 256 * (256 - 48));
+
+    let total: i32 = free_space.values().sum();
+    assert_eq!(total, atlas.free_space());
+}
+
+#[test]
+fn reconfigure() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 512));
+
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    assert_eq!(atlas.reconfigure(&DEFAULT_OPTIONS), Err(NonEmpty));
+
+    atlas.deallocate(a.id);
+    atlas.reconfigure(&AllocatorOptions {
+        alignment: size2(8, 8),
+        vertical_shelves: true,
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    }).unwrap();
+    assert_eq!(atlas.size(), size2(256, 512));
+    assert!(atlas.is_empty());
+
+    let b = atlas.allocate(size2(10, 10)).unwrap();
+    assert_eq!(b.rectangle.size(), size2(16, 16));
+    let c = atlas.allocate(size2(10, 10)).unwrap();
+    // Vertical shelves: the items of a shelf are stacked along the y axis.
+    assert_eq!(b.rectangle.min.x, c.rectangle.min.x);
+    // The columns are now 256 pixels tall, so taller items span both of them.
+    let d = atlas.allocate(size2(10, 300)).unwrap();
+    assert_eq!(d.rectangle.min.y, 0);
+    assert!(d.rectangle.min.x >= 16);
+}
+
+#[test]
+fn internal_fragmentation() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        alignment: size2(4, 4),
+        ..DEFAULT_OPTIONS
+    });
+    assert_eq!(atlas.internal_fragmentation(), 0.0);
+
+    let a = atlas.allocate(size2(16, 32)).unwrap();
+    assert_eq!(a.rectangle.area(), 16 * 32);
+    assert_eq!(atlas.internal_fragmentation(), 0.0);
+
+    // Rounded up to 32x32.
+    let b = atlas.allocate(size2(30, 30)).unwrap();
+    assert_eq!(b.rectangle.area(), 32 * 32);
+    let padding = (32 * 32 - 30 * 30) as f32;
+    let allocated = (16 * 32 + 32 * 32) as f32;
+    assert_eq!(atlas.internal_fragmentation(), padding / allocated);
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+    assert_eq!(atlas.internal_fragmentation(), 0.0);
+}
+
+#[test]
+fn fixed_shelf_height() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        fixed_shelf_height: Some(32),
+        ..DEFAULT_OPTIONS
+    });
+
+    assert!(atlas.allocate(size2(10, 33)).is_none());
+
+    let mut ids = Vec::new();
+    for i in 0..40 {
+        let alloc = atlas.allocate(size2(20 + i % 7, 1 + (i * 5) % 32)).unwrap();
+        assert_eq!(alloc.rectangle.height(), 32);
+        assert_eq!(alloc.rectangle.min.y % 32, 0);
+        ids.push(alloc.id);
     }
 
-    size
+    for id in ids {
+        atlas.deallocate(id);
+    }
+    assert!(atlas.is_empty());
 }
 
-fn adjust_size(alignment: i32, size: &mut i32) {
-    let rem = *size % alignment;
-    if rem > 0 {
-        *size += alignment - rem;
+#[test]
+fn max_allocatable_size() {
+    fn check(atlas: &BucketedAtlasAllocator) {
+        let max = atlas.max_allocatable_size();
+        if max.is_empty() {
+            assert!(atlas.clone().allocate(size2(1, 1)).is_none());
+            return;
+        }
+
+        let size = atlas.clone().allocate(max).unwrap().rectangle.size();
+        assert!(size.width >= max.width && size.height >= max.height);
+        assert!(atlas.clone().allocate(max + size2(1, 0)).is_none(), "{:?}", max);
+        assert!(atlas.clone().allocate(max + size2(0, 1)).is_none(), "{:?}", max);
+    }
+
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.max_allocatable_size(), size2(256, 256));
+    let alloc = atlas.allocate(size2(100, 10)).unwrap();
+    assert_eq!(atlas.max_allocatable_size(), size2(256, 240));
+    atlas.deallocate(alloc.id);
+
+    let options = [
+        DEFAULT_OPTIONS,
+        AllocatorOptions { num_columns: 2, alignment: size2(4, 8), ..DEFAULT_OPTIONS },
+        AllocatorOptions { num_columns: 3, vertical_shelves: true, padding: 1, ..DEFAULT_OPTIONS },
+        AllocatorOptions { fixed_shelf_height: Some(24), alignment: size2(2, 2), ..DEFAULT_OPTIONS },
+        AllocatorOptions { tile_size: Some(size2(64, 64)), ..DEFAULT_OPTIONS },
+    ];
+    for options in &options {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(300, 400), options);
+        let mut step = 0;
+        random_operations(&mut atlas, 3, 300, 60, |atlas, _, _| {
+            if step % 10 == 0 {
+                check(atlas);
+            }
+            step += 1;
+        });
+
+        // Allocate the largest size until the atlas is full.
+        loop {
+            check(&atlas);
+            let max = atlas.max_allocatable_size();
+            if max.is_empty() {
+                break;
+            }
+            atlas.allocate(max).unwrap();
+        }
     }
 }
 
 #[test]
-fn atlas_basic() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+fn dump_table() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
 
-    let full = atlas.allocate(size2(1000, 1000)).unwrap().id;
-    assert!(atlas.allocate(size2(1, 1)).is_none());
+    let a = atlas.allocate(size2(32, 64)).unwrap();
+    let b = atlas.allocate(size2(10, 20)).unwrap();
+    let c = atlas.allocate(size2(20, 20)).unwrap();
+    atlas.deallocate(a.id);
 
-    atlas.deallocate(full);
-    let a = atlas.allocate(size2(10, 10)).unwrap().id;
-    let b = atlas.allocate(size2(50, 30)).unwrap().id;
-    let c = atlas.allocate(size2(12, 45)).unwrap().id;
-    let d = atlas.allocate(size2(60, 45)).unwrap().id;
-    let e = atlas.allocate(size2(1, 1)).unwrap().id;
-    let f = atlas.allocate(size2(128, 128)).unwrap().id;
-    let g = atlas.allocate(size2(256, 256)).unwrap().id;
+    let expected = concat!(
+        "        id     x     y     w     h\n",
+        "      4098   128    64    10    24\n",
+        "      8194   138    64    20    24\n",
+    );
+    assert_eq!(atlas.dump_table(), expected);
+    assert_eq!(b.id.serialize(), 4098);
+    assert_eq!(c.id.serialize(), 8194);
+
+    // With stable ids the table contains the ids that were handed out.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        stable_ids: true,
+        ..DEFAULT_OPTIONS
+    });
+    let a = atlas.allocate(size2(32, 64)).unwrap();
+    let b = atlas.allocate(size2(10, 20)).unwrap();
+    atlas.deallocate(a.id);
+    assert!(atlas.dump_table().contains(&format!("\n{:>10} ", b.id.serialize())));
+}
 
-    atlas.deallocate(b);
-    atlas.deallocate(f);
-    atlas.deallocate(c);
-    atlas.deallocate(e);
-    let h = atlas.allocate(size2(500, 200)).unwrap().id;
-    atlas.deallocate(a);
-    let i = atlas.allocate(size2(500, 200)).unwrap().id;
-    atlas.deallocate(g);
-    atlas.deallocate(h);
-    atlas.deallocate(d);
-    atlas.deallocate(i);
+#[test]
+fn dump_table_padding() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        padding: 2,
+        ..DEFAULT_OPTIONS
+    });
 
-    let full = atlas.allocate(size2(1000, 1000)).unwrap().id;
-    assert!(atlas.allocate(size2(1, 1)).is_none());
-    atlas.deallocate(full);
+    let a = atlas.allocate(size2(10, 20)).unwrap();
+
+    // The table contains the rectangles handed out by allocate, not the padded ones.
+    let expected = format!(
+        "        id     x     y     w     h\n{:>10} {:>5} {:>5} {:>5} {:>5}\n",
+        a.id.serialize(),
+        a.rectangle.min.x,
+        a.rectangle.min.y,
+        a.rectangle.width(),
+        a.rectangle.height(),
+    );
+    assert_eq!(atlas.dump_table(), expected);
 }
 
 #[test]
-fn test_coalesce_shelves() {
+fn allocate_in_y_range() {
     let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
 
-    // Allocate 7 shelves (leaving 32px of remaining space on top).
-    let mut ids = Vec::new();
-    for _ in 0..7 {
-        for _ in 0..8 {
-            ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
-        }
+    // Long-lived allocations at the bottom.
+    let a = atlas.allocate_in_y_range(size2(100, 30), 0..128).unwrap();
+    assert!(a.rectangle.max.y <= 128);
+
+    // Fill the bottom half so that the next shelf starts at 128.
+    let b = atlas.allocate_in_y_range(size2(100, 90), 0..128).unwrap();
+    assert!(b.rectangle.max.y <= 128);
+    assert!(atlas.allocate_in_y_range(size2(200, 60), 0..128).is_none());
+
+    // Scratch allocations at the top.
+    let c = atlas.allocate_in_y_range(size2(10, 10), 128..256).unwrap();
+    assert!(c.rectangle.min.y >= 128);
+    let d = atlas.allocate_in_y_range(size2(10, 10), 128..256).unwrap();
+    assert_eq!(d.rectangle.min.y, c.rectangle.min.y);
+
+    // Doesn't fit in the range although there is space elsewhere.
+    assert!(atlas.allocate_in_y_range(size2(10, 100), 200..256).is_none());
+    assert!(atlas.allocate(size2(10, 100)).is_some());
+
+    for id in &[a.id, b.id, c.id, d.id] {
+        atlas.deallocate(*id);
     }
 
-    // Free the first shelf.
-    for i in 0..8 {
-        atlas.deallocate(ids[i]);
+    // Sizes too large to be aligned are rejected.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        alignment: size2(16, 16),
+        ..DEFAULT_OPTIONS
+    });
+    assert!(atlas.allocate_in_y_range(size2(i32::MAX, 10), 0..256).is_none());
+    assert!(atlas.allocate_in_y_range(size2(10, i32::MAX), 0..256).is_none());
+
+    // With vertical shelves the size is checked against the columns in shelf coordinates.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(1024, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+    let e = atlas.allocate_in_y_range(size2(500, 10), 0..1024).unwrap();
+    assert!(e.rectangle.width() >= 500);
+    assert_eq!(e.rectangle.height(), 10);
+    assert!(atlas.allocate_in_y_range(size2(10, 300), 0..1024).is_none());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn filled_to() {
+    let (atlas, ids) = BucketedAtlasAllocator::filled_to(size2(512, 512), &DEFAULT_OPTIONS, 0.5);
+    let occupancy = atlas.allocated_space() as f32 / (512.0 * 512.0);
+    assert!(occupancy >= 0.5);
+    assert!(occupancy < 0.6);
+
+    // Deterministic.
+    let (other, other_ids) = BucketedAtlasAllocator::filled_to(size2(512, 512), &DEFAULT_OPTIONS, 0.5);
+    assert_eq!(ids, other_ids);
+    assert_eq!(atlas.dump_table(), other.dump_table());
+
+    let (mut atlas, ids) = BucketedAtlasAllocator::filled_to(size2(512, 512), &DEFAULT_OPTIONS, 1.0);
+    assert!(!atlas.is_empty());
+    for id in ids {
+        atlas.deallocate(id);
     }
+    assert!(atlas.is_empty());
+}
 
-    // Free the 3rd and 4th shelf.
-    for i in 16..32 {
-        atlas.deallocate(ids[i]);
+#[test]
+fn tile_size() {
+    fn check_tiles(rect: &Rectangle, tile: i32) {
+        let size = rect.size();
+        if size.width <= tile {
+            assert_eq!(rect.min.x / tile, (rect.max.x - 1) / tile, "{:?}", rect);
+        } else {
+            assert_eq!(rect.min.x % tile, 0, "{:?}", rect);
+        }
+        if size.height <= tile {
+            assert_eq!(rect.min.y / tile, (rect.max.y - 1) / tile, "{:?}", rect);
+        } else {
+            assert_eq!(rect.min.y % tile, 0, "{:?}", rect);
+        }
     }
 
-    // Not enough space left in existing shelves and above.
-    // even coalescing is not sufficient.
-    assert!(atlas.allocate(size2(70, 70)).is_none());
+    for &vertical_shelves in &[false, true] {
+        let mut atlas = BucketedAtlasAllocator::with_options(
+            size2(1024, 1024),
+            &AllocatorOptions {
+                tile_size: Some(size2(64, 64)),
+                vertical_shelves,
+                ..DEFAULT_OPTIONS
+            },
+        );
 
-    // Not enough space left in existing shelves and above.
-    // The 3rd and 4th row can be coalesced to fit this allocation, though.
-    let id = atlas.allocate(size2(64, 64)).unwrap().id;
+        let mut rng = Lcg::new(3);
+        let mut next = |max: u32| rng.next() % max + 1;
 
-    // Deallocate everything
-    for i in 8..16 {
-        atlas.deallocate(ids[i]);
-    }
+        let mut ids = Vec::new();
+        for i in 0..300 {
+            let size = size2(next(100) as i32, next(100) as i32);
+            if let Some(alloc) = atlas.allocate(size) {
+                check_tiles(&alloc.rectangle, 64);
+                ids.push(alloc.id);
+            }
 
-    atlas.deallocate(id);
+            if i % 3 == 0 && !ids.is_empty() {
+                let idx = next(ids.len() as u32) as usize - 1;
+                atlas.deallocate(ids.swap_remove(idx));
+            }
+        }
 
-    for i in 32..56 {
-        atlas.deallocate(ids[i]);
+        assert!(!ids.is_empty());
+        for id in ids {
+            atlas.deallocate(id);
+        }
+        assert!(atlas.is_empty());
     }
 
-    //dump_svg(&atlas, &mut std::fs::File::create("tmp.svg").expect("!!"));
+    // Items that fit in a tile are pushed past the boundary.
+    let mut atlas = BucketedAtlasAllocator::with_options(
+        size2(256, 256),
+        &AllocatorOptions {
+            tile_size: Some(size2(64, 64)),
+            ..DEFAULT_OPTIONS
+        },
+    );
+    let a = atlas.allocate(size2(40, 40)).unwrap();
+    let b = atlas.allocate(size2(40, 40)).unwrap();
+    assert_eq!(b.rectangle.min.y, a.rectangle.min.y);
+    assert_eq!(b.rectangle.min.x % 64, 0);
+}
 
-    assert!(atlas.is_empty());
+#[test]
+#[should_panic]
+fn zero_tile_size() {
+    BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        tile_size: Some(size2(0, 64)),
+        ..DEFAULT_OPTIONS
+    });
+}
+
+#[test]
+fn allocated_space() {
+    let mut atlas = BucketedAtlasAllocator::with_options(
+        size2(256, 256),
+        &AllocatorOptions {
+            alignment: size2(4, 4),
+            ..DEFAULT_OPTIONS
+        },
+    );
+    assert_eq!(atlas.allocated_space(), 0);
+
+    let a = atlas.allocate(size2(10, 10)).unwrap();
+    let b = atlas.allocate(size2(7, 3)).unwrap();
+    assert_eq!(a.rectangle.width(), 12);
+    assert_eq!(atlas.allocated_space(), a.rectangle.area() + b.rectangle.area());
+    assert_eq!(atlas.free_space(), atlas.size().area() - atlas.allocated_space());
+    assert_eq!(atlas.occupancy(), atlas.allocated_space() as f32 / atlas.size().area() as f32);
+
+    atlas.deallocate(a.id);
+    assert_eq!(atlas.allocated_space(), b.rectangle.area());
+    atlas.deallocate(b.id);
     assert_eq!(atlas.allocated_space(), 0);
+    assert_eq!(atlas.occupancy(), 0.0);
 }
 
 #[test]
-fn grow_vertically() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+fn get() {
+    let options = [
+        DEFAULT_OPTIONS,
+        AllocatorOptions { vertical_shelves: true, num_columns: 2, ..DEFAULT_OPTIONS },
+        AllocatorOptions { stable_ids: true, alignment: size2(4, 2), ..DEFAULT_OPTIONS },
+        AllocatorOptions { tile_size: Some(size2(32, 32)), ..DEFAULT_OPTIONS },
+    ];
 
-    // Allocate 7 shelves (leaving 32px of remaining space on top).
-    let mut ids = Vec::new();
-    for _ in 0..7 {
-        for _ in 0..8 {
-            ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
+    for options in &options {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(512, 512), options);
+
+        let mut allocations = Vec::new();
+        for i in 0..50 {
+            allocations.push(atlas.allocate(size2(5 + (i * 7) % 40, 5 + (i * 3) % 30)).unwrap());
+        }
+
+        let removed: Vec<Allocation> = allocations.drain(10..20).collect();
+        for alloc in &removed {
+            atlas.deallocate(alloc.id);
+        }
+
+        for alloc in &allocations {
+            assert_eq!(atlas.get(alloc.id), alloc.rectangle);
+        }
+        for alloc in &removed {
+            assert_eq!(atlas.try_get(alloc.id), None);
         }
     }
+}
 
-    // Free the first shelf.
-    for i in 0..8 {
-        atlas.deallocate(ids[i]);
+#[test]
+fn spanning_shelves() {
+    for &vertical_shelves in &[false, true] {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(512, 512), &AllocatorOptions {
+            num_columns: 2,
+            vertical_shelves,
+            ..DEFAULT_OPTIONS
+        });
+
+        let (wide, tall) = if vertical_shelves { (20, 300) } else { (300, 20) };
+
+        let mut allocations = Vec::new();
+        allocations.push(atlas.allocate(size2(30, 30)).unwrap());
+        allocations.push(atlas.allocate(size2(wide, tall)).unwrap());
+        allocations.push(atlas.allocate(size2(wide, tall)).unwrap());
+        while let Some(alloc) = atlas.allocate(size2(60, 60)) {
+            allocations.push(alloc);
+        }
+        assert!(atlas.allocate(size2(wide, tall)).is_none());
+
+        let bounds = Rectangle { min: point2(0, 0), max: point2(512, 512) };
+        for (i, a) in allocations.iter().enumerate() {
+            assert!(bounds.contains_box(&a.rectangle));
+            assert_eq!(atlas.get(a.id), a.rectangle);
+            for b in &allocations[i + 1..] {
+                assert!(!a.rectangle.intersects(&b.rectangle), "{:?} {:?}", a.rectangle, b.rectangle);
+            }
+        }
+
+        let area: i32 = atlas.partition().iter().map(|(rect, _)| rect.area()).sum();
+        assert_eq!(area, 512 * 512);
+
+        for alloc in &allocations {
+            atlas.deallocate(alloc.id);
+        }
+        assert!(atlas.is_empty());
+
+        // Removing the spanning shelves gives their height back to the columns.
+        assert!(atlas.allocate(size2(256, 512)).is_some());
+    }
+}
+
+#[test]
+fn vertical_shelves_non_square() {
+    // The shelves are 256 pixels wide and the columns 512 pixels tall.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(512, 256), &AllocatorOptions {
+        vertical_shelves: true,
+        ..DEFAULT_OPTIONS
+    });
+
+    let alloc = atlas.allocate(size2(300, 100)).unwrap();
+    assert!(alloc.rectangle.width() >= 300 && alloc.rectangle.height() >= 100);
+    assert!(atlas.allocate(size2(100, 300)).is_none());
+}
+
+#[test]
+fn vertical_shelves_alignment() {
+    // With vertical shelves, the buckets are split along the y axis.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(104, 104), &AllocatorOptions {
+        vertical_shelves: true,
+        alignment: size2(1, 8),
+        ..DEFAULT_OPTIONS
+    });
+
+    while let Some(alloc) = atlas.allocate(size2(8, 8)) {
+        assert_eq!(alloc.rectangle.min.y % 8, 0, "{:?}", alloc.rectangle);
     }
+}
 
-    // Free the 3rd and 4th shelf.
-    for i in 16..32 {
-        atlas.deallocate(ids[i]);
+#[test]
+fn taller_than_columns() {
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+        num_columns: 2,
+        ..DEFAULT_OPTIONS
+    });
+
+    // The spanning shelf leaves 192 pixels to the columns.
+    let a = atlas.allocate(size2(200, 64)).unwrap();
+    let b = atlas.allocate(size2(100, 64)).unwrap();
+
+    // This fits in the atlas but not in the columns anymore.
+    assert!(atlas.allocate(size2(50, 200)).is_none());
+
+    let c = atlas.allocate(size2(50, 192)).unwrap();
+    assert_eq!(c.rectangle.height(), 192);
+    assert!(!c.rectangle.intersects(&a.rectangle));
+    assert!(!c.rectangle.intersects(&b.rectangle));
+}
+
+#[test]
+fn allocate_many() {
+    let sizes = [size2(64, 64), size2(128, 200), size2(64, 200)];
+
+    // Allocating in order creates a short shelf first and the tall ones don't fit anymore.
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    let in_order: Vec<Option<Allocation>> = sizes.iter().map(|size| atlas.allocate(*size)).collect();
+    assert!(in_order.iter().any(|alloc| alloc.is_none()));
+
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    let mut allocations = Vec::new();
+    atlas.allocate_many(&sizes, &mut allocations);
+    assert_eq!(allocations.len(), sizes.len());
+    for (i, alloc) in allocations.iter().enumerate() {
+        let rect = alloc.unwrap().rectangle;
+        assert!(rect.size().width >= sizes[i].width);
+        assert!(rect.size().height >= sizes[i].height);
+        for other in &allocations[i + 1..] {
+            assert!(!rect.intersects(&other.unwrap().rectangle));
+        }
     }
 
-    // Not enough space left in existing shelves and above.
-    // even coalescing is not sufficient.
-    assert!(atlas.allocate(size2(70, 70)).is_none());
+    for alloc in &allocations {
+        atlas.deallocate(alloc.unwrap().id);
+    }
+    assert!(atlas.is_empty());
 
-    // Grow just enough vertically to fit the previous region
-    atlas.grow(size2(256, 256 + 70 - 32));
+    // Rectangles that don't fit are reported in place.
+    atlas.allocate_many(&[size2(10, 10), size2(300, 300), size2(20, 20)], &mut allocations);
+    assert_eq!(allocations.len(), 3);
+    assert!(allocations[0].is_some());
+    assert!(allocations[1].is_none());
+    assert!(allocations[2].is_some());
+}
 
-    // Allocation should succeed now
-    assert!(atlas.allocate(size2(70, 70)).is_some());
+#[test]
+fn deallocate_and_get() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(10, 20)).unwrap();
+    let b = atlas.allocate(size2(10, 20)).unwrap();
+
+    // The rectangle of the item, not of the whole bucket.
+    assert_eq!(atlas.deallocate_and_get(a.id), a.rectangle);
+    assert_eq!(atlas.deallocate_and_get(b.id), b.rectangle);
+    assert!(atlas.is_empty());
 }
 
 #[test]
-fn grow_horizontally() {
+fn allocation_count() {
     let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert_eq!(atlas.allocation_count(), 0);
 
-    // Allocate 7 shelves (leaving 32px of remaining space on top).
-    let mut ids = Vec::new();
-    for _ in 0..7 {
-        for _ in 0..8 {
-            ids.push(atlas.allocate(size2(32, 32)).unwrap().id)
+    let mut allocations = Vec::new();
+    for i in 0..50 {
+        if let Some(alloc) = atlas.allocate(size2(10 + i, 20 + i)) {
+            allocations.push(alloc.id);
         }
+        assert_eq!(atlas.allocation_count(), allocations.len());
     }
+    assert!(atlas.allocate(size2(300, 300)).is_none());
+    assert_eq!(atlas.allocation_count(), allocations.len());
 
-    // Free the first shelf.
-    for i in 0..8 {
-        atlas.deallocate(ids[i]);
+    let count = allocations.len();
+    for id in allocations.iter().step_by(2) {
+        atlas.deallocate(*id);
     }
+    assert_eq!(atlas.allocation_count(), count / 2);
 
-    // Free the 3rd and 4th shelf.
-    for i in 16..32 {
-        atlas.deallocate(ids[i]);
+    atlas.clear();
+    assert_eq!(atlas.allocation_count(), 0);
+}
+
+#[test]
+fn contains() {
+    for &stable_ids in &[false, true] {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+            stable_ids,
+            ..DEFAULT_OPTIONS
+        });
+        let a = atlas.allocate(size2(10, 10)).unwrap();
+        let b = atlas.allocate(size2(10, 10)).unwrap();
+        assert!(atlas.contains(a.id));
+        assert!(atlas.contains(b.id));
+
+        atlas.deallocate(a.id);
+        assert!(!atlas.contains(a.id));
+        assert!(atlas.contains(b.id));
+
+        atlas.deallocate(b.id);
+        let c = atlas.allocate(size2(10, 10)).unwrap();
+        assert!(atlas.contains(c.id));
+        assert!(!atlas.contains(a.id));
+        assert!(!atlas.contains(b.id));
+        assert!(!atlas.contains(AllocId(u32::MAX)));
     }
+}
 
-    // Not enough space left in existing shelves and above.
-    // even coalescing is not sufficient.
-    assert!(atlas.allocate(size2(512, 32)).is_none());
+#[test]
+fn try_deallocate() {
+    for &stable_ids in &[false, true] {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+            stable_ids,
+            ..DEFAULT_OPTIONS
+        });
+        let a = atlas.allocate(size2(10, 10)).unwrap();
+        let b = atlas.allocate(size2(10, 10)).unwrap();
 
-    // Grow just enough horizontally to add more buckets
-    atlas.grow(size2(256 * 2, 256));
+        assert_eq!(atlas.try_deallocate(a.id), Ok(()));
+        assert!(atlas.try_deallocate(a.id).is_err());
+        assert_eq!(atlas.try_deallocate(AllocId(u32::MAX)), Err(DeallocError::NotAllocated));
+        assert!(atlas.contains(b.id));
 
-    // Allocation should succeed now
-    assert!(atlas.allocate(size2(512, 32)).is_some());
+        assert_eq!(atlas.try_deallocate(b.id), Ok(()));
+        assert!(atlas.is_empty());
+
+        // The bucket was reset, its generation changed.
+        let c = atlas.allocate(size2(10, 10)).unwrap();
+        assert_eq!(atlas.try_deallocate(b.id), Err(DeallocError::StaleGeneration));
+        assert_eq!(atlas.try_deallocate(c.id), Ok(()));
+    }
 }
 
 #[test]
-fn grow_to_fit_allocation() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(32, 32));
+fn iter() {
+    for &stable_ids in &[false, true] {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), &AllocatorOptions {
+            stable_ids,
+            ..DEFAULT_OPTIONS
+        });
 
-    // Allocate a shelve to make sure we have a non-empty atlas to test the update.
-    atlas.allocate(size2(32, 32)).unwrap();
+        let mut allocations = Vec::new();
+        for i in 0..30 {
+            allocations.push(atlas.allocate(size2(5 + i, 10 + i % 7)).unwrap());
+        }
+        for alloc in allocations.iter().step_by(3) {
+            atlas.deallocate(alloc.id);
+        }
+        allocations.retain(|alloc| atlas.contains(alloc.id));
+        allocations.extend((0..30).filter_map(|i| atlas.allocate(size2(5 + i, 12))));
 
-    // Try to make a big allocation that doesn't fit.
-    let big_allocation = size2(256, 256);
+        let mut iterated: Vec<Allocation> = atlas.iter().collect();
+        assert_eq!(iterated.len(), allocations.len());
 
-    assert!(atlas.allocate(big_allocation).is_none());
+        allocations.sort_by_key(|alloc| alloc.id.serialize());
+        iterated.sort_by_key(|alloc| alloc.id.serialize());
+        assert_eq!(iterated, allocations);
+    }
+}
 
-    // Grow to make enough space for the wanted allocation plus the original shelf.
-    atlas.grow(size2(256, 32 + 256));
+#[cfg(feature = "serialization")]
+#[test]
+fn serialization() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(10, 20)).unwrap();
+    let b = atlas.allocate(size2(100, 30)).unwrap();
+
+    let serialized = ron::ser::to_string(&atlas).unwrap();
+    let mut deserialized: BucketedAtlasAllocator = ron::de::from_str(&serialized).unwrap();
+
+    assert_eq!(deserialized.get(a.id), a.rectangle);
+    assert_eq!(deserialized.get(b.id), b.rectangle);
+    assert_eq!(deserialized.allocated_space(), atlas.allocated_space());
+
+    let c = deserialized.allocate(size2(10, 20)).unwrap();
+    assert!(!c.rectangle.intersects(&a.rectangle));
+    deserialized.deallocate(a.id);
+    deserialized.deallocate(b.id);
+    deserialized.deallocate(c.id);
+    assert!(deserialized.is_empty());
+}
 
-    // Adding to the original shelf should succeed.
-    assert!(atlas.allocate(size2(32, 32)).is_some());
+#[test]
+fn svg_options() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    let a = atlas.allocate(size2(32, 32)).unwrap();
+    atlas.allocate(size2(32, 32)).unwrap();
 
-    // Big allocation should also succeed now.
-    assert!(atlas.allocate(big_allocation).is_some());
+    let label = |id: AllocId| if id == a.id { Some("glyph-a".to_string()) } else { None };
+    let options = SvgOptions {
+        allocated_color: svg_fmt::rgb(1, 2, 3),
+        label: Some(&label),
+        ..SvgOptions::default()
+    };
+    let mut output = Vec::new();
+    atlas.dump_into_svg_with_options(None, &options, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains(&svg_fmt::rgb(1, 2, 3).to_string()));
+    assert!(!output.contains(&SvgOptions::default().allocated_color.to_string()));
+    assert_eq!(output.matches("glyph-a").count(), 1);
+
+    // The default output is unchanged.
+    let mut default = Vec::new();
+    atlas.dump_into_svg(None, &mut default).unwrap();
+    assert!(String::from_utf8(default).unwrap().contains(&SvgOptions::default().allocated_color.to_string()));
 }
 
 #[test]
-fn columns() {
-    let mut atlas = BucketedAtlasAllocator::with_options(size2(64, 64), &AllocatorOptions {
-        num_columns: 2,
+fn reserve() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1024, 1024));
+    atlas.reserve(32, 256);
+    let (shelves, bins) = atlas.capacity();
+    assert!(shelves >= 32);
+    assert!(bins >= 256);
+
+    for _ in 0..32 {
+        atlas.allocate(size2(30, 30)).unwrap();
+    }
+    assert_eq!(atlas.capacity(), (shelves, bins));
+
+    // Reservations are capped.
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(1024, 1024), &AllocatorOptions {
+        max_shelves: Some(4),
         ..DEFAULT_OPTIONS
     });
+    atlas.reserve(1000, usize::MAX);
+    assert!(atlas.capacity().0 < 1000);
+}
 
-    let a = atlas.allocate(size2(24, 46)).unwrap();
-    let b = atlas.allocate(size2(24, 32)).unwrap();
-    let c = atlas.allocate(size2(24, 32)).unwrap();
-
-    fn in_range(val: i32, range: std::ops::Range<i32>) -> bool {
-        let ok = val >= range.start && val < range.end;
+#[test]
+fn with_options_and_capacity() {
+    // The capacities recommended in the documentation for 16x16 glyphs.
+    let mut atlas = BucketedAtlasAllocator::with_options_and_capacity(size2(1024, 1024), &DEFAULT_OPTIONS, 64, 256);
+    let capacity = atlas.capacity();
+    assert!(capacity.0 >= 64 && capacity.1 >= 256);
+
+    while atlas.allocate(size2(16, 16)).is_some() {}
+    assert_eq!(atlas.allocation_count(), 64 * 64);
+    assert_eq!(atlas.capacity(), capacity);
+}
 
-        if !ok {
-            println!("{:?} not in {:?}", val, range);
-        }
+#[test]
+fn large_atlas() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(70000, 1024));
 
-        ok
+    let mut allocations = Vec::new();
+    while let Some(alloc) = atlas.allocate(size2(1000, 300)) {
+        allocations.push(alloc);
     }
 
-    assert!(in_range(a.rectangle.min.x, 0..32));
-    assert!(in_range(a.rectangle.max.x, 0..32));
-    assert!(in_range(b.rectangle.min.x, 32..64));
-    assert!(in_range(b.rectangle.max.x, 32..64));
-    assert!(in_range(c.rectangle.min.x, 32..64));
-    assert!(in_range(c.rectangle.max.x, 32..64));
-
-    atlas.deallocate(b.id);
-    atlas.deallocate(c.id);
-    atlas.deallocate(a.id);
+    assert!(allocations.iter().any(|alloc| alloc.rectangle.max.x > 65535));
+    for (i, a) in allocations.iter().enumerate() {
+        assert!(Rectangle::from_size(atlas.size()).contains_box(&a.rectangle));
+        for b in &allocations[i + 1..] {
+            assert!(!a.rectangle.intersects(&b.rectangle));
+        }
+    }
 
+    for alloc in &allocations {
+        atlas.deallocate(alloc.id);
+    }
     assert!(atlas.is_empty());
-    assert_eq!(atlas.allocated_space(), 0);
-
-    let a = atlas.allocate(size2(24, 46)).unwrap();
-    let b = atlas.allocate(size2(24, 32)).unwrap();
-    let c = atlas.allocate(size2(24, 32)).unwrap();
-    let d = atlas.allocate(size2(24, 8)).unwrap();
 
-    assert_eq!(a.rectangle.min.x, 0);
-    assert_eq!(b.rectangle.min.x, 32);
-    assert_eq!(c.rectangle.min.x, 32);
-    assert_eq!(d.rectangle.min.x, 0);
+    let mut atlas = BucketedAtlasAllocator::new(size2(512, 100000));
+    let alloc = atlas.allocate(size2(512, 80000)).unwrap();
+    assert_eq!(alloc.rectangle.max.y, 80000);
 }
 
 #[test]
-fn vertical() {
-    let mut atlas = BucketedAtlasAllocator::with_options(size2(128, 256), &AllocatorOptions {
-        num_columns: 2,
-        vertical_shelves: true,
-        ..DEFAULT_OPTIONS
-    });
+fn set_alignment() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1024, 1024));
 
-    assert_eq!(atlas.size(), size2(128, 256));
+    let a = atlas.allocate(size2(32, 32)).unwrap();
+    atlas.set_alignment(size2(16, 16)).unwrap();
 
-    let a = atlas.allocate(size2(32, 16)).unwrap();
-    let b = atlas.allocate(size2(16, 32)).unwrap();
+    let mut allocations = Vec::new();
+    for &(w, h) in &[(10, 10), (23, 5), (7, 40), (100, 3), (1, 1), (33, 17)] {
+        let alloc = atlas.allocate(size2(w, h)).unwrap();
+        assert_eq!(alloc.rectangle.min.x % 16, 0);
+        assert_eq!(alloc.rectangle.min.y % 16, 0);
+        assert!(alloc.rectangle.width() >= w && alloc.rectangle.height() >= h);
+        allocations.push(alloc);
+    }
 
-    assert!(a.rectangle.size().width >= 32);
-    assert!(a.rectangle.size().height >= 16);
+    // Existing allocations are not moved.
+    assert_eq!(atlas.get(a.id), a.rectangle);
 
-    assert!(b.rectangle.size().width >= 16);
-    assert!(b.rectangle.size().height >= 32);
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+    assert_eq!(atlas.set_alignment(size2(16, 16)), Err(InvalidAlignment));
+    assert_eq!(atlas.set_alignment(size2(0, 8)), Err(InvalidAlignment));
+    assert_eq!(atlas.set_alignment(size2(8, 8)), Ok(()));
+}
 
-    let c = atlas.allocate(size2(128, 128)).unwrap();
+#[test]
+fn try_allocate_errors() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
 
-    atlas.deallocate(a.id);
-    atlas.deallocate(b.id);
-    atlas.deallocate(c.id);
+    assert_eq!(atlas.try_allocate(size2(0, 10)), Err(AllocError::EmptySize));
+    assert_eq!(atlas.try_allocate(size2(10, -1)), Err(AllocError::EmptySize));
+    assert_eq!(atlas.try_allocate(size2(10, 1001)), Err(AllocError::TooLarge));
+    assert_eq!(atlas.try_allocate(size2(i32::MAX, 10)), Err(AllocError::TooLarge));
 
-    assert!(atlas.is_empty());
-    assert_eq!(atlas.allocated_space(), 0);
+    let full = atlas.try_allocate(size2(1000, 1000)).unwrap();
+    assert_eq!(atlas.try_allocate(size2(100, 100)), Err(AllocError::OutOfSpace));
+    atlas.deallocate(full.id);
+    assert!(atlas.try_allocate(size2(100, 100)).is_ok());
 }
 
 #[test]
-fn clear() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(2048, 2048));
+fn allocate_with_id() {
+    let options = AllocatorOptions { stable_ids: true, ..DEFAULT_OPTIONS };
+    let mut a = BucketedAtlasAllocator::with_options(size2(256, 256), &options);
+    let mut b = BucketedAtlasAllocator::with_options(size2(256, 256), &options);
 
-    // Run a workload a few hundred times to make sure clearing properly resets everything.
-    for _ in 0..500 {
-        atlas.clear();
-        assert_eq!(atlas.allocated_space(), 0);
+    let sizes = [size2(10, 10), size2(30, 20), size2(100, 50), size2(10, 10), size2(64, 64), size2(20, 20)];
+    let mut allocations = Vec::new();
+    for size in &sizes[..5] {
+        allocations.push(a.allocate(*size).unwrap());
+    }
+    a.deallocate(allocations[1].id);
+    allocations.push(a.allocate(sizes[5]).unwrap());
 
-        atlas.allocate(size2(8, 2)).unwrap();
-        atlas.allocate(size2(2, 8)).unwrap();
-        atlas.allocate(size2(16, 512)).unwrap();
-        atlas.allocate(size2(34, 34)).unwrap();
-        atlas.allocate(size2(34, 34)).unwrap();
-        atlas.allocate(size2(34, 34)).unwrap();
-        atlas.allocate(size2(34, 34)).unwrap();
-        atlas.allocate(size2(2, 8)).unwrap();
-        atlas.allocate(size2(2, 8)).unwrap();
-        atlas.allocate(size2(8, 2)).unwrap();
-        atlas.allocate(size2(2, 8)).unwrap();
-        atlas.allocate(size2(8, 2)).unwrap();
-        atlas.allocate(size2(8, 8)).unwrap();
-        atlas.allocate(size2(8, 8)).unwrap();
-        atlas.allocate(size2(8, 8)).unwrap();
-        atlas.allocate(size2(8, 8)).unwrap();
-        atlas.allocate(size2(82, 80)).unwrap();
-        atlas.allocate(size2(56, 56)).unwrap();
-        atlas.allocate(size2(64, 66)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(40, 40)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(155, 52)).unwrap();
-        atlas.allocate(size2(256, 52)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(24, 24)).unwrap();
-        atlas.allocate(size2(64, 64)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(84, 84)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(8, 2)).unwrap();
-        atlas.allocate(size2(34, 34)).unwrap();
-        atlas.allocate(size2(34, 34)).unwrap();
-        atlas.allocate(size2(192, 192)).unwrap();
-        atlas.allocate(size2(192, 192)).unwrap();
-        atlas.allocate(size2(52, 52)).unwrap();
-        atlas.allocate(size2(144, 144)).unwrap();
-        atlas.allocate(size2(192, 192)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(144, 144)).unwrap();
-        atlas.allocate(size2(24, 24)).unwrap();
-        atlas.allocate(size2(192, 192)).unwrap();
-        atlas.allocate(size2(192, 192)).unwrap();
-        atlas.allocate(size2(432, 243)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
-        atlas.allocate(size2(8, 2)).unwrap();
-        atlas.allocate(size2(2, 8)).unwrap();
-        atlas.allocate(size2(9, 9)).unwrap();
-        atlas.allocate(size2(14, 14)).unwrap();
-        atlas.allocate(size2(14, 14)).unwrap();
-        atlas.allocate(size2(14, 14)).unwrap();
-        atlas.allocate(size2(14, 14)).unwrap();
-        atlas.allocate(size2(8, 8)).unwrap();
-        atlas.allocate(size2(27, 27)).unwrap();
-        atlas.allocate(size2(27, 27)).unwrap();
-        atlas.allocate(size2(27, 27)).unwrap();
-        atlas.allocate(size2(27, 27)).unwrap();
-        atlas.allocate(size2(11, 12)).unwrap();
-        atlas.allocate(size2(29, 28)).unwrap();
-        atlas.allocate(size2(32, 32)).unwrap();
+    // Replay the same operations on the other atlas with the ids chosen by the first one.
+    for (size, alloc) in sizes[..5].iter().zip(&allocations) {
+        assert_eq!(b.allocate_with_id(*size, alloc.id), Some(*alloc));
     }
-}
+    b.deallocate(allocations[1].id);
+    assert_eq!(b.allocate_with_id(sizes[5], allocations[5].id), Some(allocations[5]));
+
+    // Live ids can't be reused.
+    assert!(b.allocate_with_id(size2(10, 10), allocations[0].id).is_none());
+
+    // Ids can be handed out in any order.
+    let mut c = BucketedAtlasAllocator::with_options(size2(256, 256), &options);
+    let id = AllocId::deserialize(5 | 3 << 24);
+    let alloc = c.allocate_with_id(size2(10, 10), id).unwrap();
+    assert_eq!(alloc.id, id);
+    assert_eq!(c.get(id), alloc.rectangle);
+    let other = c.allocate(size2(10, 10)).unwrap();
+    assert_ne!(other.id, id);
+    c.deallocate(id);
+    c.deallocate(other.id);
+    assert!(c.is_empty());
+
+    // Large ids don't create the slots before them.
+    let far = AllocId::deserialize(0x00FF_FFF0);
+    let alloc = c.allocate_with_id(size2(10, 10), far).unwrap();
+    assert_eq!(c.get(far), alloc.rectangle);
+    c.deallocate(far);
+    let alloc = c.allocate_with_id(size2(10, 10), far).unwrap();
+    assert_eq!(c.get(far), alloc.rectangle);
+
+    // Free slots can be picked in any order.
+    let ids: Vec<AllocId> = (0..8).map(|_| c.allocate(size2(10, 10)).unwrap().id).collect();
+    for id in &ids {
+        c.deallocate(*id);
+    }
+    for id in ids.iter().skip(3).chain(&ids[..3]) {
+        assert!(c.allocate_with_id(size2(10, 10), *id).is_some());
+    }
+    assert!(c.allocate(size2(10, 10)).is_some());
+    assert_eq!(c.iter().count(), 10);
+
+    // Ids handed out by allocate skip the ones that were chosen.
+    let mut d = BucketedAtlasAllocator::with_options(size2(256, 256), &options);
+    let chosen = AllocId::deserialize(2);
+    d.allocate_with_id(size2(10, 10), chosen).unwrap();
+    let ids: Vec<u32> = (0..3).map(|_| d.allocate(size2(10, 10)).unwrap().id.serialize()).collect();
+    assert_eq!(ids, [0, 1, 3]);
+    d.deallocate(chosen);
+    assert_eq!(d.allocate(size2(10, 10)).unwrap().id.serialize(), 2 | 1 << 24);
 
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    assert!(atlas.allocate_with_id(size2(10, 10), id).is_none());
+}
 
 #[test]
-fn fuzz_01() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+fn coalesce_top_shelves() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
 
-    assert!(atlas.allocate(size2(65280, 1)).is_none());
-    assert!(atlas.allocate(size2(1, 65280)).is_none());
-}
+    let mut allocations = Vec::new();
+    for _ in 0..8 {
+        allocations.push(atlas.allocate(size2(256, 32)).unwrap());
+    }
+    assert!(atlas.allocate(size2(256, 32)).is_none());
 
-#[test]
-fn fuzz_02() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+    // Leave the top shelves empty without cleaning them up so that the coalescing scan
+    // starts right below the end of the shelf list.
+    atlas.deallocate_from_bucket(allocations[6].id);
+    atlas.deallocate_from_bucket(allocations[7].id);
 
-    assert!(atlas.allocate(size2(255, 65599)).is_none());
+    assert!(atlas.allocate(size2(256, 96)).is_none());
+
+    let tall = atlas.allocate(size2(256, 64)).unwrap();
+    assert_eq!(tall.rectangle.min.y, 192);
 }
 
 #[test]
-fn fuzz_03() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
-
-    let sizes = &[
-        size2(999, 128),
-        size2(168492810, 10),
-        size2(45, 96),
-        size2(-16711926, 0),
-    ];
-
-    let mut allocations = Vec::new();
-    let mut allocated_space = 0;
+fn get_matches_allocation() {
+    // For any live id, get returns the rectangle returned when allocating.
+    let options = AllocatorOptions { num_columns: 2, ..DEFAULT_OPTIONS };
+    let mut atlas = BucketedAtlasAllocator::with_options(size2(512, 512), &options);
+    random_operations(&mut atlas, 29, 3000, 60, |atlas, allocations, _| {
+        for alloc in allocations {
+            assert_eq!(atlas.get(alloc.id), alloc.rectangle);
+        }
+    });
+}
 
-    for size in sizes {
-        if let Some(alloc) = atlas.allocate(*size) {
-            allocations.push(alloc);
-            allocated_space += alloc.rectangle.area();
-            assert_eq!(allocated_space, atlas.allocated_space());
+#[test]
+fn placement() {
+    let first_fit = AllocatorOptions { placement: Placement::FirstFit, ..DEFAULT_OPTIONS };
+
+    for options in &[DEFAULT_OPTIONS, first_fit] {
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(256, 256), options);
+        // Leave a bit of room in the tall shelf.
+        let tall = atlas.allocate(size2(250, 60)).unwrap();
+        let short = atlas.allocate(size2(100, 44)).unwrap();
+        assert!(short.rectangle.min.y >= tall.rectangle.max.y);
+
+        let alloc = atlas.allocate(size2(4, 40)).unwrap();
+        if options.placement == Placement::FirstFit {
+            assert_eq!(alloc.rectangle.min.y, tall.rectangle.min.y);
+        } else {
+            assert_eq!(alloc.rectangle.min.y, short.rectangle.min.y);
         }
     }
+}
 
-    for alloc in &allocations {
-        atlas.deallocate(alloc.id);
+#[test]
+fn force_pow2() {
+    let options = AllocatorOptions { force_pow2: true, ..DEFAULT_OPTIONS };
 
-        allocated_space -= alloc.rectangle.area();
-        assert_eq!(allocated_space, atlas.allocated_space());
-    }
+    let atlas = BucketedAtlasAllocator::with_options(size2(1000, 1000), &options);
+    assert_eq!(atlas.size(), size2(1024, 1024));
+    assert!(atlas.is_pow2());
 
-    assert_eq!(atlas.allocated_space(), 0);
+    let atlas = BucketedAtlasAllocator::new(size2(1024, 1000));
+    assert!(!atlas.is_pow2());
 }
 
 #[test]
-fn fuzz_04() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(1000, 1000));
+fn padding() {
+    let padding = 2;
+    for &vertical_shelves in &[false, true] {
+        let options = AllocatorOptions { padding, vertical_shelves, ..DEFAULT_OPTIONS };
+        let mut atlas = BucketedAtlasAllocator::with_options(size2(512, 512), &options);
+        let bounds = Rectangle::from_size(atlas.size());
+        let allocations = random_operations(&mut atlas, 7, 2000, 40, |_, allocations, requested| {
+            if let (Some(size), Some((alloc, previous))) = (requested, allocations.split_last()) {
+                assert!(alloc.rectangle.width() >= size.width);
+                assert!(alloc.rectangle.height() >= size.height);
+
+                let padded = alloc.rectangle.inflate(padding, padding);
+                assert!(bounds.contains_box(&padded));
+                for other in previous {
+                    assert!(!padded.intersects(&other.rectangle.inflate(padding, padding)));
+                }
+            }
+        });
 
-    assert!(atlas.allocate(size2(2560, 2147483647)).is_none());
+        for alloc in &allocations {
+            assert_eq!(atlas.get(alloc.id), alloc.rectangle);
+        }
+        assert_eq!(atlas.iter().count(), allocations.len());
+        assert!(atlas.iter().all(|alloc| atlas.get(alloc.id) == alloc.rectangle));
+    }
 }
 
 #[test]
-fn fuzz_05() {
-    let mut atlas = BucketedAtlasAllocator::new(size2(2048, 2048));
+fn debug() {
+    use alloc::format;
 
-    assert!(atlas.allocate(size2(0, -1978597547)).is_none());
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    atlas.allocate(size2(64, 32)).unwrap();
+    let output = format!("{:?}", atlas);
+    assert!(output.starts_with("BucketedAtlasAllocator { size: 256x256, columns: 1 x 256,"));
+    assert!(output.contains("shelves: 1, allocations: 1"));
+    assert_eq!(output.lines().count(), 2);
+
+    // The output is bounded for large atlases.
+    let mut atlas = BucketedAtlasAllocator::new(size2(1024, 1024));
+    while atlas.allocate(size2(16, 16)).is_some() {}
+    assert!(atlas.shelves.len() > DEBUG_MAX_SHELVES);
+    let output = format!("{:?}", atlas);
+    assert_eq!(output.lines().count(), DEBUG_MAX_SHELVES + 2);
+    assert!(output.lines().all(|line| line.len() < 200));
+}
+
+#[test]
+fn dump_ascii() {
+    let mut atlas = BucketedAtlasAllocator::new(size2(64, 32));
+    atlas.allocate(size2(32, 8)).unwrap();
+    atlas.allocate(size2(16, 8)).unwrap();
+    atlas.allocate(size2(16, 16)).unwrap();
+
+    let mut output = String::new();
+    atlas.dump_ascii(size2(4, 4), &mut output);
+    assert_eq!(output, concat!(
+        "############....\n",
+        "############....\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "@@@@............\n",
+        "................\n",
+        "................\n",
+    ));
 }
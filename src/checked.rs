@@ -0,0 +1,188 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{AtlasAllocator, BucketedAtlasAllocator, Allocation, AllocId, Size, Rectangle};
+
+/// Error returned by the methods of a [`CheckedAtlasAllocator`](struct.CheckedAtlasAllocator.html).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CheckedError {
+    /// The operation panicked.
+    ///
+    /// The wrapped allocator may have been left in an inconsistent state, so it is
+    /// poisoned and all subsequent operations fail.
+    Panicked,
+    /// A previous operation panicked and the wrapped allocator can't be used anymore.
+    Poisoned,
+}
+
+impl std::fmt::Display for CheckedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckedError::Panicked => write!(f, "the atlas allocator panicked"),
+            CheckedError::Poisoned => write!(f, "the atlas allocator is poisoned by a previous panic"),
+        }
+    }
+}
+
+impl std::error::Error for CheckedError {}
+
+/// Wraps an atlas allocator and converts panics into errors.
+///
+/// Allocators assert that the ids they are given are valid and panic otherwise (deallocating
+/// an invalid id only panics in debug builds). This is a problem when the allocator is used
+/// behind an FFI boundary, where unwinding is undefined behavior. The methods of this wrapper
+/// catch panics and report them as errors instead.
+///
+/// After a panic the wrapped allocator is considered poisoned: it may be in an inconsistent
+/// state so every subsequent operation returns `CheckedError::Poisoned`. The recommended way to
+/// recover is to drop the atlas and start over with a new one.
+///
+/// Note that this only works if the crate is compiled with `panic = "unwind"` (the default),
+/// and that the panic hook still runs (printing the panic message by default).
+pub struct CheckedAtlasAllocator<A> {
+    allocator: A,
+    poisoned: bool,
+}
+
+impl<A> CheckedAtlasAllocator<A> {
+    /// Wrap an existing allocator.
+    pub fn new(allocator: A) -> Self {
+        CheckedAtlasAllocator {
+            allocator,
+            poisoned: false,
+        }
+    }
+
+    /// Returns true if a previous operation panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Access the wrapped allocator, unless it is poisoned.
+    pub fn get_ref(&self) -> Result<&A, CheckedError> {
+        if self.poisoned {
+            return Err(CheckedError::Poisoned);
+        }
+
+        Ok(&self.allocator)
+    }
+
+    /// Unwrap the allocator, unless it is poisoned.
+    pub fn into_inner(self) -> Result<A, CheckedError> {
+        if self.poisoned {
+            return Err(CheckedError::Poisoned);
+        }
+
+        Ok(self.allocator)
+    }
+
+    fn call<R>(&mut self, op: impl FnOnce(&mut A) -> R) -> Result<R, CheckedError> {
+        if self.poisoned {
+            return Err(CheckedError::Poisoned);
+        }
+
+        let allocator = &mut self.allocator;
+        match catch_unwind(AssertUnwindSafe(move || op(allocator))) {
+            Ok(result) => Ok(result),
+            Err(..) => {
+                self.poisoned = true;
+                Err(CheckedError::Panicked)
+            }
+        }
+    }
+
+    fn call_ref<R>(&self, op: impl FnOnce(&A) -> R) -> Result<R, CheckedError> {
+        if self.poisoned {
+            return Err(CheckedError::Poisoned);
+        }
+
+        // A panic in a read-only method can't have modified the allocator so we don't
+        // need to poison it.
+        catch_unwind(AssertUnwindSafe(|| op(&self.allocator))).map_err(|_| CheckedError::Panicked)
+    }
+}
+
+macro_rules! impl_checked_allocator {
+    ($Allocator:ty) => {
+        impl CheckedAtlasAllocator<$Allocator> {
+            /// Allocate a rectangle in the atlas.
+            pub fn allocate(&mut self, size: Size) -> Result<Option<Allocation>, CheckedError> {
+                self.call(|atlas| atlas.allocate(size))
+            }
+
+            /// Deallocate a rectangle in the atlas.
+            pub fn deallocate(&mut self, id: AllocId) -> Result<(), CheckedError> {
+                self.call(|atlas| atlas.deallocate(id))
+            }
+
+            pub fn clear(&mut self) -> Result<(), CheckedError> {
+                self.call(|atlas| atlas.clear())
+            }
+
+            pub fn is_empty(&self) -> Result<bool, CheckedError> {
+                self.call_ref(|atlas| atlas.is_empty())
+            }
+
+            pub fn size(&self) -> Result<Size, CheckedError> {
+                self.call_ref(|atlas| atlas.size())
+            }
+
+            /// Amount of occupied space in the atlas.
+            pub fn allocated_space(&self) -> Result<i32, CheckedError> {
+                self.call_ref(|atlas| atlas.allocated_space())
+            }
+
+            /// How much space is available for future allocations.
+            pub fn free_space(&self) -> Result<i32, CheckedError> {
+                self.call_ref(|atlas| atlas.free_space())
+            }
+
+            /// Returns the allocation info associated to the allocation ID.
+            pub fn get(&self, id: AllocId) -> Result<Rectangle, CheckedError> {
+                self.call_ref(|atlas| atlas.get(id))
+            }
+        }
+    };
+}
+
+impl_checked_allocator!(AtlasAllocator);
+impl_checked_allocator!(BucketedAtlasAllocator);
+
+impl CheckedAtlasAllocator<BucketedAtlasAllocator> {
+    pub fn grow(&mut self, new_size: Size) -> Result<Vec<(AllocId, Rectangle)>, CheckedError> {
+        self.call(|atlas| atlas.grow(new_size))
+    }
+}
+
+#[test]
+fn checked_invalid_ids() {
+    use crate::size2;
+
+    let mut atlas = CheckedAtlasAllocator::new(BucketedAtlasAllocator::new(size2(256, 256)));
+
+    let a = atlas.allocate(size2(32, 32)).unwrap().unwrap();
+    assert_eq!(atlas.deallocate(a.id), Ok(()));
+    assert!(atlas.is_empty().unwrap());
+
+    // Deallocating the same id twice panics in the allocator (in debug builds).
+    if cfg!(debug_assertions) {
+        assert_eq!(atlas.deallocate(a.id), Err(CheckedError::Panicked));
+        assert!(atlas.is_poisoned());
+        assert_eq!(atlas.allocate(size2(32, 32)), Err(CheckedError::Poisoned));
+        assert!(atlas.into_inner().is_err());
+    }
+
+    let mut atlas = CheckedAtlasAllocator::new(AtlasAllocator::new(size2(256, 256)));
+
+    let a = atlas.allocate(size2(32, 32)).unwrap().unwrap();
+    assert_eq!(atlas.get(a.id), Ok(a.rectangle));
+    assert_eq!(atlas.deallocate(a.id), Ok(()));
+
+    // Querying a stale id panics but doesn't poison the allocator.
+    assert_eq!(atlas.get(a.id), Err(CheckedError::Panicked));
+    assert!(!atlas.is_poisoned());
+
+    if cfg!(debug_assertions) {
+        assert_eq!(atlas.deallocate(a.id), Err(CheckedError::Panicked));
+        assert_eq!(atlas.clear(), Err(CheckedError::Poisoned));
+    }
+}
@@ -46,6 +46,7 @@ pub unsafe extern "C" fn etagere_atlas_allocator_with_options(
         alignment: size2(options.width_alignment, options.height_alignment),
         num_columns: options.num_columns,
         vertical_shelves: (options.flags & ETAGERE_FLAGS_VERTICAL_SHELVES) != 0,
+        ..crate::DEFAULT_OPTIONS
     };
     Box::into_raw(Box::new(AtlasAllocator::with_options(size2(width, height), &options)))
 }
@@ -67,7 +68,7 @@ pub unsafe extern "C" fn etagere_atlas_allocator_allocate(allocator: &mut AtlasA
             return 1;
         }
         None => {
-            allocation.id = std::u32::MAX;
+            allocation.id = u32::MAX;
             allocation.rectangle.min_x = 0;
             allocation.rectangle.min_y = 0;
             allocation.rectangle.max_x = 0;
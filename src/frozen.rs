@@ -0,0 +1,94 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{AllocId, Allocation, Rectangle, Size};
+
+/// Error returned when validating the allocations or the internal state of an atlas.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ValidationError {
+    /// Two allocations overlap.
+    Overlap(AllocId, AllocId),
+    /// An allocation is not entirely within the atlas.
+    OutOfBounds(AllocId),
+    /// An internal invariant of the atlas doesn't hold, with a description of the violation.
+    InvalidState(String),
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ValidationError::Overlap(a, b) => write!(f, "allocations {} and {} overlap", a.serialize(), b.serialize()),
+            ValidationError::OutOfBounds(id) => write!(f, "allocation {} is outside of the atlas", id.serialize()),
+            ValidationError::InvalidState(message) => write!(f, "invalid atlas state: {}", message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationError {}
+
+/// A read-only snapshot of the allocations of an atlas, optimized for lookups.
+///
+/// Created with [`AtlasAllocator::finalize`](struct.AtlasAllocator.html#method.finalize) once
+/// an atlas is done being built. The allocations keep their ids, and looking them up is a
+/// single array access.
+#[derive(Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct FrozenAtlas<T = ()> {
+    size: Size,
+    // Indexed by the index of the ids.
+    allocations: Vec<Option<Allocation>>,
+    data: Vec<T>,
+    len: usize,
+}
+
+impl<T> FrozenAtlas<T> {
+    pub(crate) fn new(size: Size, allocations: Vec<Option<Allocation>>, data: Vec<T>) -> Self {
+        debug_assert_eq!(allocations.len(), data.len());
+        let len = allocations.iter().filter(|alloc| alloc.is_some()).count();
+
+        FrozenAtlas {
+            size,
+            allocations,
+            data,
+            len,
+        }
+    }
+
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Number of allocations.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the rectangle of an allocation, or `None` if the id doesn't refer to one.
+    pub fn get(&self, id: AllocId) -> Option<Rectangle> {
+        Some(self.get_allocation(id)?.rectangle)
+    }
+
+    /// Returns the user data of an allocation, or `None` if the id doesn't refer to one.
+    pub fn get_data(&self, id: AllocId) -> Option<&T> {
+        self.get_allocation(id)?;
+
+        Some(&self.data[id.index() as usize])
+    }
+
+    /// Iterate over the allocations, in increasing id index order.
+    pub fn iter(&self) -> impl Iterator<Item = Allocation> + '_ {
+        self.allocations.iter().filter_map(|alloc| *alloc)
+    }
+
+    fn get_allocation(&self, id: AllocId) -> Option<&Allocation> {
+        self.allocations
+            .get(id.index() as usize)?
+            .as_ref()
+            .filter(|alloc| alloc.id == id)
+    }
+}
@@ -0,0 +1,224 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::{AllocId, DeallocError};
+
+const SLOT_BITS: u32 = 24;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+const MAX_SLOT_COUNT: usize = SLOT_MASK as usize;
+const NOT_FREE: u32 = u32::MAX;
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+struct Slot<V> {
+    value: V,
+    generation: u8,
+    allocated: bool,
+    // Position in `free_slots`, or `NOT_FREE`.
+    free_index: u32,
+}
+
+/// A table handing out ids for values, so that ids stay valid when the values change.
+///
+/// Ids are made of a 24 bits slot index and an 8 bits generation that is incremented each
+/// time a slot is reused.
+#[derive(Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub(crate) struct IdTable<V> {
+    slots: Vec<Slot<V>>,
+    free_slots: Vec<u32>,
+    // Slots handed out by `insert_at` past the end of `slots`. They are moved to `slots` when
+    // it reaches them, so that choosing a large id doesn't create all of the slots before it.
+    sparse_slots: BTreeMap<u32, Slot<V>>,
+}
+
+impl<V: Copy> IdTable<V> {
+    pub fn new() -> Self {
+        IdTable {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            sparse_slots: BTreeMap::new(),
+        }
+    }
+
+    /// Returns false if no more ids can be handed out.
+    pub fn has_room(&self) -> bool {
+        self.has_room_for(1)
+    }
+
+    /// Returns false if fewer than `count` ids can be handed out.
+    pub fn has_room_for(&self, count: usize) -> bool {
+        self.free_slots.len() + (MAX_SLOT_COUNT - self.slots.len() - self.sparse_slots.len()) >= count
+    }
+
+    /// The caller must first check `has_room`.
+    pub fn insert(&mut self, value: V) -> AllocId {
+        let index = match self.free_slots.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = value;
+                slot.generation = slot.generation.wrapping_add(1);
+                slot.allocated = true;
+                slot.free_index = NOT_FREE;
+                index
+            }
+            None => loop {
+                debug_assert!(self.slots.len() < MAX_SLOT_COUNT);
+                let index = self.slots.len() as u32;
+                let slot = match self.sparse_slots.remove(&index) {
+                    Some(slot) if slot.allocated => {
+                        self.slots.push(slot);
+                        continue;
+                    }
+                    Some(slot) => Slot {
+                        value,
+                        generation: slot.generation.wrapping_add(1),
+                        allocated: true,
+                        free_index: NOT_FREE,
+                    },
+                    None => Slot {
+                        value,
+                        generation: 0,
+                        allocated: true,
+                        free_index: NOT_FREE,
+                    },
+                };
+                self.slots.push(slot);
+                break index;
+            }
+        };
+
+        let generation = self.slots[index as usize].generation as u32;
+
+        AllocId(index | generation << SLOT_BITS)
+    }
+
+    /// Returns true if `insert_at` can hand out this id.
+    pub fn is_available(&self, id: AllocId) -> bool {
+        let index = id.0 & SLOT_MASK;
+        match self.slot(index) {
+            Some(slot) => !slot.allocated,
+            None => (index as usize) < MAX_SLOT_COUNT && self.has_room(),
+        }
+    }
+
+    /// Hand out a specific id. The caller must first check `is_available`.
+    pub fn insert_at(&mut self, id: AllocId, value: V) {
+        debug_assert!(self.is_available(id));
+
+        let index = id.0 & SLOT_MASK;
+        let slot = Slot {
+            value,
+            generation: (id.0 >> SLOT_BITS) as u8,
+            allocated: true,
+            free_index: NOT_FREE,
+        };
+
+        match self.slots.get(index as usize) {
+            Some(&Slot { free_index, .. }) => {
+                self.free_slots.swap_remove(free_index as usize);
+                if let Some(&moved) = self.free_slots.get(free_index as usize) {
+                    self.slots[moved as usize].free_index = free_index;
+                }
+                self.slots[index as usize] = slot;
+            }
+            None => {
+                self.sparse_slots.insert(index, slot);
+            }
+        }
+    }
+
+    pub fn get(&self, id: AllocId) -> V {
+        self.slot(self.slot_index(id)).unwrap().value
+    }
+
+    /// Returns the value of the id, or the reason why the id isn't in use.
+    pub fn validate(&self, id: AllocId) -> Result<V, DeallocError> {
+        let slot = self.slot(id.0 & SLOT_MASK).ok_or(DeallocError::NotAllocated)?;
+        if slot.generation != (id.0 >> SLOT_BITS) as u8 {
+            return Err(DeallocError::StaleGeneration);
+        }
+        if !slot.allocated {
+            return Err(DeallocError::NotAllocated);
+        }
+
+        Ok(slot.value)
+    }
+
+    pub fn remove(&mut self, id: AllocId) -> V {
+        let index = self.slot_index(id);
+        match self.slots.get_mut(index as usize) {
+            Some(slot) => {
+                slot.allocated = false;
+                slot.free_index = self.free_slots.len() as u32;
+                self.free_slots.push(index);
+
+                slot.value
+            }
+            None => {
+                // Keep the generation until `slots` reaches this index.
+                let slot = self.sparse_slots.get_mut(&index).unwrap();
+                slot.allocated = false;
+
+                slot.value
+            }
+        }
+    }
+
+    /// Iterate over the ids that are in use along with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (AllocId, V)> + '_ {
+        let dense = self.slots.iter().enumerate().map(|(index, slot)| (index as u32, slot));
+        let sparse = self.sparse_slots.iter().map(|(&index, slot)| (index, slot));
+        dense.chain(sparse).filter(|(_, slot)| slot.allocated).map(|(index, slot)| {
+            (AllocId(index | (slot.generation as u32) << SLOT_BITS), slot.value)
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.free_slots.clear();
+        self.sparse_slots.clear();
+    }
+
+    fn slot(&self, index: u32) -> Option<&Slot<V>> {
+        match self.slots.get(index as usize) {
+            Some(slot) => Some(slot),
+            None => self.sparse_slots.get(&index),
+        }
+    }
+
+    fn slot_index(&self, id: AllocId) -> u32 {
+        let index = id.0 & SLOT_MASK;
+        let generation = (id.0 >> SLOT_BITS) as u8;
+
+        let slot = self.slot(index).unwrap();
+        assert!(slot.allocated);
+        assert_eq!(slot.generation, generation, "Invalid AllocId");
+
+        index
+    }
+}
+
+impl IdTable<AllocId> {
+    /// Build a map from the values to the ids that refer to them.
+    pub fn external_ids(&self) -> ExternalIds {
+        let mut ids: Vec<(u32, AllocId)> = self.iter().map(|(id, value)| (value.0, id)).collect();
+        ids.sort_unstable_by_key(|&(value, _)| value);
+
+        ExternalIds { ids }
+    }
+}
+
+/// See `IdTable::external_ids`.
+pub(crate) struct ExternalIds {
+    ids: Vec<(u32, AllocId)>,
+}
+
+impl ExternalIds {
+    /// The id referring to a value. Panics if there is none.
+    pub fn get(&self, value: AllocId) -> AllocId {
+        let index = self.ids.binary_search_by_key(&value.0, |&(value, _)| value).unwrap();
+
+        self.ids[index].1
+    }
+}
@@ -23,7 +23,8 @@
 //! - [`AtlasAllocator`] Tracks allocations for each individual item and does a reasonable
 //!   job of dealing with fragmentation, at a runtime cost.
 //! - [`BucketedAtlasAllocator`] groups items by buckets and only reclaim the space occupied
-//!   by a bucket when all of its items are deallocated. In addition, it has limited support
+//!   by an item when all items allocated after it in the same bucket are deallocated (typically
+//!   when the whole bucket is empty). In addition, it has limited support
 //!   for merging consecutive empty shelves. These limitations allow faster allocation and
 //!   deallocation, making it an appealing option when the atlas is expected to hold a very
 //!   large amount of small items.
@@ -41,6 +42,12 @@
 //! In addition, this repository contains a command-line application to experiment with and
 //! test the implementations interactively.
 //!
+//! ## `no_std`
+//!
+//! Disabling the default `std` feature makes the crate `no_std`, only depending on `alloc`.
+//! The SVG output, the `CheckedAtlasAllocator` and the implementations of `std::error::Error`
+//! require `std`. Without it, `euclid` needs its `libm` feature to be enabled.
+//!
 //! ## Example
 //!
 //! ```rust
@@ -73,20 +80,46 @@
 //! [`AtlasAllocator`]: struct.AtlasAllocator.html
 //! [`BucketedAtlasAllocator`]: struct.BucketedAtlasAllocator.html
 
-#[cfg(feature = "serde")]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "serialization")]
 #[macro_use]
 pub extern crate serde;
 pub extern crate euclid;
+#[cfg(feature = "std")]
+pub extern crate svg_fmt;
 
 mod bucketed;
 mod allocator;
+mod atlas_list;
+#[cfg(feature = "std")]
+mod checked;
+mod frozen;
+mod id_table;
+mod multi;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
+mod typed;
+#[cfg(feature = "png")]
+mod png;
 #[cfg(feature = "ffi")]
 pub mod ffi;
 
 pub use allocator::*;
+pub use atlas_list::*;
 pub use bucketed::*;
+#[cfg(feature = "std")]
+pub use checked::*;
+pub use frozen::*;
+pub use multi::*;
+pub use typed::*;
 pub use euclid::{point2, size2};
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
 pub type Point = euclid::default::Point2D<i32>;
 pub type Size = euclid::default::Size2D<i32>;
 pub type Rectangle = euclid::default::Box2D<i32>;
@@ -107,14 +140,181 @@ pub struct AllocatorOptions {
     ///
     /// Having multiple columns allows having more (smaller shelves).
     ///
+    /// The `BucketedAtlasAllocator` places the items that are too wide for a column on shelves
+    /// spanning all columns, at the top of the atlas. These shelves reduce the height of every
+    /// column, and can only be added as long as the first column isn't full. They are not
+    /// supported with `tile_size`.
+    ///
     /// Default value: 1.
     pub num_columns: i32,
+    /// Maximum number of shelves.
+    ///
+    /// The cost of an allocation grows with the number of shelves, so limiting it bounds
+    /// the worst-case allocation time at the expense of failing allocations with
+    /// `AllocError::CapacityExhausted` once the limit is reached.
+    ///
+    /// Only used by the `BucketedAtlasAllocator`.
+    ///
+    /// Default value: None (no limit).
+    pub max_shelves: Option<u16>,
+    /// Hand out ids that don't depend on the internal layout of the atlas.
+    ///
+    /// By default allocation ids encode the index of the bin containing the allocation, so
+    /// any operation moving bins around has to invalidate them. With this option, ids refer
+    /// to entries of an indirection table mapping them to the internal bins, which costs 8
+    /// bytes of memory per allocation slot (slots are reused after deallocation) and
+    /// limits the number of live allocations to 2^24 - 1.
+    ///
+    /// Only used by the `BucketedAtlasAllocator`.
+    ///
+    /// Default value: false.
+    pub stable_ids: bool,
+    /// Allocate a region at the origin of the atlas when creating or clearing it.
+    ///
+    /// This is meant for renderers that need a known texel (typically white) for solid
+    /// color draws. The region is one pixel, rounded up to the alignment. Its rectangle is
+    /// provided by `AtlasAllocator::white_pixel`.
+    ///
+    /// Only used by the `AtlasAllocator`.
+    ///
+    /// Default value: false.
+    pub reserve_white_pixel: bool,
+    /// Give all shelves the same height.
+    ///
+    /// When set, every shelf is exactly this tall (along the y axis, or the x axis if
+    /// `vertical_shelves` is set). Allocations that are taller fail and smaller ones take the
+    /// whole height of a shelf. This avoids wasting space when the content has a uniform
+    /// height, for example in an icon strip. Rectangles allocated at a specific position are
+    /// not affected.
+    ///
+    /// Default value: None.
+    pub fixed_shelf_height: Option<u16>,
+    /// Avoid placing allocations across the boundaries of a grid of tiles of this size.
+    ///
+    /// This is useful with hardware tiled textures, where sampling is less efficient when a
+    /// rectangle straddles a tile boundary. Allocations that fit within a tile are placed so
+    /// that they don't cross one, while larger allocations necessarily span several tiles and
+    /// are aligned to tile boundaries instead. This affects both the position of items within
+    /// bins and the position of the shelves, at the cost of some wasted space. Empty shelves
+    /// are not coalesced and items can't span multiple columns when this is set.
+    ///
+    /// The width and height of the tiles must be positive.
+    ///
+    /// Only used by the `BucketedAtlasAllocator`.
+    ///
+    /// Default value: None.
+    pub tile_size: Option<Size>,
+    /// How the bits of allocation ids are split between the bin index and the item index.
+    ///
+    /// Only used by the `BucketedAtlasAllocator`.
+    ///
+    /// Default value: `IdLayout::Balanced`.
+    pub id_layout: IdLayout,
+    /// How to choose the shelf an item is allocated in.
+    ///
+    /// Only used by the `BucketedAtlasAllocator`.
+    ///
+    /// Default value: `Placement::BestFit`.
+    pub placement: Placement,
+    /// Round the size of the atlas up to the next power of two when creating it.
+    ///
+    /// Many GPU texture formats and mipmap chains require power-of-two dimensions. The rounded
+    /// size is returned by `size()` and must respect the size limits of the allocator.
+    ///
+    /// Default value: false.
+    pub force_pow2: bool,
+    /// Space to leave around each allocation.
+    ///
+    /// The returned rectangles have the requested size and are inset by this amount on each
+    /// side of the space they occupy, so that two allocations are at least `2 * padding` apart.
+    /// This avoids bleeding between neighbors when sampling with filtering. The padding is
+    /// counted as allocated space by the statistics and debugging output.
+    ///
+    /// Default value: 0.
+    pub padding: i32,
+}
+
+/// The strategy used to select where to allocate an item among the shelves that can hold it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum Placement {
+    /// Visit all shelves and select the one wasting the least vertical space (the shelf
+    /// with the smallest height that fits).
+    BestFit,
+    /// Select the first shelf that fits, in the order they were added. This is faster
+    /// but typically wastes more space when the item heights vary.
+    FirstFit,
+}
+
+/// The layout of the ids of a `BucketedAtlasAllocator`.
+///
+/// Allocation ids are 32 bits and encode the index of the bin containing the allocation,
+/// the index of the item in the bin and a generation. The generation always uses 8 bits,
+/// the remaining 24 bits are split between the bin and item indices, which bounds the
+/// number of bins in the atlas and of items per bin:
+///
+/// - More bins allow more shelves and smaller bins, which helps with large atlases.
+/// - More items per bin help with large numbers of tiny items, since a bin can't hold any
+///   more item once its item limit is reached (even if some were deallocated), forcing the
+///   creation of new shelves.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub enum IdLayout {
+    /// 12 bits for the bin index and 12 bits for the item index: up to 4095 bins with
+    /// up to 4095 items each.
+    Balanced,
+    /// 10 bits for the bin index and 14 bits for the item index: up to 1023 bins with
+    /// up to 16383 items each.
+    ManyItems,
+    /// 14 bits for the bin index and 10 bits for the item index: up to 16383 bins with
+    /// up to 1023 items each.
+    ManyBins,
 }
 
+impl IdLayout {
+    /// Number of bits used for the bin index.
+    pub const fn bin_bits(self) -> u32 {
+        match self {
+            IdLayout::Balanced => 12,
+            IdLayout::ManyItems => 10,
+            IdLayout::ManyBins => 14,
+        }
+    }
+
+    /// Number of bits used for the item index.
+    pub const fn item_bits(self) -> u32 {
+        match self {
+            IdLayout::Balanced => 12,
+            IdLayout::ManyItems => 14,
+            IdLayout::ManyBins => 10,
+        }
+    }
+
+    /// Number of bits used for the generation.
+    pub const fn generation_bits(self) -> u32 {
+        8
+    }
+}
+
+const _: () = assert!(IdLayout::Balanced.bin_bits() + IdLayout::Balanced.item_bits() + IdLayout::Balanced.generation_bits() == 32);
+const _: () = assert!(IdLayout::ManyItems.bin_bits() + IdLayout::ManyItems.item_bits() + IdLayout::ManyItems.generation_bits() == 32);
+const _: () = assert!(IdLayout::ManyBins.bin_bits() + IdLayout::ManyBins.item_bits() + IdLayout::ManyBins.generation_bits() == 32);
+// Bin indices must fit in 16 bits.
+const _: () = assert!(IdLayout::ManyBins.bin_bits() < 16);
+
 pub const DEFAULT_OPTIONS: AllocatorOptions = AllocatorOptions {
     vertical_shelves: false,
     alignment: size2(1, 1),
     num_columns: 1,
+    max_shelves: None,
+    stable_ids: false,
+    reserve_white_pixel: false,
+    fixed_shelf_height: None,
+    tile_size: None,
+    id_layout: IdLayout::Balanced,
+    placement: Placement::BestFit,
+    force_pow2: false,
+    padding: 0,
 };
 
 impl Default for AllocatorOptions {
@@ -123,18 +323,350 @@ impl Default for AllocatorOptions {
     }
 }
 
+impl AllocatorOptions {
+    /// Start building options from the default ones.
+    ///
+    /// ```
+    /// use etagere::*;
+    /// let options = AllocatorOptions::builder()
+    ///     .alignment(size2(4, 4))
+    ///     .num_columns(2)
+    ///     .build();
+    /// # assert_eq!(options, AllocatorOptions { alignment: size2(4, 4), num_columns: 2, ..DEFAULT_OPTIONS });
+    /// ```
+    pub fn builder() -> AllocatorOptionsBuilder {
+        AllocatorOptionsBuilder { options: DEFAULT_OPTIONS }
+    }
+}
+
+/// Builds `AllocatorOptions` with chained method calls, see `AllocatorOptions::builder`.
+#[derive(Copy, Clone, Debug)]
+pub struct AllocatorOptionsBuilder {
+    options: AllocatorOptions,
+}
+
+impl AllocatorOptionsBuilder {
+    /// See [`AllocatorOptions::alignment`](struct.AllocatorOptions.html#structfield.alignment).
+    pub fn alignment(mut self, alignment: Size) -> Self {
+        self.options.alignment = alignment;
+        self
+    }
+
+    /// See [`AllocatorOptions::vertical_shelves`](struct.AllocatorOptions.html#structfield.vertical_shelves).
+    pub fn vertical_shelves(mut self, vertical_shelves: bool) -> Self {
+        self.options.vertical_shelves = vertical_shelves;
+        self
+    }
+
+    /// See [`AllocatorOptions::num_columns`](struct.AllocatorOptions.html#structfield.num_columns).
+    pub fn num_columns(mut self, num_columns: i32) -> Self {
+        self.options.num_columns = num_columns;
+        self
+    }
+
+    /// See [`AllocatorOptions::max_shelves`](struct.AllocatorOptions.html#structfield.max_shelves).
+    pub fn max_shelves(mut self, max_shelves: Option<u16>) -> Self {
+        self.options.max_shelves = max_shelves;
+        self
+    }
+
+    /// See [`AllocatorOptions::stable_ids`](struct.AllocatorOptions.html#structfield.stable_ids).
+    pub fn stable_ids(mut self, stable_ids: bool) -> Self {
+        self.options.stable_ids = stable_ids;
+        self
+    }
+
+    /// See [`AllocatorOptions::reserve_white_pixel`](struct.AllocatorOptions.html#structfield.reserve_white_pixel).
+    pub fn reserve_white_pixel(mut self, reserve_white_pixel: bool) -> Self {
+        self.options.reserve_white_pixel = reserve_white_pixel;
+        self
+    }
+
+    /// See [`AllocatorOptions::fixed_shelf_height`](struct.AllocatorOptions.html#structfield.fixed_shelf_height).
+    pub fn fixed_shelf_height(mut self, fixed_shelf_height: Option<u16>) -> Self {
+        self.options.fixed_shelf_height = fixed_shelf_height;
+        self
+    }
+
+    /// See [`AllocatorOptions::tile_size`](struct.AllocatorOptions.html#structfield.tile_size).
+    pub fn tile_size(mut self, tile_size: Option<Size>) -> Self {
+        self.options.tile_size = tile_size;
+        self
+    }
+
+    /// See [`AllocatorOptions::id_layout`](struct.AllocatorOptions.html#structfield.id_layout).
+    pub fn id_layout(mut self, id_layout: IdLayout) -> Self {
+        self.options.id_layout = id_layout;
+        self
+    }
+
+    /// See [`AllocatorOptions::placement`](struct.AllocatorOptions.html#structfield.placement).
+    pub fn placement(mut self, placement: Placement) -> Self {
+        self.options.placement = placement;
+        self
+    }
+
+    /// See [`AllocatorOptions::force_pow2`](struct.AllocatorOptions.html#structfield.force_pow2).
+    pub fn force_pow2(mut self, force_pow2: bool) -> Self {
+        self.options.force_pow2 = force_pow2;
+        self
+    }
+
+    /// See [`AllocatorOptions::padding`](struct.AllocatorOptions.html#structfield.padding).
+    pub fn padding(mut self, padding: i32) -> Self {
+        self.options.padding = padding;
+        self
+    }
+
+    pub fn build(self) -> AllocatorOptions {
+        self.options
+    }
+}
+
 /// The `AllocId` and `Rectangle` resulting from an allocation.
+///
+/// Allocations are ordered by id and then by rectangle, see `cmp_spatial` to sort them by
+/// position instead.
 #[repr(C)]
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct Allocation {
     pub id: AllocId,
     pub rectangle: Rectangle,
 }
 
+// Implemented manually since `Rectangle` isn't `Ord`.
+impl PartialOrd for Allocation {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Allocation {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        let key = |alloc: &Allocation| {
+            let r = &alloc.rectangle;
+            (alloc.id, r.min.x, r.min.y, r.max.x, r.max.y)
+        };
+
+        key(self).cmp(&key(other))
+    }
+}
+
+/// Order allocations by position: by increasing y and then x of their top-left corner.
+///
+/// Allocations with the same top-left corner are ordered by the bottom-right corner and then
+/// by id, so that sorting produces the same sequence for identical atlas states, regardless
+/// of the allocation order. Useful to produce reproducible output, for example in golden tests.
+///
+/// ```
+/// use etagere::*;
+/// let mut atlas = AtlasAllocator::new(size2(256, 256));
+/// let mut allocations: Vec<Allocation> = (0..4).map(|_| atlas.allocate(size2(64, 16)).unwrap()).collect();
+/// allocations.sort_by(cmp_spatial);
+/// # assert!(allocations.windows(2).all(|pair| cmp_spatial(&pair[0], &pair[1]).is_lt()));
+/// ```
+pub fn cmp_spatial(a: &Allocation, b: &Allocation) -> core::cmp::Ordering {
+    let key = |alloc: &Allocation| {
+        let r = &alloc.rectangle;
+        (r.min.y, r.min.x, r.max.y, r.max.x, alloc.id.0)
+    };
+
+    key(a).cmp(&key(b))
+}
+
+/// Options to tweak the SVG output of the allocators, see `dump_into_svg_with_options`.
+#[cfg(feature = "std")]
+pub struct SvgOptions<'l> {
+    /// Fill color of the allocated space.
+    ///
+    /// Default value: rgb(70, 70, 180).
+    pub allocated_color: svg_fmt::Color,
+    /// Fill color of the free space.
+    ///
+    /// Default value: rgb(50, 50, 50).
+    pub free_color: svg_fmt::Color,
+    /// Fill color of the space that isn't part of any shelf.
+    ///
+    /// Default value: rgb(40, 40, 40).
+    pub background_color: svg_fmt::Color,
+    /// Width of the black outline of each rectangle.
+    ///
+    /// Default value: 1.0.
+    pub stroke_width: f32,
+    /// Optionally provides a text label drawn at the center of each allocation.
+    ///
+    /// Default value: None.
+    pub label: Option<&'l dyn Fn(AllocId) -> Option<String>>,
+}
+
+#[cfg(feature = "std")]
+impl<'l> Default for SvgOptions<'l> {
+    fn default() -> Self {
+        SvgOptions {
+            allocated_color: svg_fmt::rgb(70, 70, 180),
+            free_color: svg_fmt::rgb(50, 50, 50),
+            background_color: svg_fmt::rgb(40, 40, 40),
+            stroke_width: 1.0,
+            label: None,
+        }
+    }
+}
+
+// Write the label of an allocation, if any, centered in its rectangle (in SVG coordinates).
+#[cfg(feature = "std")]
+pub(crate) fn write_svg_label(
+    options: &SvgOptions,
+    id: AllocId,
+    (x, y, w, h): (f32, f32, f32, f32),
+    output: &mut dyn std::io::Write,
+) -> std::io::Result<()> {
+    let label = match options.label.and_then(|label| label(id)) {
+        Some(label) => label,
+        None => return Ok(()),
+    };
+
+    // The label is text content, so it must not be able to inject markup.
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    let size = (h * 0.5).min(12.0);
+    writeln!(
+        output,
+        r#"    {}"#,
+        svg_fmt::text(x + w * 0.5, y + h * 0.5 + size * 0.35, escaped)
+            .size(size)
+            .color(svg_fmt::white())
+            .align(svg_fmt::Align::Center)
+    )
+}
+
+/// The reason why an allocation failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AllocError {
+    /// The requested size has a zero or negative width or height.
+    EmptySize,
+    /// The requested size wouldn't fit even if the atlas was empty.
+    TooLarge,
+    /// There is not enough space left in the atlas for the requested size.
+    OutOfSpace,
+    /// There is space left but the bins that could hold the item reached their maximum
+    /// number of items and no new shelf can be added.
+    BinItemLimit,
+    /// There is space left but the maximum number of shelves or allocations was reached.
+    CapacityExhausted,
+    /// The requested size fits in the atlas but rounding it up to the alignment made it
+    /// too large.
+    AlignmentOverflow {
+        /// The size that was passed to the allocator.
+        requested: Size,
+        /// The requested size rounded up to the alignment.
+        aligned: Size,
+    },
+}
+
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            AllocError::EmptySize => write!(f, "the requested size is empty"),
+            AllocError::TooLarge => write!(f, "the requested size is larger than the atlas"),
+            AllocError::OutOfSpace => write!(f, "not enough space in the atlas"),
+            AllocError::BinItemLimit => write!(f, "the atlas's bins reached their maximum item count"),
+            AllocError::CapacityExhausted => write!(f, "the atlas reached its maximum number of shelves or allocations"),
+            AllocError::AlignmentOverflow { requested, aligned } => write!(
+                f,
+                "the requested size {}x{} is too large for the atlas once aligned to {}x{}",
+                requested.width, requested.height, aligned.width, aligned.height,
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AllocError {}
+
+/// The reason why a deallocation failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DeallocError {
+    /// The id refers to a location of the atlas that was reused by another allocation since
+    /// (typically, the id was already deallocated).
+    StaleGeneration,
+    /// The id doesn't refer to a live allocation of the atlas.
+    NotAllocated,
+}
+
+impl core::fmt::Display for DeallocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            DeallocError::StaleGeneration => write!(f, "the allocation id is stale"),
+            DeallocError::NotAllocated => write!(f, "the allocation id doesn't refer to a live allocation"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DeallocError {}
+
+/// Error returned by operations that can only be performed on an empty atlas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NonEmpty;
+
+impl core::fmt::Display for NonEmpty {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "the atlas is not empty")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NonEmpty {}
+
+/// The reason why `BucketedAtlasAllocator::reserve_height_band` failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ReserveError {
+    /// The atlas already has shelves.
+    NonEmpty,
+    /// The height is zero or negative, taller than the atlas or than the fixed shelf height.
+    InvalidHeight,
+}
+
+impl core::fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ReserveError::NonEmpty => write!(f, "the atlas is not empty"),
+            ReserveError::InvalidHeight => write!(f, "no shelf can be reserved for this height"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReserveError {}
+
+/// Error returned when an alignment isn't compatible with the layout of an atlas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InvalidAlignment;
+
+impl core::fmt::Display for InvalidAlignment {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "the alignment doesn't divide the width of the atlas's columns")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidAlignment {}
+
 /// ID referring to an allocated rectangle.
+///
+/// The ordering of ids doesn't mean anything but is consistent, which allows storing them
+/// in sorted containers such as `BTreeMap`.
 #[repr(C)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct AllocId(pub(crate) u32);
 
@@ -165,3 +697,136 @@ impl AllocId {
     }
 }
 
+/// Format allocations as a table with one line per allocation, sorted by position.
+pub(crate) fn format_allocation_table(mut allocations: Vec<Allocation>) -> String {
+    use core::fmt::Write;
+
+    allocations.sort_by(cmp_spatial);
+
+    let mut output = String::new();
+    writeln!(output, "{:>10} {:>5} {:>5} {:>5} {:>5}", "id", "x", "y", "w", "h").unwrap();
+    for alloc in &allocations {
+        let r = &alloc.rectangle;
+        writeln!(output, "{:>10} {:>5} {:>5} {:>5} {:>5}", alloc.id.0, r.min.x, r.min.y, r.width(), r.height()).unwrap();
+    }
+
+    output
+}
+
+/// Characters used by `format_ascii` for the allocations, cycling through them per shelf.
+const ASCII_SHELF_CHARS: &[u8] = b"#@%&*+";
+
+/// Render the allocations of an atlas as a grid of characters, one per `cell_size` area.
+///
+/// Each cell shows the character of the shelf of the allocation containing the center of the
+/// cell, or `.` for free space. Allocations are provided with the index of their shelf.
+pub(crate) fn format_ascii(size: Size, cell_size: Size, allocations: &[(Rectangle, usize)], output: &mut String) {
+    assert!(!cell_size.is_empty());
+
+    let columns = (size.width + cell_size.width - 1) / cell_size.width;
+    let rows = (size.height + cell_size.height - 1) / cell_size.height;
+    for row in 0..rows {
+        for column in 0..columns {
+            let center = point2(
+                (column * cell_size.width + cell_size.width / 2).min(size.width - 1),
+                (row * cell_size.height + cell_size.height / 2).min(size.height - 1),
+            );
+            let c = allocations
+                .iter()
+                .find(|(rect, _)| rect.contains(center))
+                .map_or(b'.', |&(_, shelf)| ASCII_SHELF_CHARS[shelf % ASCII_SHELF_CHARS.len()]);
+            output.push(c as char);
+        }
+        output.push('\n');
+    }
+}
+
+/// Maximum number of shelves listed by the `Debug` output of the allocators.
+pub(crate) const DEBUG_MAX_SHELVES: usize = 16;
+
+/// The size of an atlas created with these options, see `AllocatorOptions::force_pow2`.
+pub(crate) fn atlas_size(size: Size, options: &AllocatorOptions) -> Size {
+    if options.force_pow2 {
+        // Invalid sizes are reported by the allocators.
+        pow2_size(size).unwrap_or(size)
+    } else {
+        size
+    }
+}
+
+/// Round both dimensions of a size up to the next power of two.
+///
+/// Returns `None` if the size is empty or if the rounded size overflows.
+pub(crate) fn pow2_size(size: Size) -> Option<Size> {
+    if size.is_empty() {
+        return None;
+    }
+
+    let w = (size.width as u32).checked_next_power_of_two()?;
+    let h = (size.height as u32).checked_next_power_of_two()?;
+    if w > i32::MAX as u32 || h > i32::MAX as u32 {
+        return None;
+    }
+
+    Some(size2(w as i32, h as i32))
+}
+
+/// Shrink an allocation's rectangle to the requested size.
+pub(crate) fn trim_allocation(allocation: Allocation, size: Size) -> Allocation {
+    Allocation {
+        id: allocation.id,
+        rectangle: Rectangle {
+            min: allocation.rectangle.min,
+            max: allocation.rectangle.min + size.to_vector(),
+        },
+    }
+}
+
+#[test]
+fn spatial_ordering() {
+    use core::cmp::Ordering;
+
+    let alloc = |id, x, y| Allocation {
+        id: AllocId(id),
+        rectangle: Rectangle { min: point2(x, y), max: point2(x + 10, y + 10) },
+    };
+
+    assert_eq!(cmp_spatial(&alloc(0, 20, 0), &alloc(1, 0, 10)), Ordering::Less);
+    assert_eq!(cmp_spatial(&alloc(0, 20, 10), &alloc(1, 0, 10)), Ordering::Greater);
+    assert_eq!(cmp_spatial(&alloc(1, 0, 10), &alloc(0, 0, 10)), Ordering::Greater);
+    assert_eq!(cmp_spatial(&alloc(1, 0, 10), &alloc(1, 0, 10)), Ordering::Equal);
+
+    let mut allocations = [alloc(3, 10, 10), alloc(1, 0, 10), alloc(2, 50, 0)];
+    allocations.sort_by(cmp_spatial);
+    let ids: Vec<u32> = allocations.iter().map(|alloc| alloc.id.0).collect();
+    assert_eq!(ids, vec![2, 1, 3]);
+}
+
+#[test]
+fn ordered_allocations() {
+    use alloc::collections::BTreeSet;
+
+    let mut atlas = AtlasAllocator::new(size2(256, 256));
+    let allocations: Vec<Allocation> = (0..8).map(|_| atlas.allocate(size2(16, 16)).unwrap()).collect();
+
+    let mut set = BTreeSet::new();
+    for alloc in allocations.iter().rev() {
+        assert!(set.insert(*alloc));
+    }
+    assert!(!set.insert(allocations[3]));
+    assert_eq!(set.len(), allocations.len());
+    assert!(set.iter().zip(set.iter().skip(1)).all(|(a, b)| a.id < b.id));
+
+    let ids: BTreeSet<AllocId> = allocations.iter().map(|alloc| alloc.id).collect();
+    assert_eq!(ids.len(), allocations.len());
+    assert!(ids.contains(&allocations[5].id));
+}
+
+#[test]
+fn pow2_sizes() {
+    assert_eq!(pow2_size(size2(1, 1)), Some(size2(1, 1)));
+    assert_eq!(pow2_size(size2(3, 16)), Some(size2(4, 16)));
+    assert_eq!(pow2_size(size2(100, 513)), Some(size2(128, 1024)));
+    assert_eq!(pow2_size(size2(0, 16)), None);
+    assert_eq!(pow2_size(size2(i32::MAX, 16)), None);
+}
@@ -0,0 +1,152 @@
+use alloc::vec::Vec;
+
+use crate::{AllocatorOptions, AllocId, Allocation, BucketedAtlasAllocator, Size};
+use crate::id_table::IdTable;
+
+/// Index of one of the textures managed by a [`MultiAtlas`](struct.MultiAtlas.html).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct TextureIndex(pub u32);
+
+/// Manages multiple atlases of the same size with a single allocation id space.
+///
+/// Allocations go into the first atlas that can fit them, and a new atlas is added when none
+/// of the existing ones can, up to a maximum number of atlases (textures).
+///
+/// The ids used by the underlying allocators don't have spare bits to encode the texture index,
+/// so the ids returned by the multi-atlas refer to entries of a table mapping them to the
+/// texture and allocator id. As a result ids are unique across all textures and can be used as
+/// keys on their own. They must only be used with the multi-atlas that created them.
+#[derive(Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
+pub struct MultiAtlas {
+    atlases: Vec<BucketedAtlasAllocator>,
+    size: Size,
+    options: AllocatorOptions,
+    max_atlases: usize,
+    ids: IdTable<(TextureIndex, AllocId)>,
+}
+
+impl MultiAtlas {
+    /// Create a multi-atlas that can hold up to `max_atlases` atlases of the provided size.
+    ///
+    /// Atlases are created lazily.
+    pub fn new(size: Size, options: &AllocatorOptions, max_atlases: usize) -> Self {
+        MultiAtlas {
+            atlases: Vec::new(),
+            size,
+            options: *options,
+            max_atlases,
+            ids: IdTable::new(),
+        }
+    }
+
+    /// Allocate a rectangle in one of the atlases.
+    ///
+    /// Returns the index of the texture as well as the allocation. The rectangle is in the
+    /// coordinate space of the texture.
+    pub fn allocate(&mut self, size: Size) -> Option<(TextureIndex, Allocation)> {
+        if !self.ids.has_room() {
+            return None;
+        }
+
+        let mut allocation = None;
+        for (index, atlas) in self.atlases.iter_mut().enumerate() {
+            if let Some(alloc) = atlas.allocate(size) {
+                allocation = Some((TextureIndex(index as u32), alloc));
+                break;
+            }
+        }
+
+        if allocation.is_none() && self.atlases.len() < self.max_atlases {
+            let mut atlas = BucketedAtlasAllocator::with_options(self.size, &self.options);
+            if let Some(alloc) = atlas.allocate(size) {
+                allocation = Some((TextureIndex(self.atlases.len() as u32), alloc));
+            }
+            // Don't add the atlas if the item can't even fit in an empty one.
+            if allocation.is_some() {
+                self.atlases.push(atlas);
+            }
+        }
+
+        let (texture, alloc) = allocation?;
+
+        let id = self.ids.insert((texture, alloc.id));
+
+        Some((texture, Allocation { id, rectangle: alloc.rectangle }))
+    }
+
+    /// Deallocate a rectangle.
+    pub fn deallocate(&mut self, id: AllocId) {
+        let (texture, id) = self.ids.remove(id);
+        self.atlases[texture.0 as usize].deallocate(id);
+    }
+
+    /// Returns the index of the texture that holds the allocation.
+    pub fn texture_index(&self, id: AllocId) -> TextureIndex {
+        self.ids.get(id).0
+    }
+
+    /// Access one of the atlases.
+    pub fn atlas(&self, index: TextureIndex) -> &BucketedAtlasAllocator {
+        &self.atlases[index.0 as usize]
+    }
+
+    /// Number of atlases that are currently in use.
+    pub fn num_atlases(&self) -> usize {
+        self.atlases.len()
+    }
+
+    /// Size of each atlas.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.atlases.iter().all(|atlas| atlas.is_empty())
+    }
+
+    /// Deallocate all rectangles and remove all atlases.
+    pub fn clear(&mut self) {
+        self.atlases.clear();
+        self.ids.clear();
+    }
+}
+
+#[test]
+fn multi_atlas() {
+    use crate::{size2, DEFAULT_OPTIONS};
+
+    let mut atlas = MultiAtlas::new(size2(256, 256), &DEFAULT_OPTIONS, 2);
+    assert!(atlas.is_empty());
+    assert_eq!(atlas.num_atlases(), 0);
+
+    assert!(atlas.allocate(size2(300, 10)).is_none());
+    assert_eq!(atlas.num_atlases(), 0);
+
+    let (t0, a) = atlas.allocate(size2(256, 256)).unwrap();
+    let (t1, b) = atlas.allocate(size2(256, 200)).unwrap();
+    let (t2, c) = atlas.allocate(size2(100, 24)).unwrap();
+    assert_eq!(t0, TextureIndex(0));
+    assert_eq!(t1, TextureIndex(1));
+    assert_eq!(t2, TextureIndex(1));
+    assert_eq!(atlas.num_atlases(), 2);
+    assert_ne!(a.id, b.id);
+    assert_ne!(b.id, c.id);
+    assert_eq!(atlas.texture_index(c.id), TextureIndex(1));
+
+    // Both atlases are full and we can't add more.
+    assert!(atlas.allocate(size2(256, 256)).is_none());
+
+    atlas.deallocate(a.id);
+    assert!(atlas.atlas(TextureIndex(0)).is_empty());
+
+    let (t3, d) = atlas.allocate(size2(256, 256)).unwrap();
+    assert_eq!(t3, TextureIndex(0));
+    assert_ne!(a.id, d.id);
+
+    atlas.deallocate(b.id);
+    atlas.deallocate(c.id);
+    atlas.deallocate(d.id);
+    assert!(atlas.is_empty());
+}
@@ -0,0 +1,94 @@
+//! PNG output for debugging the allocators.
+
+use std::io::Write;
+
+use crate::{Rectangle, Size};
+
+const BACKGROUND: [u8; 3] = [40, 40, 40];
+const ALLOCATED: [u8; 3] = [70, 70, 180];
+const FREE: [u8; 3] = [50, 50, 50];
+const BORDER: [u8; 3] = [0, 0, 0];
+
+/// Rasterize a partition of an atlas at one pixel per unit and write it as a PNG image.
+///
+/// Uses the colors of the SVG output. Each rectangle is outlined like the SVG strokes.
+pub(crate) fn dump_partition(
+    size: Size,
+    partition: &[(Rectangle, bool)],
+    output: &mut dyn Write,
+) -> std::io::Result<()> {
+    let width = size.width as usize;
+    let height = size.height as usize;
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for _ in 0..(width * height) {
+        pixels.extend_from_slice(&BACKGROUND);
+    }
+
+    for &(rect, allocated) in partition {
+        let color = if allocated { ALLOCATED } else { FREE };
+        let rect = match rect.intersection(&Rectangle::from_size(size)) {
+            Some(rect) => rect,
+            None => continue,
+        };
+        for y in rect.min.y..rect.max.y {
+            for x in rect.min.x..rect.max.x {
+                let border = x == rect.min.x || y == rect.min.y
+                    || x == rect.max.x - 1 || y == rect.max.y - 1;
+                let offset = (y as usize * width + x as usize) * 3;
+                pixels[offset..offset + 3].copy_from_slice(if border { &BORDER } else { &color });
+            }
+        }
+    }
+
+    write_rgb(width as u32, height as u32, &pixels, output)
+}
+
+/// Write 8-bit RGB pixels (row by row, without padding) as a PNG image.
+fn write_rgb(
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+    output: &mut dyn Write,
+) -> std::io::Result<()> {
+    let mut encoder = ::png::Encoder::new(output, width, height);
+    encoder.set_color(::png::ColorType::Rgb);
+    encoder.set_depth(::png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+#[test]
+fn png_encoding() {
+    use crate::{size2, AtlasAllocator, BucketedAtlasAllocator};
+
+    let mut atlas = AtlasAllocator::new(size2(300, 200));
+    let alloc = atlas.allocate(size2(100, 50)).unwrap();
+    let mut output = Vec::new();
+    atlas.dump_png(&mut output).unwrap();
+
+    let mut reader = ::png::Decoder::new(&output[..]).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!((info.width, info.height), (300, 200));
+    assert_eq!(info.color_type, ::png::ColorType::Rgb);
+
+    // Inside of the allocation, and inside of the free space.
+    let pixel = |x: i32, y: i32| {
+        let offset = (y as usize * 300 + x as usize) * 3;
+        [pixels[offset], pixels[offset + 1], pixels[offset + 2]]
+    };
+    let center = alloc.rectangle.center();
+    assert_eq!(pixel(center.x, center.y), ALLOCATED);
+    assert_eq!(pixel(250, 150), FREE);
+
+    let mut atlas = BucketedAtlasAllocator::new(size2(256, 256));
+    atlas.allocate(size2(10, 10)).unwrap();
+    let mut output = Vec::new();
+    atlas.dump_png(&mut output).unwrap();
+    assert!(::png::Decoder::new(&output[..]).read_info().is_ok());
+}
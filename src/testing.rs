@@ -0,0 +1,84 @@
+// Helpers shared by the tests of the allocators and the `testing` feature.
+
+#[cfg(test)]
+use alloc::vec::Vec;
+
+#[cfg(test)]
+use crate::{AllocId, Allocation, AtlasAllocator, BucketedAtlasAllocator, Size, size2};
+
+/// A small deterministic pseudo-random number generator, so that the same seed always produces
+/// the same atlas.
+pub(crate) struct Lcg(u32);
+
+impl Lcg {
+    pub fn new(seed: u32) -> Self {
+        Lcg(seed)
+    }
+
+    pub fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        self.0 >> 16
+    }
+}
+
+#[cfg(test)]
+pub(crate) trait TestAllocator {
+    fn allocate(&mut self, size: Size) -> Option<Allocation>;
+    fn deallocate(&mut self, id: AllocId);
+}
+
+#[cfg(test)]
+impl<T: Default> TestAllocator for AtlasAllocator<T> {
+    fn allocate(&mut self, size: Size) -> Option<Allocation> {
+        AtlasAllocator::allocate(self, size)
+    }
+
+    fn deallocate(&mut self, id: AllocId) {
+        AtlasAllocator::deallocate(self, id);
+    }
+}
+
+#[cfg(test)]
+impl TestAllocator for BucketedAtlasAllocator {
+    fn allocate(&mut self, size: Size) -> Option<Allocation> {
+        BucketedAtlasAllocator::allocate(self, size)
+    }
+
+    fn deallocate(&mut self, id: AllocId) {
+        BucketedAtlasAllocator::deallocate(self, id)
+    }
+}
+
+/// Run a pseudo-random sequence of `steps` allocations and deallocations with sizes up to
+/// `max_side`, and return the allocations that are still live at the end.
+///
+/// `check` is called after each step with the live allocations. If the step allocated a
+/// rectangle, it is the last of them and the requested size is provided as well.
+#[cfg(test)]
+pub(crate) fn random_operations<A: TestAllocator>(
+    atlas: &mut A,
+    seed: u32,
+    steps: usize,
+    max_side: u32,
+    mut check: impl FnMut(&A, &[Allocation], Option<Size>),
+) -> Vec<Allocation> {
+    let mut rng = Lcg::new(seed);
+    let mut allocations = Vec::new();
+    for _ in 0..steps {
+        let mut requested = None;
+        if rng.next() % 3 != 0 {
+            let size = size2(1 + (rng.next() % max_side) as i32, 1 + (rng.next() % max_side) as i32);
+            if let Some(alloc) = atlas.allocate(size) {
+                allocations.push(alloc);
+                requested = Some(size);
+            }
+        } else if !allocations.is_empty() {
+            let idx = rng.next() as usize % allocations.len();
+            atlas.deallocate(allocations.swap_remove(idx).id);
+        }
+
+        check(atlas, &allocations, requested);
+    }
+
+    allocations
+}
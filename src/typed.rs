@@ -0,0 +1,173 @@
+use core::fmt;
+use core::marker::PhantomData;
+
+use euclid::{Box2D, Size2D};
+
+use crate::{AllocId, Allocation, AllocatorOptions, AtlasAllocator, BucketedAtlasAllocator};
+
+/// An allocation of a [`TypedAtlasAllocator`](struct.TypedAtlasAllocator.html), with the
+/// rectangle expressed in the unit `U`.
+pub struct TypedAllocation<U> {
+    pub id: AllocId,
+    pub rectangle: Box2D<i32, U>,
+}
+
+impl<U> TypedAllocation<U> {
+    fn new(allocation: Allocation) -> Self {
+        TypedAllocation {
+            id: allocation.id,
+            rectangle: Box2D::from_untyped(&allocation.rectangle),
+        }
+    }
+
+    /// The same allocation without the unit.
+    pub fn to_untyped(&self) -> Allocation {
+        Allocation {
+            id: self.id,
+            rectangle: self.rectangle.to_untyped(),
+        }
+    }
+}
+
+// Implemented manually to avoid requiring the unit to implement these traits.
+impl<U> Copy for TypedAllocation<U> {}
+
+impl<U> Clone for TypedAllocation<U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<U> PartialEq for TypedAllocation<U> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.rectangle == other.rectangle
+    }
+}
+
+impl<U> fmt::Debug for TypedAllocation<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypedAllocation")
+            .field("id", &self.id)
+            .field("rectangle", &self.rectangle)
+            .finish()
+    }
+}
+
+/// Wraps an atlas allocator so that sizes and rectangles are tagged with a `euclid` unit.
+///
+/// The allocators work with `euclid::default` types which can't be told apart, which loses
+/// the type safety of code distinguishing for example texel space from normalized texture
+/// coordinates. This wrapper only converts the sizes and rectangles at its boundary, the
+/// wrapped allocator can still be accessed with `get_ref` and `get_mut`.
+///
+/// ```
+/// use etagere::*;
+///
+/// struct Texels;
+///
+/// let mut atlas = TypedAtlasAllocator::<Texels>::new(euclid::size2(1024, 1024));
+/// let alloc = atlas.allocate(euclid::size2(128, 128)).unwrap();
+/// let rect: euclid::Box2D<i32, Texels> = alloc.rectangle;
+/// # assert_eq!(rect.size(), euclid::size2(128, 128));
+/// ```
+pub struct TypedAtlasAllocator<U, A = AtlasAllocator> {
+    allocator: A,
+    _unit: PhantomData<U>,
+}
+
+impl<U, A> TypedAtlasAllocator<U, A> {
+    /// Wrap an existing allocator.
+    pub fn from_untyped(allocator: A) -> Self {
+        TypedAtlasAllocator {
+            allocator,
+            _unit: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &A {
+        &self.allocator
+    }
+
+    pub fn get_mut(&mut self) -> &mut A {
+        &mut self.allocator
+    }
+
+    pub fn into_inner(self) -> A {
+        self.allocator
+    }
+}
+
+macro_rules! impl_typed_allocator {
+    ($Allocator:ty) => {
+        impl<U> TypedAtlasAllocator<U, $Allocator> {
+            /// Create an atlas allocator with provided options.
+            pub fn with_options(size: Size2D<i32, U>, options: &AllocatorOptions) -> Self {
+                Self::from_untyped(<$Allocator>::with_options(size.to_untyped(), options))
+            }
+
+            /// Create an atlas allocator with default options.
+            pub fn new(size: Size2D<i32, U>) -> Self {
+                Self::from_untyped(<$Allocator>::new(size.to_untyped()))
+            }
+
+            /// Allocate a rectangle in the atlas.
+            pub fn allocate(&mut self, size: Size2D<i32, U>) -> Option<TypedAllocation<U>> {
+                self.allocator.allocate(size.to_untyped()).map(TypedAllocation::new)
+            }
+
+            /// Deallocate a rectangle in the atlas.
+            pub fn deallocate(&mut self, id: AllocId) {
+                self.allocator.deallocate(id)
+            }
+
+            pub fn clear(&mut self) {
+                self.allocator.clear()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.allocator.is_empty()
+            }
+
+            pub fn size(&self) -> Size2D<i32, U> {
+                Size2D::from_untyped(self.allocator.size())
+            }
+
+            /// Returns the rectangle of the allocation.
+            pub fn get(&self, id: AllocId) -> Box2D<i32, U> {
+                Box2D::from_untyped(&self.allocator.get(id))
+            }
+
+            /// Iterate over the allocations.
+            pub fn iter(&self) -> impl Iterator<Item = TypedAllocation<U>> + '_ {
+                self.allocator.iter().map(TypedAllocation::new)
+            }
+        }
+    };
+}
+
+impl_typed_allocator!(AtlasAllocator);
+impl_typed_allocator!(BucketedAtlasAllocator);
+
+#[test]
+fn typed_allocator() {
+    struct Texels;
+
+    let mut atlas = TypedAtlasAllocator::<Texels, BucketedAtlasAllocator>::new(euclid::size2(256, 256));
+    assert_eq!(atlas.size(), euclid::size2(256, 256));
+
+    let a = atlas.allocate(euclid::size2(32, 32)).unwrap();
+    let b = atlas.allocate(euclid::size2(64, 16)).unwrap();
+    assert_eq!(atlas.get(a.id), a.rectangle);
+    assert_eq!(atlas.get_ref().get(b.id), b.rectangle.to_untyped());
+    assert_eq!(atlas.iter().count(), 2);
+
+    atlas.deallocate(a.id);
+    atlas.deallocate(b.id);
+    assert!(atlas.is_empty());
+
+    let mut atlas = TypedAtlasAllocator::<Texels>::new(euclid::size2(256, 256));
+    let a = atlas.allocate(euclid::size2(32, 32)).unwrap();
+    assert_eq!(a.to_untyped(), atlas.get_ref().iter().next().unwrap());
+    atlas.clear();
+    assert!(atlas.into_inner().is_empty());
+}
@@ -0,0 +1,39 @@
+#![cfg(feature = "serialization")]
+
+use etagere::*;
+
+#[test]
+fn bucketed_round_trip() {
+    let mut reference = BucketedAtlasAllocator::new(size2(512, 512));
+    let mut ids = Vec::new();
+    for i in 0..20 {
+        ids.push(reference.allocate(size2(10 + i * 3, 8 + i % 5 * 6)).unwrap().id);
+    }
+    for id in ids.iter().step_by(4) {
+        reference.deallocate(*id);
+    }
+
+    let serialized = ron::ser::to_string(&reference).unwrap();
+    let mut atlas: BucketedAtlasAllocator = ron::de::from_str(&serialized).unwrap();
+
+    assert_eq!(atlas.size(), reference.size());
+    assert_eq!(atlas.allocated_space(), reference.allocated_space());
+    for id in ids.iter().skip(1).step_by(4) {
+        assert_eq!(atlas.get(*id), reference.get(*id));
+    }
+
+    // Both atlases keep allocating the same way.
+    for i in 0..20 {
+        let size = size2(30 - i, 12 + i % 3 * 10);
+        let expected = reference.allocate(size);
+        let alloc = atlas.allocate(size);
+        assert_eq!(alloc, expected);
+
+        if let Some(alloc) = alloc {
+            for other in atlas.iter().filter(|other| other.id != alloc.id) {
+                assert!(!alloc.rectangle.intersects(&other.rectangle));
+            }
+        }
+    }
+    assert_eq!(atlas.allocated_space(), reference.allocated_space());
+}